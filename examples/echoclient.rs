@@ -12,7 +12,7 @@ fn main() -> Result<()> {
 }
 
 fn echo_client(remote_addr: Ipv4Addr, remote_port: u16) -> Result<()> {
-    let tcp = TCP::new();
+    let tcp = TCP::new()?;
     let sock_id = tcp.connect(remote_addr, remote_port)?;
 
     let cloned_tcp = tcp.clone();
@@ -25,7 +25,11 @@ fn echo_client(remote_addr: Ipv4Addr, remote_port: u16) -> Result<()> {
 
     loop {
         let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+        if io::stdin().read_line(&mut input)? == 0 {
+            // 標準入力が閉じられたら接続も閉じる
+            tcp.close(sock_id)?;
+            return Ok(());
+        }
 
         tcp.send(sock_id, input.as_bytes())?;
 
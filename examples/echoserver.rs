@@ -1,7 +1,8 @@
 use anyhow::Result;
 use core::str;
+use std::io::{Read, Write};
 use std::{env, net::Ipv4Addr};
-use toytcp::tcp::TCP;
+use toytcp::{tcp::TCP, TcpListener};
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
@@ -11,34 +12,28 @@ fn main() -> Result<()> {
 }
 
 fn echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
-    let tcp = TCP::new();
-    let listening_socket = tcp.listen(local_addr, local_port)?;
+    let tcp = TCP::new()?;
+    let listener = TcpListener::bind(&tcp, local_addr, local_port)?;
     dbg!("listening...");
-    loop {
-        let connected_sock_id = tcp.accept(listening_socket)?;
-        dbg!(
-            "accepted!",
-            connected_sock_id.remote_addr,
-            connected_sock_id.remote_port
-        );
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        dbg!("accepted!", stream.peer_addr()?);
 
-        let cloned_tcp = tcp.clone();
         std::thread::spawn(move || {
             let mut buffer = [0; 1024];
             loop {
-                let nbytes = cloned_tcp.recv(connected_sock_id, &mut buffer).unwrap();
+                let nbytes = stream.read(&mut buffer).unwrap();
 
                 if nbytes == 0 {
+                    // dropで閉じる
                     dbg!("closing connection...");
-                    cloned_tcp.close(connected_sock_id).unwrap();
                     return;
                 }
 
                 print!("> {}", str::from_utf8(&buffer[..nbytes]).unwrap());
-                cloned_tcp
-                    .send(connected_sock_id, &buffer[..nbytes])
-                    .unwrap();
+                stream.write_all(&buffer[..nbytes]).unwrap();
             }
         });
     }
+    Ok(())
 }
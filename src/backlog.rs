@@ -0,0 +1,189 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::packet::TCPPacket;
+use crate::socket::{Addr, Endpoint, SockID};
+
+// 1ソケットあたりバックログに溜めておけるパケット数の上限
+// これを超えた分は破棄する(ackされないので相手が再送してくれる)
+const MAX_BACKLOG_PER_SOCKET: usize = 256;
+
+/// 受信済みでまだハンドラに渡していないパケット
+pub struct ReceivedPacket {
+    pub packet: TCPPacket,
+    pub local_addr: Addr,
+    pub remote_addr: Addr,
+}
+
+impl ReceivedPacket {
+    /// パケットが属する接続のID. 相手視点のsrc/destを入れ替えてこちら視点にする
+    pub fn sock_id(&self) -> SockID {
+        SockID::new(
+            Endpoint::new(self.local_addr, self.packet.get_dest()),
+            Endpoint::new(self.remote_addr, self.packet.get_src()),
+        )
+    }
+}
+
+/// 受信スレッドで処理待ちのパケットを接続(4タプル)毎に保持するバックログ
+/// 1つの接続が大量にセグメントを送ってきても他の接続が待たされ続けないように,
+/// 1イテレーションで1接続が処理できるパケット数に上限を設けてラウンドロビンで取り出す
+#[derive(Default)]
+pub struct ReceiveBacklog {
+    queues: HashMap<SockID, VecDeque<ReceivedPacket>>,
+    // ラウンドロビンで処理する順番. queuesに存在するキーと一致する
+    order: VecDeque<SockID>,
+    len: usize,
+}
+
+impl ReceiveBacklog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// パケットをその接続のバックログに積む. 上限を超えていれば破棄してfalseを返す
+    pub fn push(&mut self, received: ReceivedPacket) -> bool {
+        let key = received.sock_id();
+
+        let queue = self.queues.entry(key).or_insert_with(|| {
+            self.order.push_back(key);
+            VecDeque::new()
+        });
+        if queue.len() >= MAX_BACKLOG_PER_SOCKET {
+            return false;
+        }
+        queue.push_back(received);
+        self.len += 1;
+        true
+    }
+
+    /// 各接続から最大budget個ずつパケットを取り出す
+    /// 取り出しきれなかったパケットはバックログに残り, 次のイテレーションで処理される
+    pub fn take_round(&mut self, budget: usize) -> Vec<ReceivedPacket> {
+        let mut packets = Vec::new();
+
+        for _ in 0..self.order.len() {
+            let key = match self.order.pop_front() {
+                Some(key) => key,
+                None => break,
+            };
+            let queue = match self.queues.get_mut(&key) {
+                Some(queue) => queue,
+                None => continue,
+            };
+
+            for _ in 0..budget {
+                match queue.pop_front() {
+                    Some(received) => packets.push(received),
+                    None => break,
+                }
+            }
+
+            if queue.is_empty() {
+                self.queues.remove(&key);
+            } else {
+                self.order.push_back(key);
+            }
+        }
+
+        self.len -= packets.len();
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // src_portから来たseqのパケット
+    fn received(src_port: u16, seq: u32) -> ReceivedPacket {
+        let mut packet = TCPPacket::new(0);
+        packet.set_src(src_port);
+        packet.set_dest(40000);
+        packet.set_seq(seq);
+        ReceivedPacket {
+            packet,
+            local_addr: Ipv4Addr::LOCALHOST,
+            remote_addr: Ipv4Addr::LOCALHOST,
+        }
+    }
+
+    fn ports_and_seqs(packets: &[ReceivedPacket]) -> Vec<(u16, u32)> {
+        packets
+            .iter()
+            .map(|received| (received.packet.get_src(), received.packet.get_seq()))
+            .collect()
+    }
+
+    #[test]
+    fn each_round_takes_at_most_budget_packets_per_connection() {
+        let mut backlog = ReceiveBacklog::new();
+        assert!(backlog.is_empty());
+        // 50000が大量に送ってきた後に50001, 50002が少しだけ送ってきた
+        for seq in 0..5 {
+            assert!(backlog.push(received(50000, seq)));
+        }
+        assert!(backlog.push(received(50001, 0)));
+        assert!(backlog.push(received(50002, 0)));
+        assert!(backlog.push(received(50002, 1)));
+
+        // 後から来た接続も最初のラウンドで処理され, 各接続の中の順番は保たれる
+        assert_eq!(
+            ports_and_seqs(&backlog.take_round(2)),
+            vec![(50000, 0), (50000, 1), (50001, 0), (50002, 0), (50002, 1)]
+        );
+        // 残りは次のラウンドに回る
+        assert!(!backlog.is_empty());
+        assert_eq!(
+            ports_and_seqs(&backlog.take_round(2)),
+            vec![(50000, 2), (50000, 3)]
+        );
+        assert_eq!(ports_and_seqs(&backlog.take_round(2)), vec![(50000, 4)]);
+        assert!(backlog.is_empty());
+        assert!(backlog.take_round(2).is_empty());
+    }
+
+    #[test]
+    fn connections_take_turns_across_rounds() {
+        let mut backlog = ReceiveBacklog::new();
+        for seq in 0..2 {
+            backlog.push(received(50000, seq));
+            backlog.push(received(50001, seq));
+        }
+        assert_eq!(
+            ports_and_seqs(&backlog.take_round(1)),
+            vec![(50000, 0), (50001, 0)]
+        );
+        // ラウンドの途中で積まれた新しい接続は, 残っている接続の後ろに並ぶ
+        backlog.push(received(50002, 0));
+        assert_eq!(
+            ports_and_seqs(&backlog.take_round(1)),
+            vec![(50000, 1), (50001, 1), (50002, 0)]
+        );
+        assert!(backlog.is_empty());
+    }
+
+    #[test]
+    fn packets_beyond_the_per_socket_limit_are_dropped() {
+        let mut backlog = ReceiveBacklog::new();
+        for seq in 0..MAX_BACKLOG_PER_SOCKET as u32 {
+            assert!(backlog.push(received(50000, seq)));
+        }
+        assert!(!backlog.push(received(50000, MAX_BACKLOG_PER_SOCKET as u32)));
+        // 上限は接続毎なので, 他の接続のパケットは積める
+        assert!(backlog.push(received(50001, 0)));
+
+        let packets = backlog.take_round(usize::MAX);
+        assert_eq!(packets.len(), MAX_BACKLOG_PER_SOCKET + 1);
+        assert_eq!(
+            ports_and_seqs(&packets[MAX_BACKLOG_PER_SOCKET - 1..]),
+            vec![(50000, MAX_BACKLOG_PER_SOCKET as u32 - 1), (50001, 0)]
+        );
+        // 取り出して空いたら, また積める
+        assert!(backlog.push(received(50000, 0)));
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::{Context, Result};
+use std::{env, path::Path};
+use toytcp::{diagram, eventlog};
+
+/// TcpConfig::event_log_dirで出力したイベントログを集計して表示する
+/// usage: toytcp-analyze [--dot | --mermaid] <log file>...
+/// --dot, --mermaidを指定した場合は集計の代わりに状態遷移図を出力する
+fn main() -> Result<()> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let format = match args.first().map(String::as_str) {
+        Some("--dot") | Some("--mermaid") => Some(args.remove(0)),
+        _ => None,
+    };
+    if args.is_empty() {
+        anyhow::bail!("usage: toytcp-analyze [--dot | --mermaid] <log file>...");
+    }
+
+    for path in args {
+        let records = eventlog::read_log(Path::new(&path)).context(path.clone())?;
+
+        match format.as_deref() {
+            Some("--dot") => print!("{}", diagram::to_dot(&records)),
+            Some("--mermaid") => print!("{}", diagram::to_mermaid(&records)),
+            _ => print_summary(&path, &eventlog::summarize(&records)),
+        }
+    }
+    Ok(())
+}
+
+fn print_summary(path: &str, summary: &eventlog::LogSummary) {
+    println!("{}", path);
+    match summary.handshake_time {
+        Some(t) => println!("  handshake time : {:.3} ms", t as f64 / 1000.0),
+        None => println!("  handshake time : -"),
+    }
+    println!("  retransmissions: {}", summary.retransmissions);
+    println!("  bytes sent     : {}", summary.bytes_sent);
+    println!("  bytes received : {}", summary.bytes_received);
+    println!(
+        "  duration       : {:.3} s",
+        summary.duration as f64 / 1_000_000.0
+    );
+    println!("  throughput     : {:.1} bytes/s", summary.throughput());
+}
@@ -0,0 +1,325 @@
+use anyhow::{bail, Context, Result};
+use rand::Rng;
+use std::{
+    env,
+    net::Ipv4Addr,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use toytcp::{
+    config::{Backend, TcpConfig},
+    filter::SegmentFilter,
+    stats::StreamHash,
+    tcp::{How, SockID, TCP},
+};
+
+const PORT: u16 = 40000;
+/// 接続の最初に送るヘッダの長さ. 閉じ方(1バイト), ペイロードの長さ(8バイト), ハッシュ(8バイト)
+const HEADER_LEN: usize = 17;
+/// TIME_WAIT(2MSL)の接続が消えるまで待つ時間
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(90);
+/// これだけの間どの接続も終わらなければ, どこかで止まっているとみなす
+const STALL_TIMEOUT: Duration = Duration::from_secs(120);
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// ループバックの上で接続の確立と切断を長時間繰り返し, データの破損やソケット/メモリのリークを探す
+/// usage: toytcp-soak [--duration SECS] [--workers N] [--loss RATE] [--max-payload BYTES]
+/// 各接続ではランダムな長さのデータをランダムな大きさとランダムな間隔で書き込み, 受信側でハッシュを比べる
+/// 半分の接続はshutdownで送信側だけを閉じ, 受信側が返すハッシュを読んでから閉じる
+/// 終了時には全ての接続が消え, メモリの使用量が0に戻っていることを確かめる. 失敗すれば終了コード1で終わる
+/// スタックのdbg!の出力が多いので, 標準エラー出力は捨てるとよい(結果は標準出力に出す)
+fn main() -> Result<()> {
+    let options = Options::parse(env::args().skip(1))?;
+    println!("{:?}", options);
+
+    let loss = options.loss;
+    let tcp = TCP::with_config(TcpConfig {
+        backend: Backend::Loopback,
+        // 全ての送信セグメントをlossの確率で落とす
+        egress_filter: Some(SegmentFilter::new(move |_| {
+            loss == 0.0 || !rand::thread_rng().gen_bool(loss)
+        })),
+        ..TcpConfig::default()
+    })?;
+    let listener = tcp.listen(Ipv4Addr::LOCALHOST, PORT)?;
+    let soak = Arc::new(Soak::default());
+
+    let cloned_tcp = tcp.clone();
+    let cloned_soak = soak.clone();
+    thread::spawn(move || serve(cloned_tcp, listener, cloned_soak));
+
+    let deadline = Instant::now() + options.duration;
+    let workers: Vec<_> = (0..options.workers)
+        .map(|_| {
+            let tcp = tcp.clone();
+            let soak = soak.clone();
+            let max_payload = options.max_payload;
+            thread::spawn(move || {
+                while Instant::now() < deadline && !soak.failed.load(Ordering::SeqCst) {
+                    if let Err(error) = run_client(&tcp, max_payload, &soak) {
+                        soak.fail(format!("client: {:?}", error));
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let started = Instant::now();
+    let mut last_completed = (0, Instant::now());
+    while workers.iter().any(|worker| !worker.is_finished()) {
+        thread::sleep(REPORT_INTERVAL);
+        let completed = soak.completed.load(Ordering::SeqCst);
+        println!(
+            "[{:>6}s] connections={} bytes={} connect_failures={} sockets={} memory={}",
+            started.elapsed().as_secs(),
+            completed,
+            soak.bytes.load(Ordering::SeqCst),
+            soak.connect_failures.load(Ordering::SeqCst),
+            tcp.connections().len(),
+            tcp.stack_stats().memory.total(),
+        );
+        if soak.failed.load(Ordering::SeqCst) {
+            process::exit(1);
+        }
+        if completed != last_completed.0 {
+            last_completed = (completed, Instant::now());
+        } else if last_completed.1.elapsed() > STALL_TIMEOUT {
+            println!("FAIL: no connection completed for {:?}", STALL_TIMEOUT);
+            print_connections(&tcp);
+            process::exit(1);
+        }
+    }
+    for worker in workers {
+        worker.join().expect("worker panicked");
+    }
+    if soak.failed.load(Ordering::SeqCst) {
+        process::exit(1);
+    }
+
+    // TIME_WAITの接続が消えれば, リスニングソケットだけが残りメモリも全て解放されているはず
+    let drain_deadline = Instant::now() + DRAIN_TIMEOUT;
+    while !is_baseline(&tcp) {
+        if Instant::now() > drain_deadline {
+            println!("FAIL: sockets or memory did not return to baseline");
+            print_connections(&tcp);
+            process::exit(1);
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    println!(
+        "OK: connections={} bytes={} connect_failures={}",
+        soak.completed.load(Ordering::SeqCst),
+        soak.bytes.load(Ordering::SeqCst),
+        soak.connect_failures.load(Ordering::SeqCst),
+    );
+    println!("{:#?}", tcp.stack_stats());
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Options {
+    duration: Duration,
+    workers: usize,
+    /// 送信セグメントを落とす確率
+    loss: f64,
+    /// 1接続で送るデータの最大長
+    max_payload: usize,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Options {
+            duration: Duration::from_secs(60),
+            workers: 8,
+            loss: 0.01,
+            max_payload: 256 * 1024,
+        };
+        while let Some(arg) = args.next() {
+            let value = args.next().context(format!("missing value for {}", arg))?;
+            match arg.as_str() {
+                "--duration" => options.duration = Duration::from_secs(value.parse()?),
+                "--workers" => options.workers = value.parse()?,
+                "--loss" => options.loss = value.parse()?,
+                "--max-payload" => options.max_payload = value.parse()?,
+                _ => bail!(
+                    "usage: toytcp-soak [--duration SECS] [--workers N] [--loss RATE] [--max-payload BYTES]"
+                ),
+            }
+        }
+        if !(0.0..1.0).contains(&options.loss) {
+            bail!("--loss must be in [0, 1)");
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Default)]
+struct Soak {
+    completed: AtomicU64,
+    bytes: AtomicU64,
+    /// 落としたSYNの再送が上限に達するなどして確立できなかった接続. ロスを入れていれば起こりうるので失敗にはしない
+    connect_failures: AtomicU64,
+    failed: AtomicBool,
+}
+
+impl Soak {
+    fn fail(&self, message: String) {
+        println!("FAIL: {}", message);
+        self.failed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// 接続の閉じ方
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CloseMode {
+    /// 送り終わったらすぐにcloseする
+    Close = 0,
+    /// shutdownで送信側だけを閉じ, 受信側が返すハッシュを読んでからcloseする
+    HalfClose = 1,
+}
+
+fn run_client(tcp: &TCP, max_payload: usize, soak: &Soak) -> Result<()> {
+    let mut rng = rand::thread_rng();
+    let client = match tcp.connect(Ipv4Addr::LOCALHOST, PORT) {
+        Ok(client) => client,
+        Err(_) => {
+            soak.connect_failures.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        }
+    };
+
+    let payload: Vec<u8> = (0..rng.gen_range(0..=max_payload))
+        .map(|_| rng.gen())
+        .collect();
+    let mut hash = StreamHash::default();
+    hash.update(&payload);
+    let mode = if rng.gen_bool(0.5) {
+        CloseMode::HalfClose
+    } else {
+        CloseMode::Close
+    };
+    let mut header = vec![mode as u8];
+    header.extend_from_slice(&hash.bytes.to_be_bytes());
+    header.extend_from_slice(&hash.hash.to_be_bytes());
+    tcp.send(client, &header).context("send")?;
+
+    let mut rest = &payload[..];
+    while !rest.is_empty() {
+        let len = rng.gen_range(1..=rest.len().min(16 * 1024));
+        tcp.send(client, &rest[..len]).context("send")?;
+        rest = &rest[len..];
+        if rng.gen_bool(0.1) {
+            thread::sleep(Duration::from_millis(rng.gen_range(0..5)));
+        }
+    }
+
+    if mode == CloseMode::HalfClose {
+        tcp.shutdown(client, How::Write).context("shutdown")?;
+        let reply = read_to_end(tcp, client).context("recv")?;
+        if reply != hash.hash.to_be_bytes() {
+            bail!(
+                "{}: server saw a different stream: {:?} != {:x}",
+                client,
+                reply,
+                hash.hash
+            );
+        }
+    }
+    tcp.close(client).context("close")?;
+    soak.completed.fetch_add(1, Ordering::SeqCst);
+    soak.bytes.fetch_add(payload.len() as u64, Ordering::SeqCst);
+    Ok(())
+}
+
+fn serve(tcp: Arc<TCP>, listener: SockID, soak: Arc<Soak>) {
+    loop {
+        let server = match tcp.accept(listener) {
+            Ok(server) => server,
+            Err(error) => {
+                soak.fail(format!("accept: {:?}", error));
+                return;
+            }
+        };
+        let tcp = tcp.clone();
+        let soak = soak.clone();
+        thread::spawn(move || {
+            if let Err(error) = handle(&tcp, server) {
+                soak.fail(format!("server: {:?}", error));
+            }
+        });
+    }
+}
+
+/// ヘッダとデータを相手のFINまで読み, ヘッダのハッシュと比べる
+fn handle(tcp: &TCP, server: SockID) -> Result<()> {
+    let received = read_to_end(tcp, server).context("recv")?;
+    if received.len() < HEADER_LEN {
+        bail!("{}: connection closed before the header", server);
+    }
+    let (header, payload) = received.split_at(HEADER_LEN);
+    let expected = StreamHash {
+        bytes: u64::from_be_bytes(header[1..9].try_into()?),
+        hash: u64::from_be_bytes(header[9..17].try_into()?),
+    };
+    let mut actual = StreamHash::default();
+    actual.update(payload);
+    if actual != expected {
+        bail!(
+            "{}: corrupted stream: {:?} != {:?}",
+            server,
+            actual,
+            expected
+        );
+    }
+    // スタックが数えたrecvのハッシュとも一致するはず
+    let mut counted = StreamHash::default();
+    counted.update(&received);
+    let stats = tcp.socket_stats(server)?;
+    if stats.received_stream != counted {
+        bail!(
+            "{}: socket stats disagree: {:?} != {:?}",
+            server,
+            stats.received_stream,
+            counted
+        );
+    }
+
+    if header[0] == CloseMode::HalfClose as u8 {
+        tcp.send(server, &actual.hash.to_be_bytes())
+            .context("send")?;
+    }
+    tcp.close(server).context("close")
+}
+
+fn read_to_end(tcp: &TCP, sock_id: SockID) -> Result<Vec<u8>> {
+    let mut received = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let nbytes = tcp.recv(sock_id, &mut buffer)?;
+        if nbytes == 0 {
+            return Ok(received);
+        }
+        received.extend_from_slice(&buffer[..nbytes]);
+    }
+}
+
+fn is_baseline(tcp: &TCP) -> bool {
+    tcp.connections().len() == 1 && tcp.stack_stats().memory.total() == 0
+}
+
+fn print_connections(tcp: &TCP) {
+    for info in tcp.connections() {
+        println!(
+            "  {} {} idle={:?} memory={}",
+            info.sock_id,
+            info.status,
+            info.idle,
+            info.memory.total()
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{bail, Result};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::tcp::{self, TcpOptionNumbers, TcpPacket};
+use pnet::packet::Packet;
+use pnet::util;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::backlog::ReceivedPacket;
+use crate::config::ChecksumOffload;
+use crate::device::{Device, OutgoingSegment};
+use crate::packet::{TCPPacket, TCP_HEADER_SIZE};
+use crate::socket::Addr;
+use crate::stats::ChecksumCounters;
+
+/// 送信するセグメントのチェックサムを計算し, 受信したセグメントのチェックサムを検証するデバイスのラッパー
+/// オフロードを指定した方向はNICがやってくれたものとみなして何もしない
+/// 計算と検証にかかった時間はChecksumCountersに記録する
+/// verify_encodingが有効なら, 送信するセグメントをpnetのパーサーで読み直して書き間違いがないか確かめる
+pub struct ChecksumDevice {
+    inner: Arc<dyn Device>,
+    offload: ChecksumOffload,
+    verify_encoding: bool,
+    counters: Arc<ChecksumCounters>,
+}
+
+impl ChecksumDevice {
+    pub fn new(
+        inner: Arc<dyn Device>,
+        offload: ChecksumOffload,
+        verify_encoding: bool,
+        counters: Arc<ChecksumCounters>,
+    ) -> Self {
+        Self {
+            inner,
+            offload,
+            verify_encoding,
+            counters,
+        }
+    }
+
+    /// 送信するセグメントがpnetのパーサーから見ても書いたつもりの内容になっているか確かめ, 食い違っていればエラーにする
+    fn verify(&self, packet: &TCPPacket, local_addr: Addr, remote_addr: Addr) -> Result<()> {
+        if !self.verify_encoding {
+            return Ok(());
+        }
+        let mismatches = encoding_mismatches(packet, local_addr, remote_addr, !self.offload.tx);
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+        self.counters.record_encoding_mismatch();
+        bail!(
+            "segment is encoded incorrectly: {}:{} -> {}:{} {}",
+            local_addr,
+            packet.get_src(),
+            remote_addr,
+            packet.get_dest(),
+            mismatches.join(", ")
+        );
+    }
+
+    fn set_checksum(&self, packet: &mut TCPPacket, local_addr: Addr, remote_addr: Addr) {
+        let start = Instant::now();
+        packet.set_checksum(util::ipv4_checksum(
+            packet.packet(),
+            8,   // skipword
+            &[], // extra_data
+            &local_addr,
+            &remote_addr,
+            IpNextHeaderProtocols::Tcp,
+        ));
+        self.counters.record_computed(start.elapsed());
+    }
+}
+
+impl Device for ChecksumDevice {
+    fn send(&self, packet: &TCPPacket, local_addr: Addr, remote_addr: Addr) -> Result<usize> {
+        if self.offload.tx {
+            self.verify(packet, local_addr, remote_addr)?;
+            return self.inner.send(packet, local_addr, remote_addr);
+        }
+
+        let mut packet = packet.clone();
+        self.set_checksum(&mut packet, local_addr, remote_addr);
+        self.verify(&packet, local_addr, remote_addr)?;
+        self.inner.send(&packet, local_addr, remote_addr)
+    }
+
+    fn send_batch(&self, segments: &[OutgoingSegment]) -> Result<usize> {
+        if self.offload.tx {
+            for segment in segments {
+                self.verify(&segment.packet, segment.local_addr, segment.remote_addr)?;
+            }
+            return self.inner.send_batch(segments);
+        }
+
+        let mut segments = segments.to_vec();
+        for segment in &mut segments {
+            self.set_checksum(&mut segment.packet, segment.local_addr, segment.remote_addr);
+            self.verify(&segment.packet, segment.local_addr, segment.remote_addr)?;
+        }
+        self.inner.send_batch(&segments)
+    }
+
+    fn recv(&self, timeout: Option<Duration>) -> Result<Option<ReceivedPacket>> {
+        let received = match self.inner.recv(timeout)? {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+        if self.offload.rx {
+            return Ok(Some(received));
+        }
+
+        let start = Instant::now();
+        let correct = received
+            .packet
+            .is_correct_checksum(received.local_addr, received.remote_addr);
+        self.counters.record_verified(start.elapsed(), correct);
+        if !correct {
+            bail!("invalid checksum");
+        }
+        Ok(Some(received))
+    }
+
+    fn source_addr(&self, remote_addr: Addr) -> Result<Addr> {
+        self.inner.source_addr(remote_addr)
+    }
+}
+
+/// packetをpnetのTcpPacketとして読み直し, TCPPacketのゲッターで読んだ値と食い違っているフィールドを返す
+/// チェックサムはpnetの実装で計算し直した値と比べる. checksumがfalse(送信側オフロード)なら比べない
+pub fn encoding_mismatches(
+    packet: &TCPPacket,
+    local_addr: Addr,
+    remote_addr: Addr,
+    checksum: bool,
+) -> Vec<String> {
+    let parsed = match TcpPacket::new(packet.packet()) {
+        Some(parsed) => parsed,
+        None => return vec![format!("too short: {} bytes", packet.packet().len())],
+    };
+    let mut mismatches = Vec::new();
+    let mut compare = |field: &str, ours: u64, theirs: u64| {
+        if ours != theirs {
+            mismatches.push(format!("{}: {} != {}", field, ours, theirs));
+        }
+    };
+    compare("src", packet.get_src() as u64, parsed.get_source() as u64);
+    compare(
+        "dest",
+        packet.get_dest() as u64,
+        parsed.get_destination() as u64,
+    );
+    compare("seq", packet.get_seq() as u64, parsed.get_sequence() as u64);
+    compare(
+        "ack",
+        packet.get_ack() as u64,
+        parsed.get_acknowledgement() as u64,
+    );
+    // 予約ビット(とNSフラグ)は使っていないので0のはず
+    compare(
+        "flags",
+        packet.get_flag().bits() as u64,
+        parsed.get_flags() as u64,
+    );
+    compare("reserved", 0, parsed.get_reserved() as u64);
+    compare(
+        "window",
+        packet.get_window_size() as u64,
+        parsed.get_window() as u64,
+    );
+    compare(
+        "urgent pointer",
+        packet.get_urgent_pointer() as u64,
+        parsed.get_urgent_ptr() as u64,
+    );
+
+    // データオフセットはセグメントに収まっていて, オプション領域には書いたつもりのオプションが並んでいるはず
+    let header_len = parsed.get_data_offset() as usize * 4;
+    if header_len < TCP_HEADER_SIZE || header_len > packet.packet().len() {
+        mismatches.push(format!("data offset: {} bytes", header_len));
+        return mismatches;
+    }
+    let options = parsed
+        .get_options_iter()
+        .filter(|option| {
+            let number = option.get_number();
+            number != TcpOptionNumbers::NOP && number != TcpOptionNumbers::EOL
+        })
+        .count();
+    let mut compare = |field: &str, ours: u64, theirs: u64| {
+        if ours != theirs {
+            mismatches.push(format!("{}: {} != {}", field, ours, theirs));
+        }
+    };
+    compare("options", packet.options().len() as u64, options as u64);
+    compare(
+        "payload length",
+        packet.payload().len() as u64,
+        parsed.payload().len() as u64,
+    );
+    if checksum {
+        compare(
+            "checksum",
+            packet.get_checksum() as u64,
+            tcp::ipv4_checksum(&parsed, &local_addr, &remote_addr) as u64,
+        );
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::LoopbackDevice;
+    use crate::packet::TcpOption;
+    use crate::tcpflags::TcpFlags;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn encoding_mismatches_are_reported_by_field() {
+        let local_addr = Ipv4Addr::new(10, 0, 0, 1);
+        let remote_addr = Ipv4Addr::new(10, 0, 0, 2);
+        let mut packet = TCPPacket::with_options(&[TcpOption::WindowScale(7)], 5);
+        packet.set_src(40000);
+        packet.set_dest(80);
+        packet.set_seq(1000);
+        packet.set_ack(2000);
+        packet.set_flag(TcpFlags::SYN | TcpFlags::ACK | TcpFlags::ECE);
+        packet.set_window_size(4380);
+        packet.set_payload(b"hello");
+        packet.set_checksum(util::ipv4_checksum(
+            packet.packet(),
+            8,
+            &[],
+            &local_addr,
+            &remote_addr,
+            IpNextHeaderProtocols::Tcp,
+        ));
+        assert!(encoding_mismatches(&packet, local_addr, remote_addr, true).is_empty());
+
+        // 送信元と宛先を取り違えて計算したチェックサム
+        let mismatches = encoding_mismatches(&packet, remote_addr, Ipv4Addr::LOCALHOST, true);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].starts_with("checksum"));
+        assert!(encoding_mismatches(&packet, remote_addr, Ipv4Addr::LOCALHOST, false).is_empty());
+
+        // データオフセットを重ねて書いてしまい, セグメントの長さを超えている
+        packet.set_data_offset(8);
+        let mismatches = encoding_mismatches(&packet, local_addr, remote_addr, false);
+        assert_eq!(mismatches, vec!["data offset: 56 bytes".to_string()]);
+    }
+
+    fn segment() -> TCPPacket {
+        let mut packet = TCPPacket::new(5);
+        packet.set_src(40000);
+        packet.set_dest(80);
+        packet.set_flag(TcpFlags::ACK);
+        packet.set_payload(b"hello");
+        packet
+    }
+
+    #[test]
+    fn checksums_are_computed_and_verified_unless_offloaded() {
+        let loopback: Arc<dyn Device> = Arc::new(LoopbackDevice::default());
+        let counters = Arc::new(ChecksumCounters::default());
+        let device = ChecksumDevice::new(
+            loopback.clone(),
+            ChecksumOffload::default(),
+            false,
+            counters.clone(),
+        );
+        let addr = Ipv4Addr::LOCALHOST;
+
+        device.send(&segment(), addr, addr).unwrap();
+        let received = device.recv(Some(Duration::ZERO)).unwrap().unwrap();
+        assert_ne!(received.packet.get_checksum(), 0);
+
+        // 計算されていないチェックサムは検証で弾かれる
+        loopback.send(&segment(), addr, addr).unwrap();
+        assert!(device.recv(Some(Duration::ZERO)).is_err());
+        let stats = counters.snapshot();
+        assert_eq!((stats.computed, stats.verified, stats.failures), (1, 2, 1));
+
+        // オフロードした方向は何もしない
+        let counters = Arc::new(ChecksumCounters::default());
+        let offload = ChecksumOffload { rx: true, tx: true };
+        let device = ChecksumDevice::new(loopback, offload, false, counters.clone());
+        device.send(&segment(), addr, addr).unwrap();
+        let received = device.recv(Some(Duration::ZERO)).unwrap().unwrap();
+        assert_eq!(received.packet.get_checksum(), 0);
+        let stats = counters.snapshot();
+        assert_eq!((stats.computed, stats.verified, stats.failures), (0, 0, 0));
+    }
+}
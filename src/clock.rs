@@ -0,0 +1,47 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::sync::LockResultExt;
+
+/// スタック内部のタイマーが使う時計
+/// 通常はシステムの時計を使い, 決定的モード(TcpConfig::deterministic)ではadvanceした分だけ進むシミュレーション時計を使う
+pub struct Clock {
+    simulated: Option<Mutex<SystemTime>>,
+}
+
+impl Clock {
+    pub fn system() -> Self {
+        Self { simulated: None }
+    }
+
+    /// UNIX_EPOCHから始まるシミュレーション時計
+    pub fn simulated() -> Self {
+        Self {
+            simulated: Some(Mutex::new(UNIX_EPOCH)),
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        match &self.simulated {
+            Some(now) => *now.lock().recover(),
+            None => SystemTime::now(),
+        }
+    }
+
+    /// timeからの経過時間. timeが未来なら0
+    pub fn since(&self, time: SystemTime) -> Duration {
+        self.now().duration_since(time).unwrap_or_default()
+    }
+
+    /// timeまでの残り時間. timeが過去なら0
+    pub fn until(&self, time: SystemTime) -> Duration {
+        time.duration_since(self.now()).unwrap_or_default()
+    }
+
+    /// シミュレーション時計を進める. システムの時計なら何もしない
+    pub fn advance(&self, duration: Duration) {
+        if let Some(now) = &self.simulated {
+            *now.lock().recover() += duration;
+        }
+    }
+}
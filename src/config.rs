@@ -0,0 +1,319 @@
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::filter::SegmentFilter;
+use crate::packet::TCPPacket;
+use crate::policy::ComplianceMode;
+use crate::portalloc::{PortAllocator, RandomPorts};
+use crate::rtt::MAX_RTO;
+use crate::socket::SOCKET_BUFFER_SIZE;
+use crate::tcpflags::TcpFlags;
+
+/// TCPスタック全体の設定
+/// TCP::with_configに渡す. TCP::newはデフォルト値を使う
+#[derive(Clone, Debug)]
+pub struct TcpConfig {
+    /// 接続毎のイベントログ(JSON Lines)を書き出すディレクトリ. Noneならログを取らない
+    pub event_log_dir: Option<PathBuf>,
+    /// 受信したセグメントをどこまで厳密にチェックするか
+    pub compliance: ComplianceMode,
+    /// 無通信のまま一定時間経過した接続を切断する. リスニングソケットに設定した値はacceptした接続に引き継がれる
+    pub idle_timeout: Option<IdleTimeout>,
+    /// 全ての送信セグメント(再送, RSTを含む)に対して送信前に評価されるフィルタ
+    pub egress_filter: Option<SegmentFilter>,
+    /// 初期輻輳ウィンドウ(セグメント数). デフォルトはRFC 6928の10セグメント
+    pub initial_window: usize,
+    /// 全ソケットが保持できるバイト数の上限. 超えている間は新しい接続を受け付けない
+    pub memory_ceiling: Option<usize>,
+    /// リスニングソケット毎の, ハンドシェイク中か接続済みでまだacceptされていない接続の上限. 溢れた分のSYNは破棄する
+    pub accept_backlog: usize,
+    /// 受信バッファの使用量がこれを跨いだ時にTCP::subscribeの購読者へ通知する. Noneなら通知しない
+    pub recv_buffer_watermarks: Option<BufferWatermarks>,
+    /// セグメントを送受信するデバイス
+    pub backend: Backend,
+    /// trueならスレッドを起動せず, TCP::poll_receiveとTCP::advance_timeを呼んで駆動する
+    /// タイマーもadvance_timeで進めたシミュレーション時計で動くので, ループバックと組み合わせればロスや再送のシナリオを1ステップずつ再現できる
+    /// ブロックするはずのAPIはブロックせずにエラーを返す(connectだけはSYNを送ってすぐに返る)
+    pub deterministic: bool,
+    /// チェックサムの計算/検証を省略する方向. ループバックのように経路上で壊れない場合は無駄になる
+    pub checksum_offload: ChecksumOffload,
+    /// 送信するセグメントをpnetのパーサーで読み直し, ヘッダの各フィールドとチェックサムが正しく書けているか確かめる. デバッグ用
+    /// 食い違っていたら相手に黙って捨てられる前に送信をエラーにする. 食い違いの数はStackStats::checksumに記録する
+    pub verify_encoding: bool,
+    /// Someなら送信するセグメントをこの数まで積めるTXリングに溜め, 専用のスレッドからまとめて送信する
+    /// リングが一杯の間は送信がブロックする. 決定的モードではスレッドを起動しないので無視される
+    /// Backend::Rawは1セグメント毎にsendtoするので, まとめてもシステムコールの数は減らない
+    pub tx_ring: Option<usize>,
+    /// 受信したセグメントの送信元アドレスの検証(RFC 3704のingress filtering). 偽装された送信元を弾く
+    pub reverse_path: ReversePath,
+    /// 使うプロトコル拡張. 相手との交渉が必要なものは, 相手も対応している場合だけハンドシェイクで有効になる
+    pub capabilities: Capabilities,
+    /// 接続毎の受信バッファの大きさ(バイト). 64KBを超える場合はcapabilities.window_scaleが必要
+    /// 広告するウィンドウスケールのシフト数はこの大きさから自動で決まる
+    pub recv_buffer_size: usize,
+    /// 接続毎の送信バッファの大きさ(バイト). sendはデータをここにコピーして返り, 送信スレッドがウィンドウの空きに合わせて送る
+    /// 一杯の時はsendが空くまでブロックする
+    pub send_buffer_size: usize,
+    /// Someなら読み出しの速さとRTTに合わせて, 受信バッファ(と広告するウィンドウ)をこの大きさまで自動で広げる
+    /// 速い接続がrecv_buffer_sizeで頭打ちにならないようにする. ウィンドウスケールのシフト数はこの大きさから決まる
+    pub recv_buffer_autotune: Option<usize>,
+    /// 再送タイムアウトの上限. 再送する度にRTOを倍にしていくが, これより長くはしない
+    pub max_rto: Duration,
+    /// 1回のタイマー処理(100ms毎)で再送するセグメントの数の上限(全ソケットの合計)
+    /// 多数のソケットで同時にロスが起きた時に再送がバーストにならないようにする. 超えた分は次のタイマーで再送する
+    pub retransmit_budget: usize,
+    /// sendがセグメントを送る間隔. 決定的モードでは時刻が進まないので無視される
+    pub pacing: Pacing,
+    /// acceptを待っている接続がこの数に達する度に, リスニングソケットの購読者へ通知する
+    /// accept_backlogに達してSYNが捨てられ始める前に気付けるよう, それより小さい値にする
+    pub accept_queue_watermark: Option<usize>,
+    /// 受信したデータへのACKを遅らせる時間(遅延ACK). 続けて届いたセグメントへのACKを1つにまとめる
+    /// フルサイズのセグメントが2つ届いた時はすぐに返す. Noneなら受信スレッドのイテレーション毎にすぐ返す
+    /// 受信スレッドはこの間隔で起きて期限を確認するので, 実際に遅れるのは最大でこの2倍になる
+    pub delayed_ack: Option<Duration>,
+    /// Someなら全接続の状態(TCP::conntrack)を定期的にJSONでファイルへ書き出す
+    /// 決定的モードではスレッドを起動しないので無視される. 必要な時にTCP::conntrackを呼ぶ
+    pub conntrack_export: Option<ConntrackExport>,
+    /// Someなら一定時間何も受信していない接続にプローブを送り, 相手が消えていないか確かめる
+    /// リスニングソケットに設定した値はacceptした接続に引き継がれる. TCP::set_keepaliveで接続毎に変えられる
+    pub keepalive: Option<KeepAlive>,
+    /// Someなら相手からの応答を待ったまま(ackされていないデータ, ウィンドウが開くのを待っている送信バッファ,
+    /// 応答のないキープアライブのプローブがある状態で)何も受信せずにこの時間が過ぎた接続を中断する(TCP_USER_TIMEOUT, RFC 5482)
+    /// リスニングソケットに設定した値はacceptした接続に引き継がれる. TCP::set_user_timeoutで接続毎に変えられる
+    pub user_timeout: Option<Duration>,
+    /// trueならスレッドの起床回数とsocketsのロックの取得回数をサブシステム毎に数え, TCP::wakeup_auditで返す
+    /// 何もしていないスタックが使うCPUを測るためのもので, 数える分だけ余計なコストがかかる
+    pub wakeup_audit: bool,
+    /// SYN_RCVDの接続がSYN/ACKを再送する回数の上限(Linuxのtcp_synack_retries)
+    /// 再送の間隔はINITIAL_RTOから倍にしていき, 上限を超えても最後のACKが届かなければ半開きの接続を削除する
+    pub synack_retries: u8,
+    /// connectがSYNを再送する回数の上限(Linuxのtcp_syn_retries). 再送の間隔はRTOを倍にしていく
+    /// 上限を超えても応答がなければ, connectは"connection timed out"エラーを返す
+    pub syn_retries: u8,
+    /// connectで使うローカルポートの選び方. デフォルトは毎回ランダムに選ぶ
+    /// テストでポートを再現したい場合はSequentialPortsやsecretを固定したHashedPortsを使う
+    pub port_allocator: Arc<dyn PortAllocator>,
+    /// trueならリスニングソケットはSYNを受けてもソケットを作らず, 接続の情報をSYN/ACKのISN(SYNクッキー)に埋め込んで返す
+    /// 最後のACKでクッキーを検証できた時に初めてソケットを作るので, SYN floodを受けても半開きの接続でメモリを使い切らない
+    /// SYN/ACKは再送せず, ECNは使わない
+    pub syn_cookies: bool,
+    /// スタック全体で1秒あたりに返すチャレンジACKの上限(Linuxのtcp_challenge_ack_limit)
+    /// 同期済みの接続に届いたSYNや, ウィンドウ内だがRCV.NXTちょうどではないRSTには, 従わずにチャレンジACKを返す. RFC 5961
+    pub challenge_ack_limit: u32,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            event_log_dir: None,
+            compliance: ComplianceMode::default(),
+            idle_timeout: None,
+            egress_filter: None,
+            initial_window: 10,
+            memory_ceiling: None,
+            accept_backlog: 128,
+            recv_buffer_watermarks: None,
+            backend: Backend::default(),
+            deterministic: false,
+            checksum_offload: ChecksumOffload::default(),
+            verify_encoding: false,
+            tx_ring: None,
+            reverse_path: ReversePath::default(),
+            capabilities: Capabilities::default(),
+            recv_buffer_size: SOCKET_BUFFER_SIZE,
+            recv_buffer_autotune: None,
+            send_buffer_size: 64 * 1024,
+            max_rto: MAX_RTO,
+            retransmit_budget: 64,
+            pacing: Pacing::default(),
+            accept_queue_watermark: None,
+            // Linuxの遅延ACKの最小値. 最大でも200msを超えないようにする
+            delayed_ack: Some(Duration::from_millis(40)),
+            conntrack_export: None,
+            keepalive: None,
+            user_timeout: None,
+            wakeup_audit: false,
+            synack_retries: 5,
+            syn_retries: 4,
+            port_allocator: Arc::new(RandomPorts),
+            syn_cookies: false,
+            challenge_ack_limit: 1000,
+        }
+    }
+}
+
+/// 接続毎に有効にするプロトコル拡張
+/// TcpConfig::capabilitiesで使いたいものを指定し, 各接続のConnectionInfo::capabilitiesで実際に交渉できたものが分かる
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// SACK(RFC 2018). 抜けたセグメントだけを再送する
+    pub sack: bool,
+    /// タイムスタンプオプション(RFC 7323). 再送したセグメントでもRTTを測れ, 古い重複セグメントをPAWSで弾ける
+    pub timestamps: bool,
+    /// ウィンドウスケール(RFC 7323). 64KBより大きい受信バッファを使い切れる. シフト数はrecv_buffer_sizeから決める
+    pub window_scale: bool,
+    /// Nagleのアルゴリズム(RFC 896). ackされていないデータがある間はMSS未満の書き込みをまとめて送る
+    /// 相手と交渉するものではなく, TCP::set_nodelayで接続毎に切り替えられる
+    pub nagle: bool,
+    /// ECN(RFC 3168). 経路上のルーターがパケットを落とす代わりにIPヘッダに付けた印で輻輳を知り, ロスを待たずにcwndを縮める
+    /// ECNの付いたSYNを落とす経路もあるので, デフォルトでは使わない
+    pub ecn: bool,
+    /// TCP Fast Open(RFC 7413). 実験的な機能なのでデフォルトでは使わない
+    /// サーバーは最初の接続でクッキーを渡し, クッキーを持っているクライアントがTCP::connect_with_dataでSYNに載せたデータを
+    /// ハンドシェイクの完了を待たずに受け取ってacceptに返す. 1往復早く相手にデータを届けられる
+    pub fast_open: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            sack: true,
+            timestamps: true,
+            window_scale: true,
+            nagle: true,
+            ecn: false,
+            fast_open: false,
+        }
+    }
+}
+
+impl Capabilities {
+    /// どの拡張も使わない
+    pub fn none() -> Self {
+        Self {
+            sack: false,
+            timestamps: false,
+            window_scale: false,
+            nagle: false,
+            ecn: false,
+            fast_open: false,
+        }
+    }
+
+    /// 相手のSYN(SYN/ACK)に付いていたオプションから, 両方が対応しているものだけを残す
+    pub fn negotiate(&mut self, syn: &TCPPacket) {
+        self.sack &= syn.is_sack_permitted();
+        self.timestamps &= syn.timestamps().is_some();
+        self.window_scale &= syn.window_scale().is_some();
+        // ECNを使いたいSYNはECEとCWRの両方を立て, それに応えるSYN/ACKはECEだけを立てる
+        let ecn_flags = syn.get_flag() & (TcpFlags::ECE | TcpFlags::CWR);
+        self.ecn &= if syn.get_flag().has_ack() {
+            ecn_flags == TcpFlags::ECE
+        } else {
+            ecn_flags == TcpFlags::ECE | TcpFlags::CWR
+        };
+        self.fast_open &= syn.fast_open_cookie().is_some();
+        // nagleはこちらの送り方だけの話なのでそのまま
+    }
+}
+
+/// セグメントの送受信に使うデバイス
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// rawソケットで実際のネットワークとやり取りする. root権限が必要
+    #[default]
+    Raw,
+    /// 送信したセグメントを同じTCPの中で受信する. テスト用
+    Loopback,
+}
+
+/// NICのチェックサムオフロードの模倣. trueにした方向はスタックでチェックサムを扱わない
+/// 片方だけオフロードしたループバックでは, 送信側が計算しないチェックサムを受信側が検証して全て破棄してしまうので注意
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumOffload {
+    /// 受信したセグメントのチェックサムを検証しない
+    pub rx: bool,
+    /// 送信するセグメントのチェックサムを計算しない
+    pub tx: bool,
+}
+
+/// 無通信の接続を切断する方法
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleAction {
+    /// FINを送って通常通り閉じる
+    Fin,
+    /// RSTを送って即座に破棄する
+    Reset,
+}
+
+/// キープアライブの設定(LinuxのTCP_KEEPIDLE, TCP_KEEPINTVL, TCP_KEEPCNTに相当)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepAlive {
+    /// 最後にセグメントを受信してから最初のプローブを送るまでの時間
+    pub idle: Duration,
+    /// 応答のないプローブを送り直す間隔
+    pub interval: Duration,
+    /// 応答のないまま送るプローブの数. 全てに応答がなければ接続を中断する
+    pub probes: u32,
+}
+
+/// TCP::conntrackの書き出し先と間隔
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConntrackExport {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdleTimeout {
+    pub duration: Duration,
+    pub action: IdleAction,
+}
+
+/// 受信バッファの使用量(バイト数)の閾値
+/// highに達したらBufferHighWatermarkを, その後lowまで下がったらBufferLowWatermarkを1度ずつ通知する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferWatermarks {
+    pub high: usize,
+    pub low: usize,
+}
+
+/// sendがセグメントを送る間隔の決め方
+/// 輻輳ウィンドウ分のデータを一気に送ると途中のルーターのキューが溢れやすいので, 間隔を空けて均等に送る
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// 間隔を空けずに送る
+    Off,
+    /// 輻輳ウィンドウとSRTTから推定した帯域に合わせて送る. RTTを測るまでは間隔を空けない
+    #[default]
+    Auto,
+    /// 指定したレート(バイト/秒)で送る
+    Rate(u64),
+}
+
+/// 送信元アドレスの検証方法. 弾いたセグメントはRSTも返さずに破棄する
+/// 経路表を持っていないので, 本来の「その送信元への経路が受信したインターフェースを向いているか」の代わりに近似する
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ReversePath {
+    /// 検証しない
+    #[default]
+    Off,
+    /// どこからも届くはずのない送信元(0.0.0.0/8, 240.0.0.0/4, 自分自身のアドレスなど)だけを弾く
+    Loose,
+    /// Looseに加えて, 送信元がいずれかのプレフィックスに含まれていなければ弾く
+    /// 受信するインターフェースの先にあるネットワークを列挙する
+    Strict(Vec<Prefix>),
+}
+
+/// IPv4のプレフィックス(例: 10.0.0.0/8)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Prefix {
+    pub addr: Ipv4Addr,
+    pub len: u8,
+}
+
+impl Prefix {
+    pub fn new(addr: Ipv4Addr, len: u8) -> Self {
+        Self {
+            addr,
+            len: len.min(32),
+        }
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+        u32::from(addr) & mask == u32::from(self.addr) & mask
+    }
+}
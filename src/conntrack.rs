@@ -0,0 +1,114 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::socket::{Addr, TcpStatus};
+
+/// 全接続の4タプル, 状態, タイマーを書き出したもの. TCP::conntrackで取得する
+/// 外部のダッシュボードやスクリプトから, 実際のconntrackの情報と並べて扱えるようにする
+/// timeはUNIXエポックからのマイクロ秒
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ConntrackSnapshot {
+    pub time: u64,
+    pub entries: Vec<ConntrackEntry>,
+}
+
+/// 1接続分のエントリ. 時間は全てミリ秒で, 動いていないタイマーはNone
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ConntrackEntry {
+    pub src: Addr,
+    pub sport: u16,
+    pub dst: Addr,
+    pub dport: u16,
+    /// conntrackと同じ表記の状態(ESTABLISHED, TIME_WAITなど)
+    pub state: String,
+    /// 最後にセグメントを受信した or データを送信してからの経過時間
+    pub idle_ms: u64,
+    pub rto_ms: u64,
+    /// 再送タイマーが満了するまでの時間
+    pub retransmit_in_ms: Option<u64>,
+    /// 遅延ACKを返すまでの時間
+    pub delayed_ack_in_ms: Option<u64>,
+    /// TIME_WAITの2MSLなど, 接続が削除されるまでの時間
+    pub expires_in_ms: Option<u64>,
+    /// 送信してまだackされていないバイト数
+    pub unacked: u32,
+}
+
+impl ConntrackSnapshot {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).context("failed to serialize conntrack snapshot")
+    }
+
+    /// 1行のJSONとして書き出す. UNIXソケットなどに流す場合はこちらを使う
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        writeln!(writer, "{}", self.to_json()?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// ファイルに書き出す. 読む側が書きかけのファイルを見ないよう, 一時ファイルに書いてから置き換える
+    pub fn write_file(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_json()?).context(format!("failed to write {:?}", tmp))?;
+        fs::rename(&tmp, path).context(format!("failed to rename {:?} to {:?}", tmp, path))?;
+        Ok(())
+    }
+}
+
+/// conntrackでの状態の表記. conntrackはFIN_WAIT_1/2を区別しないのでどちらもFIN_WAITにする
+/// 同時クローズのCLOSINGは, conntrackでは両方向のFINを見て最後のACKを待っているLAST_ACKになる
+pub fn state_name(status: TcpStatus) -> &'static str {
+    match status {
+        TcpStatus::Listen => "LISTEN",
+        TcpStatus::SynSent => "SYN_SENT",
+        TcpStatus::SynRcvd => "SYN_RECV",
+        TcpStatus::Established => "ESTABLISHED",
+        TcpStatus::FinWait1 | TcpStatus::FinWait2 => "FIN_WAIT",
+        TcpStatus::TimeWait => "TIME_WAIT",
+        TcpStatus::CloseWait => "CLOSE_WAIT",
+        TcpStatus::Closing | TcpStatus::LastAck => "LAST_ACK",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn snapshot_round_trips_through_json() {
+        let snapshot = ConntrackSnapshot {
+            time: 1_000_000,
+            entries: vec![ConntrackEntry {
+                src: Ipv4Addr::LOCALHOST,
+                sport: 40000,
+                dst: Ipv4Addr::new(10, 0, 0, 1),
+                dport: 80,
+                state: state_name(TcpStatus::FinWait2).to_string(),
+                idle_ms: 5,
+                rto_ms: 200,
+                retransmit_in_ms: None,
+                delayed_ack_in_ms: Some(40),
+                expires_in_ms: None,
+                unacked: 0,
+            }],
+        };
+        let json = snapshot.to_json().unwrap();
+        assert!(json.contains(r#""src":"127.0.0.1""#));
+        assert!(json.contains(r#""state":"FIN_WAIT""#));
+        assert_eq!(
+            serde_json::from_str::<ConntrackSnapshot>(&json).unwrap(),
+            snapshot
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("toytcp-conntrack-{}.json", std::process::id()));
+        snapshot.write_file(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written, json);
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{bail, Context, Result};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, MutableIpv4Packet};
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::Packet;
+use pnet::transport::{self, TransportChannelType, TransportReceiver, TransportSender};
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::backlog::ReceivedPacket;
+use crate::packet::{Ecn, TCPPacket, MAX_PACKET_SIZE};
+use crate::socket::Addr;
+use crate::sync::LockResultExt;
+use crate::tcp::get_source_ipv4_addr;
+
+/// セグメントを送受信するデバイス. TcpConfig::backendで選ぶ
+pub trait Device: Send + Sync {
+    fn send(&self, packet: &TCPPacket, local_addr: Addr, remote_addr: Addr) -> Result<usize>;
+
+    /// 複数のセグメントをまとめて送信し, 下位のデバイス(rawソケットなど)への書き込み回数を返す
+    /// デフォルトでは1つずつsendする
+    fn send_batch(&self, segments: &[OutgoingSegment]) -> Result<usize> {
+        for segment in segments {
+            self.send(&segment.packet, segment.local_addr, segment.remote_addr)?;
+        }
+        Ok(segments.len())
+    }
+
+    /// セグメントを1つ受信する. timeoutがNoneなら届くまでブロックし, タイムアウトした場合はOk(None)を返す
+    fn recv(&self, timeout: Option<Duration>) -> Result<Option<ReceivedPacket>>;
+
+    /// remote_addrへ接続する時の送信元IPアドレス
+    fn source_addr(&self, remote_addr: Addr) -> Result<Addr>;
+}
+
+/// 送信待ちのセグメント
+#[derive(Clone, Debug)]
+pub struct OutgoingSegment {
+    pub packet: TCPPacket,
+    pub local_addr: Addr,
+    pub remote_addr: Addr,
+}
+
+// 送信するIPパケットのTTL
+const DEFAULT_TTL: u8 = 64;
+const IPV4_HEADER_SIZE: usize = 20;
+
+/// rawソケットで実際のネットワークとやり取りする. root権限が必要
+/// ECNフィールドを書き込めるように, 送信するIPヘッダも自分で組み立てる
+pub struct RawDevice {
+    sender: Mutex<TransportSender>,
+    receiver: Mutex<TransportReceiver>,
+}
+
+impl RawDevice {
+    pub fn new() -> Result<Self> {
+        let (sender, _) = transport::transport_channel(
+            MAX_PACKET_SIZE,
+            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
+        )?;
+        let (_, receiver) = transport::transport_channel(
+            655535,
+            // IPアドレスが必要なのでLayer3(Ipパケットレベルで取得する)
+            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
+        )?;
+        Ok(Self {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+        })
+    }
+}
+
+// rawソケットもIPヘッダの組み立てもIPv4専用なので, アドレスはIpv4Addrのまま扱う
+impl Device for RawDevice {
+    fn send(
+        &self,
+        packet: &TCPPacket,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+    ) -> Result<usize> {
+        let datagram = ipv4_datagram(packet, local_addr, remote_addr)?;
+        let sent_size = self
+            .sender
+            .lock()
+            .recover()
+            .send_to(datagram, IpAddr::V4(remote_addr))?;
+        Ok(sent_size)
+    }
+
+    fn send_batch(&self, segments: &[OutgoingSegment]) -> Result<usize> {
+        // pnetにはsendmmsgがないので1セグメント毎にsendtoを呼ぶ. まとめて減らせるのはロックの取得だけ
+        let mut sender = self.sender.lock().recover();
+        for segment in segments {
+            let datagram = ipv4_datagram(&segment.packet, segment.local_addr, segment.remote_addr)?;
+            sender.send_to(datagram, IpAddr::V4(segment.remote_addr))?;
+        }
+        Ok(segments.len())
+    }
+
+    fn recv(&self, timeout: Option<Duration>) -> Result<Option<ReceivedPacket>> {
+        let mut receiver = self.receiver.lock().recover();
+        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
+        let (packet, remote_addr) = match timeout {
+            Some(timeout) => match packet_iter.next_with_timeout(timeout)? {
+                Some(next) => next,
+                None => return Ok(None),
+            },
+            None => packet_iter.next()?,
+        };
+
+        // packetは相手視点になるため, こちら視点のlocal_addrは相手視点のremote_addrで, こちら視点のremote_addrは相手視点のlocal_addrとなる
+        let local_addr = packet.get_destination();
+        let remote_addr = match remote_addr {
+            IpAddr::V4(addr) => addr,
+            _ => bail!("not an ipv4 packet"),
+        };
+
+        // pnetのTcpPacketから自前定義のTCPPacketを作成
+        let tcp_packet = TcpPacket::new(packet.payload()).context("invalid tcp packet")?;
+        let mut tcp_packet = TCPPacket::from(tcp_packet);
+        tcp_packet.set_ecn(Ecn::from_bits(packet.get_ecn()));
+        Ok(Some(ReceivedPacket {
+            packet: tcp_packet,
+            local_addr,
+            remote_addr,
+        }))
+    }
+
+    fn source_addr(&self, _remote_addr: Ipv4Addr) -> Result<Ipv4Addr> {
+        get_source_ipv4_addr()
+    }
+}
+
+/// セグメントにIPヘッダを付ける. ECNフィールドにはセグメントのECNをそのまま書く
+fn ipv4_datagram(
+    packet: &TCPPacket,
+    local_addr: Ipv4Addr,
+    remote_addr: Ipv4Addr,
+) -> Result<MutableIpv4Packet<'static>> {
+    let total_len = IPV4_HEADER_SIZE + packet.packet().len();
+    let mut datagram = MutableIpv4Packet::owned(vec![0; total_len]).context("too short buffer")?;
+    datagram.set_version(4);
+    datagram.set_header_length((IPV4_HEADER_SIZE / 4) as u8);
+    datagram.set_ecn(packet.get_ecn().bits());
+    datagram.set_total_length(total_len as u16);
+    datagram.set_flags(Ipv4Flags::DontFragment);
+    datagram.set_ttl(DEFAULT_TTL);
+    datagram.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    datagram.set_source(local_addr);
+    datagram.set_destination(remote_addr);
+    datagram.set_payload(packet.packet());
+    datagram.set_checksum(ipv4::checksum(&datagram.to_immutable()));
+    Ok(datagram)
+}
+
+/// 送信したセグメントをそのまま自分で受信するデバイス
+/// 同じTCPの中のソケット同士でしか通信できないが, root権限なしでテストを動かせる
+#[derive(Default)]
+pub struct LoopbackDevice {
+    queue: Mutex<VecDeque<ReceivedPacket>>,
+    condvar: Condvar,
+}
+
+impl Device for LoopbackDevice {
+    fn send(&self, packet: &TCPPacket, local_addr: Addr, remote_addr: Addr) -> Result<usize> {
+        // 受信側から見ると送信元と宛先が入れ替わる
+        self.queue.lock().recover().push_back(ReceivedPacket {
+            packet: packet.clone(),
+            local_addr: remote_addr,
+            remote_addr: local_addr,
+        });
+        self.condvar.notify_one();
+        Ok(packet.packet().len())
+    }
+
+    fn send_batch(&self, segments: &[OutgoingSegment]) -> Result<usize> {
+        let mut queue = self.queue.lock().recover();
+        for segment in segments {
+            queue.push_back(ReceivedPacket {
+                packet: segment.packet.clone(),
+                local_addr: segment.remote_addr,
+                remote_addr: segment.local_addr,
+            });
+        }
+        self.condvar.notify_one();
+        Ok(1)
+    }
+
+    fn recv(&self, timeout: Option<Duration>) -> Result<Option<ReceivedPacket>> {
+        let mut queue = self.queue.lock().recover();
+        loop {
+            if let Some(received) = queue.pop_front() {
+                return Ok(Some(received));
+            }
+            match timeout {
+                Some(timeout) => {
+                    queue = self.condvar.wait_timeout(queue, timeout).recover().0;
+                    return Ok(queue.pop_front());
+                }
+                None => queue = self.condvar.wait(queue).recover(),
+            }
+        }
+    }
+
+    fn source_addr(&self, remote_addr: Addr) -> Result<Addr> {
+        // 同じホストの中で完結するので宛先がそのまま送信元になる
+        Ok(remote_addr)
+    }
+}
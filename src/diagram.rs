@@ -0,0 +1,172 @@
+use std::fmt::Write;
+
+use crate::eventlog::{LogEvent, LogRecord, SegmentRecord};
+
+/// 状態遷移1つ分. triggerは遷移の直前に送受信したセグメント
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub from: String,
+    pub to: String,
+    /// 最初のイベントからの経過時間(マイクロ秒)
+    pub elapsed: u64,
+    pub trigger: Option<Trigger>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trigger {
+    pub direction: &'static str,
+    pub segment: SegmentRecord,
+}
+
+/// イベントログから状態遷移を取り出す
+pub fn transitions(records: &[LogRecord]) -> Vec<Transition> {
+    let start = records.first().map(|r| r.time).unwrap_or(0);
+    let mut trigger = None;
+    let mut transitions = Vec::new();
+
+    for record in records {
+        match &record.event {
+            LogEvent::SegmentReceived(segment) => {
+                trigger = Some(Trigger {
+                    direction: "recv",
+                    segment: segment.clone(),
+                })
+            }
+            LogEvent::SegmentSent(segment) | LogEvent::SegmentRetransmitted(segment) => {
+                trigger = Some(Trigger {
+                    direction: "send",
+                    segment: segment.clone(),
+                })
+            }
+            LogEvent::StateTransition { from, to } => transitions.push(Transition {
+                from: from.clone(),
+                to: to.clone(),
+                elapsed: record.time - start,
+                trigger: trigger.take(),
+            }),
+            LogEvent::TimerFired { .. } => {}
+        }
+    }
+    transitions
+}
+
+fn label(transition: &Transition) -> String {
+    let mut label = format!("+{:.3}ms", transition.elapsed as f64 / 1000.0);
+    if let Some(trigger) = &transition.trigger {
+        let segment = &trigger.segment;
+        write!(
+            label,
+            " {} {} seq={} ack={} len={}",
+            trigger.direction, segment.flags, segment.seq, segment.ack, segment.len
+        )
+        .unwrap();
+    }
+    label
+}
+
+/// Graphvizのdot形式で出力する. 辺には遷移の順番を振る
+pub fn to_dot(records: &[LogRecord]) -> String {
+    let mut dot = String::from("digraph tcp {\n    rankdir=LR;\n");
+    for (i, transition) in transitions(records).iter().enumerate() {
+        writeln!(
+            dot,
+            "    \"{}\" -> \"{}\" [label=\"{}: {}\"];",
+            transition.from,
+            transition.to,
+            i + 1,
+            label(transition)
+        )
+        .unwrap();
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Mermaidのstate diagram形式で出力する
+pub fn to_mermaid(records: &[LogRecord]) -> String {
+    let transitions = transitions(records);
+    let mut mermaid = String::from("stateDiagram-v2\n");
+    if let Some(first) = transitions.first() {
+        writeln!(mermaid, "    [*] --> {}", first.from).unwrap();
+    }
+    for (i, transition) in transitions.iter().enumerate() {
+        writeln!(
+            mermaid,
+            "    {} --> {}: {}. {}",
+            transition.from,
+            transition.to,
+            i + 1,
+            label(transition)
+        )
+        .unwrap();
+    }
+    mermaid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcpflags::TcpFlags;
+
+    // 能動的にオープンしたクライアント側のログ. タイマーはどの遷移のきっかけにもならない
+    fn handshake_log() -> Vec<LogRecord> {
+        let segment = |seq, ack, flags| SegmentRecord {
+            seq,
+            ack,
+            flags,
+            len: 0,
+        };
+        let transition = |from: &str, to: &str| LogEvent::StateTransition {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        [
+            (1_000, transition("Closed", "SynSent")),
+            (1_000, LogEvent::SegmentSent(segment(100, 0, TcpFlags::SYN))),
+            (
+                2_500,
+                LogEvent::SegmentReceived(segment(500, 101, TcpFlags::SYN | TcpFlags::ACK)),
+            ),
+            (
+                2_500,
+                LogEvent::TimerFired {
+                    timer: "delayed_ack".to_string(),
+                },
+            ),
+            (2_750, transition("SynSent", "Established")),
+        ]
+        .into_iter()
+        .map(|(time, event)| LogRecord { time, event })
+        .collect()
+    }
+
+    #[test]
+    fn transitions_remember_the_segment_that_triggered_them() {
+        let transitions = transitions(&handshake_log());
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(transitions[0].trigger, None);
+        assert_eq!(transitions[1].elapsed, 1_750);
+        let trigger = transitions[1].trigger.as_ref().unwrap();
+        assert_eq!(trigger.direction, "recv");
+        assert_eq!(trigger.segment.seq, 500);
+    }
+
+    #[test]
+    fn dot_and_mermaid_number_the_transitions() {
+        assert_eq!(
+            to_dot(&handshake_log()),
+            "digraph tcp {\n    rankdir=LR;\n    \
+             \"Closed\" -> \"SynSent\" [label=\"1: +0.000ms\"];\n    \
+             \"SynSent\" -> \"Established\" [label=\"2: +1.750ms recv SYN ACK seq=500 ack=101 len=0\"];\n\
+             }\n"
+        );
+        assert_eq!(
+            to_mermaid(&handshake_log()),
+            "stateDiagram-v2\n    \
+             [*] --> Closed\n    \
+             Closed --> SynSent: 1. +0.000ms\n    \
+             SynSent --> Established: 2. +1.750ms recv SYN ACK seq=500 ack=101 len=0\n"
+        );
+        assert_eq!(to_mermaid(&[]), "stateDiagram-v2\n");
+    }
+}
@@ -0,0 +1,466 @@
+use anyhow::{bail, Result};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::task;
+use std::time::{Duration, Instant};
+
+use crate::stats::CloseReason;
+use crate::sync::{Condvar, LockResultExt, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TCPEventKind {
+    ConnectionCompleted,
+    Acked,
+    DataArrived,
+    ConnectionClosed,
+    /// RSTで接続が中断された. 相手から受け取ったか(ResetReceived), こちらから送ったか(ResetSent)
+    Reset(CloseReason),
+    /// 再送やキープアライブのプローブに応答がなく接続が中断された
+    Timeout(CloseReason),
+    /// 処理中のパニックなど, スタック内部のエラーで接続が中断された
+    Error(CloseReason),
+}
+
+/// TCP::subscribeで受け取れるソケット毎の通知
+/// TCPEventKindと違いAPIのブロッキングには使わず, 購読しているアプリケーションに順番通り全て届く
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketNotification {
+    /// 受信バッファの使用量がhighに達した. 相手からの送信を止めるべき
+    BufferHighWatermark { used: usize },
+    /// highに達した後, recvで読み出されてlowまで下がった
+    BufferLowWatermark { used: usize },
+    /// acceptを待っている接続がTcpConfig::accept_queue_watermarkに達した. acceptするスレッドを増やすべき
+    AcceptQueueHighWatermark { len: usize },
+    /// RTTを1回測った. TCP::set_rtt_notificationsで有効にした場合のみ届く
+    /// 測った値(rtt)と, それを反映した後のSRTT, RTTVAR
+    RttSample {
+        rtt: Duration,
+        srtt: Duration,
+        rttvar: Duration,
+    },
+    /// 緊急データが届いた. TCP::recv_urgentで読み出せる. pendingはまだ読まれていない緊急データのバイト数
+    UrgentData { pending: usize },
+}
+
+impl TCPEventKind {
+    /// 接続がreasonで終了した時に発行する失敗のイベント. 正常な終了ならNone
+    pub fn failure(reason: CloseReason) -> Option<Self> {
+        match reason {
+            CloseReason::ResetReceived | CloseReason::ResetSent => Some(Self::Reset(reason)),
+            CloseReason::RetransmissionExhausted
+            | CloseReason::KeepAliveTimeout
+            | CloseReason::UserTimeout => Some(Self::Timeout(reason)),
+            CloseReason::InternalError => Some(Self::Error(reason)),
+            CloseReason::Fin | CloseReason::TimeWaitReaped | CloseReason::ListenerClosed => None,
+        }
+    }
+
+    /// Reset, Timeout, Errorのどれか
+    pub fn is_failure(self) -> bool {
+        matches!(self, Self::Reset(_) | Self::Timeout(_) | Self::Error(_))
+    }
+
+    fn bit(self) -> u8 {
+        match self {
+            Self::ConnectionCompleted => 1,
+            Self::Acked => 1 << 1,
+            Self::DataArrived => 1 << 2,
+            Self::ConnectionClosed => 1 << 3,
+            // 失敗はビットではなくEventState::failureに残す
+            Self::Reset(_) | Self::Timeout(_) | Self::Error(_) => 0,
+        }
+    }
+
+    // 失敗のイベントで待機が中断された時のエラーメッセージ
+    fn error_message(self) -> &'static str {
+        match self {
+            Self::Reset(CloseReason::ResetReceived) => "connection reset",
+            Self::Reset(_) => "connection aborted",
+            Self::Timeout(_) => "connection timed out",
+            Self::Error(_) => "connection aborted by an internal error",
+            _ => "socket has been closed",
+        }
+    }
+}
+
+#[derive(Default)]
+struct EventState {
+    // 発行済みでまだ待機側に消費されていないイベント(TCPEventKind::bitの論理和)
+    pending: u8,
+    // ソケットがテーブルから削除された
+    removed: bool,
+    // 最初に発行された失敗のイベント. 消費されずに残り, 以降のwaitは全てこれのエラーを返す
+    failure: Option<TCPEventKind>,
+}
+
+/// ソケット毎のイベント通知. 発行したイベントはそのソケットで待機しているスレッドだけを起こし,
+/// 他のソケットのイベントで上書きされることはない
+/// 待機側はsocketsのロックを外す前にArcをcloneしておくことで, 待機中にソケットが削除されても確実に起こされる
+#[derive(Default)]
+pub struct SocketEvents {
+    state: Mutex<EventState>,
+    condvar: Condvar,
+    subscribers: Mutex<Vec<Sender<SocketNotification>>>,
+    // pollで待っているスレッドやmioのイベントソース. イベントの種類に関わらず全てに知らせる
+    watchers: Mutex<Vec<Arc<dyn Notify>>>,
+    // async_recvなどのFutureを待っているタスク. 次のイベントで一度だけ起こし, Futureがpollされる度に登録し直す
+    tasks: Mutex<Vec<task::Waker>>,
+}
+
+impl SocketEvents {
+    /// イベントを発行する. 待機しているスレッドがいなければ次にwaitされるまで保持しておく
+    /// 失敗のイベントは最初の1つだけを残し, 何を待っているかに関わらず全ての待機を起こす
+    pub fn publish(&self, kind: TCPEventKind) {
+        let mut state = self.state.lock().recover();
+        if kind.is_failure() {
+            state.failure.get_or_insert(kind);
+        } else {
+            state.pending |= kind.bit();
+        }
+        self.condvar.notify_all();
+        drop(state);
+        self.wake_all();
+    }
+
+    /// kindが発行されるまで待機し, 発行されたら消費してtrueを返す
+    /// deadlineを過ぎても発行されなければfalseを返す. Noneならいつまでも待つ
+    /// 失敗のイベントが発行されていればkindが発行済みでもそちらを優先し, 理由に応じたエラーを返す
+    /// 待機中にソケットが削除された場合もエラーを返す
+    pub fn wait(&self, kind: TCPEventKind, deadline: Option<Instant>) -> Result<bool> {
+        let mut state = self.state.lock().recover();
+        loop {
+            if let Some(failure) = state.failure {
+                bail!(failure.error_message());
+            }
+            if state.pending & kind.bit() > 0 {
+                state.pending &= !kind.bit();
+                return Ok(true);
+            }
+            if state.removed {
+                bail!("socket has been closed");
+            }
+            // cvarがnotifyされるまでstateのロックを外して待機
+            // 何も発行されていないのに起きる(spurious wakeup)こともあるので, 起きたら状態を確認し直し, 残りの時間だけ待ち直す
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    self.condvar.wait_timeout(state, deadline - now).recover().0
+                }
+                None => self.condvar.wait(state).recover(),
+            };
+        }
+    }
+
+    /// ソケットがテーブルから削除されたことを通知し, 待機している全てのスレッドを起こす
+    /// 購読者のチャンネルも閉じる
+    pub fn mark_removed(&self) {
+        let mut state = self.state.lock().recover();
+        state.removed = true;
+        self.condvar.notify_all();
+        drop(state);
+        self.subscribers.lock().recover().clear();
+        self.wake_all();
+    }
+
+    /// 発行された失敗のイベント. 接続が中断されていなければNone
+    pub fn failure(&self) -> Option<TCPEventKind> {
+        self.state.lock().recover().failure
+    }
+
+    /// 通知を受け取るチャンネルを作る
+    pub fn subscribe(&self) -> Receiver<SocketNotification> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().recover().push(sender);
+        receiver
+    }
+
+    /// 以降に発行されるイベントをwatcherに知らせる
+    pub fn register(&self, watcher: Arc<dyn Notify>) {
+        self.watchers.lock().recover().push(watcher);
+    }
+
+    pub fn deregister(&self, watcher: &Arc<dyn Notify>) {
+        self.watchers
+            .lock()
+            .recover()
+            .retain(|registered| !Arc::ptr_eq(registered, watcher));
+    }
+
+    /// 次に発行されるイベントでタスクを起こす. 同じタスクは重ねて登録しない
+    pub fn register_task(&self, waker: &task::Waker) {
+        let mut tasks = self.tasks.lock().recover();
+        if !tasks.iter().any(|task| task.will_wake(waker)) {
+            tasks.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&self) {
+        for watcher in self.watchers.lock().recover().iter() {
+            watcher.notify();
+        }
+        // タスクを起こすとその場でpollされることもあるので, ロックを外してから起こす
+        let tasks = std::mem::take(&mut *self.tasks.lock().recover());
+        for task in tasks {
+            task.wake();
+        }
+    }
+
+    /// 全ての購読者に通知する. Receiverが捨てられた購読者はここで取り除く
+    pub fn notify(&self, notification: SocketNotification) {
+        self.subscribers
+            .lock()
+            .recover()
+            .retain(|subscriber| subscriber.send(notification).is_ok());
+    }
+}
+
+/// SocketEventsにイベントが発行される度に知らせを受けるもの. 発行したスレッドから呼ばれるのでブロックしないこと
+pub trait Notify: Send + Sync {
+    fn notify(&self);
+}
+
+/// 複数のソケットのイベントをまとめて待つための通知先. TCP::pollが待つ間だけ各ソケットのSocketEventsに登録する
+/// ソケット毎のCondvarはそのソケットを待つスレッドしか起こさないので, 1つのスレッドで多数のソケットを待つにはこちらを使う
+#[derive(Default)]
+pub struct Waker {
+    // 前回のwaitから起こされたか. 待ち始める前に届いた通知も取りこぼさない
+    woken: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        *self.woken.lock().recover() = true;
+        self.condvar.notify_all();
+    }
+
+    /// wakeされるかdeadlineを過ぎるまで待つ. Noneならいつまでも待つ. 起こされたかを返す
+    pub fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut woken = self.woken.lock().recover();
+        while !*woken {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    woken = self.condvar.wait_timeout(woken, deadline - now).recover().0;
+                }
+                None => woken = self.condvar.wait(woken).recover(),
+            }
+        }
+        *woken = false;
+        true
+    }
+}
+
+impl Notify for Waker {
+    fn notify(&self) {
+        self.wake();
+    }
+}
+
+// loomの同期プリミティブはloom::modelの外では使えないので, 通常のテストはloomのビルドから外す
+#[cfg(all(test, not(toytcp_loom)))]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn event_published_before_wait_is_not_lost() {
+        // ロックを外してからwaitするまでの間にpublishされた場合
+        let events = SocketEvents::default();
+        events.publish(TCPEventKind::DataArrived);
+        events.wait(TCPEventKind::DataArrived, None).unwrap();
+    }
+
+    #[test]
+    fn concurrent_publish_and_wait_do_not_lose_wakeups() {
+        // 待機側と発行側を交互に走らせ, どんな順番でも1回のpublishで1回のwaitが返ることを確認する
+        for _ in 0..1000 {
+            let events = Arc::new(SocketEvents::default());
+            let waiter = {
+                let events = events.clone();
+                thread::spawn(move || events.wait(TCPEventKind::Acked, None))
+            };
+            events.publish(TCPEventKind::Acked);
+            waiter.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn other_kinds_do_not_consume_the_event() {
+        let events = SocketEvents::default();
+        events.publish(TCPEventKind::Acked);
+        events.publish(TCPEventKind::DataArrived);
+        events.wait(TCPEventKind::DataArrived, None).unwrap();
+        events.wait(TCPEventKind::Acked, None).unwrap();
+    }
+
+    #[test]
+    fn failure_takes_precedence_and_is_kept() {
+        let events = Arc::new(SocketEvents::default());
+        let waiter = {
+            let events = events.clone();
+            thread::spawn(move || events.wait(TCPEventKind::Acked, None))
+        };
+        events.publish(TCPEventKind::DataArrived);
+        events.publish(TCPEventKind::Timeout(CloseReason::KeepAliveTimeout));
+        // 後から発行された失敗は最初のものを上書きしない
+        events.publish(TCPEventKind::Reset(CloseReason::ResetReceived));
+        let error = waiter.join().unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "connection timed out");
+
+        // 発行済みのイベントより失敗を優先し, 何度waitしてもエラーを返す
+        for _ in 0..2 {
+            assert!(events.wait(TCPEventKind::DataArrived, None).is_err());
+        }
+        assert_eq!(
+            events.failure(),
+            Some(TCPEventKind::Timeout(CloseReason::KeepAliveTimeout))
+        );
+    }
+
+    #[test]
+    fn wait_gives_up_at_the_deadline() {
+        let events = Arc::new(SocketEvents::default());
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(50);
+        // 別の種類のイベントで起こされても, deadlineまで待ち直す
+        let publisher = {
+            let events = events.clone();
+            thread::spawn(move || events.publish(TCPEventKind::Acked))
+        };
+        assert!(!events
+            .wait(TCPEventKind::DataArrived, Some(deadline))
+            .unwrap());
+        assert!(Instant::now() >= deadline);
+        publisher.join().unwrap();
+
+        // 発行済みなら, 過ぎたdeadlineでも消費して返る
+        events.publish(TCPEventKind::DataArrived);
+        assert!(events.wait(TCPEventKind::DataArrived, Some(start)).unwrap());
+    }
+
+    #[test]
+    fn registered_waker_is_woken_by_any_event() {
+        let events = SocketEvents::default();
+        let waker = Arc::new(Waker::default());
+        let watcher: Arc<dyn Notify> = waker.clone();
+        events.register(watcher.clone());
+        // 待ち始める前の通知も残っている
+        events.publish(TCPEventKind::Acked);
+        assert!(waker.wait(Some(Instant::now())));
+        assert!(!waker.wait(Some(Instant::now() + Duration::from_millis(10))));
+
+        let waiter = {
+            let waker = waker.clone();
+            thread::spawn(move || waker.wait(None))
+        };
+        events.mark_removed();
+        assert!(waiter.join().unwrap());
+
+        events.deregister(&watcher);
+        events.publish(TCPEventKind::DataArrived);
+        assert!(!waker.wait(Some(Instant::now())));
+    }
+
+    #[test]
+    fn removal_wakes_every_waiter() {
+        for _ in 0..100 {
+            let events = Arc::new(SocketEvents::default());
+            let waiters: Vec<_> = [TCPEventKind::Acked, TCPEventKind::DataArrived]
+                .into_iter()
+                .map(|kind| {
+                    let events = events.clone();
+                    thread::spawn(move || events.wait(kind, None))
+                })
+                .collect();
+            events.mark_removed();
+            for waiter in waiters {
+                assert!(waiter.join().unwrap().is_err());
+            }
+        }
+    }
+}
+
+// loomでスレッドの実行順を全て試し, どの順番でも通知を取りこぼさないことを確認する
+// RUSTFLAGS="--cfg toytcp_loom" cargo test --release --lib loom
+#[cfg(all(test, toytcp_loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn publish_and_wait_in_any_order() {
+        loom::model(|| {
+            let events = Arc::new(SocketEvents::default());
+            let publisher = {
+                let events = events.clone();
+                thread::spawn(move || events.publish(TCPEventKind::DataArrived))
+            };
+            assert!(events.wait(TCPEventKind::DataArrived, None).unwrap());
+            publisher.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn failure_and_removal_wake_a_waiter_of_any_kind() {
+        loom::model(|| {
+            let events = Arc::new(SocketEvents::default());
+            let aborter = {
+                let events = events.clone();
+                thread::spawn(move || {
+                    events.publish(TCPEventKind::Reset(CloseReason::ResetReceived));
+                    events.mark_removed();
+                })
+            };
+            let error = events.wait(TCPEventKind::Acked, None).unwrap_err();
+            assert_eq!(error.to_string(), "connection reset");
+            aborter.join().unwrap();
+        });
+    }
+
+    // send/recvと同じ手順で待つ. socketsのロックの中で状態を確かめ, 足りなければArcを持ったままロックを外して待ち,
+    // 起きたらロックを取り直して確かめ直す. 相手はロックの中で状態を変えてからpublishする
+    // 2回に分けて届けるので, 1回目の通知で起きて足りずにまた待つ順番も試される
+    fn wait_after_dropping_the_lock(kind: TCPEventKind) {
+        loom::model(move || {
+            let sockets = Arc::new(Mutex::new(0));
+            let events = Arc::new(SocketEvents::default());
+            let waiter = {
+                let sockets = sockets.clone();
+                let events = events.clone();
+                thread::spawn(move || loop {
+                    let available = sockets.lock().recover();
+                    if *available >= 2 {
+                        return *available;
+                    }
+                    drop(available);
+                    assert!(events.wait(kind, None).unwrap());
+                })
+            };
+            for _ in 0..2 {
+                let mut available = sockets.lock().recover();
+                *available += 1;
+                events.publish(kind);
+            }
+            assert_eq!(waiter.join().unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn recv_waiting_for_data_is_not_lost() {
+        wait_after_dropping_the_lock(TCPEventKind::DataArrived);
+    }
+
+    #[test]
+    fn send_waiting_for_room_is_not_lost() {
+        wait_after_dropping_the_lock(TCPEventKind::Acked);
+    }
+}
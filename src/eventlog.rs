@@ -0,0 +1,283 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::packet::TCPPacket;
+use crate::socket::{Endpoint, SockID};
+use crate::tcpflags::TcpFlags;
+use pnet::packet::Packet;
+
+/// イベントログの1行分
+/// timeはUNIXエポックからのマイクロ秒(壁時計なので巻き戻ることもある)
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LogRecord {
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: LogEvent,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LogEvent {
+    StateTransition { from: String, to: String },
+    TimerFired { timer: String },
+    SegmentSent(SegmentRecord),
+    SegmentReceived(SegmentRecord),
+    SegmentRetransmitted(SegmentRecord),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SegmentRecord {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: TcpFlags,
+    pub len: usize,
+}
+
+impl From<&TCPPacket> for SegmentRecord {
+    fn from(packet: &TCPPacket) -> Self {
+        Self {
+            seq: packet.get_seq(),
+            ack: packet.get_ack(),
+            flags: packet.get_flag(),
+            len: packet.payload().len(),
+        }
+    }
+}
+
+/// 接続毎のイベントログ
+/// 1イベント1行のJSON(JSON Lines)でファイルに書き出す
+pub struct EventLog {
+    writer: BufWriter<File>,
+}
+
+impl EventLog {
+    /// dir以下に接続の4タプルから決まる名前でログファイルを開く
+    /// 同じ4タプルが再利用されても前の接続のログを消さないよう追記する
+    pub fn create(dir: &Path, sock_id: SockID) -> Result<Self> {
+        let path = dir.join(format!(
+            "{}_{}-{}_{}.jsonl",
+            sock_id.local.addr(),
+            sock_id.local.port(),
+            sock_id.remote.addr(),
+            sock_id.remote.port()
+        ));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(format!("failed to open {:?}", path))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, event: LogEvent) {
+        let record = LogRecord {
+            time: now_micros(),
+            event,
+        };
+        // ログの書き込みに失敗しても通信自体は続けたいのでエラーは無視する
+        if let Ok(line) = serde_json::to_string(&record) {
+            let _ = writeln!(self.writer, "{}", line);
+            let _ = self.writer.flush();
+        }
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// ログファイルを読み込む
+pub fn read_log(path: &Path) -> Result<Vec<LogRecord>> {
+    let file = File::open(path).context(format!("failed to open {:?}", path))?;
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).context(format!("invalid record: {}", line))?);
+    }
+    Ok(records)
+}
+
+/// ログから集計した接続の概要
+#[derive(Debug, Default, PartialEq)]
+pub struct LogSummary {
+    /// 最初のSYNからEstablishedになるまでの時間(マイクロ秒). 確立していなければNone
+    pub handshake_time: Option<u64>,
+    pub retransmissions: usize,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    /// 最初のイベントから最後のイベントまでの時間(マイクロ秒)
+    pub duration: u64,
+}
+
+impl LogSummary {
+    /// 送受信したペイロードの合計から計算したスループット(bytes/sec)
+    pub fn throughput(&self) -> f64 {
+        if self.duration == 0 {
+            return 0.0;
+        }
+        (self.bytes_sent + self.bytes_received) as f64 / (self.duration as f64 / 1_000_000.0)
+    }
+}
+
+pub fn summarize(records: &[LogRecord]) -> LogSummary {
+    let mut summary = LogSummary::default();
+    let mut syn_time = None;
+
+    for record in records {
+        match &record.event {
+            LogEvent::SegmentSent(segment) | LogEvent::SegmentReceived(segment)
+                if segment.flags.has_syn() && syn_time.is_none() =>
+            {
+                syn_time = Some(record.time);
+            }
+            _ => {}
+        }
+
+        match &record.event {
+            LogEvent::StateTransition { to, .. } if to == "Established" => {
+                if let (None, Some(syn_time)) = (summary.handshake_time, syn_time) {
+                    summary.handshake_time = Some(record.time.saturating_sub(syn_time));
+                }
+            }
+            LogEvent::SegmentSent(segment) => summary.bytes_sent += segment.len,
+            LogEvent::SegmentReceived(segment) => summary.bytes_received += segment.len,
+            LogEvent::SegmentRetransmitted(_) => summary.retransmissions += 1,
+            _ => {}
+        }
+    }
+
+    if let (Some(first), Some(last)) = (records.first(), records.last()) {
+        summary.duration = last.time.saturating_sub(first.time);
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(flags: TcpFlags, len: usize) -> SegmentRecord {
+        SegmentRecord {
+            seq: 0,
+            ack: 0,
+            flags,
+            len,
+        }
+    }
+
+    fn record(time: u64, event: LogEvent) -> LogRecord {
+        LogRecord { time, event }
+    }
+
+    #[test]
+    fn summarize_counts_handshake_bytes_and_retransmissions() {
+        let records = [
+            record(1_000, LogEvent::SegmentSent(segment(TcpFlags::SYN, 0))),
+            record(
+                1_300,
+                LogEvent::SegmentReceived(segment(TcpFlags::SYN | TcpFlags::ACK, 0)),
+            ),
+            record(
+                1_500,
+                LogEvent::StateTransition {
+                    from: "SynSent".to_string(),
+                    to: "Established".to_string(),
+                },
+            ),
+            record(2_000, LogEvent::SegmentSent(segment(TcpFlags::ACK, 1000))),
+            record(
+                2_500,
+                LogEvent::TimerFired {
+                    timer: "retransmission".to_string(),
+                },
+            ),
+            record(
+                2_500,
+                LogEvent::SegmentRetransmitted(segment(TcpFlags::ACK, 1000)),
+            ),
+            record(
+                501_000,
+                LogEvent::SegmentReceived(segment(TcpFlags::ACK, 500)),
+            ),
+        ];
+
+        let summary = summarize(&records);
+        assert_eq!(
+            summary,
+            LogSummary {
+                handshake_time: Some(500),
+                retransmissions: 1,
+                bytes_sent: 1000,
+                bytes_received: 500,
+                duration: 500_000,
+            }
+        );
+        assert_eq!(summary.throughput(), 3000.0);
+    }
+
+    #[test]
+    fn summarize_without_handshake() {
+        assert_eq!(summarize(&[]), LogSummary::default());
+        let records = [record(
+            10,
+            LogEvent::SegmentReceived(segment(TcpFlags::ACK, 100)),
+        )];
+        let summary = summarize(&records);
+        assert_eq!(summary.handshake_time, None);
+        assert_eq!(summary.bytes_received, 100);
+        assert_eq!(summary.throughput(), 0.0);
+    }
+
+    #[test]
+    fn summarize_tolerates_a_clock_going_backwards() {
+        let records = [
+            record(2_000, LogEvent::SegmentSent(segment(TcpFlags::SYN, 0))),
+            record(
+                1_000,
+                LogEvent::StateTransition {
+                    from: "SynSent".to_string(),
+                    to: "Established".to_string(),
+                },
+            ),
+        ];
+        let summary = summarize(&records);
+        assert_eq!(summary.handshake_time, Some(0));
+        assert_eq!(summary.duration, 0);
+    }
+
+    #[test]
+    fn reused_tuple_appends_to_the_existing_log() {
+        let dir = std::env::temp_dir().join(format!("toytcp-eventlog-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sock_id = SockID {
+            local: "127.0.0.1:40000".parse().unwrap(),
+            remote: "127.0.0.1:80".parse().unwrap(),
+        };
+        for _ in 0..2 {
+            let mut log = EventLog::create(&dir, sock_id).unwrap();
+            log.record(LogEvent::TimerFired {
+                timer: "retransmission".to_string(),
+            });
+        }
+        let path = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert_eq!(read_log(&path).unwrap().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use crate::socket::Addr;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// サーバーが発行するクッキーの長さ
+pub const COOKIE_LEN: usize = 8;
+
+/// TCP Fast Open(RFC 7413)のサーバー側のクッキー
+/// クライアントのIPアドレスの鍵付きハッシュにする. 最初の接続のSYN/ACKで渡し,
+/// 次からはSYNに載せて送り返してもらえば, そのクライアントは以前にハンドシェイクを終えたことがあると分かる
+/// その場合はSYNのデータを3ウェイハンドシェイクを待たずに受け取る
+#[derive(Debug)]
+pub struct FastOpenCookies {
+    secret: u64,
+}
+
+impl FastOpenCookies {
+    pub fn new(secret: u64) -> Self {
+        Self { secret }
+    }
+
+    /// clientに渡すクッキー
+    pub fn generate(&self, client: Addr) -> Vec<u8> {
+        let mut hasher = DefaultHasher::new();
+        (client, self.secret).hash(&mut hasher);
+        hasher.finish().to_be_bytes()[..COOKIE_LEN].to_vec()
+    }
+
+    /// clientのSYNに付いていたクッキーが, こちらが渡したものか
+    pub fn validate(&self, client: Addr, cookie: &[u8]) -> bool {
+        cookie == self.generate(client)
+    }
+}
+
+impl Default for FastOpenCookies {
+    fn default() -> Self {
+        Self::new(rand::thread_rng().gen())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn cookie_is_bound_to_the_client_and_the_secret() {
+        let cookies = FastOpenCookies::new(1);
+        let client = Ipv4Addr::new(10, 0, 0, 2);
+        let cookie = cookies.generate(client);
+        assert_eq!(cookie.len(), COOKIE_LEN);
+        assert!(cookies.validate(client, &cookie));
+
+        assert!(!cookies.validate(Ipv4Addr::new(10, 0, 0, 3), &cookie));
+        assert!(!FastOpenCookies::new(2).validate(client, &cookie));
+        assert!(!cookies.validate(client, &cookie[..4]));
+        assert!(!cookies.validate(client, &[]));
+    }
+}
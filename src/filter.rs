@@ -0,0 +1,57 @@
+use pnet::packet::Packet;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::packet::TCPPacket;
+use crate::socket::Addr;
+use crate::tcpflags::TcpFlags;
+
+/// フィルタに渡されるセグメントの情報. アドレスとポートはこちら視点
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub local_addr: Addr,
+    pub remote_addr: Addr,
+    pub local_port: u16,
+    pub remote_port: u16,
+    pub flags: TcpFlags,
+    pub seq: u32,
+    pub ack: u32,
+    pub payload_len: usize,
+}
+
+impl SegmentInfo {
+    /// こちらから送信するセグメントの情報を作る
+    pub fn outgoing(local_addr: Addr, remote_addr: Addr, packet: &TCPPacket) -> Self {
+        Self {
+            local_addr,
+            remote_addr,
+            local_port: packet.get_src(),
+            remote_port: packet.get_dest(),
+            flags: packet.get_flag(),
+            seq: packet.get_seq(),
+            ack: packet.get_ack(),
+            payload_len: packet.payload().len(),
+        }
+    }
+}
+
+/// セグメントを通すかどうかを判定するフィルタ. trueを返したセグメントだけが通過する
+/// テストで特定の方向やフラグのセグメントを落とし, 片方向の障害を再現するために使う
+#[derive(Clone)]
+pub struct SegmentFilter(Arc<dyn Fn(&SegmentInfo) -> bool + Send + Sync>);
+
+impl SegmentFilter {
+    pub fn new(f: impl Fn(&SegmentInfo) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn allows(&self, info: &SegmentInfo) -> bool {
+        (self.0)(info)
+    }
+}
+
+impl Debug for SegmentFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SegmentFilter")
+    }
+}
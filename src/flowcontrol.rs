@@ -0,0 +1,873 @@
+use anyhow::{bail, Result};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
+
+use crate::packet::SackBlock;
+use crate::socket::SeqNum;
+
+/// ウィンドウスケールのシフト数の上限. RFC 7323
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// 受信バッファの大きさから, 広告するウィンドウスケールのシフト数を決める
+/// バッファ全体をヘッダのウィンドウフィールド(16ビット)で表せる最小のシフト数にする
+/// ウィンドウスケールを使わない場合(scaling == false)は64KBまでしか広告できないので, それより大きいバッファはエラーにする
+pub fn window_scale_for(buffer_size: usize, scaling: bool) -> Result<u8> {
+    if buffer_size == 0 {
+        bail!("receive buffer size must not be zero");
+    }
+    let max_window = (u16::MAX as usize) << MAX_WINDOW_SCALE;
+    if buffer_size > max_window {
+        bail!(
+            "receive buffer of {} bytes exceeds the largest scalable window ({} bytes)",
+            buffer_size,
+            max_window
+        );
+    }
+
+    let mut shift = 0;
+    while buffer_size >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    if shift > 0 && !scaling {
+        bail!(
+            "receive buffer of {} bytes does not fit in the 16-bit window field without window scaling",
+            buffer_size
+        );
+    }
+    Ok(shift)
+}
+
+/// 送信側のシーケンス番号とウィンドウ
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendWindow {
+    pub unacked_seq: u32, // 送信後まだackされてないseqの先頭
+    pub next: u32,        // 次の送信
+    pub window: u32, // 相手が最後に広告した受信ウィンドウ(SND.WND). unacked_seqから数える. ウィンドウスケールは掛けた後の値
+    pub wl1: u32,    // ウィンドウを最後に更新したセグメントのseq(SND.WL1)
+    pub wl2: u32,    // ウィンドウを最後に更新したセグメントのack(SND.WL2)
+    pub initial_seq: u32, // 初期送信sequence、何に使ってるかよく分からない
+    pub cwnd: u32,   // 輻輳ウィンドウ. ackされていないデータはこれを超えて送らない
+    pub ssthresh: u32, // cwndがこれより小さい間はスロースタート, 以上なら輻輳回避
+}
+
+/// 受信側のシーケンス番号とウィンドウ
+/// 受信バッファの先頭からbuffer_len - windowバイトがrecvで読み出せるデータになっている
+/// 順番が入れ替わって届いたデータは受信バッファには置かず, 穴が埋まるまでReassemblyQueueで保持する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvWindow {
+    pub next: u32,        // 次受診するsequence
+    pub window: u32, // 受診ウィンドウサイズ. ウィンドウスケールで広告できるので16ビットに収まるとは限らない
+    pub initial_seq: u32, // 初期受診sequence, 何に使ってるかよく分からない
+}
+
+/// セグメントのペイロードを受信ウィンドウに合わせて切り詰めた結果
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trimmed {
+    /// 全て受信済みのデータの再送
+    Duplicate,
+    /// 受信ウィンドウの右端より後ろから始まっていて, 1バイトも受け取れない
+    BeyondWindow,
+    /// 受け取る部分のペイロード内の範囲. 左端の受信済みの部分と, 右端のウィンドウからはみ出した部分を除いてある
+    Data(Range<usize>),
+}
+
+/// 順番が入れ替わって届き, nextより前のデータを待っているセグメント
+/// 重なったり隣接したりするデータは1つの範囲にまとめ, 穴が埋まった分だけ先頭から取り出す
+/// キーはbase(最後に見たnext)からのオフセットなので, シーケンス番号が2^32を跨いでも順序が崩れない
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReassemblyQueue {
+    base: u32,
+    segments: BTreeMap<u32, Vec<u8>>,
+    bytes: usize,
+}
+
+/// 順番が入れ替わって届き, nextより先に受信済みになっているデータの範囲. SACKブロックの元になる
+/// RFC 2018に従い, 最後に受信したセグメントを含む範囲を先頭にして並べる
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutOfOrderRanges {
+    ranges: Vec<SackBlock>,
+}
+
+/// 受信済みのデータの再送(重複セグメント)の記録
+/// ACKが届かなかっただけなら1回返し直せば再送は止まるはず. 同じ範囲が何度も届き続ける場合は相手がおかしいので,
+/// 1, 2, 4, 8...回目にだけACKを返し, 壊れた相手やロスのひどい経路に同じ速さでACKを返し続けないようにする
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DuplicateSegments {
+    // 最後に届いた重複セグメントの範囲と, 続けて届いた回数
+    last: Option<(u32, usize)>,
+    repeats: u32,
+    /// 届いた重複セグメントの数
+    pub received: u64,
+    /// 返さずに済ませたACKの数
+    pub acks_suppressed: u64,
+}
+
+/// 受信バッファの自動調整. Linuxのtcp_rcv_space_adjustと同じ考え方
+/// 相手は1RTTに受信ウィンドウ分しか送れないので, 速い接続では最初の受信バッファの大きさで転送速度が頭打ちになる
+/// RTT毎にアプリケーションが読み出したバイト数を測り, これまでの周期より多く読めていればその2倍まで受信バッファを広げる
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecvAutoTune {
+    // 広げられる受信バッファの大きさの上限
+    max: usize,
+    // 受信側で測ったRTT. データを受け取るだけの接続は送信側のRTT(srtt)を測れないので, 届いたデータのtsecrから測る
+    rtt: Option<Duration>,
+    // 今の周期の始まりと, その間に読み出したバイト数
+    started: Option<SystemTime>,
+    copied: usize,
+    // これまでの周期で1RTTに読み出せた最大のバイト数
+    space: usize,
+}
+
+impl SendWindow {
+    pub fn new(window: u32) -> Self {
+        Self {
+            unacked_seq: 0,
+            next: 0,
+            window,
+            wl1: 0,
+            wl2: 0,
+            initial_seq: 0,
+            cwnd: u32::MAX,
+            ssthresh: u32::MAX,
+        }
+    }
+
+    /// 送信済みでまだackされていないバイト数
+    pub fn in_flight(&self) -> u32 {
+        self.next.wrapping_sub(self.unacked_seq)
+    }
+
+    /// 今すぐ送信できるバイト数
+    /// 相手の受信ウィンドウと輻輳ウィンドウの空きのうち小さい方になる
+    pub fn writable(&self) -> usize {
+        let window_available = self.window.saturating_sub(self.in_flight()) as usize;
+        let cwnd_available = self.cwnd.saturating_sub(self.in_flight()) as usize;
+        cmp::min(window_available, cwnd_available)
+    }
+
+    /// 次に送信できるセグメントのサイズ. writableをさらにmssと残りのデータ量で制限する
+    pub fn sendable(&self, mss: usize, remaining: usize) -> usize {
+        cmp::min(mss, cmp::min(self.writable(), remaining))
+    }
+
+    /// lenバイトのデータを送信した
+    pub fn on_sent(&mut self, len: usize) {
+        self.next = self.next.wrapping_add(len as u32);
+    }
+
+    /// ハンドシェイクで相手のSYN(SYN/ACK)を受け取った. 最初のウィンドウとSND.WL1/SND.WL2を設定する
+    pub fn init_window(&mut self, seq: u32, ack: u32, window: u32) {
+        self.window = window;
+        self.wl1 = seq;
+        self.wl2 = ack;
+    }
+
+    /// seqとackを持つセグメントが広告したウィンドウでSND.WNDを更新し, 更新したらtrueを返す
+    /// 前回更新したセグメントより古いもの(seqが小さいか, seqが同じでackが小さいもの)では更新しない
+    /// 入れ替わって届いた古いセグメントのウィンドウで, 新しいackに対して古いウィンドウを使ってしまわないようにする. RFC 9293 3.10.7.4
+    /// ackはSND.UNA以上であることを呼び出し側で確認しておくこと
+    pub fn update_window(&mut self, seq: u32, ack: u32, window: u32) -> bool {
+        if SeqNum(seq).lt(SeqNum(self.wl1)) || (seq == self.wl1 && SeqNum(ack).lt(SeqNum(self.wl2)))
+        {
+            return false;
+        }
+        self.window = window;
+        self.wl1 = seq;
+        self.wl2 = ack;
+        true
+    }
+
+    /// 新しいデータがackされたので輻輳ウィンドウを広げる. RFC 5681
+    /// スロースタートではackされたバイト数(1回のACKにつき最大mss)ずつ, 輻輳回避では1RTTに約1mssずつ増やす
+    pub fn grow_cwnd(&mut self, acked: usize, mss: usize) {
+        let increase = if self.cwnd < self.ssthresh {
+            cmp::min(acked, mss) as u32
+        } else {
+            cmp::max(1, (mss * mss) as u32 / self.cwnd)
+        };
+        self.cwnd = self.cwnd.saturating_add(increase);
+    }
+
+    /// 再送タイムアウトでロスを検知した. ssthreshを送信中のデータの半分にし, 輻輳ウィンドウを1セグメントまで縮める
+    pub fn on_loss(&mut self, mss: usize) {
+        self.ssthresh = cmp::max(self.in_flight() / 2, 2 * mss as u32);
+        self.cwnd = mss as u32;
+    }
+
+    /// ロスを伴わずに輻輳を知らされた(ECE). ssthreshを送信中のデータの半分にし, cwndもそこまで縮める
+    /// ロスしたセグメントは無いので, タイムアウトの時のように1セグメントまでは縮めない. RFC 3168
+    pub fn on_congestion_signal(&mut self, mss: usize) {
+        self.ssthresh = cmp::max(self.in_flight() / 2, 2 * mss as u32);
+        self.cwnd = self.ssthresh;
+    }
+}
+
+impl RecvWindow {
+    pub fn new(window: u32) -> Self {
+        Self {
+            next: 0,
+            window,
+            initial_seq: 0,
+        }
+    }
+
+    /// 受信バッファに溜まっていてrecvで読み出せるバイト数
+    pub fn readable(&self, buffer_len: usize) -> usize {
+        buffer_len - self.window as usize
+    }
+
+    /// seqから始まるlenバイトのセグメントを, まだ受信しておらず受信ウィンドウ[next, next + window)に収まる部分に切り詰める
+    /// 左端はnextより前の受信済みの部分を, 右端はウィンドウからはみ出した部分を切り捨てる
+    /// 切り詰めた範囲はそのまま受信バッファ(順番通りなら)か再構築キューにコピーしてよい
+    pub fn trim(&self, seq: u32, len: usize) -> Trimmed {
+        let ahead = seq.wrapping_sub(self.next) as i32;
+        // 左端: nextより前の部分
+        let start = if ahead < 0 {
+            ahead.unsigned_abs() as usize
+        } else {
+            0
+        };
+        if ahead < 0 && start >= len {
+            return Trimmed::Duplicate;
+        }
+        // 右端: セグメントの先頭(左端を切り捨てた後)から受信ウィンドウの右端まで
+        let offset = cmp::max(ahead, 0) as usize;
+        if offset > 0 && offset >= self.window as usize {
+            return Trimmed::BeyondWindow;
+        }
+        let room = self.window as usize - offset;
+        Trimmed::Data(start..start + cmp::min(len - start, room))
+    }
+
+    /// 順番通りに揃ったlenバイトを受信バッファにコピーした. nextを進めてウィンドウを減らす
+    pub fn on_received(&mut self, len: usize) {
+        self.next = self.next.wrapping_add(len as u32);
+        self.window = self.window.saturating_sub(len as u32);
+    }
+
+    /// 順番通りに揃ったlenバイトの緊急データを受信バッファとは別に取り出した. nextだけを進める
+    pub fn on_out_of_band(&mut self, len: usize) {
+        self.next = self.next.wrapping_add(len as u32);
+    }
+
+    /// recvでlenバイト読み出した
+    pub fn on_read(&mut self, len: usize) {
+        self.window = self.window.saturating_add(len as u32);
+    }
+
+    /// ヘッダのウィンドウフィールド(16ビット)に載せる値. shiftはこちらが広告しているウィンドウスケール
+    /// SYNのウィンドウはスケールしない. RFC 7323
+    pub fn advertised(&self, shift: u8, syn: bool) -> u16 {
+        let window = if syn {
+            self.window
+        } else {
+            self.window >> shift
+        };
+        cmp::min(window, u16::MAX as u32) as u16
+    }
+}
+
+impl ReassemblyQueue {
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// 保持しているバイト数
+    pub fn len(&self) -> usize {
+        self.bytes
+    }
+
+    /// seqから始まるlenバイトを全て保持しているか. 重複して届いたセグメントの判定に使う
+    pub fn contains(&mut self, next: u32, seq: u32, len: usize) -> bool {
+        self.rebase(next);
+        let start = seq.wrapping_sub(self.base);
+        self.segments
+            .range(..=start)
+            .next_back()
+            .is_some_and(|(&offset, segment)| offset + segment.len() as u32 >= start + len as u32)
+    }
+
+    /// nextより先のseqから始まるdataを保持する. 重なったり隣接したりする範囲とは1つにまとめる
+    /// 既に保持している部分は先に届いたデータを残し, 新しいデータは穴の部分だけを使う
+    pub fn insert(&mut self, next: u32, seq: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.rebase(next);
+        let mut start = seq.wrapping_sub(self.base);
+        let mut end = start + data.len() as u32;
+
+        // まとめる範囲を集める. 今回のデータの上に先に届いていたデータを重ねる
+        let mut merged = Vec::new();
+        let overlapping: Vec<u32> = self
+            .segments
+            .range(..=end)
+            .filter(|(&offset, segment)| offset + segment.len() as u32 >= start)
+            .map(|(&offset, _)| offset)
+            .collect();
+        for offset in overlapping {
+            let segment = self.segments.remove(&offset).unwrap();
+            self.bytes -= segment.len();
+            merged.push((offset, segment));
+        }
+        if let Some((offset, _)) = merged.first() {
+            start = cmp::min(start, *offset);
+        }
+        if let Some((offset, segment)) = merged.last() {
+            end = cmp::max(end, offset + segment.len() as u32);
+        }
+
+        let mut combined = vec![0; (end - start) as usize];
+        let seq_offset = seq.wrapping_sub(self.base);
+        for (offset, segment) in [(seq_offset, data)].into_iter().chain(
+            merged
+                .iter()
+                .map(|(offset, segment)| (*offset, segment.as_slice())),
+        ) {
+            let at = (offset - start) as usize;
+            combined[at..at + segment.len()].copy_from_slice(segment);
+        }
+        self.bytes += combined.len();
+        self.segments.insert(start, combined);
+    }
+
+    /// nextから始まる, 穴が埋まって順番通りに揃ったデータを取り出す. まだ穴があればNone
+    pub fn pop(&mut self, next: u32) -> Option<Vec<u8>> {
+        self.rebase(next);
+        let segment = self.segments.remove(&0)?;
+        self.bytes -= segment.len();
+        Some(segment)
+    }
+
+    /// キーを新しいnextからのオフセットに付け替える. nextより前になったデータは捨てる
+    fn rebase(&mut self, next: u32) {
+        let delta = next.wrapping_sub(self.base);
+        self.base = next;
+        if delta == 0 || self.segments.is_empty() {
+            return;
+        }
+
+        self.bytes = 0;
+        for (offset, mut segment) in mem::take(&mut self.segments) {
+            let end = offset + segment.len() as u32;
+            if end <= delta {
+                continue;
+            }
+            if offset < delta {
+                segment.drain(..(delta - offset) as usize);
+            }
+            self.bytes += segment.len();
+            self.segments.insert(offset.saturating_sub(delta), segment);
+        }
+    }
+}
+
+impl OutOfOrderRanges {
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// start..endを受信した. 重なったり隣接したりする範囲とまとめて先頭に置く
+    pub fn insert(&mut self, start: u32, end: u32) {
+        let mut merged = SackBlock { start, end };
+        self.ranges.retain(|range| {
+            let overlaps = range.start.wrapping_sub(merged.end) as i32 <= 0
+                && merged.start.wrapping_sub(range.end) as i32 <= 0;
+            if overlaps {
+                if (range.start.wrapping_sub(merged.start) as i32) < 0 {
+                    merged.start = range.start;
+                }
+                if range.end.wrapping_sub(merged.end) as i32 > 0 {
+                    merged.end = range.end;
+                }
+            }
+            !overlaps
+        });
+        self.ranges.insert(0, merged);
+    }
+
+    /// nextまで順番通りに受信し終えた. それより前の範囲は取り除く
+    pub fn advance(&mut self, next: u32) {
+        self.ranges
+            .retain(|range| range.end.wrapping_sub(next) as i32 > 0);
+        for range in self.ranges.iter_mut() {
+            if (range.start.wrapping_sub(next) as i32) < 0 {
+                range.start = next;
+            }
+        }
+    }
+
+    /// 相手に返すSACKブロック. 最大max個
+    pub fn blocks(&self, max: usize) -> Vec<SackBlock> {
+        self.ranges.iter().take(max).copied().collect()
+    }
+}
+
+impl RecvAutoTune {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            rtt: None,
+            started: None,
+            copied: 0,
+            space: 0,
+        }
+    }
+
+    /// 受信側でRTTを測った. 小さくなったらすぐに, 大きくなったら1/8ずつ追いかける
+    pub fn on_rtt_sample(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) if sample > rtt => rtt + (sample - rtt) / 8,
+            _ => sample,
+        });
+    }
+
+    /// recvでlenバイト読み出した. srttは送信側で測ったRTT, currentは今の受信バッファの大きさ
+    /// 1RTT経って周期が終わり, 受信バッファを広げるべきなら新しい大きさを返す
+    /// RTTをまだ測れていなければ何もしない
+    pub fn on_read(
+        &mut self,
+        len: usize,
+        now: SystemTime,
+        srtt: Option<Duration>,
+        current: usize,
+    ) -> Option<usize> {
+        let rtt = self.rtt.or(srtt)?;
+        let started = *self.started.get_or_insert(now);
+        self.copied += len;
+        if now.duration_since(started).unwrap_or_default() < rtt {
+            return None;
+        }
+
+        let copied = mem::take(&mut self.copied);
+        self.started = Some(now);
+        if copied <= self.space {
+            return None;
+        }
+        self.space = copied;
+        let size = cmp::min(self.max, 2 * copied);
+        (size > current).then_some(size)
+    }
+}
+
+impl DuplicateSegments {
+    /// seqから始まるlenバイトの重複セグメントが届いた. ACKを返し直すべきならtrueを返す
+    /// 別の範囲が届いたら数え直す
+    pub fn record(&mut self, seq: u32, len: usize) -> bool {
+        self.received += 1;
+        if self.last == Some((seq, len)) {
+            self.repeats += 1;
+        } else {
+            self.last = Some((seq, len));
+            self.repeats = 1;
+        }
+        let ack = self.repeats.is_power_of_two();
+        if !ack {
+            self.acks_suppressed += 1;
+        }
+        ack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUFFER_LEN: usize = 4380;
+    const MSS: usize = 1460;
+
+    fn send_window(window: u32, cwnd: u32) -> SendWindow {
+        SendWindow {
+            unacked_seq: 1000,
+            next: 1000,
+            window,
+            wl1: 5000,
+            wl2: 1000,
+            initial_seq: 999,
+            cwnd,
+            ssthresh: u32::MAX,
+        }
+    }
+
+    #[test]
+    fn cwnd_grows_exponentially_then_linearly() {
+        let mut window = send_window(4380, MSS as u32);
+        window.ssthresh = 4 * MSS as u32;
+        // スロースタートではACK毎に1mssずつ増える
+        for _ in 0..3 {
+            window.grow_cwnd(MSS, MSS);
+        }
+        assert_eq!(window.cwnd, 4 * MSS as u32);
+
+        // 輻輳回避ではcwnd分のデータがackされてやっと1mss増える
+        for _ in 0..4 {
+            window.grow_cwnd(MSS, MSS);
+        }
+        assert!(window.cwnd > 4 * MSS as u32 && window.cwnd <= 5 * MSS as u32);
+    }
+
+    #[test]
+    fn loss_halves_ssthresh_and_collapses_cwnd() {
+        let mut window = send_window(u16::MAX as u32, 10 * MSS as u32);
+        window.on_sent(8 * MSS);
+        window.on_loss(MSS);
+        assert_eq!(window.ssthresh, 4 * MSS as u32);
+        assert_eq!(window.cwnd, MSS as u32);
+
+        // 送信中のデータが少なくてもssthreshは2mss以上にする
+        let mut window = send_window(u16::MAX as u32, 10 * MSS as u32);
+        window.on_loss(MSS);
+        assert_eq!(window.ssthresh, 2 * MSS as u32);
+    }
+
+    #[test]
+    fn congestion_signal_halves_cwnd_without_collapsing() {
+        let mut window = send_window(u16::MAX as u32, 10 * MSS as u32);
+        window.on_sent(8 * MSS);
+        window.on_congestion_signal(MSS);
+        assert_eq!(window.ssthresh, 4 * MSS as u32);
+        assert_eq!(window.cwnd, 4 * MSS as u32);
+    }
+
+    #[test]
+    fn recv_buffer_grows_with_the_bytes_read_per_rtt() {
+        let rtt = Duration::from_millis(10);
+        let start = SystemTime::UNIX_EPOCH;
+        let mut autotune = RecvAutoTune::new(8 * BUFFER_LEN);
+        // RTTを測るまでは何もしない
+        assert_eq!(autotune.on_read(BUFFER_LEN, start, None, BUFFER_LEN), None);
+        // 1RTT経つまでは測っている途中
+        assert_eq!(
+            autotune.on_read(BUFFER_LEN, start, Some(rtt), BUFFER_LEN),
+            None
+        );
+        assert_eq!(
+            autotune.on_read(BUFFER_LEN, start + rtt, Some(rtt), BUFFER_LEN),
+            Some(4 * BUFFER_LEN)
+        );
+        // これまでより多く読めなければ広げない
+        assert_eq!(
+            autotune.on_read(BUFFER_LEN, start + rtt * 2, Some(rtt), 4 * BUFFER_LEN),
+            None
+        );
+        assert_eq!(
+            autotune.on_read(3 * BUFFER_LEN, start + rtt * 2, Some(rtt), 4 * BUFFER_LEN),
+            None
+        );
+        // 上限より大きくはしない
+        assert_eq!(
+            autotune.on_read(3 * BUFFER_LEN, start + rtt * 3, Some(rtt), 4 * BUFFER_LEN),
+            Some(8 * BUFFER_LEN)
+        );
+    }
+
+    #[test]
+    fn window_scale_is_the_smallest_shift_that_fits() {
+        assert_eq!(window_scale_for(BUFFER_LEN, true).unwrap(), 0);
+        assert_eq!(window_scale_for(u16::MAX as usize, false).unwrap(), 0);
+        assert_eq!(window_scale_for(u16::MAX as usize + 1, true).unwrap(), 1);
+        assert_eq!(window_scale_for(1 << 20, true).unwrap(), 5);
+        assert_eq!(
+            window_scale_for((u16::MAX as usize) << MAX_WINDOW_SCALE, true).unwrap(),
+            MAX_WINDOW_SCALE
+        );
+
+        assert!(window_scale_for(0, true).is_err());
+        assert!(window_scale_for(u16::MAX as usize + 1, false).is_err());
+        assert!(window_scale_for(((u16::MAX as usize) << MAX_WINDOW_SCALE) + 1, true).is_err());
+    }
+
+    #[test]
+    fn advertised_window_is_scaled_except_on_syn() {
+        let mut window = recv_window();
+        window.window = 1 << 20;
+        assert_eq!(window.advertised(5, false), 1 << 15);
+        assert_eq!(window.advertised(5, true), u16::MAX);
+        assert_eq!(window.advertised(0, false), u16::MAX);
+    }
+
+    fn recv_window() -> RecvWindow {
+        RecvWindow {
+            next: 5000,
+            window: BUFFER_LEN as u32,
+            initial_seq: 4999,
+        }
+    }
+
+    #[test]
+    fn writable_is_limited_by_peer_window() {
+        assert_eq!(send_window(100, u32::MAX).writable(), 100);
+    }
+
+    #[test]
+    fn writable_is_limited_by_cwnd_minus_in_flight() {
+        let mut window = send_window(4380, 2000);
+        assert_eq!(window.writable(), 2000);
+        window.on_sent(1500);
+        assert_eq!(window.in_flight(), 1500);
+        assert_eq!(window.writable(), 500);
+        window.on_sent(500);
+        assert_eq!(window.writable(), 0);
+    }
+
+    #[test]
+    fn writable_is_zero_when_in_flight_exceeds_cwnd() {
+        // RTOでcwndが縮んだ直後は送信済みのデータがcwndを超えている
+        let mut window = send_window(4380, 4380);
+        window.on_sent(3000);
+        window.cwnd = MSS as u32;
+        assert_eq!(window.writable(), 0);
+    }
+
+    #[test]
+    fn sendable_is_limited_by_mss_and_remaining() {
+        let window = send_window(4380, u32::MAX);
+        assert_eq!(window.sendable(MSS, 10000), MSS);
+        assert_eq!(window.sendable(MSS, 10), 10);
+        assert_eq!(send_window(0, u32::MAX).sendable(MSS, 10), 0);
+    }
+
+    #[test]
+    fn send_and_ack_restore_window() {
+        let mut window = send_window(4380, u32::MAX);
+        window.on_sent(1460);
+        window.on_sent(1460);
+        assert_eq!(window.next, 1000 + 2920);
+        assert_eq!(window.writable(), 4380 - 2920);
+
+        // ウィンドウはSND.UNAから数えるので, ackが進めば同じウィンドウのままでも空く
+        window.unacked_seq = 1000 + 1460;
+        assert_eq!(window.writable(), 4380 - 1460);
+        assert_eq!(window.in_flight(), 1460);
+    }
+
+    #[test]
+    fn send_window_does_not_underflow() {
+        let mut window = send_window(100, u32::MAX);
+        window.on_sent(200);
+        assert_eq!(window.writable(), 0);
+    }
+
+    #[test]
+    fn stale_segments_do_not_update_window() {
+        let mut window = send_window(4380, u32::MAX);
+        window.on_sent(2920);
+
+        // 新しいackと一緒に広告されたウィンドウ
+        assert!(window.update_window(5000, 1000 + 1460, 1000));
+        assert_eq!((window.window, window.wl1, window.wl2), (1000, 5000, 2460));
+        // seqが同じで古いackを持つセグメントが後から届いても戻さない
+        assert!(!window.update_window(5000, 1000, 4380));
+        assert_eq!(window.window, 1000);
+        // seqが古ければackが新しくても更新しない
+        assert!(!window.update_window(4999, 1000 + 2920, 4380));
+        assert_eq!(window.window, 1000);
+        // 同じセグメントの再送やackの進まないウィンドウの更新は受け入れる
+        assert!(window.update_window(5000, 1000 + 1460, 2000));
+        assert!(window.update_window(5100, 1000 + 1460, 0));
+        assert_eq!(window.window, 0);
+        assert_eq!(window.writable(), 0);
+
+        // seqが2^32を跨いでも新しいセグメントで更新する
+        window.init_window(u32::MAX - 10, 1000, 100);
+        assert!(window.update_window(10, 1000, 4380));
+        assert!(!window.update_window(u32::MAX - 5, 1000, 100));
+        assert_eq!(window.window, 4380);
+    }
+
+    #[test]
+    fn send_sequence_wraps_around() {
+        let mut window = send_window(4380, u32::MAX);
+        window.unacked_seq = u32::MAX - 100;
+        window.next = u32::MAX - 100;
+        window.on_sent(1000);
+        assert_eq!(window.next, 899);
+        assert_eq!(window.in_flight(), 1000);
+    }
+
+    #[test]
+    fn in_order_segment_advances_next() {
+        let mut window = recv_window();
+        assert_eq!(window.trim(5000, 100), Trimmed::Data(0..100));
+        window.on_received(100);
+        assert_eq!(window.next, 5100);
+        assert_eq!(window.readable(BUFFER_LEN), 100);
+    }
+
+    #[test]
+    fn out_of_order_segment_waits_for_the_gap() {
+        let mut window = recv_window();
+        let mut queue = ReassemblyQueue::default();
+        assert_eq!(window.trim(5100, 100), Trimmed::Data(0..100));
+        queue.insert(window.next, 5100, &[2; 100]);
+        assert_eq!(queue.pop(window.next), None);
+        assert_eq!(window.readable(BUFFER_LEN), 0);
+        assert_eq!(queue.len(), 100);
+
+        // 穴が埋まると先に届いていた分も取り出せるようになる
+        window.on_received(100);
+        assert_eq!(queue.pop(window.next), Some(vec![2; 100]));
+        assert!(queue.is_empty());
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn separate_holes_are_filled_independently() {
+        let mut queue = ReassemblyQueue::default();
+        queue.insert(5000, 5300, &[4; 100]);
+        queue.insert(5000, 5100, &[2; 100]);
+        assert_eq!(queue.len(), 200);
+
+        // 1つ目の穴だけが埋まっても, 2つ目の穴の先のデータは取り出せない
+        assert_eq!(queue.pop(5100), Some(vec![2; 100]));
+        assert_eq!(queue.pop(5200), None);
+        assert_eq!(queue.pop(5300), Some(vec![4; 100]));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn reassembly_merges_overlapping_and_adjacent_segments() {
+        let mut queue = ReassemblyQueue::default();
+        queue.insert(5000, 5100, &[1; 100]);
+        queue.insert(5000, 5300, &[3; 100]);
+        queue.insert(5000, 5150, &[2; 150]);
+        assert_eq!(queue.len(), 300);
+        assert!(queue.contains(5000, 5120, 180));
+        assert!(!queue.contains(5000, 5050, 100));
+        assert!(!queue.contains(5000, 5350, 100));
+
+        // 重なった部分は先に届いていたデータが残る
+        let mut expected = vec![1; 100];
+        expected.extend_from_slice(&[2; 100]);
+        expected.extend_from_slice(&[3; 100]);
+        assert_eq!(queue.pop(5100), Some(expected));
+    }
+
+    #[test]
+    fn reassembly_trims_data_overtaken_by_next() {
+        let mut queue = ReassemblyQueue::default();
+        queue.insert(5000, 5100, &(0..100).collect::<Vec<u8>>());
+        // 順番通りのセグメントが穴を埋めてさらにキューの途中まで届いた
+        assert_eq!(queue.pop(5150), Some((50..100).collect::<Vec<u8>>()));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn reassembly_handles_sequence_wraparound() {
+        let mut queue = ReassemblyQueue::default();
+        let next = u32::MAX - 49;
+        queue.insert(next, 50, &[5; 100]);
+        queue.insert(next, u32::MAX - 9, &[4; 60]);
+        assert_eq!(queue.pop(next), None);
+        let mut expected = vec![4; 60];
+        expected.extend_from_slice(&[5; 100]);
+        assert_eq!(queue.pop(u32::MAX - 9), Some(expected));
+    }
+
+    #[test]
+    fn trim_skips_already_received_prefix() {
+        let mut window = recv_window();
+        window.on_received(1000);
+        assert_eq!(window.trim(5900, 200), Trimmed::Data(100..200));
+        assert_eq!(window.trim(6100, 100), Trimmed::Data(0..100));
+    }
+
+    #[test]
+    fn trim_truncates_at_window_end() {
+        let mut window = recv_window();
+        window.on_received(4000);
+        assert_eq!(window.trim(9000, 1000), Trimmed::Data(0..380));
+        assert_eq!(window.trim(9380, 100), Trimmed::BeyondWindow);
+        // 両端がはみ出していれば両方を切り捨てる
+        assert_eq!(window.trim(8900, 1000), Trimmed::Data(100..480));
+    }
+
+    #[test]
+    fn trim_at_zero_window_keeps_nothing_new() {
+        let mut window = recv_window();
+        window.on_received(BUFFER_LEN);
+        assert_eq!(window.window, 0);
+        // 次に受け取るはずのセグメントも1バイトも入らないが, ACKでウィンドウを伝え直すためにDataで返す
+        assert_eq!(window.trim(9380, 100), Trimmed::Data(0..0));
+        assert_eq!(window.trim(9330, 100), Trimmed::Data(50..50));
+        assert_eq!(window.trim(9381, 100), Trimmed::BeyondWindow);
+    }
+
+    #[test]
+    fn trim_rejects_already_received_data() {
+        let mut window = recv_window();
+        window.on_received(100);
+        assert_eq!(window.trim(5000, 100), Trimmed::Duplicate);
+        assert_eq!(window.trim(4000, 100), Trimmed::Duplicate);
+    }
+
+    #[test]
+    fn repeated_duplicates_are_acked_with_backoff() {
+        let mut duplicates = DuplicateSegments::default();
+        let acked: Vec<_> = (1..=9).map(|_| duplicates.record(100, 10)).collect();
+        assert_eq!(
+            acked,
+            [true, true, false, true, false, false, false, true, false]
+        );
+        // 別の範囲が届いたら最初から数える
+        assert!(duplicates.record(110, 10));
+        assert!(duplicates.record(110, 10));
+        assert_eq!(duplicates.received, 11);
+        assert_eq!(duplicates.acks_suppressed, 5);
+    }
+
+    #[test]
+    fn read_reopens_window() {
+        let mut window = recv_window();
+        window.on_received(1000);
+        window.on_read(600);
+        assert_eq!(window.readable(BUFFER_LEN), 400);
+        assert_eq!(window.window, (BUFFER_LEN - 400) as u32);
+    }
+
+    #[test]
+    fn recv_sequence_wraps_around() {
+        let mut window = recv_window();
+        window.next = u32::MAX - 49;
+        assert_eq!(window.trim(u32::MAX - 49, 100), Trimmed::Data(0..100));
+        window.on_received(100);
+        assert_eq!(window.next, 50);
+        assert_eq!(window.readable(BUFFER_LEN), 100);
+
+        // nextが0付近に回り込んだ後も, 少し前のseqは受信済みと判定される
+        assert_eq!(window.trim(u32::MAX - 10, 10), Trimmed::Duplicate);
+    }
+
+    fn block(start: u32, end: u32) -> SackBlock {
+        SackBlock { start, end }
+    }
+
+    #[test]
+    fn out_of_order_ranges_merge_and_keep_latest_first() {
+        let mut ranges = OutOfOrderRanges::default();
+        ranges.insert(2000, 3000);
+        ranges.insert(4000, 5000);
+        assert_eq!(ranges.blocks(4), vec![block(4000, 5000), block(2000, 3000)]);
+
+        // 隣接する範囲はまとめて先頭に来る
+        ranges.insert(3000, 3500);
+        assert_eq!(ranges.blocks(4), vec![block(2000, 3500), block(4000, 5000)]);
+        assert_eq!(ranges.blocks(1), vec![block(2000, 3500)]);
+    }
+
+    #[test]
+    fn out_of_order_ranges_advance_drops_delivered_data() {
+        let mut ranges = OutOfOrderRanges::default();
+        ranges.insert(2000, 3000);
+        ranges.insert(4000, 5000);
+        ranges.advance(3000);
+        assert_eq!(ranges.blocks(4), vec![block(4000, 5000)]);
+        ranges.advance(4500);
+        assert_eq!(ranges.blocks(4), vec![block(4500, 5000)]);
+        ranges.advance(5000);
+        assert!(ranges.is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+// TCP::async_connect, async_accept, async_send, async_recvが返すFuture
+// 操作をブロックせずに試し, 進められなければソケットのSocketEventsにタスクのWakerを登録してPendingを返す
+// Wakerは受信スレッドやタイマースレッドがイベントを発行した時に起こされる. 特定のランタイムには依存しない
+
+use anyhow::Result;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::socket::{Addr, SockID};
+use crate::tcp::TCP;
+
+/// TCP::async_connectが返すFuture. 確立したソケットのIDを返す
+pub struct ConnectFuture<'a> {
+    tcp: &'a TCP,
+    addr: Addr,
+    port: u16,
+    // SYNを送ったソケット. 最初にpollされるまではNone
+    sock_id: Option<SockID>,
+}
+
+impl<'a> ConnectFuture<'a> {
+    pub(crate) fn new(tcp: &'a TCP, addr: Addr, port: u16) -> Self {
+        Self {
+            tcp,
+            addr,
+            port,
+            sock_id: None,
+        }
+    }
+}
+
+impl Future for ConnectFuture<'_> {
+    type Output = Result<SockID>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let sock_id = match self.sock_id {
+            Some(sock_id) => sock_id,
+            None => match self.tcp.start_connect(self.addr, self.port) {
+                Ok(sock_id) => *self.sock_id.insert(sock_id),
+                Err(error) => return Poll::Ready(Err(error)),
+            },
+        };
+        self.tcp
+            .poll_connected(sock_id, cx.waker())
+            .map_ok(|()| sock_id)
+    }
+}
+
+/// TCP::async_acceptが返すFuture
+pub struct AcceptFuture<'a> {
+    tcp: &'a TCP,
+    sock_id: SockID,
+}
+
+impl<'a> AcceptFuture<'a> {
+    pub(crate) fn new(tcp: &'a TCP, sock_id: SockID) -> Self {
+        Self { tcp, sock_id }
+    }
+}
+
+impl Future for AcceptFuture<'_> {
+    type Output = Result<SockID>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.tcp.poll_accept(self.sock_id, cx.waker())
+    }
+}
+
+/// TCP::async_sendが返すFuture. bufferが全て送信バッファに入ったら完了する
+pub struct SendFuture<'a> {
+    tcp: &'a TCP,
+    sock_id: SockID,
+    buffer: &'a [u8],
+    // 送信バッファに入れ終えたバイト数
+    written: usize,
+}
+
+impl<'a> SendFuture<'a> {
+    pub(crate) fn new(tcp: &'a TCP, sock_id: SockID, buffer: &'a [u8]) -> Self {
+        Self {
+            tcp,
+            sock_id,
+            buffer,
+            written: 0,
+        }
+    }
+}
+
+impl Future for SendFuture<'_> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        while self.written < self.buffer.len() {
+            let rest = &self.buffer[self.written..];
+            match self.tcp.poll_send(self.sock_id, rest, cx.waker()) {
+                Poll::Ready(Ok(len)) => self.written += len,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// TCP::async_recvが返すFuture. 読み込んだバイト数を返し, 相手のFINを読んだら0を返す
+pub struct RecvFuture<'a> {
+    tcp: &'a TCP,
+    sock_id: SockID,
+    buffer: &'a mut [u8],
+}
+
+impl<'a> RecvFuture<'a> {
+    pub(crate) fn new(tcp: &'a TCP, sock_id: SockID, buffer: &'a mut [u8]) -> Self {
+        Self {
+            tcp,
+            sock_id,
+            buffer,
+        }
+    }
+}
+
+impl Future for RecvFuture<'_> {
+    type Output = Result<usize>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.tcp.poll_recv(this.sock_id, this.buffer, cx.waker())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, TcpConfig};
+    use std::net::Ipv4Addr;
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread::{self, Thread};
+
+    // テスト用の最小のエグゼキュータ. 起こされるまでスレッドをparkする
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn futures_are_woken_by_socket_events() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            deterministic: true,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let woken = || counter.0.swap(0, Ordering::SeqCst) > 0;
+
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let mut accept = pin!(tcp.async_accept(listener));
+        let mut connect = pin!(tcp.async_connect(Ipv4Addr::LOCALHOST, 40000));
+        assert!(accept.as_mut().poll(&mut cx).is_pending());
+        // 最初のpollでSYNを送る
+        assert!(connect.as_mut().poll(&mut cx).is_pending());
+        assert!(!woken());
+
+        tcp.poll_receive().unwrap();
+        assert!(woken());
+        let Poll::Ready(server) = accept.as_mut().poll(&mut cx) else {
+            panic!("accept is still pending");
+        };
+        let Poll::Ready(client) = connect.as_mut().poll(&mut cx) else {
+            panic!("connect is still pending");
+        };
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        let mut buffer = [0; 16];
+        let mut recv = pin!(tcp.async_recv(server, &mut buffer));
+        assert!(recv.as_mut().poll(&mut cx).is_pending());
+        let mut send = pin!(tcp.async_send(client, b"hello"));
+        assert!(matches!(send.as_mut().poll(&mut cx), Poll::Ready(Ok(()))));
+        woken();
+        tcp.poll_receive().unwrap();
+        assert!(woken());
+        assert!(matches!(recv.as_mut().poll(&mut cx), Poll::Ready(Ok(5))));
+        assert_eq!(&buffer[..5], b"hello");
+    }
+
+    #[test]
+    fn async_server_and_client_exchange_a_large_payload() {
+        let config = TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        };
+        // 送信バッファより大きく, 何度も空くのを待つ
+        let payload: Vec<u8> = (0..config.send_buffer_size * 3 + 1)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let tcp = TCP::with_config(config).unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+
+        let cloned_tcp = tcp.clone();
+        let server = thread::spawn(move || {
+            let tcp = cloned_tcp;
+            block_on(async {
+                let server = tcp.async_accept(listener).await?;
+                let mut received = Vec::new();
+                let mut buffer = [0; 4096];
+                loop {
+                    let nbytes = tcp.async_recv(server, &mut buffer).await?;
+                    if nbytes == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buffer[..nbytes]);
+                }
+                tcp.async_send(server, &(received.len() as u64).to_be_bytes())
+                    .await?;
+                tcp.close(server)?;
+                Ok::<_, anyhow::Error>(received)
+            })
+        });
+
+        let reply = block_on(async {
+            let client = tcp.async_connect(Ipv4Addr::LOCALHOST, 40000).await?;
+            tcp.async_send(client, &payload).await?;
+            tcp.shutdown(client, crate::tcp::How::Write)?;
+            let mut reply = [0; 8];
+            let nbytes = tcp.async_recv(client, &mut reply).await?;
+            assert_eq!(nbytes, 8);
+            tcp.close(client)?;
+            Ok::<_, anyhow::Error>(u64::from_be_bytes(reply))
+        })
+        .unwrap();
+
+        assert_eq!(reply, payload.len() as u64);
+        assert!(server.join().unwrap().unwrap() == payload);
+    }
+}
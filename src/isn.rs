@@ -0,0 +1,77 @@
+use crate::socket::DefaultEndpoint;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime};
+
+/// ISNの時刻成分が1進む間隔. RFC 793の4マイクロ秒のタイマー
+const TICK: Duration = Duration::from_micros(4);
+
+/// 初期シーケンス番号(ISN)の生成. RFC 6528
+/// ISN = M + F(localip, localport, remoteip, remoteport, secret)
+/// Mは4マイクロ秒毎に1進むタイマーで, Fは接続の4つ組とsecretの鍵付きハッシュ
+/// 4つ組毎に推測できない位置から始まるので他の接続から次のISNを推測されにくく,
+/// 同じ4つ組の中では時刻と共に増えていくので, 古い接続(TIME_WAITなど)のシーケンス番号と重なりにくい
+#[derive(Debug)]
+pub struct IsnGenerator {
+    secret: u64,
+}
+
+impl IsnGenerator {
+    pub fn new(secret: u64) -> Self {
+        Self { secret }
+    }
+
+    /// localからremoteへの接続のISN
+    pub fn generate(
+        &self,
+        local: DefaultEndpoint,
+        remote: DefaultEndpoint,
+        now: SystemTime,
+    ) -> u32 {
+        let ticks = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros()
+            / TICK.as_micros();
+        (ticks as u32).wrapping_add(self.hash(local, remote))
+    }
+
+    fn hash(&self, local: DefaultEndpoint, remote: DefaultEndpoint) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        (local, remote, self.secret).hash(&mut hasher);
+        hasher.finish() as u32
+    }
+}
+
+impl Default for IsnGenerator {
+    fn default() -> Self {
+        Self::new(rand::thread_rng().gen())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn isn_depends_on_the_connection_and_advances_with_time() {
+        let isns = IsnGenerator::new(1);
+        let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let isn = isns.generate(local, remote, now);
+
+        // 同じ接続なら4マイクロ秒毎に1ずつ進む
+        assert_eq!(
+            isns.generate(local, remote, now + Duration::from_millis(1)),
+            isn.wrapping_add(250)
+        );
+        // 接続やsecretが違えば推測できない位置から始まる
+        assert_ne!(isns.generate(remote, local, now), isn);
+        let other_port = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40001);
+        assert_ne!(isns.generate(local, other_port, now), isn);
+        assert_ne!(IsnGenerator::new(2).generate(local, remote, now), isn);
+    }
+}
@@ -1,4 +1,39 @@
+mod backlog;
+mod checksum;
+mod clock;
+pub mod config;
+pub mod conntrack;
+mod device;
+pub mod diagram;
+mod event;
+pub mod eventlog;
+mod fastopen;
+pub mod filter;
+mod flowcontrol;
+pub mod future;
+mod isn;
+#[cfg(feature = "mio")]
+pub mod mio;
+#[cfg(feature = "mux")]
+pub mod mux;
+mod pacing;
 mod packet;
+pub mod policy;
+pub mod poll;
+pub mod portalloc;
+mod rtt;
+#[cfg(feature = "services")]
+pub mod services;
 mod socket;
+pub mod stats;
+mod stream;
+mod sync;
+mod syncookie;
 pub mod tcp;
-mod tcpflags;
+pub mod tcpflags;
+mod timers;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+mod txring;
+
+pub use stream::{Incoming, TcpListener, TcpStream};
@@ -0,0 +1,206 @@
+// mioのリアクタでtoytcpのソケットをOSのソケットと一緒に待つためのイベントソース
+// 登録したソケットでイベントが発行される度に内部のパイプへ1バイト書き, パイプの読み出し側をmioに待たせる
+// mioはエッジトリガーなので, このソースのトークンでイベントが届いたらreadyで状態の変わったソケットを全て取り出す
+
+use anyhow::{bail, Result};
+use mio::event::Source;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::event::Notify;
+use crate::poll::{PollEvents, PollFd};
+use crate::socket::SockID;
+use crate::sync::LockResultExt;
+use crate::tcp::TCP;
+
+/// パイプに書いてmioのリアクタを起こす
+struct PipeNotify {
+    sender: pipe::Sender,
+}
+
+impl Notify for PipeNotify {
+    fn notify(&self) {
+        // パイプが一杯なら既に起こしてあるので, 書けなくてもよい
+        let _ = (&self.sender).write(&[1]);
+    }
+}
+
+/// toytcpのソケットをmioのPollに載せるイベントソース
+/// register_socketでトークン毎にソケットを登録し, このソース自体はmio::Registryに登録する
+pub struct MioSource {
+    tcp: Arc<TCP>,
+    receiver: pipe::Receiver,
+    notify: Arc<dyn Notify>,
+    sockets: Mutex<HashMap<Token, (SockID, PollEvents)>>,
+}
+
+impl MioSource {
+    pub fn new(tcp: &Arc<TCP>) -> Result<Self> {
+        let (sender, receiver) = pipe::new()?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            receiver,
+            notify: Arc::new(PipeNotify { sender }),
+            sockets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// sock_idをtokenで登録し, eventsの状態になったらreadyで返す. ERRORは含めなくても返す
+    /// 登録する前から読み書きできる場合に備えて, 登録した時点で一度リアクタを起こす
+    pub fn register_socket(&self, sock_id: SockID, token: Token, events: PollEvents) -> Result<()> {
+        let mut sockets = self.sockets.lock().recover();
+        if sockets.contains_key(&token) {
+            bail!("token is already registered: {:?}", token);
+        }
+        self.tcp.watch(sock_id, self.notify.clone())?;
+        sockets.insert(token, (sock_id, events));
+        self.notify.notify();
+        Ok(())
+    }
+
+    /// tokenの登録を外し, 登録されていたソケットを返す
+    pub fn deregister_socket(&self, token: Token) -> Option<SockID> {
+        let (sock_id, _) = self.sockets.lock().recover().remove(&token)?;
+        self.tcp.unwatch(sock_id, &self.notify);
+        Some(sock_id)
+    }
+
+    /// 登録したソケットのうち, 登録した状態になっているか中断されたものをトークンと一緒に返す
+    /// このソースのトークンでイベントが届く度に呼ぶ
+    pub fn ready(&self) -> Result<Vec<(Token, PollEvents)>> {
+        // 先にパイプを空にしておけば, 調べている間に発行されたイベントでもう一度起こされる
+        let mut buffer = [0; 64];
+        loop {
+            match (&self.receiver).read(&mut buffer) {
+                Ok(nbytes) if nbytes > 0 => continue,
+                Ok(_) => break,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        let (tokens, mut fds): (Vec<_>, Vec<_>) = self
+            .sockets
+            .lock()
+            .recover()
+            .iter()
+            .map(|(&token, &(sock_id, events))| (token, PollFd::new(sock_id, events)))
+            .unzip();
+        self.tcp.poll(&mut fds, Some(Duration::ZERO))?;
+        Ok(tokens
+            .into_iter()
+            .zip(fds)
+            .filter(|(_, fd)| !fd.revents.is_empty())
+            .map(|(token, fd)| (token, fd.revents))
+            .collect())
+    }
+}
+
+impl Source for MioSource {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.receiver.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.receiver.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.receiver.deregister(registry)
+    }
+}
+
+impl Drop for MioSource {
+    fn drop(&mut self) {
+        for (_, (sock_id, _)) in self.sockets.lock().recover().drain() {
+            self.tcp.unwatch(sock_id, &self.notify);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, TcpConfig};
+    use mio::{Events, Poll, Waker};
+
+    const TOYTCP: Token = Token(0);
+    const OS_WAKER: Token = Token(1);
+    const SERVER: Token = Token(10);
+    const CLIENT: Token = Token(11);
+
+    // 1つのPollでtoytcpのソケットとOSのイベント(mio::Waker)を待ち, 届いたトークンを集める
+    fn poll_tokens(poll: &mut Poll, source: &MioSource) -> Vec<(Token, PollEvents)> {
+        let mut events = Events::with_capacity(16);
+        poll.poll(&mut events, Some(Duration::from_secs(10)))
+            .unwrap();
+        assert!(!events.is_empty(), "poll timed out");
+        let mut ready = Vec::new();
+        for event in events.iter() {
+            if event.token() == TOYTCP {
+                ready.extend(source.ready().unwrap());
+            } else {
+                ready.push((event.token(), PollEvents::empty()));
+            }
+        }
+        ready
+    }
+
+    #[test]
+    fn toytcp_sockets_are_multiplexed_with_os_sources() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let mut poll = Poll::new().unwrap();
+        let waker = Waker::new(poll.registry(), OS_WAKER).unwrap();
+        let mut source = MioSource::new(&tcp).unwrap();
+        poll.registry()
+            .register(&mut source, TOYTCP, Interest::READABLE)
+            .unwrap();
+        source
+            .register_socket(server, SERVER, PollEvents::READABLE)
+            .unwrap();
+        source
+            .register_socket(client, CLIENT, PollEvents::WRITABLE)
+            .unwrap();
+        assert!(source
+            .register_socket(client, CLIENT, PollEvents::READABLE)
+            .is_err());
+
+        // 登録した時点の状態も報告される
+        assert_eq!(
+            poll_tokens(&mut poll, &source),
+            vec![(CLIENT, PollEvents::WRITABLE)]
+        );
+
+        source.deregister_socket(CLIENT).unwrap();
+        tcp.send(client, b"hello").unwrap();
+        let mut ready = Vec::new();
+        while !ready.contains(&(SERVER, PollEvents::READABLE)) {
+            ready = poll_tokens(&mut poll, &source);
+        }
+
+        waker.wake().unwrap();
+        let mut ready = Vec::new();
+        while !ready.iter().any(|&(token, _)| token == OS_WAKER) {
+            ready = poll_tokens(&mut poll, &source);
+        }
+    }
+}
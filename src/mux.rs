@@ -0,0 +1,705 @@
+// yamux(https://github.com/hashicorp/yamux/blob/master/spec.md)を簡略化したストリームの多重化レイヤ
+// 1つのtoytcpの接続の上に複数の仮想的なストリームを載せる
+// 各ストリームのフレームが交互に流れるので, スタックをより現実に近いトラフィックで試すのにも使う
+
+use anyhow::{bail, Context, Result};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use crate::socket::SockID;
+use crate::sync::LockResultExt;
+use crate::tcp::TCP;
+
+const VERSION: u8 = 0;
+pub const HEADER_LEN: usize = 12;
+/// ストリーム毎の受信ウィンドウの初期値. 相手はWindowUpdateが届くまでこれを超えて送らない
+pub const INITIAL_STREAM_WINDOW: u32 = 64 * 1024;
+// 1つのDataフレームに載せる最大のバイト数. 1つのストリームが接続を長く占有しないようにする
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+// 受信バッファを読み込む単位
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// ストリームを開く. 最初のフレームに立てる
+pub const FLAG_SYN: u16 = 1;
+/// こちらからはもう送らない
+pub const FLAG_FIN: u16 = 4;
+/// ストリームを中断する
+pub const FLAG_RST: u16 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    /// ヘッダの後ろにlengthバイトのデータが続く
+    Data = 0,
+    /// 相手の送信ウィンドウをlengthバイト広げる. ペイロードは無い
+    WindowUpdate = 1,
+}
+
+/// フレームのヘッダ. 全てネットワークバイトオーダー
+/// version(1) | type(1) | flags(2) | stream_id(4) | length(4)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub frame_type: FrameType,
+    pub flags: u16,
+    pub stream_id: u32,
+    pub length: u32,
+}
+
+impl FrameHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0] = VERSION;
+        bytes[1] = self.frame_type as u8;
+        bytes[2..4].copy_from_slice(&self.flags.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.stream_id.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            bail!("frame header is too short: {} bytes", bytes.len());
+        }
+        if bytes[0] != VERSION {
+            bail!("unsupported mux version: {}", bytes[0]);
+        }
+        let frame_type = match bytes[1] {
+            0 => FrameType::Data,
+            1 => FrameType::WindowUpdate,
+            other => bail!("unknown frame type: {}", other),
+        };
+        Ok(Self {
+            frame_type,
+            flags: u16::from_be_bytes([bytes[2], bytes[3]]),
+            stream_id: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            length: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        })
+    }
+
+    /// ヘッダを含めたフレーム全体の長さ
+    fn frame_len(&self) -> usize {
+        match self.frame_type {
+            FrameType::Data => HEADER_LEN + self.length as usize,
+            FrameType::WindowUpdate => HEADER_LEN,
+        }
+    }
+}
+
+struct StreamState {
+    recv_buffer: VecDeque<u8>,
+    // 相手がまだ送ってよいバイト数(こちらの受信ウィンドウの残り)
+    recv_window: u32,
+    // recvで読み出したが, まだWindowUpdateで相手に返していないバイト数
+    consumed: u32,
+    // こちらがまだ送ってよいバイト数
+    send_window: u32,
+    // 相手からFINが届いた
+    remote_closed: bool,
+    // こちらからFINを送った
+    local_closed: bool,
+    reset: bool,
+    // MuxStreamが捨てられた. 以降に届くデータは誰も読まないので捨てる
+    dropped: bool,
+}
+
+impl Default for StreamState {
+    fn default() -> Self {
+        Self {
+            recv_buffer: VecDeque::new(),
+            recv_window: INITIAL_STREAM_WINDOW,
+            consumed: 0,
+            send_window: INITIAL_STREAM_WINDOW,
+            remote_closed: false,
+            local_closed: false,
+            reset: false,
+            dropped: false,
+        }
+    }
+}
+
+struct SessionState {
+    streams: HashMap<u32, StreamState>,
+    // 相手が開いてまだacceptされていないストリーム
+    accept_queue: VecDeque<u32>,
+    // 次に開くストリームのID. クライアントは奇数, サーバは偶数を使い, 2ずつ増やす
+    next_id: u32,
+    // 下の接続が閉じられた. 以降はどのストリームも使えない
+    closed: bool,
+}
+
+struct Shared {
+    tcp: Arc<TCP>,
+    sock_id: SockID,
+    state: Mutex<SessionState>,
+    // ストリームの状態が変わる度に, 待っている全てのsend, recv, acceptを起こす
+    condvar: Condvar,
+    // 1つのフレームの途中に他のフレームが割り込まないよう, フレーム毎に書き込む
+    writer: Mutex<()>,
+}
+
+impl Shared {
+    fn write_frame(&self, header: FrameHeader, payload: &[u8]) -> Result<()> {
+        let mut frame = header.encode().to_vec();
+        frame.extend_from_slice(payload);
+        let _writer = self.writer.lock().recover();
+        // フレームの途中で切れると以降の区切りが分からなくなるので, 書き込みのタイムアウトは設定しない
+        self.tcp.send(self.sock_id, &frame)?;
+        Ok(())
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, SessionState>) -> MutexGuard<'a, SessionState> {
+        self.condvar.wait(state).recover()
+    }
+
+    /// 受信スレッドの関数. 接続からフレームを読み出し, 宛先のストリームに振り分ける
+    fn read_frames(&self) -> Result<()> {
+        let mut pending = Vec::new();
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        loop {
+            let nbytes = self.tcp.recv(self.sock_id, &mut buffer)?;
+            if nbytes == 0 {
+                return Ok(());
+            }
+            pending.extend_from_slice(&buffer[..nbytes]);
+
+            // 全体が揃っているフレームから処理する
+            let mut offset = 0;
+            while pending.len() - offset >= HEADER_LEN {
+                let header = FrameHeader::decode(&pending[offset..])?;
+                let end = offset + header.frame_len();
+                if pending.len() < end {
+                    break;
+                }
+                self.handle_frame(header, &pending[offset + HEADER_LEN..end])?;
+                offset = end;
+            }
+            pending.drain(..offset);
+        }
+    }
+
+    fn handle_frame(&self, header: FrameHeader, payload: &[u8]) -> Result<()> {
+        if self.receive_frame(header, payload) {
+            // ウィンドウを守らなかったストリームだけを中断し, 他のストリームは使い続ける
+            self.write_frame(rst_frame(header.stream_id), &[])?;
+        }
+        Ok(())
+    }
+
+    /// フレームを宛先のストリームに反映する. ストリームを中断すべき場合はtrueを返す
+    fn receive_frame(&self, header: FrameHeader, payload: &[u8]) -> bool {
+        let mut state = self.state.lock().recover();
+        let id = header.stream_id;
+        if header.flags & FLAG_SYN > 0 && !state.streams.contains_key(&id) {
+            state.streams.insert(id, StreamState::default());
+            state.accept_queue.push_back(id);
+        }
+        // 既に閉じて取り除いたストリーム宛てのフレームは捨てる
+        let stream = match state.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return false,
+        };
+        if stream.reset {
+            return false;
+        }
+
+        match header.frame_type {
+            FrameType::Data => {
+                if payload.len() as u32 > stream.recv_window {
+                    dbg!("stream exceeded its receive window", id);
+                    stream.reset = true;
+                    stream.recv_buffer.clear();
+                    self.condvar.notify_all();
+                    return true;
+                }
+                stream.recv_window -= payload.len() as u32;
+                if !stream.dropped {
+                    stream.recv_buffer.extend(payload);
+                }
+            }
+            FrameType::WindowUpdate => {
+                stream.send_window = stream.send_window.saturating_add(header.length);
+            }
+        }
+        if header.flags & FLAG_FIN > 0 {
+            stream.remote_closed = true;
+        }
+        if header.flags & FLAG_RST > 0 {
+            stream.reset = true;
+        }
+        remove_if_finished(&mut state, id);
+        self.condvar.notify_all();
+        false
+    }
+}
+
+fn rst_frame(stream_id: u32) -> FrameHeader {
+    FrameHeader {
+        frame_type: FrameType::Data,
+        flags: FLAG_RST,
+        stream_id,
+        length: 0,
+    }
+}
+
+/// 両方向ともFINを送り合い, 読み残しも無くなったストリームを取り除く
+/// 中断されたストリームはMuxStreamが捨てられた時に取り除く
+fn remove_if_finished(state: &mut SessionState, id: u32) {
+    if state.streams.get(&id).is_some_and(|stream| {
+        (stream.local_closed && stream.remote_closed && stream.recv_buffer.is_empty())
+            || (stream.dropped && stream.reset)
+    }) {
+        state.streams.remove(&id);
+    }
+}
+
+/// 1つのtoytcpの接続を多重化したもの. 接続の両端でそれぞれclientとserverを作る
+pub struct Session {
+    shared: Arc<Shared>,
+}
+
+impl Session {
+    /// connectした側. 奇数のストリームIDを使う
+    pub fn client(tcp: &Arc<TCP>, sock_id: SockID) -> Result<Self> {
+        Self::new(tcp, sock_id, 1)
+    }
+
+    /// acceptした側. 偶数のストリームIDを使う
+    pub fn server(tcp: &Arc<TCP>, sock_id: SockID) -> Result<Self> {
+        Self::new(tcp, sock_id, 2)
+    }
+
+    fn new(tcp: &Arc<TCP>, sock_id: SockID, first_id: u32) -> Result<Self> {
+        // 小さなフレームも溜めずにすぐ送る. 相手のrecvがNagleと遅延ACKで待たされないようにする
+        tcp.set_nodelay(sock_id, true)?;
+        let shared = Arc::new(Shared {
+            tcp: tcp.clone(),
+            sock_id,
+            state: Mutex::new(SessionState {
+                streams: HashMap::new(),
+                accept_queue: VecDeque::new(),
+                next_id: first_id,
+                closed: false,
+            }),
+            condvar: Condvar::new(),
+            writer: Mutex::new(()),
+        });
+
+        let cloned_shared = shared.clone();
+        thread::spawn(move || {
+            if let Err(error) = cloned_shared.read_frames() {
+                dbg!(error);
+            }
+            cloned_shared.state.lock().recover().closed = true;
+            cloned_shared.condvar.notify_all();
+        });
+
+        Ok(Self { shared })
+    }
+
+    /// 新しいストリームを開く. 相手のacceptを待たずに返り, すぐに送信できる
+    pub fn open(&self) -> Result<MuxStream> {
+        let id = {
+            let mut state = self.shared.state.lock().recover();
+            if state.closed {
+                bail!("mux session has been closed");
+            }
+            let id = state.next_id;
+            state.next_id += 2;
+            state.streams.insert(id, StreamState::default());
+            id
+        };
+        let header = FrameHeader {
+            frame_type: FrameType::WindowUpdate,
+            flags: FLAG_SYN,
+            stream_id: id,
+            length: 0,
+        };
+        self.shared.write_frame(header, &[])?;
+        Ok(MuxStream {
+            id,
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// 相手が開いたストリームを1つ受け取る. 届くまでブロックする
+    pub fn accept(&self) -> Result<MuxStream> {
+        let mut state = self.shared.state.lock().recover();
+        loop {
+            if let Some(id) = state.accept_queue.pop_front() {
+                return Ok(MuxStream {
+                    id,
+                    shared: self.shared.clone(),
+                });
+            }
+            if state.closed {
+                bail!("mux session has been closed");
+            }
+            state = self.shared.wait(state);
+        }
+    }
+
+    /// 開いているストリームの数
+    pub fn stream_count(&self) -> usize {
+        self.shared.state.lock().recover().streams.len()
+    }
+
+    /// 下の接続を閉じる. 以降は全てのストリームが使えなくなり, 待っているsend, recv, acceptはエラーを返す
+    /// TCP::closeと同じく, 相手も閉じるまでブロックする
+    pub fn close(&self) -> Result<()> {
+        self.shared.state.lock().recover().closed = true;
+        self.shared.condvar.notify_all();
+        self.shared.tcp.close(self.shared.sock_id)
+    }
+}
+
+/// Sessionの上の仮想的なストリーム. 他のストリームとは独立にフロー制御される
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// データを送る. 相手の受信ウィンドウが空くまでブロックする
+    pub fn send(&self, buffer: &[u8]) -> Result<()> {
+        let mut cursor = 0;
+        while cursor < buffer.len() {
+            let len = {
+                let mut state = self.shared.state.lock().recover();
+                loop {
+                    if state.closed {
+                        bail!("mux session has been closed");
+                    }
+                    let stream = state
+                        .streams
+                        .get_mut(&self.id)
+                        .context(format!("no such stream: {}", self.id))?;
+                    if stream.reset {
+                        bail!("stream {} has been reset", self.id);
+                    }
+                    if stream.local_closed {
+                        bail!("stream {} is closed for writing", self.id);
+                    }
+                    if stream.send_window > 0 {
+                        // ロックを外して書き込む前にウィンドウを使っておく
+                        let len = cmp::min(
+                            stream.send_window as usize,
+                            cmp::min(buffer.len() - cursor, MAX_FRAME_PAYLOAD),
+                        );
+                        stream.send_window -= len as u32;
+                        break len;
+                    }
+                    state = self.shared.wait(state);
+                }
+            };
+
+            let header = FrameHeader {
+                frame_type: FrameType::Data,
+                flags: 0,
+                stream_id: self.id,
+                length: len as u32,
+            };
+            self.shared
+                .write_frame(header, &buffer[cursor..cursor + len])?;
+            cursor += len;
+        }
+        Ok(())
+    }
+
+    /// データをbufferに読み込み, 読み込んだサイズを返す. 相手がcloseしていれば0を返す
+    /// 読んだ分が受信ウィンドウの半分に達したらWindowUpdateで相手に返す
+    pub fn recv(&self, buffer: &mut [u8]) -> Result<usize> {
+        let mut state = self.shared.state.lock().recover();
+        let (nbytes, window_update) = loop {
+            let stream = state
+                .streams
+                .get_mut(&self.id)
+                .context(format!("no such stream: {}", self.id))?;
+            if stream.reset {
+                bail!("stream {} has been reset", self.id);
+            }
+            if !stream.recv_buffer.is_empty() {
+                let nbytes = cmp::min(buffer.len(), stream.recv_buffer.len());
+                for (dst, src) in buffer.iter_mut().zip(stream.recv_buffer.drain(..nbytes)) {
+                    *dst = src;
+                }
+                stream.consumed += nbytes as u32;
+                let window_update = if stream.consumed >= INITIAL_STREAM_WINDOW / 2 {
+                    let delta = stream.consumed;
+                    stream.consumed = 0;
+                    stream.recv_window += delta;
+                    Some(delta)
+                } else {
+                    None
+                };
+                break (nbytes, window_update);
+            }
+            if stream.remote_closed {
+                remove_if_finished(&mut state, self.id);
+                return Ok(0);
+            }
+            if state.closed {
+                bail!("mux session has been closed");
+            }
+            state = self.shared.wait(state);
+        };
+        drop(state);
+
+        if let Some(delta) = window_update {
+            let header = FrameHeader {
+                frame_type: FrameType::WindowUpdate,
+                flags: 0,
+                stream_id: self.id,
+                length: delta,
+            };
+            self.shared.write_frame(header, &[])?;
+        }
+        Ok(nbytes)
+    }
+
+    /// こちらからの送信を終える. 相手のrecvは読み終わると0を返す
+    pub fn close(&self) -> Result<()> {
+        {
+            let mut state = self.shared.state.lock().recover();
+            let stream = state
+                .streams
+                .get_mut(&self.id)
+                .context(format!("no such stream: {}", self.id))?;
+            if stream.local_closed {
+                return Ok(());
+            }
+            stream.local_closed = true;
+            remove_if_finished(&mut state, self.id);
+        }
+        let header = FrameHeader {
+            frame_type: FrameType::Data,
+            flags: FLAG_FIN,
+            stream_id: self.id,
+            length: 0,
+        };
+        self.shared.write_frame(header, &[])
+    }
+
+    /// ストリームを中断する. 相手のsendとrecvはエラーを返し, 送信中や未読のデータは捨てられる
+    pub fn reset(&self) -> Result<()> {
+        {
+            let mut state = self.shared.state.lock().recover();
+            let stream = state
+                .streams
+                .get_mut(&self.id)
+                .context(format!("no such stream: {}", self.id))?;
+            if stream.reset {
+                return Ok(());
+            }
+            stream.reset = true;
+            stream.recv_buffer.clear();
+            self.shared.condvar.notify_all();
+        }
+        self.shared.write_frame(rst_frame(self.id), &[])
+    }
+}
+
+impl Drop for MuxStream {
+    /// closeしていなければFINを送る. 以降に届くデータは捨てる
+    fn drop(&mut self) {
+        let send_fin = {
+            let mut state = self.shared.state.lock().recover();
+            let stream = match state.streams.get_mut(&self.id) {
+                Some(stream) => stream,
+                None => return,
+            };
+            stream.dropped = true;
+            stream.recv_buffer.clear();
+            let send_fin = !stream.local_closed && !stream.reset && !state.closed;
+            remove_if_finished(&mut state, self.id);
+            send_fin
+        };
+        if send_fin {
+            if let Err(error) = self.close() {
+                dbg!(error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, TcpConfig};
+
+    #[test]
+    fn frame_header_round_trips() {
+        let header = FrameHeader {
+            frame_type: FrameType::WindowUpdate,
+            flags: FLAG_SYN | FLAG_FIN,
+            stream_id: 0x01020304,
+            length: 70000,
+        };
+        let bytes = header.encode();
+        assert_eq!(&bytes[..4], &[0, 1, 0, 5]);
+        assert_eq!(FrameHeader::decode(&bytes).unwrap(), header);
+
+        let mut bad_version = bytes;
+        bad_version[0] = 1;
+        assert!(FrameHeader::decode(&bad_version).is_err());
+        assert!(FrameHeader::decode(&bytes[..HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn interleaved_streams_deliver_every_byte() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let client = Session::client(&tcp, client).unwrap();
+        let server = Session::server(&tcp, server).unwrap();
+
+        // 受信ウィンドウより大きいデータを全てのストリームから同時に送る
+        let size = INITIAL_STREAM_WINDOW as usize * 3 + 1;
+        let payload = |id: u32| -> Vec<u8> {
+            (0..size)
+                .map(|i| ((i * 7 + id as usize) % 251) as u8)
+                .collect()
+        };
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let stream = client.open().unwrap();
+                let data = payload(stream.id());
+                thread::spawn(move || {
+                    stream.send(&data).unwrap();
+                    stream.close().unwrap();
+                })
+            })
+            .collect();
+
+        let receivers: Vec<_> = (0..4)
+            .map(|_| {
+                let stream = server.accept().unwrap();
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    let mut buffer = [0; 3000];
+                    loop {
+                        let nbytes = stream.recv(&mut buffer).unwrap();
+                        if nbytes == 0 {
+                            break;
+                        }
+                        received.extend_from_slice(&buffer[..nbytes]);
+                    }
+                    stream.close().unwrap();
+                    (stream.id(), received)
+                })
+            })
+            .collect();
+
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        for receiver in receivers {
+            let (id, received) = receiver.join().unwrap();
+            assert_eq!(id % 2, 1);
+            assert!(received == payload(id), "stream {} was corrupted", id);
+        }
+        assert_eq!(server.stream_count(), 0);
+    }
+
+    fn session_pair() -> (Arc<TCP>, Session, Session, SockID) {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let client_session = Session::client(&tcp, client).unwrap();
+        let server_session = Session::server(&tcp, server).unwrap();
+        (tcp, client_session, server_session, client)
+    }
+
+    #[test]
+    fn dropping_a_stream_closes_it() {
+        let (_tcp, client, server, _) = session_pair();
+        let stream = client.open().unwrap();
+        stream.send(b"bye").unwrap();
+        drop(stream);
+
+        let accepted = server.accept().unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(accepted.recv(&mut buffer).unwrap(), 3);
+        assert_eq!(&buffer[..3], b"bye");
+        assert_eq!(accepted.recv(&mut buffer).unwrap(), 0);
+        drop(accepted);
+        assert_eq!(server.stream_count(), 0);
+    }
+
+    #[test]
+    fn reset_aborts_the_stream_on_both_sides() {
+        let (_tcp, client, server, _) = session_pair();
+        let stream = client.open().unwrap();
+        stream.send(b"unread").unwrap();
+        let accepted = server.accept().unwrap();
+        stream.reset().unwrap();
+
+        // RSTより先に届いたデータは読めることもあるが, 0(FIN)ではなくエラーで終わる
+        let mut buffer = [0; 16];
+        let error = loop {
+            match accepted.recv(&mut buffer) {
+                Ok(nbytes) => assert!(nbytes > 0),
+                Err(error) => break error,
+            }
+        };
+        assert!(error.to_string().contains("has been reset"));
+        assert!(accepted.send(b"late").is_err());
+        assert!(stream.send(b"late").is_err());
+
+        drop(stream);
+        drop(accepted);
+        assert_eq!(client.stream_count(), 0);
+        assert_eq!(server.stream_count(), 0);
+    }
+
+    #[test]
+    fn window_violation_resets_only_the_offending_stream() {
+        let (tcp, client, server, client_sock) = session_pair();
+        let offender = client.open().unwrap();
+        let bystander = client.open().unwrap();
+        let offender_peer = server.accept().unwrap();
+        let bystander_peer = server.accept().unwrap();
+
+        // ウィンドウを無視して1フレームで送りつける
+        let header = FrameHeader {
+            frame_type: FrameType::Data,
+            flags: 0,
+            stream_id: offender.id(),
+            length: INITIAL_STREAM_WINDOW + 1,
+        };
+        let mut frame = header.encode().to_vec();
+        frame.resize(HEADER_LEN + INITIAL_STREAM_WINDOW as usize + 1, 0);
+        tcp.send(client_sock, &frame).unwrap();
+
+        let mut buffer = [0; 16];
+        assert!(offender_peer.recv(&mut buffer).is_err());
+        // 送った側にもRSTが届く
+        while offender.send(b"x").is_ok() {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        bystander.send(b"still here").unwrap();
+        let nbytes = bystander_peer.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"still here");
+    }
+
+    #[test]
+    fn closing_the_session_fails_pending_accepts() {
+        let (_tcp, client, server, _) = session_pair();
+        let closer = thread::spawn(move || client.close());
+
+        assert!(server.accept().is_err());
+        server.close().unwrap();
+        closer.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+use crate::config::Pacing;
+
+// スロースタート中は次のRTTでcwndが倍になるので, 推定した帯域の2倍で送る(Linuxのtcp_pacing_ss_ratioと同じ)
+const SLOW_START_GAIN: f64 = 2.0;
+// 輻輳回避中も少しだけ余裕を持たせる(Linuxのtcp_pacing_ca_ratioと同じ)
+const CONGESTION_AVOIDANCE_GAIN: f64 = 1.2;
+
+/// 送信するセグメントの間隔を空け, cwnd分のデータが一気に送られてバーストにならないようにする
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pacer {
+    // この時刻までは次のセグメントを送らない
+    next_send: Option<SystemTime>,
+}
+
+impl Pacer {
+    /// 次のセグメントを送れるようになるまでの時間
+    pub fn delay(&self, now: SystemTime) -> Duration {
+        self.next_send
+            .and_then(|next_send| next_send.duration_since(now).ok())
+            .unwrap_or_default()
+    }
+
+    /// lenバイトのセグメントを送った. rate(バイト/秒)で送る場合に次のセグメントを送れる時刻を決める
+    /// しばらく送っていなかった場合でも, 溜まった時間分をまとめて送れるようにはしない
+    pub fn on_sent(&mut self, now: SystemTime, len: usize, rate: Option<u64>) {
+        self.next_send = rate.filter(|rate| *rate > 0).map(|rate| {
+            let start = cmp::max(now, self.next_send.unwrap_or(now));
+            start + Duration::from_secs_f64(len as f64 / rate as f64)
+        });
+    }
+}
+
+/// ペーシングのレート(バイト/秒). Noneならペーシングしない
+/// Autoの場合はcwndをSRTTで割った推定帯域を使う. まだRTTを測っていなければペーシングしない
+pub fn pacing_rate(
+    pacing: Pacing,
+    cwnd: u32,
+    ssthresh: u32,
+    srtt: Option<Duration>,
+) -> Option<u64> {
+    match pacing {
+        Pacing::Off => None,
+        Pacing::Rate(rate) => Some(rate),
+        Pacing::Auto => {
+            let srtt = srtt.filter(|srtt| !srtt.is_zero())?;
+            let gain = if cwnd < ssthresh {
+                SLOW_START_GAIN
+            } else {
+                CONGESTION_AVOIDANCE_GAIN
+            };
+            Some((cwnd as f64 * gain / srtt.as_secs_f64()) as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_are_spaced_by_rate() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut pacer = Pacer::default();
+        assert_eq!(pacer.delay(now), Duration::ZERO);
+
+        // 1000バイト/秒で500バイト送ったら, 次は0.5秒後
+        pacer.on_sent(now, 500, Some(1000));
+        assert_eq!(pacer.delay(now), Duration::from_millis(500));
+        // 待たずに続けて送ると, その分だけ後ろにずれる
+        pacer.on_sent(now, 500, Some(1000));
+        assert_eq!(pacer.delay(now), Duration::from_secs(1));
+        assert_eq!(pacer.delay(now + Duration::from_secs(2)), Duration::ZERO);
+
+        // 暇だった時間の分をまとめて送れるようにはならない
+        let later = now + Duration::from_secs(10);
+        pacer.on_sent(later, 500, Some(1000));
+        assert_eq!(pacer.delay(later), Duration::from_millis(500));
+
+        pacer.on_sent(later, 500, None);
+        assert_eq!(pacer.delay(later), Duration::ZERO);
+    }
+
+    #[test]
+    fn auto_rate_follows_cwnd_over_srtt() {
+        let srtt = Some(Duration::from_millis(100));
+        // スロースタート中は推定帯域(10000バイト / 0.1秒)の2倍
+        assert_eq!(
+            pacing_rate(Pacing::Auto, 10000, u32::MAX, srtt),
+            Some(200_000)
+        );
+        assert_eq!(pacing_rate(Pacing::Auto, 10000, 5000, srtt), Some(120_000));
+        assert_eq!(pacing_rate(Pacing::Auto, 10000, u32::MAX, None), None);
+        assert_eq!(pacing_rate(Pacing::Off, 10000, u32::MAX, srtt), None);
+        assert_eq!(
+            pacing_rate(Pacing::Rate(42), 10000, u32::MAX, None),
+            Some(42)
+        );
+    }
+}
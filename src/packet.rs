@@ -2,21 +2,140 @@ use std::{fmt::Debug, net::Ipv4Addr};
 
 use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, util, Packet};
 
+use crate::tcpflags::TcpFlags;
+
 pub const TCP_HEADER_SIZE: usize = 20;
 pub const MAX_PACKET_SIZE: usize = 65535;
+// 1つのセグメントに載せるSACKブロックの上限. オプション領域(40バイト)に収まる数
+pub const MAX_SACK_BLOCKS: usize = 4;
+// タイムスタンプ(10バイト)と一緒に載せる場合のSACKブロックの上限
+pub const MAX_SACK_BLOCKS_WITH_TIMESTAMPS: usize = 3;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_WINDOW_SCALE: u8 = 3;
+const OPTION_SACK_PERMITTED: u8 = 4;
+const OPTION_SACK: u8 = 5;
+const OPTION_TIMESTAMPS: u8 = 8;
+const OPTION_FAST_OPEN: u8 = 34;
+
+/// TCPオプション. 使っているものだけ扱い, それ以外は受信しても読み飛ばす
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TcpOption {
+    /// SYNに載せて, 以降こちらが広告するウィンドウを何ビットシフトしているかを相手に伝える. RFC 7323
+    WindowScale(u8),
+    /// SYNに載せて, SACKを使えることを相手に伝える. RFC 2018
+    SackPermitted,
+    /// 受信済みの不連続なデータの範囲
+    Sack(Vec<SackBlock>),
+    /// 送信時刻(tsval)と, 相手から最後に受け取ったtsvalのエコー(tsecr). RFC 7323
+    Timestamps { tsval: u32, tsecr: u32 },
+    /// TCP Fast Openのクッキー. RFC 7413
+    /// 空ならクッキーの要求で, SYNに載せたクッキーが正しければSYNのデータをそのまま受け取ってもらえる
+    FastOpen(Vec<u8>),
+}
+
+/// SACKブロック. start..endのシーケンス番号のデータを受信している
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SackBlock {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TcpOption {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            TcpOption::WindowScale(shift) => {
+                buffer.extend_from_slice(&[OPTION_WINDOW_SCALE, 3, *shift])
+            }
+            TcpOption::SackPermitted => buffer.extend_from_slice(&[OPTION_SACK_PERMITTED, 2]),
+            TcpOption::Sack(blocks) => {
+                let blocks = &blocks[..blocks.len().min(MAX_SACK_BLOCKS)];
+                buffer.extend_from_slice(&[OPTION_SACK, 2 + 8 * blocks.len() as u8]);
+                for block in blocks {
+                    buffer.extend_from_slice(&block.start.to_be_bytes());
+                    buffer.extend_from_slice(&block.end.to_be_bytes());
+                }
+            }
+            TcpOption::Timestamps { tsval, tsecr } => {
+                buffer.extend_from_slice(&[OPTION_TIMESTAMPS, 10]);
+                buffer.extend_from_slice(&tsval.to_be_bytes());
+                buffer.extend_from_slice(&tsecr.to_be_bytes());
+            }
+            TcpOption::FastOpen(cookie) => {
+                buffer.extend_from_slice(&[OPTION_FAST_OPEN, 2 + cookie.len() as u8]);
+                buffer.extend_from_slice(cookie);
+            }
+        }
+    }
+}
+
+/// IPヘッダのECNフィールド(RFC 3168)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Ecn {
+    /// ECNに対応していない
+    #[default]
+    NotEct,
+    /// ECNに対応している. 経路上のルーターは混雑した時に落とす代わりにCeへ書き換えられる
+    Ect0,
+    Ect1,
+    /// 経路上で輻輳を経験した
+    Ce,
+}
+
+impl Ecn {
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
 
 // TCPセグメント
 // https://www.infraexpert.com/study/tcpip8.html
 #[derive(Clone)]
 pub struct TCPPacket {
     buffer: Vec<u8>,
+    // IPヘッダのECNフィールド. TCPヘッダの外だが, デバイスがIPヘッダとの間で読み書きするのでセグメントと一緒に持ち回る
+    ecn: Ecn,
 }
 
 impl TCPPacket {
     pub fn new(payload_len: usize) -> Self {
-        Self {
-            buffer: vec![0; TCP_HEADER_SIZE + payload_len],
+        Self::with_options(&[], payload_len)
+    }
+
+    /// オプション付きのセグメントを作る. オプションは4バイト境界までNOPで埋め, data offsetもここで設定する
+    pub fn with_options(options: &[TcpOption], payload_len: usize) -> Self {
+        let mut encoded = Vec::new();
+        for option in options {
+            option.encode(&mut encoded);
+        }
+        while encoded.len() % 4 != 0 {
+            encoded.push(OPTION_NOP);
         }
+
+        let header_len = TCP_HEADER_SIZE + encoded.len();
+        let mut buffer = vec![0; header_len + payload_len];
+        buffer[TCP_HEADER_SIZE..header_len].copy_from_slice(&encoded);
+        let mut packet = Self {
+            buffer,
+            ecn: Ecn::NotEct,
+        };
+        packet.set_data_offset((header_len / 4) as u8);
+        packet
     }
 
     pub fn get_src(&self) -> u16 {
@@ -48,9 +167,8 @@ impl TCPPacket {
         ])
     }
 
-    pub fn get_flag(&self) -> u8 {
-        // u8::from_be_bytes([self.buffer[13]])
-        self.buffer[13]
+    pub fn get_flag(&self) -> TcpFlags {
+        TcpFlags::from_bits(self.buffer[13])
     }
 
     pub fn get_window_size(&self) -> u16 {
@@ -61,6 +179,119 @@ impl TCPPacket {
         u16::from_be_bytes([self.buffer[16], self.buffer[17]])
     }
 
+    /// 緊急ポインタ. URGフラグが立っている時だけ意味を持ち, ペイロードの先頭からこのバイト数が緊急データになる
+    /// RFC 6093に従い, 緊急データの最後のバイトの次を指すものとして扱う
+    pub fn get_urgent_pointer(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[18], self.buffer[19]])
+    }
+
+    pub fn get_ecn(&self) -> Ecn {
+        self.ecn
+    }
+
+    /// オプションを含めたヘッダの長さ. data offsetが壊れていてもバッファの範囲に収める
+    pub fn header_len(&self) -> usize {
+        let len = (self.buffer[12] >> 4) as usize * 4;
+        len.clamp(TCP_HEADER_SIZE, self.buffer.len())
+    }
+
+    /// ヘッダのオプションを読み出す. 知らない種類は読み飛ばし, 壊れていればそこで打ち切る
+    pub fn options(&self) -> Vec<TcpOption> {
+        let area = &self.buffer[TCP_HEADER_SIZE..self.header_len()];
+        let mut options = Vec::new();
+        let mut i = 0;
+        while i < area.len() {
+            match area[i] {
+                OPTION_END => break,
+                OPTION_NOP => {
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            let len = match area.get(i + 1) {
+                Some(&len) if len >= 2 && i + len as usize <= area.len() => len as usize,
+                _ => break,
+            };
+            let body = &area[i + 2..i + len];
+            match area[i] {
+                OPTION_WINDOW_SCALE if len == 3 => options.push(TcpOption::WindowScale(body[0])),
+                OPTION_SACK_PERMITTED => options.push(TcpOption::SackPermitted),
+                OPTION_SACK => options.push(TcpOption::Sack(
+                    body.chunks_exact(8)
+                        .map(|block| SackBlock {
+                            start: u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+                            end: u32::from_be_bytes([block[4], block[5], block[6], block[7]]),
+                        })
+                        .collect(),
+                )),
+                OPTION_TIMESTAMPS if len == 10 => options.push(TcpOption::Timestamps {
+                    tsval: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                    tsecr: u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+                }),
+                // クッキーは4から16バイト
+                OPTION_FAST_OPEN if len == 2 || (6..=18).contains(&len) => {
+                    options.push(TcpOption::FastOpen(body.to_vec()))
+                }
+                _ => {}
+            }
+            i += len;
+        }
+        options
+    }
+
+    /// SACK-permittedオプションが付いているか
+    pub fn is_sack_permitted(&self) -> bool {
+        self.options().contains(&TcpOption::SackPermitted)
+    }
+
+    /// ウィンドウスケールオプションのシフト数
+    pub fn window_scale(&self) -> Option<u8> {
+        self.options().into_iter().find_map(|option| match option {
+            TcpOption::WindowScale(shift) => Some(shift),
+            _ => None,
+        })
+    }
+
+    /// タイムスタンプオプションの(tsval, tsecr)
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        self.options().into_iter().find_map(|option| match option {
+            TcpOption::Timestamps { tsval, tsecr } => Some((tsval, tsecr)),
+            _ => None,
+        })
+    }
+
+    /// TCP Fast Openオプションのクッキー. クッキーの要求なら空のVecになる
+    pub fn fast_open_cookie(&self) -> Option<Vec<u8>> {
+        self.options().into_iter().find_map(|option| match option {
+            TcpOption::FastOpen(cookie) => Some(cookie),
+            _ => None,
+        })
+    }
+
+    /// SACKオプションのブロック. 付いていなければ空
+    pub fn sack_blocks(&self) -> Vec<SackBlock> {
+        self.options()
+            .into_iter()
+            .flat_map(|option| match option {
+                TcpOption::Sack(blocks) => blocks,
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// シーケンス番号空間で占める長さ. SYNとFINはそれぞれ1つ分のシーケンス番号を消費する
+    pub fn segment_len(&self) -> usize {
+        let mut len = self.payload().len();
+        if self.get_flag().has_syn() {
+            len += 1;
+        }
+        if self.get_flag().has_fin() {
+            len += 1;
+        }
+        len
+    }
+
     pub fn set_src(&mut self, port: u16) {
         self.buffer[0..2].copy_from_slice(&port.to_be_bytes())
     }
@@ -81,8 +312,8 @@ impl TCPPacket {
         self.buffer[12] |= offset << 4;
     }
 
-    pub fn set_flag(&mut self, flag: u8) {
-        self.buffer[13] = flag
+    pub fn set_flag(&mut self, flag: TcpFlags) {
+        self.buffer[13] = flag.bits()
     }
 
     pub fn set_window_size(&mut self, window_size: u16) {
@@ -93,15 +324,23 @@ impl TCPPacket {
         self.buffer[16..18].copy_from_slice(&checksum.to_be_bytes());
     }
 
+    pub fn set_urgent_pointer(&mut self, pointer: u16) {
+        self.buffer[18..20].copy_from_slice(&pointer.to_be_bytes());
+    }
+
+    pub fn set_ecn(&mut self, ecn: Ecn) {
+        self.ecn = ecn;
+    }
+
     pub fn set_payload(&mut self, payroad: &[u8]) {
-        self.buffer[TCP_HEADER_SIZE..TCP_HEADER_SIZE + payroad.len() as usize]
-            .copy_from_slice(payroad);
+        let header_len = self.header_len();
+        self.buffer[header_len..header_len + payroad.len()].copy_from_slice(payroad);
     }
 
     pub fn is_correct_checksum(&self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> bool {
         self.get_checksum()
             == util::ipv4_checksum(
-                &self.packet(),
+                self.packet(),
                 8,
                 &[],
                 &local_addr,
@@ -117,7 +356,7 @@ impl Packet for TCPPacket {
     }
 
     fn payload(&self) -> &[u8] {
-        &self.buffer[TCP_HEADER_SIZE..]
+        &self.buffer[self.header_len()..]
     }
 }
 
@@ -142,6 +381,65 @@ impl<'a> From<TcpPacket<'a>> for TCPPacket {
     fn from(packet: TcpPacket<'a>) -> Self {
         Self {
             buffer: packet.packet().to_vec(),
+            ecn: Ecn::NotEct,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_round_trip_and_shift_payload() {
+        let blocks = vec![
+            SackBlock {
+                start: 1000,
+                end: 2000,
+            },
+            SackBlock {
+                start: u32::MAX - 10,
+                end: 5,
+            },
+        ];
+        let mut packet = TCPPacket::with_options(&[TcpOption::Sack(blocks.clone())], 3);
+        packet.set_payload(b"abc");
+
+        // 2 + 8 * 2 = 18バイトを4バイト境界まで埋める
+        assert_eq!(packet.header_len(), TCP_HEADER_SIZE + 20);
+        assert_eq!(packet.payload(), b"abc");
+        assert_eq!(packet.sack_blocks(), blocks);
+        assert!(!packet.is_sack_permitted());
+
+        let syn = TCPPacket::with_options(
+            &[
+                TcpOption::Timestamps { tsval: 7, tsecr: 0 },
+                TcpOption::SackPermitted,
+                TcpOption::WindowScale(7),
+            ],
+            0,
+        );
+        assert!(syn.is_sack_permitted());
+        assert_eq!(syn.timestamps(), Some((7, 0)));
+        assert_eq!(syn.window_scale(), Some(7));
+        assert_eq!(syn.header_len(), TCP_HEADER_SIZE + 16);
+        assert_eq!(packet.window_scale(), None);
+        assert!(TCPPacket::new(0).options().is_empty());
+
+        let mut syn = TCPPacket::with_options(&[TcpOption::FastOpen(vec![1; 8])], 5);
+        syn.set_payload(b"hello");
+        assert_eq!(syn.fast_open_cookie(), Some(vec![1; 8]));
+        assert_eq!(syn.payload(), b"hello");
+        let request = TCPPacket::with_options(&[TcpOption::FastOpen(Vec::new())], 0);
+        assert_eq!(request.fast_open_cookie(), Some(Vec::new()));
+        assert_eq!(packet.fast_open_cookie(), None);
+    }
+
+    #[test]
+    fn malformed_options_are_ignored() {
+        let mut packet = TCPPacket::with_options(&[TcpOption::SackPermitted], 0);
+        // 長さがオプション領域をはみ出している
+        packet.buffer[TCP_HEADER_SIZE + 1] = 40;
+        assert!(packet.options().is_empty());
+    }
+}
@@ -0,0 +1,356 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Prefix, ReversePath};
+use crate::packet::TCPPacket;
+use crate::socket::{Socket, TcpStatus};
+
+/// プロトコルへの準拠度合い
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ComplianceMode {
+    /// 従来通りの緩いチェック. 学習用に多少おかしなセグメントも受け入れる
+    #[default]
+    Permissive,
+    /// RFC 793の受信チェックを全て行い, 違反したセグメントは破棄してRST/チャレンジACKを返す
+    Strict,
+}
+
+/// 受信したセグメントをどう扱うか
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// ハンドラに渡して処理する
+    Accept,
+    /// 何もせず破棄する
+    Drop,
+    /// 破棄して現在のSND.NXT/RCV.NXTでACKを返す(チャレンジACK)
+    Ack,
+    /// 破棄してRSTを返す
+    Reset { seq: u32, ack: Option<u32> },
+}
+
+/// 各状態のハンドラに渡す前にセグメントを検査するポリシー
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompliancePolicy {
+    mode: ComplianceMode,
+}
+
+impl CompliancePolicy {
+    pub fn new(mode: ComplianceMode) -> Self {
+        Self { mode }
+    }
+
+    pub fn mode(&self) -> ComplianceMode {
+        self.mode
+    }
+
+    pub fn check(&self, socket: &Socket, packet: &TCPPacket) -> Verdict {
+        if self.mode == ComplianceMode::Permissive {
+            return Verdict::Accept;
+        }
+
+        let flag = packet.get_flag();
+
+        match socket.status {
+            TcpStatus::Listen => {
+                if flag.has_rst() {
+                    Verdict::Drop
+                } else if flag.has_ack() {
+                    Verdict::Reset {
+                        seq: packet.get_ack(),
+                        ack: None,
+                    }
+                } else if flag.has_syn() {
+                    Verdict::Accept
+                } else {
+                    Verdict::Drop
+                }
+            }
+            TcpStatus::SynSent => {
+                if flag.has_ack()
+                    && (packet.get_ack().wrapping_sub(socket.send_param.initial_seq) as i32 <= 0
+                        || packet.get_ack().wrapping_sub(socket.send_param.next) as i32 > 0)
+                {
+                    if flag.has_rst() {
+                        return Verdict::Drop;
+                    }
+                    return Verdict::Reset {
+                        seq: packet.get_ack(),
+                        ack: None,
+                    };
+                }
+                if !flag.has_syn() && !flag.has_rst() {
+                    return Verdict::Drop;
+                }
+                Verdict::Accept
+            }
+            // 同時オープンで届く相手のSYN/ACK. SYNは受信済みのシーケンス番号なので受け入れテストには通らない
+            TcpStatus::SynRcvd
+                if flag.has_syn()
+                    && flag.has_ack()
+                    && packet.get_seq().wrapping_add(1) == socket.recv_param.next
+                    && packet.get_ack() == socket.send_param.next =>
+            {
+                Verdict::Accept
+            }
+            // SYN/ACKが届かずに再送されてきた相手のSYN. SYN/ACKを送り直すためにハンドラに渡す
+            // TCP Fast OpenでSYNのデータを受け取っていれば, RCV.NXTはデータの後ろにある
+            TcpStatus::SynRcvd
+                if flag.has_syn()
+                    && !flag.has_ack()
+                    && packet.get_seq() == socket.recv_param.initial_seq =>
+            {
+                Verdict::Accept
+            }
+            _ => self.check_synchronized(socket, packet),
+        }
+    }
+
+    /// SYN_RCVD以降の状態に対するRFC 793のチェック
+    fn check_synchronized(&self, socket: &Socket, packet: &TCPPacket) -> Verdict {
+        let flag = packet.get_flag();
+
+        if !socket.is_acceptable(packet) {
+            if flag.has_rst() {
+                return Verdict::Drop;
+            }
+            return Verdict::Ack;
+        }
+
+        if flag.has_syn() {
+            // 同期済みの接続にウィンドウ内のSYNが届いた. RFC 5961に従いチャレンジACKを返す
+            return Verdict::Ack;
+        }
+
+        if !flag.has_ack() {
+            if flag.has_rst() {
+                return Verdict::Accept;
+            }
+            return Verdict::Drop;
+        }
+
+        let ack = packet.get_ack();
+        if socket.status == TcpStatus::SynRcvd {
+            if ack.wrapping_sub(socket.send_param.unacked_seq) as i32 <= 0
+                || ack.wrapping_sub(socket.send_param.next) as i32 > 0
+            {
+                return Verdict::Reset {
+                    seq: ack,
+                    ack: None,
+                };
+            }
+        } else if ack.wrapping_sub(socket.send_param.next) as i32 > 0 {
+            // まだ送っていないデータに対するACK
+            return Verdict::Ack;
+        }
+
+        Verdict::Accept
+    }
+}
+
+/// どのソケットにも該当しない(CLOSED状態の)セグメントに対する応答. RFC 793 3.4節
+/// ACKが立っていればSEG.ACKをシーケンス番号にしたRSTを, 立っていなければSEG.SEQ + SEG.LENをackしたRST/ACKを返す
+pub fn reset_for_closed(packet: &TCPPacket) -> Verdict {
+    let flag = packet.get_flag();
+    if flag.has_rst() {
+        // RSTに対してRSTは返さない
+        return Verdict::Drop;
+    }
+
+    if flag.has_ack() {
+        Verdict::Reset {
+            seq: packet.get_ack(),
+            ack: None,
+        }
+    } else {
+        Verdict::Reset {
+            seq: 0,
+            ack: Some(packet.get_seq().wrapping_add(packet.segment_len() as u32)),
+        }
+    }
+}
+
+/// スタック全体で1秒あたりに返すチャレンジACKの数を制限する. RFC 5961 7節
+/// 攻撃者が偽装したセグメントを大量に送りつけて, こちらにACKを大量に返させるのを防ぐ
+#[derive(Debug)]
+pub struct ChallengeAckLimiter {
+    limit: u32,
+    // 今数えている1秒間の始まりと, その間に返した数
+    window_start: Option<SystemTime>,
+    sent: u32,
+}
+
+impl ChallengeAckLimiter {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            window_start: None,
+            sent: 0,
+        }
+    }
+
+    /// nowにチャレンジACKを返してよければ数えてtrueを返す
+    pub fn allow(&mut self, now: SystemTime) -> bool {
+        let expired = self.window_start.is_none_or(|start| {
+            now.duration_since(start)
+                .is_ok_and(|elapsed| elapsed >= Duration::from_secs(1))
+        });
+        if expired {
+            self.window_start = Some(now);
+            self.sent = 0;
+        }
+        if self.sent >= self.limit {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+/// TCPで使えないアドレス. connectが返すエラーはanyhow::Errorからdowncastして取り出せる
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressError {
+    Broadcast(Ipv4Addr),
+    Multicast(Ipv4Addr),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::Broadcast(addr) => write!(f, "broadcast address: {}", addr),
+            AddressError::Multicast(addr) => write!(f, "multicast address: {}", addr),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// TCPの接続はユニキャストアドレス同士でしか張れない. RFC 1122 4.2.3.10
+/// サブネット毎のブロードキャストアドレスはネットマスクが分からないため判定できず, 255.255.255.255だけを弾く
+pub fn check_unicast(addr: Ipv4Addr) -> Result<(), AddressError> {
+    if addr.is_broadcast() {
+        Err(AddressError::Broadcast(addr))
+    } else if addr.is_multicast() {
+        Err(AddressError::Multicast(addr))
+    } else {
+        Ok(())
+    }
+}
+
+/// 受信したセグメントの送信元remote_addrがlocal_addrに届くものとして妥当か. RFC 3704
+pub fn reverse_path_allows(
+    mode: &ReversePath,
+    local_addr: Ipv4Addr,
+    remote_addr: Ipv4Addr,
+) -> bool {
+    let prefixes = match mode {
+        ReversePath::Off => return true,
+        ReversePath::Loose => &[][..],
+        ReversePath::Strict(prefixes) => &prefixes[..],
+    };
+
+    let martian = Prefix::new(Ipv4Addr::new(0, 0, 0, 0), 8).contains(remote_addr)
+        || Prefix::new(Ipv4Addr::new(240, 0, 0, 0), 4).contains(remote_addr)
+        // ループバックアドレスはループバックの中でしか使われない
+        || (remote_addr.is_loopback() && !local_addr.is_loopback())
+        // 自分自身を騙るセグメント(LAND攻撃)
+        || (remote_addr == local_addr && !local_addr.is_loopback());
+    if martian {
+        return false;
+    }
+
+    matches!(mode, ReversePath::Loose) || prefixes.iter().any(|prefix| prefix.contains(remote_addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::device::LoopbackDevice;
+    use crate::socket::SockID;
+    use crate::tcpflags::TcpFlags;
+    use std::net::SocketAddrV4;
+    use std::sync::Arc;
+
+    // こちらはISN 1000でSYNだけを送った(SND.NXT = 1001), 相手のシーケンス番号は5000から受け取る
+    fn socket(status: TcpStatus) -> Socket {
+        let mut socket = Socket::new(
+            Arc::new(LoopbackDevice::default()),
+            Arc::new(Clock::simulated()),
+            SockID::new(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 40000),
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 40001),
+            ),
+            status,
+        );
+        socket.send_param.initial_seq = 1000;
+        socket.send_param.unacked_seq = 1000;
+        socket.send_param.next = 1001;
+        socket.recv_param.initial_seq = 4999;
+        socket.recv_param.next = 5000;
+        socket.recv_param.window = 1000;
+        socket
+    }
+
+    fn segment(flag: TcpFlags, seq: u32, ack: u32) -> TCPPacket {
+        let mut packet = TCPPacket::new(0);
+        packet.set_flag(flag);
+        packet.set_seq(seq);
+        packet.set_ack(ack);
+        packet
+    }
+
+    #[test]
+    fn check_follows_rfc793_in_strict_mode_and_accepts_everything_otherwise() {
+        use TcpStatus::*;
+        let reset = |seq| Verdict::Reset { seq, ack: None };
+        let syn = TcpFlags::SYN;
+        let ack = TcpFlags::ACK;
+        let rst = TcpFlags::RST;
+        let cases = [
+            // LISTENはSYNだけを受け付け, ACKにはRSTを返す
+            (Listen, syn, 0, 0, Verdict::Accept),
+            (Listen, ack, 0, 1234, reset(1234)),
+            (Listen, rst, 0, 0, Verdict::Drop),
+            (Listen, TcpFlags::FIN, 0, 0, Verdict::Drop),
+            // SYN_SENTは送ったSYNをackするSYN/ACKか, 同時オープンのSYNを受け付ける
+            (SynSent, syn | ack, 4999, 1001, Verdict::Accept),
+            (SynSent, syn, 4999, 0, Verdict::Accept),
+            (SynSent, syn | ack, 4999, 1000, reset(1000)),
+            (SynSent, syn | ack, 4999, 1002, reset(1002)),
+            (SynSent, rst | ack, 0, 2000, Verdict::Drop),
+            (SynSent, ack, 4999, 1001, Verdict::Drop),
+            // SYN_RCVDはSYN/ACKをackするACKで接続が確立する
+            (SynRcvd, ack, 5000, 1001, Verdict::Accept),
+            (SynRcvd, ack, 5000, 1000, reset(1000)),
+            (SynRcvd, syn | ack, 4999, 1001, Verdict::Accept),
+            // 同期済みの状態ではウィンドウ外のセグメントとSYNにチャレンジACKを返す
+            (Established, ack, 5000, 1001, Verdict::Accept),
+            (Established, ack, 100, 1001, Verdict::Ack),
+            (Established, ack, 5000, 2000, Verdict::Ack),
+            (Established, syn, 5000, 0, Verdict::Ack),
+            (Established, rst, 5000, 0, Verdict::Accept),
+            (Established, rst, 100, 0, Verdict::Drop),
+            (Established, TcpFlags::FIN, 5000, 0, Verdict::Drop),
+            (CloseWait, ack, 5999, 1001, Verdict::Accept),
+            (CloseWait, ack, 6000, 1001, Verdict::Ack),
+        ];
+
+        let strict = CompliancePolicy::new(ComplianceMode::Strict);
+        let permissive = CompliancePolicy::new(ComplianceMode::Permissive);
+        for (status, flag, seq, ack, verdict) in cases {
+            let socket = socket(status);
+            let packet = segment(flag, seq, ack);
+            assert_eq!(
+                strict.check(&socket, &packet),
+                verdict,
+                "{:?} {:?} seq={} ack={}",
+                status,
+                flag,
+                seq,
+                ack
+            );
+            assert_eq!(permissive.check(&socket, &packet), Verdict::Accept);
+        }
+    }
+}
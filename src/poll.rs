@@ -0,0 +1,95 @@
+use std::fmt::{self, Debug};
+use std::ops::{BitOr, BitOrAssign};
+
+use crate::socket::SockID;
+
+/// TCP::pollで調べる, または報告されるソケットの状態の集合(poll(2)のPOLLIN, POLLOUT, POLLERR)
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PollEvents(u8);
+
+impl PollEvents {
+    /// recvがブロックせずに返る. 受信バッファにrecv_lowat以上溜まっているか, 相手のFINを受信している
+    /// リスニングソケットならacceptを待っている接続がある
+    pub const READABLE: Self = Self(1);
+    /// sendがブロックせずに送信バッファへ書き込める. 確立していて送信バッファにsend_lowat以上の空きがある
+    pub const WRITABLE: Self = Self(1 << 1);
+    /// 接続が中断されたか, ソケットが既に削除されている. 調べたいものに含めなくても報告する
+    pub const ERROR: Self = Self(1 << 2);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// otherが全て含まれているか
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_readable(self) -> bool {
+        self.contains(Self::READABLE)
+    }
+
+    pub const fn is_writable(self) -> bool {
+        self.contains(Self::WRITABLE)
+    }
+
+    pub const fn is_error(self) -> bool {
+        self.contains(Self::ERROR)
+    }
+
+    /// otherに含まれるものだけを残す
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for PollEvents {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PollEvents {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl Debug for PollEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = [
+            (Self::READABLE, "READABLE"),
+            (Self::WRITABLE, "WRITABLE"),
+            (Self::ERROR, "ERROR"),
+        ]
+        .into_iter()
+        .filter(|(event, _)| self.contains(*event))
+        .map(|(_, name)| name)
+        .collect();
+        write!(f, "PollEvents({})", names.join(" | "))
+    }
+}
+
+/// TCP::pollに渡す1つのソケット. eventsに調べたい状態を入れておくと, reventsに成り立っているものが入る
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PollFd {
+    pub sock_id: SockID,
+    pub events: PollEvents,
+    pub revents: PollEvents,
+}
+
+impl PollFd {
+    pub fn new(sock_id: SockID, events: PollEvents) -> Self {
+        Self {
+            sock_id,
+            events,
+            revents: PollEvents::empty(),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::socket::{Addr, DefaultEndpoint};
+use crate::sync::LockResultExt;
+
+/// connectで使うエフェメラルポートの範囲
+pub const PORT_RANGE: Range<u16> = 40000..60000;
+
+/// connectで使うローカルポートの選び方. TcpConfig::port_allocatorで差し替えられる
+pub trait PortAllocator: Debug + Send + Sync {
+    /// local_addrからremoteへ接続する時のローカルポートをPORT_RANGEから選ぶ
+    /// in_useがtrueを返すポートは使えないので飛ばし, 空いているポートが見つからなければNoneを返す
+    fn allocate(
+        &self,
+        local_addr: Addr,
+        remote: DefaultEndpoint,
+        in_use: &dyn Fn(u16) -> bool,
+    ) -> Option<u16>;
+}
+
+fn port_count() -> u32 {
+    (PORT_RANGE.end - PORT_RANGE.start) as u32
+}
+
+fn port_at(offset: u32) -> u16 {
+    PORT_RANGE.start + (offset % port_count()) as u16
+}
+
+/// 毎回ランダムに選ぶ. 次に使われるポートを推測されにくい. デフォルト
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomPorts;
+
+impl PortAllocator for RandomPorts {
+    fn allocate(
+        &self,
+        _local_addr: Addr,
+        _remote: DefaultEndpoint,
+        in_use: &dyn Fn(u16) -> bool,
+    ) -> Option<u16> {
+        let mut rng = rand::thread_rng();
+        (0..port_count())
+            .map(|_| rng.gen_range(PORT_RANGE))
+            .find(|port| !in_use(*port))
+    }
+}
+
+/// 前回の次のポートから順番に選び, 範囲の最後まで来たら先頭に戻る
+/// 閉じたばかりのポートをすぐに使い回さないので接続の入れ替わりが激しくても衝突しにくく, テストではポートが再現できる
+#[derive(Debug)]
+pub struct SequentialPorts {
+    next: Mutex<u16>,
+}
+
+impl SequentialPorts {
+    /// startから順番に選ぶ. PORT_RANGEの外なら先頭から選ぶ
+    pub fn starting_at(start: u16) -> Self {
+        let start = if PORT_RANGE.contains(&start) {
+            start
+        } else {
+            PORT_RANGE.start
+        };
+        Self {
+            next: Mutex::new(start),
+        }
+    }
+}
+
+impl Default for SequentialPorts {
+    fn default() -> Self {
+        Self::starting_at(PORT_RANGE.start)
+    }
+}
+
+impl PortAllocator for SequentialPorts {
+    fn allocate(
+        &self,
+        _local_addr: Addr,
+        _remote: DefaultEndpoint,
+        in_use: &dyn Fn(u16) -> bool,
+    ) -> Option<u16> {
+        let mut next = self.next.lock().recover();
+        for _ in 0..port_count() {
+            let port = *next;
+            *next = port_at((port - PORT_RANGE.start) as u32 + 1);
+            if !in_use(port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+/// 接続先とsecretのハッシュで決まる位置から順番に選ぶ. RFC 6056 3.3.3(Algorithm 3)
+/// 同じ接続先へは前回の次のポートから選ぶので続けて接続しても衝突しにくく, 他の接続先からは次に使われるポートを推測できない
+/// secretを固定すればテストでポートが再現できる
+#[derive(Debug)]
+pub struct HashedPorts {
+    secret: u64,
+    // 選んだ回数. 全接続先で共有し, 同じ接続先へ続けて接続した時にずらす
+    next: AtomicU32,
+}
+
+impl HashedPorts {
+    pub fn new(secret: u64) -> Self {
+        Self {
+            secret,
+            next: AtomicU32::new(0),
+        }
+    }
+
+    fn offset(&self, local_addr: Addr, remote: DefaultEndpoint) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        (local_addr, remote, self.secret).hash(&mut hasher);
+        hasher.finish() as u32
+    }
+}
+
+impl Default for HashedPorts {
+    fn default() -> Self {
+        Self::new(rand::thread_rng().gen())
+    }
+}
+
+impl PortAllocator for HashedPorts {
+    fn allocate(
+        &self,
+        local_addr: Addr,
+        remote: DefaultEndpoint,
+        in_use: &dyn Fn(u16) -> bool,
+    ) -> Option<u16> {
+        let offset = self.offset(local_addr, remote) % port_count();
+        for _ in 0..port_count() {
+            let next = self.next.fetch_add(1, Ordering::Relaxed) % port_count();
+            let port = port_at(offset + next);
+            if !in_use(port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn remote(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), port)
+    }
+
+    #[test]
+    fn sequential_ports_skip_used_ones_and_wrap_around() {
+        let allocator = SequentialPorts::starting_at(PORT_RANGE.end - 2);
+        let in_use = |port: u16| port == PORT_RANGE.end - 1;
+        let ports: Vec<_> = (0..3)
+            .map(|_| allocator.allocate(LOCAL, remote(80), &in_use).unwrap())
+            .collect();
+        assert_eq!(
+            ports,
+            [PORT_RANGE.end - 2, PORT_RANGE.start, PORT_RANGE.start + 1]
+        );
+        assert_eq!(allocator.allocate(LOCAL, remote(80), &|_| true), None);
+    }
+
+    #[test]
+    fn hashed_ports_depend_on_destination_and_secret() {
+        let allocate = |secret: u64, port: u16| {
+            HashedPorts::new(secret)
+                .allocate(LOCAL, remote(port), &|_| false)
+                .unwrap()
+        };
+        // secretと接続先が同じなら同じポートになる
+        assert_eq!(allocate(1, 80), allocate(1, 80));
+        assert!((1..10).any(|secret| allocate(secret, 80) != allocate(1, 80)));
+        assert!((81..90).any(|port| allocate(1, port) != allocate(1, 80)));
+
+        // 同じ接続先へ続けて接続した場合は次のポートから選ぶ
+        let allocator = HashedPorts::new(1);
+        let first = allocator.allocate(LOCAL, remote(80), &|_| false).unwrap();
+        let second = allocator
+            .allocate(LOCAL, remote(80), &|port| port == first)
+            .unwrap();
+        assert!(PORT_RANGE.contains(&first) && PORT_RANGE.contains(&second));
+        assert_ne!(first, second);
+    }
+}
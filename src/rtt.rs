@@ -0,0 +1,161 @@
+use std::cmp;
+use std::time::Duration;
+
+/// RTTを1度も測っていない時の再送タイムアウト. RFC 6298
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+// RFC 6298では1秒以上が推奨だが, LANでロスから素早く回復できるようLinuxと同じ200msまで下げる
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+// タイマーの粒度(タイマースレッドの間隔). RTTVARがこれより小さくてもRTOはSRTT+粒度以上にする
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(100);
+
+/// RTTの推定値(SRTT, RTTVAR)と, そこから計算した再送タイムアウト(RTO). RFC 6298
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RttEstimator {
+    // 最後に測ったRTT
+    latest: Option<Duration>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    // SRTTとRTTVARから計算したRTO. 実際に使うRTOはこれをbackoff回倍にしたもの
+    base_rto: Duration,
+    backoff: u32,
+    max_rto: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            latest: None,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            base_rto: INITIAL_RTO,
+            backoff: 0,
+            max_rto: MAX_RTO,
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn latest(&self) -> Option<Duration> {
+        self.latest
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.base_rto
+            .checked_mul(1 << self.backoff.min(31))
+            .map_or(self.max_rto, |rto| cmp::min(rto, self.max_rto))
+    }
+
+    /// RTOの上限を変える. TcpConfig::max_rto
+    pub fn set_max_rto(&mut self, max_rto: Duration) {
+        self.max_rto = max_rto;
+    }
+
+    /// RTTを1回測った. Karnのアルゴリズムに従い, 再送したセグメントから測った値は渡さないこと
+    /// (タイムスタンプオプションでエコーされた時刻から測った値は再送したセグメントでも正しい)
+    pub fn on_sample(&mut self, rtt: Duration) {
+        self.latest = Some(rtt);
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or_default();
+        self.base_rto = (srtt + cmp::max(CLOCK_GRANULARITY, self.rttvar * 4))
+            .clamp(MIN_RTO, cmp::max(MIN_RTO, self.max_rto));
+        self.backoff = 0;
+    }
+
+    /// 再送タイムアウトした. 新しいACKが届くかRTTを測るまでRTOを倍にしていく(指数バックオフ)
+    pub fn on_timeout(&mut self) {
+        self.backoff = self.backoff.saturating_add(1);
+    }
+
+    /// 新しいデータがackされた. 経路は回復しているのでバックオフをやめる
+    pub fn reset_backoff(&mut self) {
+        self.backoff = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_sample_initializes_srtt_and_rttvar() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+        rtt.on_sample(Duration::from_millis(400));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(400)));
+        assert_eq!(rtt.rttvar(), Duration::from_millis(200));
+        assert_eq!(rtt.rto(), Duration::from_millis(1200));
+    }
+
+    #[test]
+    fn later_samples_are_smoothed() {
+        let mut rtt = RttEstimator::default();
+        rtt.on_sample(Duration::from_millis(400));
+        rtt.on_sample(Duration::from_millis(800));
+        assert_eq!(rtt.latest(), Some(Duration::from_millis(800)));
+        // SRTT = 7/8 * 400 + 1/8 * 800, RTTVAR = 3/4 * 200 + 1/4 * 400
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(450)));
+        assert_eq!(rtt.rttvar(), Duration::from_millis(250));
+        assert_eq!(rtt.rto(), Duration::from_millis(1450));
+    }
+
+    #[test]
+    fn rto_is_clamped_to_min_and_max() {
+        let mut rtt = RttEstimator::default();
+        rtt.on_sample(Duration::from_micros(100));
+        assert_eq!(rtt.rto(), MIN_RTO);
+
+        rtt.on_sample(Duration::from_secs(100));
+        assert_eq!(rtt.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn timeout_backs_off_until_next_sample() {
+        let mut rtt = RttEstimator::default();
+        rtt.on_timeout();
+        rtt.on_timeout();
+        assert_eq!(rtt.rto(), INITIAL_RTO * 4);
+        for _ in 0..10 {
+            rtt.on_timeout();
+        }
+        assert_eq!(rtt.rto(), MAX_RTO);
+
+        rtt.on_sample(Duration::from_millis(10));
+        assert_eq!(rtt.rto(), MIN_RTO);
+    }
+
+    #[test]
+    fn backoff_is_capped_and_reset_by_new_ack() {
+        let mut rtt = RttEstimator::default();
+        rtt.set_max_rto(Duration::from_secs(3));
+        rtt.on_timeout();
+        assert_eq!(rtt.rto(), INITIAL_RTO * 2);
+        rtt.on_timeout();
+        assert_eq!(rtt.rto(), Duration::from_secs(3));
+        for _ in 0..100 {
+            rtt.on_timeout();
+        }
+        assert_eq!(rtt.rto(), Duration::from_secs(3));
+
+        rtt.reset_backoff();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+    }
+}
@@ -0,0 +1,141 @@
+// inetdの古典的なテスト用サービス(echo: RFC 862, discard: RFC 863, chargen: RFC 864)
+// ncやtelnetなど実際のクライアントとの相互接続試験や長時間の負荷試験に使う
+
+use anyhow::Result;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::thread;
+
+use crate::socket::{SockID, TcpStatus};
+use crate::tcp::TCP;
+
+// chargenの1行の文字数(改行を除く)
+const CHARGEN_LINE_LEN: usize = 72;
+// chargenで使う印字可能なASCII文字(' '..='~')の数
+const CHARGEN_CHARS: usize = 95;
+
+/// spawnで提供できるサービス
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Service {
+    /// 受信したデータをそのまま送り返す
+    Echo,
+    /// 受信したデータを捨てる
+    Discard,
+    /// 相手が閉じるまで文字のパターンを送り続ける
+    Chargen,
+}
+
+impl Service {
+    /// 本来のwell-knownポート. 1024未満なので使うには権限が必要
+    pub fn well_known_port(self) -> u16 {
+        match self {
+            Service::Echo => 7,
+            Service::Discard => 9,
+            Service::Chargen => 19,
+        }
+    }
+}
+
+/// addr:portでlistenし, 別スレッドでacceptしてserviceを提供する. リスニングソケットのIDを返す
+/// リスニングソケットをcloseするとacceptがエラーになり, スレッドも終了する
+pub fn spawn(tcp: &Arc<TCP>, service: Service, addr: Ipv4Addr, port: u16) -> Result<SockID> {
+    let listening_socket = tcp.listen(addr, port)?;
+
+    let tcp = tcp.clone();
+    thread::spawn(move || {
+        while let Ok(sock_id) = tcp.accept(listening_socket) {
+            let tcp = tcp.clone();
+            thread::spawn(move || {
+                let result = match service {
+                    Service::Echo => echo(&tcp, sock_id),
+                    Service::Discard => discard(&tcp, sock_id),
+                    Service::Chargen => chargen(&tcp, sock_id),
+                };
+                if let Err(error) = result {
+                    dbg!(service, error);
+                }
+            });
+        }
+    });
+
+    Ok(listening_socket)
+}
+
+fn echo(tcp: &TCP, sock_id: SockID) -> Result<()> {
+    let mut buffer = [0; 1024];
+    loop {
+        let nbytes = tcp.recv(sock_id, &mut buffer)?;
+        if nbytes == 0 {
+            return tcp.close(sock_id);
+        }
+        tcp.send(sock_id, &buffer[..nbytes])?;
+    }
+}
+
+fn discard(tcp: &TCP, sock_id: SockID) -> Result<()> {
+    let mut buffer = [0; 1024];
+    while tcp.recv(sock_id, &mut buffer)? > 0 {}
+    tcp.close(sock_id)
+}
+
+fn chargen(tcp: &TCP, sock_id: SockID) -> Result<()> {
+    let mut offset = 0;
+    // 相手からFINを受け取ったら送るのをやめて閉じる
+    while tcp.socket_stats(sock_id)?.status != TcpStatus::CloseWait {
+        tcp.send(sock_id, &chargen_line(offset))?;
+        offset = (offset + 1) % CHARGEN_CHARS;
+    }
+    tcp.close(sock_id)
+}
+
+/// offset文字目から始まる1行. 行毎に1文字ずつずらしていく
+fn chargen_line(offset: usize) -> Vec<u8> {
+    let mut line: Vec<u8> = (0..CHARGEN_LINE_LEN)
+        .map(|i| b' ' + ((offset + i) % CHARGEN_CHARS) as u8)
+        .collect();
+    line.extend_from_slice(b"\r\n");
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, TcpConfig};
+
+    #[test]
+    fn chargen_lines_rotate_through_printable_ascii() {
+        let first = chargen_line(0);
+        assert_eq!(first.len(), CHARGEN_LINE_LEN + 2);
+        assert!(first.starts_with(b" !\"#$%"));
+        assert!(first.ends_with(b"efg\r\n"));
+        assert_eq!(
+            &chargen_line(1)[..CHARGEN_LINE_LEN - 1],
+            &first[1..CHARGEN_LINE_LEN]
+        );
+        // '~'の次は' 'に戻る
+        assert!(chargen_line(CHARGEN_CHARS - 1).starts_with(b"~ !"));
+    }
+
+    #[test]
+    fn echo_sends_back_what_it_receives() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        spawn(&tcp, Service::Echo, Ipv4Addr::LOCALHOST, 40007).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40007).unwrap();
+        tcp.send(client, b"hello, echo").unwrap();
+
+        let mut echoed = Vec::new();
+        let mut buffer = [0; 64];
+        while echoed.len() < 11 {
+            let nbytes = tcp.recv(client, &mut buffer).unwrap();
+            assert!(nbytes > 0);
+            echoed.extend_from_slice(&buffer[..nbytes]);
+        }
+        assert_eq!(echoed, b"hello, echo");
+        // こちらが閉じればechoも閉じ返す
+        tcp.close(client).unwrap();
+    }
+}
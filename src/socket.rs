@@ -1,48 +1,121 @@
 use anyhow::{Context, Ok, Result};
-use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::Packet;
-use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
-use pnet::util;
+use std::cmp;
 use std::collections::VecDeque;
-use std::fmt::Display;
-use std::net::Ipv4Addr;
-use std::time::SystemTime;
+use std::fmt::{self, Display};
+use std::hash::Hash;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use std::vec;
 
-use crate::packet::{TCPPacket, MAX_PACKET_SIZE};
-use crate::tcpflags;
-use crate::tcpflags::get_bit_mask;
+use crate::clock::Clock;
+use crate::config::{BufferWatermarks, Capabilities, IdleTimeout, KeepAlive};
+use crate::device::Device;
+use crate::event::{SocketEvents, SocketNotification};
+use crate::eventlog::{EventLog, LogEvent, SegmentRecord};
+use crate::filter::{SegmentFilter, SegmentInfo};
+use crate::flowcontrol::{
+    DuplicateSegments, OutOfOrderRanges, ReassemblyQueue, RecvAutoTune, RecvWindow, SendWindow,
+    MAX_WINDOW_SCALE,
+};
+use crate::pacing::Pacer;
+use crate::packet::{Ecn, TCPPacket, TcpOption, MAX_SACK_BLOCKS, MAX_SACK_BLOCKS_WITH_TIMESTAMPS};
+use crate::rtt::RttEstimator;
+#[cfg(feature = "stream-hash")]
+use crate::stats::StreamHash;
+use crate::stats::{CloseReason, ListenerStats, MemoryUsage};
+use crate::tcpflags::TcpFlags;
 
-const SOCKET_BUFFER_SIZE: usize = 4380;
+pub const SOCKET_BUFFER_SIZE: usize = 4380;
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
-pub struct SockID {
-    pub local_addr: Ipv4Addr,
-    pub remote_addr: Ipv4Addr,
-    pub local_port: u16,
-    pub remote_port: u16,
+/// 32bitで一周するシーケンス番号
+/// u32のまま<で比べると2^32を跨いだところで大小が逆転するので, 差を符号付きで見て比べる(RFC 1982)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqNum(pub u32);
+
+impl SeqNum {
+    pub fn lt(self, other: SeqNum) -> bool {
+        (self.0.wrapping_sub(other.0) as i32) < 0
+    }
+
+    pub fn leq(self, other: SeqNum) -> bool {
+        self == other || self.lt(other)
+    }
+
+    /// startから始まるsizeの範囲[start, start + size)に含まれるか
+    pub fn in_window(self, start: SeqNum, size: u32) -> bool {
+        self.0.wrapping_sub(start.0) < size
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct SendParam {
-    pub unacked_seq: u32, // 送信後まだackされてないseqの先頭
-    pub next: u32,        // 次の送信
-    pub window: u16,      // 送信ウィンドウサイズ
-    pub initial_seq: u32, // 初期送信sequence、何に使ってるかよく分からない
+/// 接続の片側の端点(アドレスとポート)
+/// スタックはDefaultEndpointとそのAddrで端点とアドレスを受け渡すので, アドレスファミリー(IPv6など)を増やしても
+/// ハンドラや公開APIのシグネチャは書き換えずに済む. IPv4に依存するのはrawソケットとIPヘッダの組み立て(RawDevice),
+/// 疑似ヘッダのチェックサム, reverse-pathフィルタのPrefix, connected_pairのループバックアドレスだけ
+pub trait Endpoint: Copy + Eq + Hash + Ord + fmt::Debug + Display + Send + Sync + 'static {
+    type Addr: Copy + Eq + Hash + fmt::Debug + Display + Send + Sync;
+
+    /// アドレスもポートも未定の端点. リスニングソケットの接続先に使う
+    const UNSPECIFIED: Self;
+
+    fn new(addr: Self::Addr, port: u16) -> Self;
+    fn addr(&self) -> Self::Addr;
+    fn port(&self) -> u16;
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct RecvParam {
-    pub next: u32,        // 次受診するsequence
-    pub window: u16,      // 受診ウィンドウサイズ
-    pub initial_seq: u32, // 初期受診sequence, 何に使ってるかよく分からない
-    pub tail: u32,        // 受診sequenceの最後尾, 何に使ってるかよく分からない
+impl Endpoint for SocketAddrV4 {
+    type Addr = Ipv4Addr;
+
+    const UNSPECIFIED: Self = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+
+    fn new(addr: Ipv4Addr, port: u16) -> Self {
+        SocketAddrV4::new(addr, port)
+    }
+
+    fn addr(&self) -> Ipv4Addr {
+        *self.ip()
+    }
+
+    fn port(&self) -> u16 {
+        SocketAddrV4::port(self)
+    }
+}
+
+/// スタックが使う端点の型. 今はIPv4だけに対応している
+pub type DefaultEndpoint = SocketAddrV4;
+
+/// DefaultEndpointのアドレス. 公開API, Device, ReceivedPacket, SegmentInfoはこの型でアドレスをやり取りする
+pub type Addr = <DefaultEndpoint as Endpoint>::Addr;
+
+/// 接続を識別する自分側と相手側の端点の組. デフォルトはIPv4
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct SockID<E: Endpoint = DefaultEndpoint> {
+    pub local: E,
+    pub remote: E,
+}
+
+impl<E: Endpoint> SockID<E> {
+    pub fn new(local: E, remote: E) -> Self {
+        Self { local, remote }
+    }
+
+    /// localで待ち受けるリスニングソケットのID. 接続先は未定
+    pub fn listening(local: E) -> Self {
+        Self::new(local, E::UNSPECIFIED)
+    }
+}
+
+impl<E: Endpoint> Display for SockID<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.local, self.remote)
+    }
 }
 
 pub struct Socket {
     pub sock_id: SockID,
-    pub send_param: SendParam,
-    pub recv_param: RecvParam,
+    pub send_param: SendWindow,
+    pub recv_param: RecvWindow,
     pub status: TcpStatus,
     pub recv_buffer: Vec<u8>,
 
@@ -55,11 +128,145 @@ pub struct Socket {
 
     // 自分を生成したリスニングソケット, server側の接続済みソケットのみ使用
     pub listening_socket: Option<SockID>,
+    // SYNを受信した時刻, server側の接続済みソケットのみ使用. acceptまでの時間を測るのに使う
+    pub syn_received_at: Option<SystemTime>,
+
+    // リスニングソケットのみ使用. accept_queue_lenはTCP::listener_statsで返す時に埋める
+    pub listener_stats: ListenerStats,
+
+    pub device: Arc<dyn Device>,
+    pub clock: Arc<Clock>,
+
+    // TcpConfig::event_log_dirが指定されている場合のみ使用
+    pub event_log: Option<EventLog>,
+
+    // 接続が終了する理由. ソケットを削除する際に統計情報として記録する
+    pub close_reason: Option<CloseReason>,
+
+    // 最後にセグメントを受信した or データを送信した時刻
+    pub last_activity: SystemTime,
+    pub idle_timeout: Option<IdleTimeout>,
+    // 最後にセグメントを受信した時刻. キープアライブはこれを基準にプローブを送る
+    pub last_received: SystemTime,
+    pub keepalive: Option<KeepAlive>,
+    // 最後に受信してから送った, 応答のないプローブの数
+    pub keepalive_probes_sent: u32,
+    pub user_timeout: Option<Duration>,
+    // 相手からの応答を待ち始めた時刻. 待つものがなくなったらNoneに戻す. ユーザータイムアウトはこれとlast_receivedの遅い方から測る
+    pub stalled_since: Option<SystemTime>,
+    // sendが相手のウィンドウが開くのを待っている間, 次にウィンドウプローブを送る時刻(パーシストタイマー)
+    pub persist_deadline: Option<SystemTime>,
+    // ウィンドウが開くまでに送ったウィンドウプローブの数. 送る度に間隔を倍にする
+    pub window_probes_sent: u32,
+    // TCP::force_closeでFINを送って閉じ始めた場合, FINがackされなくてもこの時刻を過ぎたら削除する
+    // TIME_WAITでは2MSLのタイマーの期限
+    pub closing_deadline: Option<SystemTime>,
+
+    // 送信前に評価するフィルタ. TcpConfig::egress_filterから設定される
+    pub egress_filter: Option<SegmentFilter>,
+
+    // 再送していないセグメントから測ったRTTの最小値
+    pub min_rtt: Option<Duration>,
+    // RTTの推定値と再送タイムアウト
+    pub rtt: RttEstimator,
+    // RTTを測る度にeventsの購読者へ通知する. TCP::set_rtt_notifications
+    pub rtt_notifications: bool,
+    // 再送タイムアウトで輻輳ウィンドウを縮める前のcwndとssthresh. タイムアウトが誤検知だった場合に元に戻す
+    pub cwnd_before_rto: Option<(u32, u32)>,
+    // 送信するセグメントの間隔. TcpConfig::pacing
+    pub pacer: Pacer,
+    // sendが受け付けてまだ送信していないデータ(送信バッファ). ウィンドウが開くのを待っているものと,
+    // Nagleのアルゴリズムで保留しているもの(ackされていないデータが無くなったら送る)がある
+    pub unsent: Vec<u8>,
+    // 送信バッファの大きさ. TcpConfig::send_buffer_size
+    pub send_buffer_size: usize,
+    // close, shutdownでFINを送ることになったが, 送信バッファのデータを送り終えるまで待っている
+    pub fin_queued: bool,
+
+    // 受信バッファにこのバイト数が溜まるまでreadableとみなさない(SO_RCVLOWAT)
+    pub recv_lowat: usize,
+
+    // 送信できる空きがこのバイト数以上になるまでwritableとみなさない(SO_SNDLOWAT)
+    pub send_lowat: usize,
+
+    // ノンブロッキングモード(O_NONBLOCK). send, recv, acceptは待つ代わりにWouldBlockを返す
+    pub nonblocking: bool,
+
+    // recvがデータを, sendが送信バッファの空きを待つ時間の上限(SO_RCVTIMEO/SO_SNDTIMEO). 過ぎるとTimedOutを返す
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+
+    // 送ったSYNとFINがackされたか
+    pub control: ControlSegments,
+
+    // 受信スレッドの1イテレーション中に受け取ったデータに対してまだACKを返していない
+    // イテレーションの最後にまとめて1つのACKを返す
+    pub ack_pending: bool,
+    // 同じくイテレーション中に届いたデータをまだrecvに通知(DataArrived)していない
+    // PSHの立ったセグメントが届いた場合はイテレーションの終わりを待たずにすぐ通知する
+    pub delivery_pending: bool,
+    // 遅延ACKの期限. これを過ぎたら受信スレッドがACKを返す. TcpConfig::delayed_ack
+    pub ack_deadline: Option<SystemTime>,
+    // 前回ACKを返してから届いたフルサイズのセグメントの数. 2つになったら遅延させずに返す
+    pub full_segments_unacked: u8,
+
+    // このソケット宛てのイベント通知. 待機する側はcloneしてからsocketsのロックを外す
+    pub events: Arc<SocketEvents>,
+    // recvを1つずつ順番に処理するためのロック. データを待っている間も持ったままにする
+    pub reader: Arc<Mutex<()>>,
 
-    pub sender: TransportSender,
+    // 受信バッファの使用量の閾値. 跨いだ時にeventsの購読者へ通知する
+    pub recv_watermarks: Option<BufferWatermarks>,
+    // BufferHighWatermarkを通知してからまだBufferLowWatermarkを通知していない
+    pub recv_buffer_above_high: bool,
+
+    // 使うプロトコル拡張. ハンドシェイクが終わるまではSYNに載せるオプション(TcpConfig::capabilities)で,
+    // 相手のSYN(SYN/ACK)を受け取ったら両方が対応しているものだけになる
+    pub capabilities: Capabilities,
+    // こちらが広告するウィンドウを何ビットシフトしているか. 相手がウィンドウスケールに対応していなければ0にする
+    pub recv_wscale: u8,
+    // 相手が広告するウィンドウを何ビットシフトしているか. 相手のSYNのウィンドウスケールオプションの値
+    pub send_wscale: u8,
+    // SYN(SYN/ACK)に載せるTCP Fast Openのクッキー. クライアントは覚えているクッキーか空(要求)を, サーバーは発行したクッキーを載せる
+    pub fast_open_cookie: Option<Vec<u8>>,
+    // 順番が入れ替わって先に届いたデータの範囲. ACKにSACKブロックとして載せる
+    pub out_of_order: OutOfOrderRanges,
+    // 順番が入れ替わって先に届き, 前の穴が埋まるのを待っているデータ
+    pub reassembly: ReassemblyQueue,
+    // 最後に送ったACKで広告した受信ウィンドウの右端(ack + ウィンドウ). 読み出してここから十分に開いたら知らせる
+    pub advertised_edge: u32,
+    // 受信済みのデータの再送. 同じ範囲が繰り返し届く場合はACKを間引く
+    pub duplicates: DuplicateSegments,
+    // 受信バッファの自動調整. TcpConfig::recv_buffer_autotuneがNoneならNone
+    pub autotune: Option<RecvAutoTune>,
+    // 相手のFINのシーケンス番号. FINより前のデータが欠けていても覚えておき, 穴が埋まった時点で受け付ける
+    pub peer_fin_seq: Option<u32>,
+    // 受信した緊急データ. 通常のデータとは別にTCP::recv_urgentで読み出す
+    pub urgent: VecDeque<u8>,
+
+    // CEの印が付いたセグメントを受け取った. 相手からCWRが届くまでACKにECEを立て続ける
+    pub ece_pending: bool,
+    // ECEを受けてcwndを縮めた. 次に送るデータにCWRを立てる
+    pub cwr_pending: bool,
+    // ECEでcwndを縮めた時のSND.NXT. ここまでackされるまではECEを受けても縮め直さない(1RTTに1回)
+    pub ecn_recover: Option<u32>,
+
+    // アプリケーションがcloseした. 以降に届いたデータは読まれないので捨てる
+    pub closed_by_app: bool,
+    // アプリケーションがshutdown(How::Read)した. closeと同じく以降に届いたデータは捨てるが, recvは0を返す
+    pub read_shutdown: bool,
+
+    // 相手から受け取ったtsvalのうち, 次に送るセグメントでエコーする値(TS.Recent)
+    pub ts_recent: u32,
+
+    // sendが受け付けたバイト列とrecvで渡したバイト列のハッシュ
+    #[cfg(feature = "stream-hash")]
+    pub sent_stream: StreamHash,
+    #[cfg(feature = "stream-hash")]
+    pub received_stream: StreamHash,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TcpStatus {
     Listen,
     SynSent,
@@ -67,26 +274,76 @@ pub enum TcpStatus {
     Established,
     FinWait1,
     FinWait2,
+    // FINを送った後, それがackされる前に相手のFINを受け取った(同時クローズ). FINがackされたらTIME_WAITへ進む
+    Closing,
     TimeWait,
     CloseWait,
     LastAck,
 }
 
+/// 送ったSYNとFINの状況
+/// どちらもシーケンス番号を1つ消費するがペイロードを持たないので, 再送キューのエントリの長さからはackされたか分からない
+/// ハンドシェイクと切断の処理はこれを見て判断する
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ControlSegments {
+    /// SYN(SYN/ACK)を最初に送った時刻
+    pub syn_sent_at: Option<SystemTime>,
+    pub syn_acked: bool,
+    /// 送ったFINのシーケンス番号
+    pub fin_seq: Option<u32>,
+    pub fin_acked: bool,
+}
+
+impl ControlSegments {
+    /// SND.UNAがunacked_seqまで進んだ. SYNはISN, FINはfin_seqを超えたらackされている
+    /// 今回新しくackされたもの(SYN, FIN)を返す
+    pub fn on_ack(&mut self, initial_seq: u32, unacked_seq: u32) -> (bool, bool) {
+        let syn_newly_acked = self.syn_sent_at.is_some()
+            && !self.syn_acked
+            && SeqNum(initial_seq).lt(SeqNum(unacked_seq));
+        let fin_newly_acked = !self.fin_acked
+            && self
+                .fin_seq
+                .is_some_and(|fin_seq| SeqNum(fin_seq).lt(SeqNum(unacked_seq)));
+        self.syn_acked |= syn_newly_acked;
+        self.fin_acked |= fin_newly_acked;
+        (syn_newly_acked, fin_newly_acked)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RetransmissionQueueEntry {
     pub packet: TCPPacket,
+    // キューに入れた(最初に送信した)時刻. 再送しても更新しない
+    pub queued_at: SystemTime,
     pub latest_transmission_time: SystemTime,
     pub transmission_count: u8,
+    // 相手からSACKで受信済みと伝えられた. タイムアウトしても再送しない
+    pub sacked: bool,
 }
 
 impl RetransmissionQueueEntry {
-    fn new(packet: TCPPacket) -> Self {
+    pub fn new(packet: TCPPacket, now: SystemTime) -> Self {
         Self {
             packet,
-            latest_transmission_time: SystemTime::now(),
+            queued_at: now,
+            latest_transmission_time: now,
             transmission_count: 1,
+            sacked: false,
         }
     }
+
+    /// セグメントの次のシーケンス番号. SYNとFINの分も含む
+    pub fn end_seq(&self) -> u32 {
+        self.packet
+            .get_seq()
+            .wrapping_add(self.packet.segment_len() as u32)
+    }
+
+    /// 最後までackされたか. 相手が受信ウィンドウに収まる分だけ受け取った場合など, 途中までしかackされていなければ再送が必要
+    pub fn is_acked(&self, unacked_seq: u32) -> bool {
+        SeqNum(self.end_seq()).leq(SeqNum(unacked_seq))
+    }
 }
 
 impl Display for TcpStatus {
@@ -98,6 +355,7 @@ impl Display for TcpStatus {
             TcpStatus::Established => write!(f, "Established"),
             TcpStatus::FinWait1 => write!(f, "FinWait1"),
             TcpStatus::FinWait2 => write!(f, "FinWait2"),
+            TcpStatus::Closing => write!(f, "Closing"),
             TcpStatus::TimeWait => write!(f, "TimeWait"),
             TcpStatus::CloseWait => write!(f, "CloseWait"),
             TcpStatus::LastAck => write!(f, "LastAck"),
@@ -107,71 +365,118 @@ impl Display for TcpStatus {
 
 impl Socket {
     pub fn new(
-        local_addr: Ipv4Addr,
-        remote_addr: Ipv4Addr,
-        local_port: u16,
-        remote_port: u16,
+        device: Arc<dyn Device>,
+        clock: Arc<Clock>,
+        sock_id: SockID,
         status: TcpStatus,
-    ) -> Result<Self> {
-        let (sender, _) = transport::transport_channel(
-            MAX_PACKET_SIZE,
-            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
-        )?;
-
-        let sock_id = SockID {
-            local_addr,
-            remote_addr,
-            local_port,
-            remote_port,
-        };
+    ) -> Self {
+        let now = clock.now();
 
-        Ok(Self {
+        Self {
             sock_id,
-            send_param: SendParam {
-                unacked_seq: 0,
-                initial_seq: 0,
-                next: 0,
-                window: SOCKET_BUFFER_SIZE as u16,
-            },
-            recv_param: RecvParam {
-                initial_seq: 0,
-                next: 0,
-                window: SOCKET_BUFFER_SIZE as u16,
-                tail: 0,
-            },
+            send_param: SendWindow::new(SOCKET_BUFFER_SIZE as u32),
+            recv_param: RecvWindow::new(SOCKET_BUFFER_SIZE as u32),
             status,
             recv_buffer: vec![0; SOCKET_BUFFER_SIZE],
             retransmission_queue: VecDeque::new(),
             connection_queue: VecDeque::new(),
             listening_socket: None,
-            sender,
-        })
+            syn_received_at: None,
+            listener_stats: ListenerStats::default(),
+            device,
+            clock,
+            event_log: None,
+            close_reason: None,
+            last_activity: now,
+            idle_timeout: None,
+            last_received: now,
+            keepalive: None,
+            keepalive_probes_sent: 0,
+            user_timeout: None,
+            stalled_since: None,
+            persist_deadline: None,
+            window_probes_sent: 0,
+            closing_deadline: None,
+            egress_filter: None,
+            min_rtt: None,
+            rtt: RttEstimator::default(),
+            rtt_notifications: false,
+            cwnd_before_rto: None,
+            pacer: Pacer::default(),
+            unsent: Vec::new(),
+            send_buffer_size: SOCKET_BUFFER_SIZE,
+            fin_queued: false,
+            recv_lowat: 1,
+            send_lowat: 1,
+            nonblocking: false,
+            read_timeout: None,
+            write_timeout: None,
+            control: ControlSegments::default(),
+            ack_pending: false,
+            delivery_pending: false,
+            ack_deadline: None,
+            full_segments_unacked: 0,
+            events: Arc::new(SocketEvents::default()),
+            reader: Arc::new(Mutex::new(())),
+            recv_watermarks: None,
+            recv_buffer_above_high: false,
+            capabilities: Capabilities::none(),
+            recv_wscale: 0,
+            send_wscale: 0,
+            fast_open_cookie: None,
+            out_of_order: OutOfOrderRanges::default(),
+            reassembly: ReassemblyQueue::default(),
+            advertised_edge: 0,
+            duplicates: DuplicateSegments::default(),
+            autotune: None,
+            peer_fin_seq: None,
+            urgent: VecDeque::new(),
+            ece_pending: false,
+            cwr_pending: false,
+            ecn_recover: None,
+            closed_by_app: false,
+            read_shutdown: false,
+            ts_recent: 0,
+            #[cfg(feature = "stream-hash")]
+            sent_stream: StreamHash::default(),
+            #[cfg(feature = "stream-hash")]
+            received_stream: StreamHash::default(),
+        }
     }
 
     pub fn send_tcp_packet(
         &mut self,
         sequence: u32,
         ack: u32,
-        flag: u8,
+        flag: TcpFlags,
+        payload: &[u8],
+    ) -> Result<usize> {
+        self.send_tcp_packet_with_urgent(sequence, ack, flag, payload, 0)
+    }
+
+    /// 緊急ポインタを指定してセグメントを送る. URGフラグを立てる場合に使う
+    pub fn send_tcp_packet_with_urgent(
+        &mut self,
+        sequence: u32,
+        ack: u32,
+        flag: TcpFlags,
         payload: &[u8],
+        urgent_pointer: u16,
     ) -> Result<usize> {
-        let mut tcp_packet = TCPPacket::new(payload.len());
-        tcp_packet.set_src(self.sock_id.local_port);
-        tcp_packet.set_dest(self.sock_id.remote_port);
+        let mut tcp_packet = TCPPacket::with_options(&self.options_for(flag), payload.len());
+        tcp_packet.set_src(self.sock_id.local.port());
+        tcp_packet.set_dest(self.sock_id.remote.port());
         tcp_packet.set_seq(sequence);
-        tcp_packet.set_data_offset(5); // 今回はオプションフィールドを使わないため、必然的に固定になる
-        tcp_packet.set_flag(flag);
+        tcp_packet.set_flag(self.ecn_flags(flag, payload.len()));
+        if self.capabilities.ecn && !payload.is_empty() {
+            tcp_packet.set_ecn(Ecn::Ect0);
+        }
+        tcp_packet.set_urgent_pointer(urgent_pointer);
         tcp_packet.set_ack(ack);
-        tcp_packet.set_window_size(self.recv_param.window);
+        let window = self.recv_param.advertised(self.recv_wscale, flag.has_syn());
+        tcp_packet.set_window_size(window);
         tcp_packet.set_payload(payload);
-        tcp_packet.set_checksum(util::ipv4_checksum(
-            &tcp_packet.packet(),
-            8,   // skipword
-            &[], // extra_data
-            &&self.sock_id.local_addr,
-            &&self.sock_id.remote_addr,
-            IpNextHeaderProtocols::Tcp,
-        ));
+        // チェックサムは送信時にデバイスで計算する
 
         dbg!(tcp_packet.get_seq());
         dbg!(tcp_packet.get_ack());
@@ -180,25 +485,407 @@ impl Socket {
         dbg!(self.sock_id);
 
         let sent_size = self
-            .sender
-            .send_to(
-                tcp_packet.clone(),
-                std::net::IpAddr::V4(self.sock_id.remote_addr),
-            )
+            .transmit(&tcp_packet)
             .context(format!("failed to send: \n{:?}", tcp_packet))?;
         dbg!(&tcp_packet);
+        if !payload.is_empty() {
+            self.last_activity = self.clock.now();
+        }
+        // ACKフラグが立っていれば保留中のACKも兼ねられる
+        if flag.has_ack() {
+            self.ack_pending = false;
+            self.ack_deadline = None;
+            self.full_segments_unacked = 0;
+            let shift = if flag.has_syn() { 0 } else { self.recv_wscale };
+            self.advertised_edge = ack.wrapping_add((window as u32) << shift);
+        }
+        self.log_event(LogEvent::SegmentSent(SegmentRecord::from(&tcp_packet)));
+        if flag.has_syn() && self.control.syn_sent_at.is_none() {
+            // 同時オープンでSYNと同じシーケンス番号で送り直すSYN/ACKでは更新しない
+            self.control.syn_sent_at = Some(self.clock.now());
+        }
+        if flag.has_fin() {
+            // FINはペイロードの直後のシーケンス番号を消費する
+            self.control.fin_seq = Some(sequence.wrapping_add(payload.len() as u32));
+        }
 
-        if !payload.is_empty() || tcp_packet.get_flag() & get_bit_mask(tcpflags::ACK) > 0 {
+        // ペイロードかACK以外のフラグ(SYN, FIN)があれば積む
+        // RSTはackされないので再送キューには積まない. ECNのために足したECEとCWRは見ない
+        if (!payload.is_empty() || flag.intersects(!TcpFlags::ACK)) && !flag.has_rst() {
             dbg!("push_back into retransmittion queue");
             dbg!(tcp_packet.get_flag());
             self.retransmission_queue
-                .push_back(RetransmissionQueueEntry::new(tcp_packet));
+                .push_back(RetransmissionQueueEntry::new(tcp_packet, self.clock.now()));
         }
 
         Ok(sent_size)
     }
 
+    /// ECNを使う場合に送信するセグメントに足すフラグ. RFC 3168
+    /// SYNにはECEとCWR, SYN/ACKにはECEを立てて使いたいことを伝える
+    /// 接続中はCEの印が付いたセグメントを受け取ってからCWRが届くまでACKにECEを立て, ECEを受けてcwndを縮めたら次のデータにCWRを立てる
+    fn ecn_flags(&mut self, flag: TcpFlags, payload_len: usize) -> TcpFlags {
+        if !self.capabilities.ecn || flag.has_rst() {
+            return flag;
+        }
+        if flag.has_syn() {
+            return if flag.has_ack() {
+                flag | TcpFlags::ECE
+            } else {
+                flag | TcpFlags::ECE | TcpFlags::CWR
+            };
+        }
+        let mut flag = flag;
+        if flag.has_ack() && self.ece_pending {
+            flag |= TcpFlags::ECE;
+        }
+        if payload_len > 0 && self.cwr_pending {
+            flag |= TcpFlags::CWR;
+            self.cwr_pending = false;
+        }
+        flag
+    }
+
+    /// 送信するセグメントに載せるオプション
+    /// タイムスタンプは全てのセグメントに載せる
+    /// SYNにはSACK-permitted, それ以外のACKには先に届いているデータがあればSACKブロックを載せる
+    fn options_for(&self, flag: TcpFlags) -> Vec<TcpOption> {
+        let mut options = Vec::new();
+        if self.capabilities.timestamps {
+            options.push(TcpOption::Timestamps {
+                tsval: self.timestamp_now(),
+                tsecr: self.ts_recent,
+            });
+        }
+        if flag.has_syn() && self.capabilities.window_scale {
+            options.push(TcpOption::WindowScale(self.recv_wscale));
+        }
+        if let Some(cookie) = self.fast_open_cookie.as_ref().filter(|_| flag.has_syn()) {
+            options.push(TcpOption::FastOpen(cookie.clone()));
+        }
+        if !self.capabilities.sack {
+            return options;
+        }
+        if flag.has_syn() {
+            options.push(TcpOption::SackPermitted);
+        } else if flag.has_ack() && !self.out_of_order.is_empty() {
+            let max = if self.capabilities.timestamps {
+                MAX_SACK_BLOCKS_WITH_TIMESTAMPS
+            } else {
+                MAX_SACK_BLOCKS
+            };
+            options.push(TcpOption::Sack(self.out_of_order.blocks(max)));
+        }
+        options
+    }
+
+    /// 相手がpacketで広告した受信ウィンドウのバイト数. SYNのウィンドウはスケールしない. RFC 7323
+    pub fn peer_window(&self, packet: &TCPPacket) -> u32 {
+        let window = packet.get_window_size() as u32;
+        if packet.get_flag().has_syn() {
+            window
+        } else {
+            window << self.send_wscale
+        }
+    }
+
+    /// タイムスタンプオプションのtsvalに使う時刻(ミリ秒). 1周しても比較はSeqNumと同じく回り込みを考慮する
+    pub fn timestamp_now(&self) -> u32 {
+        self.clock
+            .now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u32
+    }
+
+    /// 相手のSYN(SYN/ACK)に付いていたオプションから, 使うプロトコル拡張を決める
+    pub fn negotiate_options(&mut self, syn: &TCPPacket) {
+        self.capabilities.negotiate(syn);
+        if self.capabilities.window_scale {
+            self.send_wscale = cmp::min(syn.window_scale().unwrap_or(0), MAX_WINDOW_SCALE);
+        } else {
+            self.recv_wscale = 0;
+            self.send_wscale = 0;
+        }
+        if let Some((tsval, _)) = syn.timestamps() {
+            self.ts_recent = tsval;
+        }
+    }
+
+    /// PAWS(RFC 7323). 以前に受信したものより古いtsvalを持つセグメントは, 前の接続や遅れて届いた重複とみなしてfalseを返す
+    /// 受け入れる場合は, 次のACKでエコーするts_recentを更新する
+    pub fn accept_timestamp(&mut self, packet: &TCPPacket) -> bool {
+        if !self.capabilities.timestamps {
+            return true;
+        }
+        let tsval = match packet.timestamps() {
+            Some((tsval, _)) => tsval,
+            None => return true,
+        };
+        if SeqNum(tsval).lt(SeqNum(self.ts_recent)) {
+            return false;
+        }
+        // 先の方のデータのtsvalで更新すると, 穴を埋める再送のtsvalが古いと判定されてしまう
+        if SeqNum(packet.get_seq()).leq(SeqNum(self.recv_param.next)) {
+            self.ts_recent = tsval;
+        }
+        true
+    }
+
+    /// パケットを送信する. egress_filterに落とされた場合は送信せずに0を返す
+    /// 落とされたパケットも経路上でロスしたのと同じように扱うため, 再送キューには積まれる
+    pub fn transmit(&mut self, packet: &TCPPacket) -> Result<usize> {
+        if let Some(filter) = &self.egress_filter {
+            let info = SegmentInfo::outgoing(
+                self.sock_id.local.addr(),
+                self.sock_id.remote.addr(),
+                packet,
+            );
+            if !filter.allows(&info) {
+                dbg!("blocked by egress filter");
+                return Ok(0);
+            }
+        }
+
+        self.device.send(
+            packet,
+            self.sock_id.local.addr(),
+            self.sock_id.remote.addr(),
+        )
+    }
+
     pub fn get_sock_id(&self) -> SockID {
         self.sock_id
     }
+
+    /// 受信バッファに溜まっていてrecvで読み出せるバイト数
+    pub fn readable_bytes(&self) -> usize {
+        self.recv_param.readable(self.recv_buffer.len())
+    }
+
+    /// 順番通りに揃ったデータを受信バッファの読み出せるデータの後ろにコピーし, nextを進める
+    /// dataはRecvWindow::trimで受信ウィンドウに収まるように切り詰めてあること
+    pub fn deliver(&mut self, data: &[u8]) {
+        debug_assert!(data.len() <= self.recv_param.window as usize);
+        let offset = self.readable_bytes();
+        self.recv_buffer[offset..offset + data.len()].copy_from_slice(data);
+        self.recv_param.on_received(data.len());
+    }
+
+    /// 受信バッファの大きさを変える. データを受信する前(ハンドシェイクの前)にだけ呼ぶ
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.recv_buffer = vec![0; size];
+        self.recv_param = RecvWindow::new(size as u32);
+    }
+
+    /// 受信バッファをsizeまで広げ, 広げた分だけ受信ウィンドウを開く. 縮めることはしない
+    /// 読み出せるデータは先頭に詰めてあるので, 後ろに足せばよい
+    pub fn grow_recv_buffer(&mut self, size: usize) {
+        let len = self.recv_buffer.len();
+        if size <= len {
+            return;
+        }
+        self.recv_buffer.resize(size, 0);
+        self.recv_param.window += (size - len) as u32;
+    }
+
+    /// 受信バッファに溜まっているデータを読まずに捨てる
+    pub fn discard_received(&mut self) {
+        let readable = self.readable_bytes();
+        self.recv_buffer.copy_within(readable.., 0);
+        self.recv_param.on_read(readable);
+    }
+
+    /// アプリケーションがもう読まないので, 届いたデータは受信バッファに残さず捨てる
+    pub fn discards_received(&self) -> bool {
+        self.closed_by_app || self.read_shutdown
+    }
+
+    /// RFC 793のセグメント受け入れテスト. セグメントの一部でも受信ウィンドウに入っていれば受け入れる
+    /// セグメントの長さとウィンドウの大きさの組み合わせで4通りに分かれる
+    pub fn is_acceptable(&self, packet: &TCPPacket) -> bool {
+        let rcv_nxt = SeqNum(self.recv_param.next);
+        let rcv_wnd = self.recv_param.window;
+        let seq = packet.get_seq();
+        let seg_len = packet.segment_len() as u32;
+        let in_window = |n: u32| SeqNum(n).in_window(rcv_nxt, rcv_wnd);
+
+        match (seg_len, rcv_wnd) {
+            (0, 0) => seq == rcv_nxt.0,
+            (0, _) => in_window(seq),
+            (_, 0) => false,
+            (_, _) => in_window(seq) || in_window(seq.wrapping_add(seg_len - 1)),
+        }
+    }
+
+    /// 既にFINを送っていて, これ以上データを送れない
+    pub fn is_write_closed(&self) -> bool {
+        matches!(
+            self.status,
+            TcpStatus::FinWait1
+                | TcpStatus::FinWait2
+                | TcpStatus::Closing
+                | TcpStatus::TimeWait
+                | TcpStatus::LastAck
+        )
+    }
+
+    /// 相手のFINまでのデータを全て受信済みで, これ以上データが届かない
+    /// 受信バッファに溜まっている分を読み終えれば, recvは0を返す
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_fin_seq
+            .is_some_and(|fin_seq| fin_seq.wrapping_add(1) == self.recv_param.next)
+    }
+
+    /// recvがブロックせずに返れるか
+    /// 受信バッファにrecv_lowat以上溜まっているか, FINを受信していればreadableとする
+    pub fn is_readable(&self) -> bool {
+        self.readable_bytes() >= self.recv_lowat || self.is_peer_closed()
+    }
+
+    /// 相手からの応答を待っているものがあるか
+    /// ackされていないデータ(SYN, FINを含む), ウィンドウが開くのを待っている送信バッファ, 応答のないキープアライブのプローブ
+    pub fn awaits_peer(&self) -> bool {
+        !self.retransmission_queue.is_empty()
+            || !self.unsent.is_empty()
+            || self.fin_queued
+            || self.keepalive_probes_sent > 0
+    }
+
+    /// sendがブロックせずに受け付けられるバイト数. 送信バッファの空き
+    pub fn writable_bytes(&self) -> usize {
+        self.send_buffer_size.saturating_sub(self.unsent.len())
+    }
+
+    /// sendがブロックせずにsend_lowat以上書き込めるか. send_lowatは送信バッファの大きさで頭打ちにする
+    pub fn is_writable(&self) -> bool {
+        self.writable_bytes() >= cmp::min(self.send_lowat, self.send_buffer_size)
+    }
+
+    /// 再送キューのエントリが全て[unacked_seq, next)に収まっているか確認する. デバッグビルドでのみ検査する
+    /// ackされたエントリを取り除いた後に呼ぶこと
+    pub fn debug_check_retransmission_queue(&self) {
+        let in_flight = self.send_param.in_flight();
+        for item in &self.retransmission_queue {
+            // 途中までackされたセグメントも残るので, 終わりがまだackされていない範囲にあるかを見る
+            let end = item.end_seq();
+            debug_assert!(
+                SeqNum(end).in_window(
+                    SeqNum(self.send_param.unacked_seq.wrapping_add(1)),
+                    in_flight
+                ),
+                "retransmission entry out of range: end={} unacked_seq={} next={} sock_id={:?}",
+                end,
+                self.send_param.unacked_seq,
+                self.send_param.next,
+                self.sock_id
+            );
+        }
+    }
+
+    /// このソケットが保持しているデータのバイト数
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let recv_buffer = self.readable_bytes();
+        let reassembly = self.reassembly.len();
+        let retransmission_queue = self
+            .retransmission_queue
+            .iter()
+            .map(|item| item.packet.packet().len())
+            .sum();
+
+        MemoryUsage {
+            recv_buffer,
+            reassembly,
+            send_buffer: self.unsent.len(),
+            retransmission_queue,
+        }
+    }
+
+    /// 受信バッファの使用量が閾値を跨いでいれば購読者に通知する
+    /// 受信バッファにデータが入った時とrecvで読み出された時に呼ぶ
+    pub fn update_recv_watermark(&mut self) {
+        let watermarks = match self.recv_watermarks {
+            Some(watermarks) => watermarks,
+            None => return,
+        };
+
+        let used = self.readable_bytes();
+        if !self.recv_buffer_above_high && used >= watermarks.high {
+            self.recv_buffer_above_high = true;
+            self.events
+                .notify(SocketNotification::BufferHighWatermark { used });
+        } else if self.recv_buffer_above_high && used <= watermarks.low {
+            self.recv_buffer_above_high = false;
+            self.events
+                .notify(SocketNotification::BufferLowWatermark { used });
+        }
+    }
+
+    /// 状態を遷移させる. イベントログが有効なら遷移を記録する
+    pub fn set_status(&mut self, status: TcpStatus) {
+        self.log_event(LogEvent::StateTransition {
+            from: self.status.to_string(),
+            to: status.to_string(),
+        });
+        self.status = status;
+    }
+
+    pub fn log_event(&mut self, event: LogEvent) {
+        if let Some(event_log) = self.event_log.as_mut() {
+            event_log.record(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seq_num_compares_across_wraparound() {
+        let before = SeqNum(u32::MAX - 10);
+        let after = SeqNum(5);
+        assert!(before.lt(after));
+        assert!(!after.lt(before));
+        assert!(before.leq(before));
+        assert!(!before.lt(before));
+        assert!(SeqNum(1).lt(SeqNum(2)));
+    }
+
+    #[test]
+    fn seq_num_in_window_wraps() {
+        let start = SeqNum(u32::MAX - 10);
+        assert!(start.in_window(start, 100));
+        assert!(SeqNum(5).in_window(start, 100));
+        assert!(!SeqNum(89).in_window(start, 100));
+        assert!(!SeqNum(u32::MAX - 11).in_window(start, 100));
+        assert!(!start.in_window(start, 0));
+    }
+
+    #[test]
+    fn listening_sock_id_has_unspecified_remote() {
+        let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 40000);
+        let sock_id = SockID::listening(local);
+        assert_eq!(sock_id.remote.addr(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(sock_id.remote.port(), 0);
+        assert_eq!(sock_id.to_string(), "127.0.0.1:40000 -> 0.0.0.0:0");
+    }
+
+    #[test]
+    fn control_segments_are_acked_past_their_sequence_number() {
+        let mut control = ControlSegments {
+            syn_sent_at: Some(SystemTime::UNIX_EPOCH),
+            ..ControlSegments::default()
+        };
+        assert_eq!(control.on_ack(u32::MAX, u32::MAX), (false, false));
+        assert_eq!(control.on_ack(u32::MAX, 0), (true, false));
+        assert!(control.syn_acked);
+
+        // データの分だけackが進んでもFINはまだ
+        control.fin_seq = Some(100);
+        assert_eq!(control.on_ack(u32::MAX, 100), (false, false));
+        assert_eq!(control.on_ack(u32::MAX, 101), (false, true));
+        assert_eq!(control.on_ack(u32::MAX, 101), (false, false));
+        assert!(control.fin_acked);
+    }
 }
@@ -0,0 +1,889 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::Clock;
+use crate::config::Capabilities;
+use crate::socket::{SockID, TcpStatus};
+use crate::sync::LockResultExt;
+
+// recently_closedに保持しておく接続数の上限. 古いものから捨てる
+const RECENTLY_CLOSED_CAPACITY: usize = 64;
+// レートの集計に使う1秒単位のバケットの数. 直前の60秒分 + 現在進行中の1秒
+const RATE_BUCKETS: u64 = 61;
+
+/// LatencyHistogramの各バケットの上限(この値未満). 最後のバケットは上限なし
+pub const LATENCY_BUCKET_BOUNDS: [Duration; 5] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+];
+
+/// 接続が終了した理由
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// FINの交換による正常な終了
+    Fin,
+    /// こちらからRSTを送って終了
+    ResetSent,
+    /// 相手からRSTを受け取って終了
+    ResetReceived,
+    /// 再送回数の上限に達したため終了
+    RetransmissionExhausted,
+    /// TIME_WAITの2MSLタイマーが満了して削除された
+    TimeWaitReaped,
+    /// リスニングソケットのclose
+    ListenerClosed,
+    /// 処理中にパニックしたため, その接続だけ中断した
+    InternalError,
+    /// キープアライブのプローブに応答がなかったため終了
+    KeepAliveTimeout,
+    /// 相手からの応答を待ったままユーザータイムアウトを過ぎたため終了
+    UserTimeout,
+}
+
+/// 終了した接続の記録
+#[derive(Clone, Debug)]
+pub struct ClosedConnection {
+    pub sock_id: SockID,
+    pub reason: CloseReason,
+    /// 削除された時点の状態
+    pub final_status: TcpStatus,
+    pub closed_at: SystemTime,
+}
+
+/// スタック全体の統計情報のスナップショット
+#[derive(Clone, Debug, Default)]
+pub struct StackStats {
+    pub fin_closes: u64,
+    pub reset_sent_closes: u64,
+    pub reset_received_closes: u64,
+    pub retransmission_aborts: u64,
+    pub time_wait_reaps: u64,
+    /// 処理中のパニックで中断した接続の数
+    pub internal_error_closes: u64,
+    /// キープアライブのプローブに応答がなく中断した接続の数
+    pub keepalive_timeouts: u64,
+    /// ユーザータイムアウトを過ぎて中断した接続の数
+    pub user_timeouts: u64,
+    /// 後から不要だったと分かった再送タイムアウトの回数
+    pub spurious_rtos: u64,
+    pub handshakes_completed: Rate,
+    pub handshakes_failed: Rate,
+    pub closes: Rate,
+    /// 全ソケットが保持しているバイト数の合計
+    pub memory: MemoryUsage,
+    /// TcpConfig::memory_ceilingを超えていたために拒否した接続の数
+    pub memory_ceiling_rejections: u64,
+    /// 送信元アドレスの検証(TcpConfig::reverse_path)に失敗して破棄したセグメントの数
+    pub reverse_path_drops: u64,
+    /// 古くなりすぎたため再送せずに取り除いた再送キューのエントリの数
+    pub stale_retransmissions: u64,
+    /// 相手がSACKで受信済みと伝えてきたため再送タイムアウトでも再送しなかったセグメントの数
+    pub sack_skipped_retransmissions: u64,
+    /// タイムスタンプが古かったためにPAWSで破棄したセグメントの数
+    pub paws_drops: u64,
+    /// 受信ウィンドウ外のセグメントを破棄し, 現在のRCV.NXTとウィンドウでACKを返した回数
+    pub out_of_window_acks: u64,
+    /// 1回のタイマー処理で再送できる数(TcpConfig::retransmit_budget)を超えたため次に回した再送の数
+    pub deferred_retransmissions: u64,
+    /// 送信したキープアライブのプローブの数
+    pub keepalive_probes: u64,
+    /// 受信済みのデータしか含まず, バッファに触れずにACKだけ返したセグメントの数
+    pub duplicate_segments: u64,
+    /// 相手からECEを受けて, ロスを待たずに輻輳ウィンドウを縮めた回数
+    pub ecn_cwnd_reductions: u64,
+    /// sendが相手のゼロウィンドウで待っている間に, 開いたことを知らせるACKが失われていないか確かめるために送ったプローブの数
+    pub window_probes: u64,
+    /// 同じ範囲の重複セグメントが繰り返し届いたために返さなかったACKの数
+    pub duplicate_acks_suppressed: u64,
+    /// 返したチャレンジACKの数(RFC 5961)
+    pub challenge_acks: u64,
+    /// TcpConfig::challenge_ack_limitを超えたために返さなかったチャレンジACKの数
+    pub challenge_acks_suppressed: u64,
+    /// 読み出して受信ウィンドウが開いたことを知らせるために送ったACKの数
+    pub window_updates: u64,
+    /// 受信バッファの自動調整で受信バッファを広げた回数
+    pub recv_buffers_grown: u64,
+    /// 宛先がブロードキャスト/マルチキャストアドレスだったために拒否したconnectの数
+    pub rejected_connects: u64,
+    /// 送信元か宛先がブロードキャスト/マルチキャストアドレスだったために破棄したセグメントの数
+    pub rejected_segments: u64,
+    pub checksum: ChecksumStats,
+    pub tx_ring: TxRingStats,
+}
+
+/// TXリング(TcpConfig::tx_ring)の送信スレッドの統計
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TxRingStats {
+    /// 送信したセグメントの数
+    pub packets: u64,
+    /// リングからまとめて取り出した回数
+    pub batches: u64,
+    /// 下位のデバイスへの書き込み(システムコール)の回数
+    pub writes: u64,
+    /// リングが一杯だったためにsendが待たされた回数
+    pub full_waits: u64,
+}
+
+impl TxRingStats {
+    /// 書き込み1回あたりのセグメント数
+    pub fn packets_per_write(&self) -> f64 {
+        if self.writes == 0 {
+            return 0.0;
+        }
+        self.packets as f64 / self.writes as f64
+    }
+}
+
+/// チェックサムの計算と検証の回数, かかった時間
+/// TcpConfig::checksum_offloadでオフロードした方向は数えない
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumStats {
+    /// 送信時に計算した回数
+    pub computed: u64,
+    /// 受信時に検証した回数
+    pub verified: u64,
+    /// 検証で不正だったため破棄した数
+    pub failures: u64,
+    /// TcpConfig::verify_encodingで読み直した送信セグメントが, 書いたつもりの内容と食い違っていた数
+    pub encoding_mismatches: u64,
+    /// 計算と検証にかかった時間の合計
+    pub time: Duration,
+}
+
+/// ソケットが保持しているデータのバイト数
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// 受信済みでまだrecvで読まれていないデータ
+    pub recv_buffer: usize,
+    /// 順番が入れ替わって届き, 前のデータを待っているデータ
+    pub reassembly: usize,
+    /// sendに渡されたがまだ送信していないデータ
+    pub send_buffer: usize,
+    /// 送信済みでまだackされていないセグメント
+    pub retransmission_queue: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.recv_buffer + self.reassembly + self.send_buffer + self.retransmission_queue
+    }
+}
+
+impl std::ops::AddAssign for MemoryUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.recv_buffer += other.recv_buffer;
+        self.reassembly += other.reassembly;
+        self.send_buffer += other.send_buffer;
+        self.retransmission_queue += other.retransmission_queue;
+    }
+}
+
+/// ソケット単位の統計情報
+#[derive(Clone, Debug)]
+pub struct SocketStats {
+    pub sock_id: SockID,
+    pub status: TcpStatus,
+    pub memory: MemoryUsage,
+    /// 最後に測ったRTT. まだ測っていなければNone
+    pub latest_rtt: Option<Duration>,
+    /// これまでに測ったRTTの最小値
+    pub min_rtt: Option<Duration>,
+    /// 平滑化したRTT. まだ測っていなければNone
+    pub srtt: Option<Duration>,
+    /// RTTのばらつき(RTTVAR). RFC 6298
+    pub rttvar: Duration,
+    /// 現在の再送タイムアウト
+    pub rto: Duration,
+    /// 輻輳ウィンドウ(バイト)
+    pub cwnd: u32,
+    /// スロースタートの閾値(バイト)
+    pub ssthresh: u32,
+    /// 今の受信バッファの大きさ(バイト). 自動調整で広がる
+    pub recv_buffer_size: usize,
+    /// 届いた受信済みのデータの再送(重複セグメント)の数
+    pub duplicates_received: u64,
+    /// 同じ範囲の重複セグメントが繰り返し届いたために返さなかったACKの数
+    pub duplicate_acks_suppressed: u64,
+    /// sendが受け付けたバイト列
+    #[cfg(feature = "stream-hash")]
+    pub sent_stream: StreamHash,
+    /// recvでアプリケーションに渡したバイト列
+    #[cfg(feature = "stream-hash")]
+    pub received_stream: StreamHash,
+}
+
+/// バイト列の長さとハッシュ(FNV-1a)
+/// 区切り方に関係なく同じバイト列なら同じ値になるので, 送信側と受信側を比べれば破損や並び替え, 重複が分かる
+#[cfg(feature = "stream-hash")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamHash {
+    pub bytes: u64,
+    pub hash: u64,
+}
+
+#[cfg(feature = "stream-hash")]
+impl Default for StreamHash {
+    fn default() -> Self {
+        Self {
+            bytes: 0,
+            hash: 0xcbf29ce484222325,
+        }
+    }
+}
+
+#[cfg(feature = "stream-hash")]
+impl StreamHash {
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+        self.bytes += data.len() as u64;
+    }
+}
+
+/// TCP::connectionsで列挙される接続の情報. TCP::close_matchingのフィルタにも渡される
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub sock_id: SockID,
+    pub status: TcpStatus,
+    /// 最後にセグメントを受信した or データを送信してからの経過時間
+    pub idle: Duration,
+    pub memory: MemoryUsage,
+    /// 使っているプロトコル拡張. ハンドシェイクが終わっていれば相手と交渉した結果になる
+    pub capabilities: Capabilities,
+}
+
+/// リスニングソケット単位の統計情報
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenerStats {
+    pub syns_received: u64,
+    pub handshakes_completed: u64,
+    /// acceptされていない接続がTcpConfig::accept_backlogに達していたために破棄したSYNの数
+    pub queue_overflows: u64,
+    /// ハンドシェイクの最後のACKが届かなかったり, 相手のSYNが再送されてきたりして再送したSYN/ACKの数
+    pub synack_retransmissions: u64,
+    /// SYN/ACKの再送が上限に達して削除した半開きの接続の数
+    pub half_open_timeouts: u64,
+    /// TcpConfig::syn_cookiesでソケットを作らずに返したSYN/ACKの数
+    pub syn_cookies_sent: u64,
+    /// 最後のACKのクッキーを検証できて作った接続の数
+    pub syn_cookies_validated: u64,
+    /// クッキーを検証できずにRSTを返したACKの数. 期限切れか偽造されたもの
+    pub syn_cookies_rejected: u64,
+    /// TCP Fast OpenのクッキーをSYN/ACKで渡した数. クッキーを要求されたか, SYNのクッキーが正しくなかったもの
+    pub fast_open_cookies_sent: u64,
+    /// TCP Fast Openの正しいクッキーが付いていて, SYNのデータを受け取った接続の数
+    pub fast_open_accepted: u64,
+    /// 現在acceptを待っている接続の数
+    pub accept_queue_len: usize,
+    /// SYNを受信してからacceptで取り出されるまでの時間
+    pub accept_latency: LatencyHistogram,
+}
+
+/// 所要時間の分布. counts[i]はLATENCY_BUCKET_BOUNDS[i]未満(かつ1つ前の上限以上)の件数
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub counts: [u64; LATENCY_BUCKET_BOUNDS.len() + 1],
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = LATENCY_BUCKET_BOUNDS
+            .iter()
+            .position(|bound| latency < *bound)
+            .unwrap_or(LATENCY_BUCKET_BOUNDS.len());
+        self.counts[bucket] += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// 直近の一定時間に発生した件数
+/// 現在進行中の1秒は含めず, 直前に完了した1秒(と60秒)で集計する
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rate {
+    pub last_second: u64,
+    pub last_minute: u64,
+}
+
+/// 1秒毎のバケットに件数を数えるリングバッファ
+struct RateWindow {
+    buckets: [u64; RATE_BUCKETS as usize],
+    // 現在進行中のバケットが何秒目か
+    current: u64,
+}
+
+impl RateWindow {
+    fn new() -> Self {
+        Self {
+            buckets: [0; RATE_BUCKETS as usize],
+            current: 0,
+        }
+    }
+
+    fn bucket(&mut self, sec: u64) -> &mut u64 {
+        &mut self.buckets[(sec % RATE_BUCKETS) as usize]
+    }
+
+    /// 経過時間secまでバケットを進める. 飛ばしたバケットは0にする
+    fn advance(&mut self, sec: u64) {
+        if sec <= self.current {
+            return;
+        }
+        let from = cmp::max(self.current + 1, sec.saturating_sub(RATE_BUCKETS - 1));
+        for s in from..=sec {
+            *self.bucket(s) = 0;
+        }
+        self.current = sec;
+    }
+
+    fn record(&mut self, sec: u64) {
+        self.advance(sec);
+        *self.bucket(sec) += 1;
+    }
+
+    fn rate(&mut self, sec: u64) -> Rate {
+        self.advance(sec);
+        let mut rate = Rate::default();
+        for back in 1..RATE_BUCKETS {
+            if back > sec {
+                break;
+            }
+            let count = *self.bucket(sec - back);
+            if back == 1 {
+                rate.last_second = count;
+            }
+            rate.last_minute += count;
+        }
+        rate
+    }
+}
+
+// 決定的モードでもadvance_timeに合わせてバケットが進むように, スタックの時計で経過時間を測る
+struct ConnectionRates {
+    clock: Arc<Clock>,
+    start: SystemTime,
+    handshakes_completed: RateWindow,
+    handshakes_failed: RateWindow,
+    closes: RateWindow,
+}
+
+impl ConnectionRates {
+    fn new(clock: Arc<Clock>) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            handshakes_completed: RateWindow::new(),
+            handshakes_failed: RateWindow::new(),
+            closes: RateWindow::new(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.since(self.start).as_secs()
+    }
+}
+
+/// TCP内部で保持しているカウンタ
+/// sockets以外のロックを取らずに更新できるようにAtomicで持つ. レートだけはバケットをまとめて更新するためMutexで守る
+pub struct StackCounters {
+    fin_closes: AtomicU64,
+    reset_sent_closes: AtomicU64,
+    reset_received_closes: AtomicU64,
+    retransmission_aborts: AtomicU64,
+    time_wait_reaps: AtomicU64,
+    internal_error_closes: AtomicU64,
+    keepalive_timeouts: AtomicU64,
+    user_timeouts: AtomicU64,
+    spurious_rtos: AtomicU64,
+    memory_ceiling_rejections: AtomicU64,
+    reverse_path_drops: AtomicU64,
+    stale_retransmissions: AtomicU64,
+    sack_skipped_retransmissions: AtomicU64,
+    paws_drops: AtomicU64,
+    out_of_window_acks: AtomicU64,
+    deferred_retransmissions: AtomicU64,
+    keepalive_probes: AtomicU64,
+    duplicate_segments: AtomicU64,
+    ecn_cwnd_reductions: AtomicU64,
+    window_probes: AtomicU64,
+    duplicate_acks_suppressed: AtomicU64,
+    challenge_acks: AtomicU64,
+    challenge_acks_suppressed: AtomicU64,
+    window_updates: AtomicU64,
+    recv_buffers_grown: AtomicU64,
+    rejected_connects: AtomicU64,
+    rejected_segments: AtomicU64,
+    rates: Mutex<ConnectionRates>,
+}
+
+impl StackCounters {
+    pub fn new(clock: Arc<Clock>) -> Self {
+        Self {
+            fin_closes: AtomicU64::new(0),
+            reset_sent_closes: AtomicU64::new(0),
+            reset_received_closes: AtomicU64::new(0),
+            retransmission_aborts: AtomicU64::new(0),
+            time_wait_reaps: AtomicU64::new(0),
+            internal_error_closes: AtomicU64::new(0),
+            keepalive_timeouts: AtomicU64::new(0),
+            user_timeouts: AtomicU64::new(0),
+            spurious_rtos: AtomicU64::new(0),
+            memory_ceiling_rejections: AtomicU64::new(0),
+            reverse_path_drops: AtomicU64::new(0),
+            stale_retransmissions: AtomicU64::new(0),
+            sack_skipped_retransmissions: AtomicU64::new(0),
+            paws_drops: AtomicU64::new(0),
+            out_of_window_acks: AtomicU64::new(0),
+            deferred_retransmissions: AtomicU64::new(0),
+            keepalive_probes: AtomicU64::new(0),
+            duplicate_segments: AtomicU64::new(0),
+            ecn_cwnd_reductions: AtomicU64::new(0),
+            window_probes: AtomicU64::new(0),
+            duplicate_acks_suppressed: AtomicU64::new(0),
+            challenge_acks: AtomicU64::new(0),
+            challenge_acks_suppressed: AtomicU64::new(0),
+            window_updates: AtomicU64::new(0),
+            recv_buffers_grown: AtomicU64::new(0),
+            rejected_connects: AtomicU64::new(0),
+            rejected_segments: AtomicU64::new(0),
+            rates: Mutex::new(ConnectionRates::new(clock)),
+        }
+    }
+
+    pub fn record_handshake_completed(&self) {
+        let mut rates = self.rates.lock().recover();
+        let now = rates.now();
+        rates.handshakes_completed.record(now);
+    }
+
+    pub fn record_handshake_failed(&self) {
+        let mut rates = self.rates.lock().recover();
+        let now = rates.now();
+        rates.handshakes_failed.record(now);
+    }
+
+    pub fn record_spurious_rto(&self) {
+        self.spurious_rtos.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_memory_ceiling_rejection(&self) {
+        self.memory_ceiling_rejections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reverse_path_drop(&self) {
+        self.reverse_path_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stale_retransmission(&self) {
+        self.stale_retransmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sack_skipped_retransmission(&self) {
+        self.sack_skipped_retransmissions
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_paws_drop(&self) {
+        self.paws_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_out_of_window_ack(&self) {
+        self.out_of_window_acks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_deferred_retransmission(&self) {
+        self.deferred_retransmissions
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_keepalive_probe(&self) {
+        self.keepalive_probes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_duplicate_segment(&self) {
+        self.duplicate_segments.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_ecn_cwnd_reduction(&self) {
+        self.ecn_cwnd_reductions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_window_probe(&self) {
+        self.window_probes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_duplicate_ack_suppressed(&self) {
+        self.duplicate_acks_suppressed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_challenge_ack(&self) {
+        self.challenge_acks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_challenge_ack_suppressed(&self) {
+        self.challenge_acks_suppressed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_window_update(&self) {
+        self.window_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_recv_buffer_grown(&self) {
+        self.recv_buffers_grown.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_connect(&self) {
+        self.rejected_connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_segment(&self) {
+        self.rejected_segments.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_close(&self, reason: CloseReason) {
+        if reason != CloseReason::ListenerClosed {
+            let mut rates = self.rates.lock().recover();
+            let now = rates.now();
+            rates.closes.record(now);
+        }
+
+        let counter = match reason {
+            CloseReason::Fin => &self.fin_closes,
+            CloseReason::ResetSent => &self.reset_sent_closes,
+            CloseReason::ResetReceived => &self.reset_received_closes,
+            CloseReason::RetransmissionExhausted => &self.retransmission_aborts,
+            CloseReason::TimeWaitReaped => &self.time_wait_reaps,
+            CloseReason::InternalError => &self.internal_error_closes,
+            CloseReason::KeepAliveTimeout => &self.keepalive_timeouts,
+            CloseReason::UserTimeout => &self.user_timeouts,
+            CloseReason::ListenerClosed => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// memoryは呼び出し側で全ソケットを集計して渡す
+    pub fn snapshot(&self, memory: MemoryUsage) -> StackStats {
+        let mut rates = self.rates.lock().recover();
+        let now = rates.now();
+        StackStats {
+            handshakes_completed: rates.handshakes_completed.rate(now),
+            handshakes_failed: rates.handshakes_failed.rate(now),
+            closes: rates.closes.rate(now),
+            fin_closes: self.fin_closes.load(Ordering::Relaxed),
+            reset_sent_closes: self.reset_sent_closes.load(Ordering::Relaxed),
+            reset_received_closes: self.reset_received_closes.load(Ordering::Relaxed),
+            retransmission_aborts: self.retransmission_aborts.load(Ordering::Relaxed),
+            time_wait_reaps: self.time_wait_reaps.load(Ordering::Relaxed),
+            internal_error_closes: self.internal_error_closes.load(Ordering::Relaxed),
+            keepalive_timeouts: self.keepalive_timeouts.load(Ordering::Relaxed),
+            user_timeouts: self.user_timeouts.load(Ordering::Relaxed),
+            spurious_rtos: self.spurious_rtos.load(Ordering::Relaxed),
+            memory,
+            memory_ceiling_rejections: self.memory_ceiling_rejections.load(Ordering::Relaxed),
+            reverse_path_drops: self.reverse_path_drops.load(Ordering::Relaxed),
+            stale_retransmissions: self.stale_retransmissions.load(Ordering::Relaxed),
+            sack_skipped_retransmissions: self.sack_skipped_retransmissions.load(Ordering::Relaxed),
+            paws_drops: self.paws_drops.load(Ordering::Relaxed),
+            out_of_window_acks: self.out_of_window_acks.load(Ordering::Relaxed),
+            deferred_retransmissions: self.deferred_retransmissions.load(Ordering::Relaxed),
+            keepalive_probes: self.keepalive_probes.load(Ordering::Relaxed),
+            duplicate_segments: self.duplicate_segments.load(Ordering::Relaxed),
+            ecn_cwnd_reductions: self.ecn_cwnd_reductions.load(Ordering::Relaxed),
+            window_probes: self.window_probes.load(Ordering::Relaxed),
+            duplicate_acks_suppressed: self.duplicate_acks_suppressed.load(Ordering::Relaxed),
+            challenge_acks: self.challenge_acks.load(Ordering::Relaxed),
+            challenge_acks_suppressed: self.challenge_acks_suppressed.load(Ordering::Relaxed),
+            window_updates: self.window_updates.load(Ordering::Relaxed),
+            recv_buffers_grown: self.recv_buffers_grown.load(Ordering::Relaxed),
+            rejected_connects: self.rejected_connects.load(Ordering::Relaxed),
+            rejected_segments: self.rejected_segments.load(Ordering::Relaxed),
+            checksum: ChecksumStats::default(),
+            tx_ring: TxRingStats::default(),
+        }
+    }
+}
+
+/// ChecksumStatsの元になるカウンタ. デバイスから更新されるためTCPとArcで共有する
+#[derive(Default)]
+pub struct ChecksumCounters {
+    computed: AtomicU64,
+    verified: AtomicU64,
+    failures: AtomicU64,
+    encoding_mismatches: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl ChecksumCounters {
+    pub fn record_computed(&self, elapsed: Duration) {
+        self.computed.fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_verified(&self, elapsed: Duration, correct: bool) {
+        self.verified.fetch_add(1, Ordering::Relaxed);
+        if !correct {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_encoding_mismatch(&self) {
+        self.encoding_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ChecksumStats {
+        ChecksumStats {
+            computed: self.computed.load(Ordering::Relaxed),
+            verified: self.verified.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            encoding_mismatches: self.encoding_mismatches.load(Ordering::Relaxed),
+            time: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// TxRingStatsの元になるカウンタ. 送信スレッドから更新される
+#[derive(Default)]
+pub struct TxRingCounters {
+    packets: AtomicU64,
+    batches: AtomicU64,
+    writes: AtomicU64,
+    full_waits: AtomicU64,
+}
+
+impl TxRingCounters {
+    pub fn record_batch(&self, packets: usize, writes: usize) {
+        self.packets.fetch_add(packets as u64, Ordering::Relaxed);
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.writes.fetch_add(writes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_full_wait(&self) {
+        self.full_waits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TxRingStats {
+        TxRingStats {
+            packets: self.packets.load(Ordering::Relaxed),
+            batches: self.batches.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            full_waits: self.full_waits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 起床回数とsocketsのロックの取得回数を数える単位
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    /// 受信スレッドのループ. 何も届かなくても遅延ACKの間隔で起きる
+    Receive,
+    /// 100ms毎に起きる再送タイマー
+    Timer,
+    /// 期限を迎えた時だけ起きるハンドシェイクのタイマー
+    HandshakeTimer,
+    /// send. 送信バッファにコピーするだけだが, 一杯の時は空きを待って起き直す
+    Send,
+    /// 送信バッファのデータを送る送信スレッド. sendされた時とペーシングで決まった時刻にだけ起きる
+    Sender,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 5] = [
+        Subsystem::Receive,
+        Subsystem::Timer,
+        Subsystem::HandshakeTimer,
+        Subsystem::Send,
+        Subsystem::Sender,
+    ];
+}
+
+/// 全く通信していないスタックの起床回数の目標(全サブシステムの合計, 1秒あたり)
+/// タイマーを期限駆動にすれば, 期限の近いソケットがない間はほとんど起きなくて済むはず
+pub const IDLE_WAKEUPS_TARGET: f64 = 1.0;
+
+/// サブシステム1つ分の起床回数とロックの取得回数
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SubsystemActivity {
+    pub subsystem: Subsystem,
+    pub wakeups: u64,
+    pub lock_acquisitions: u64,
+    pub wakeups_per_sec: f64,
+    pub lock_acquisitions_per_sec: f64,
+}
+
+/// TCP::wakeup_auditの結果. スレッドが起きてロックを取るコストを, 何もしていない時も含めて測る
+#[derive(Clone, Debug, PartialEq)]
+pub struct WakeupReport {
+    /// 数え始めてからの時間
+    pub elapsed: Duration,
+    pub subsystems: Vec<SubsystemActivity>,
+}
+
+impl WakeupReport {
+    pub fn get(&self, subsystem: Subsystem) -> Option<&SubsystemActivity> {
+        self.subsystems
+            .iter()
+            .find(|activity| activity.subsystem == subsystem)
+    }
+
+    /// 全サブシステムの合計の起床回数(1秒あたり)
+    pub fn wakeups_per_sec(&self) -> f64 {
+        self.subsystems
+            .iter()
+            .map(|activity| activity.wakeups_per_sec)
+            .sum()
+    }
+
+    /// 何もしていない間に測った場合に, IDLE_WAKEUPS_TARGETを満たしているか
+    pub fn meets_idle_target(&self) -> bool {
+        self.wakeups_per_sec() <= IDLE_WAKEUPS_TARGET
+    }
+}
+
+/// WakeupReportの元になるカウンタ. 無効な場合は何も数えない
+pub struct WakeupCounters {
+    enabled: bool,
+    started_at: Mutex<Instant>,
+    wakeups: [AtomicU64; Subsystem::ALL.len()],
+    lock_acquisitions: [AtomicU64; Subsystem::ALL.len()],
+}
+
+impl WakeupCounters {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            started_at: Mutex::new(Instant::now()),
+            wakeups: Default::default(),
+            lock_acquisitions: Default::default(),
+        }
+    }
+
+    pub fn record_wakeup(&self, subsystem: Subsystem) {
+        if self.enabled {
+            self.wakeups[subsystem as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_lock(&self, subsystem: Subsystem) {
+        if self.enabled {
+            self.lock_acquisitions[subsystem as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 0から数え直す. 通信が終わってから何もしていない間だけを測る場合に使う
+    pub fn reset(&self) {
+        let mut started_at = self.started_at.lock().recover();
+        for counter in self.wakeups.iter().chain(&self.lock_acquisitions) {
+            counter.store(0, Ordering::Relaxed);
+        }
+        *started_at = Instant::now();
+    }
+
+    pub fn report(&self) -> Option<WakeupReport> {
+        if !self.enabled {
+            return None;
+        }
+        let elapsed = self.started_at.lock().recover().elapsed();
+        let per_sec = |count: u64| count as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        let subsystems = Subsystem::ALL
+            .iter()
+            .map(|&subsystem| {
+                let wakeups = self.wakeups[subsystem as usize].load(Ordering::Relaxed);
+                let lock_acquisitions =
+                    self.lock_acquisitions[subsystem as usize].load(Ordering::Relaxed);
+                SubsystemActivity {
+                    subsystem,
+                    wakeups,
+                    lock_acquisitions,
+                    wakeups_per_sec: per_sec(wakeups),
+                    lock_acquisitions_per_sec: per_sec(lock_acquisitions),
+                }
+            })
+            .collect();
+        Some(WakeupReport {
+            elapsed,
+            subsystems,
+        })
+    }
+}
+
+/// 直近に終了した接続を保持するリングバッファ
+#[derive(Default)]
+pub struct RecentlyClosed {
+    entries: VecDeque<ClosedConnection>,
+}
+
+impl RecentlyClosed {
+    pub fn push(&mut self, entry: ClosedConnection) {
+        if self.entries.len() >= RECENTLY_CLOSED_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// 古い順に返す
+    pub fn to_vec(&self) -> Vec<ClosedConnection> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_follow_the_stack_clock() {
+        let clock = Arc::new(Clock::simulated());
+        let counters = StackCounters::new(clock.clone());
+        counters.record_handshake_completed();
+        counters.record_handshake_completed();
+        counters.record_handshake_failed();
+
+        // 進行中の1秒はまだ数えない
+        let stats = counters.snapshot(MemoryUsage::default());
+        assert_eq!(stats.handshakes_completed, Rate::default());
+
+        clock.advance(Duration::from_secs(1));
+        counters.record_handshake_completed();
+        let stats = counters.snapshot(MemoryUsage::default());
+        assert_eq!(
+            stats.handshakes_completed,
+            Rate {
+                last_second: 2,
+                last_minute: 2,
+            }
+        );
+        assert_eq!(stats.handshakes_failed.last_second, 1);
+
+        clock.advance(Duration::from_secs(2));
+        let stats = counters.snapshot(MemoryUsage::default());
+        assert_eq!(
+            stats.handshakes_completed,
+            Rate {
+                last_second: 0,
+                last_minute: 3,
+            }
+        );
+
+        // 60秒経つと直前の1分から外れる
+        clock.advance(Duration::from_secs(60));
+        let stats = counters.snapshot(MemoryUsage::default());
+        assert_eq!(stats.handshakes_completed, Rate::default());
+        assert_eq!(stats.handshakes_failed, Rate::default());
+    }
+}
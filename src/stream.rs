@@ -0,0 +1,405 @@
+use anyhow::Result;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::socket::{Addr, DefaultEndpoint, SockID};
+use crate::tcp::{How, TimedOut, WouldBlock, TCP};
+
+/// 1つの接続をstd::net::TcpStreamと同じように扱うラッパー
+/// Read/Writeを実装しているので, BufReaderやシリアライザなどstd::ioの上に書かれたコードをそのまま載せられる
+/// dropすると接続をcloseする. エラーはanyhow::Errorをio::Errorに包んで返す
+/// ノンブロッキングモードでは, すぐに完了できない読み書きはio::ErrorKind::WouldBlockのエラーになる
+/// タイムアウトを設定した読み書きが時間内に終わらなければio::ErrorKind::TimedOutのエラーになる
+pub struct TcpStream {
+    tcp: Arc<TCP>,
+    sock_id: SockID,
+}
+
+impl TcpStream {
+    /// addr:portに接続する. 確立するまでブロックする
+    pub fn connect(tcp: &Arc<TCP>, addr: Addr, port: u16) -> Result<Self> {
+        let sock_id = tcp.connect(addr, port)?;
+        Ok(Self::from_sock_id(tcp, sock_id))
+    }
+
+    /// リスニングソケットlistenerで接続を1つ受け付ける
+    pub fn accept(tcp: &Arc<TCP>, listener: SockID) -> Result<Self> {
+        let sock_id = tcp.accept(listener)?;
+        Ok(Self::from_sock_id(tcp, sock_id))
+    }
+
+    /// 確立済みの接続を包む. 以降closeはこのTcpStreamに任せる
+    pub fn from_sock_id(tcp: &Arc<TCP>, sock_id: SockID) -> Self {
+        Self {
+            tcp: tcp.clone(),
+            sock_id,
+        }
+    }
+
+    pub fn sock_id(&self) -> SockID {
+        self.sock_id
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::V4(self.sock_id.local))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::V4(self.sock_id.remote))
+    }
+
+    /// 接続の片側または両側を閉じる. TCP::shutdownを参照
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let how = match how {
+            Shutdown::Read => How::Read,
+            Shutdown::Write => How::Write,
+            Shutdown::Both => How::Both,
+        };
+        self.tcp.shutdown(self.sock_id, how).map_err(io_error)
+    }
+
+    /// TCP::set_nonblockingを参照. 書き込みは全て送信バッファに入る時だけ受け付ける
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.tcp
+            .set_nonblocking(self.sock_id, nonblocking)
+            .map_err(io_error)
+    }
+
+    /// TCP::set_read_timeoutを参照
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp
+            .set_read_timeout(self.sock_id, timeout)
+            .map_err(io_error)
+    }
+
+    /// TCP::set_write_timeoutを参照. 時間切れになった書き込みは途中まで送信バッファに入っている
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp
+            .set_write_timeout(self.sock_id, timeout)
+            .map_err(io_error)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.tcp
+            .set_nodelay(self.sock_id, nodelay)
+            .map_err(io_error)
+    }
+}
+
+// std::net::TcpStreamと同じく&TcpStreamでも読み書きできる. recvとsendはスタックの中で直列化される
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.tcp.recv(self.sock_id, buf).map_err(io_error)
+    }
+}
+
+impl Write for &TcpStream {
+    /// 送信バッファに入れた分だけを書き込んだことにする. タイムアウトで途中までしか入らなければその分を返す
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // TCP::sendは空のバッファをエラーにするが, Writeでは何もせずに0を返す
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.tcp.send(self.sock_id, buf).map_err(io_error)
+    }
+
+    /// 送信バッファのデータは送信スレッドが送るので, ここで待つものはない
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        if let Err(error) = self.tcp.close(self.sock_id) {
+            dbg!(error);
+        }
+    }
+}
+
+/// リスニングソケットをstd::net::TcpListenerと同じように扱うラッパー
+/// dropするとリスニングソケットをcloseし, まだacceptされていない接続も中断する
+pub struct TcpListener {
+    tcp: Arc<TCP>,
+    sock_id: SockID,
+}
+
+impl TcpListener {
+    /// addr:portで待ち受ける
+    pub fn bind(tcp: &Arc<TCP>, addr: Addr, port: u16) -> Result<Self> {
+        let sock_id = tcp.listen(addr, port)?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            sock_id,
+        })
+    }
+
+    pub fn sock_id(&self) -> SockID {
+        self.sock_id
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::V4(self.sock_id.local))
+    }
+
+    /// 接続を1つ受け付け, 相手のアドレスと一緒に返す. 確立した接続が来るまでブロックする
+    pub fn accept(&self) -> Result<(TcpStream, DefaultEndpoint)> {
+        let stream = TcpStream::accept(&self.tcp, self.sock_id)?;
+        let peer = stream.sock_id.remote;
+        Ok((stream, peer))
+    }
+
+    /// ノンブロッキングモードでは, acceptを待っている接続がなければWouldBlockのエラーを返す
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.tcp.set_nonblocking(self.sock_id, nonblocking)
+    }
+
+    /// acceptを繰り返すイテレータ. Noneを返すことはない
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        if let Err(error) = self.tcp.close(self.sock_id) {
+            dbg!(error);
+        }
+    }
+}
+
+/// TcpListener::incomingが返すイテレータ
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Result<TcpStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
+
+/// WouldBlockはio::ErrorKind::WouldBlockにして, std::ioのコードがリトライできるようにする
+/// TimedOutはio::ErrorKind::TimedOutにする
+pub(crate) fn io_error(error: anyhow::Error) -> io::Error {
+    if error.is::<WouldBlock>() {
+        io::Error::new(io::ErrorKind::WouldBlock, error)
+    } else if error.is::<TimedOut>() {
+        io::Error::new(io::ErrorKind::TimedOut, error)
+    } else {
+        io::Error::other(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, TcpConfig};
+    use std::io::{BufRead, BufReader};
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    #[test]
+    fn listener_serves_incoming_connections() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let listener = TcpListener::bind(&tcp, Ipv4Addr::LOCALHOST, 40000).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), 40000);
+        let server = thread::spawn(move || {
+            // 届いた順に1行ずつエコーする
+            for stream in listener.incoming().take(3) {
+                let stream = stream.unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                (&stream).write_all(line.as_bytes()).unwrap();
+            }
+        });
+
+        for i in 0..3 {
+            let mut client = TcpStream::connect(&tcp, Ipv4Addr::LOCALHOST, 40000).unwrap();
+            writeln!(client, "hello {}", i).unwrap();
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, format!("hello {}\n", i));
+        }
+        // incomingを抜けてdropされたリスニングソケットは閉じている
+        server.join().unwrap();
+        assert!(TcpStream::connect(&tcp, Ipv4Addr::LOCALHOST, 40000).is_err());
+    }
+
+    #[test]
+    fn accept_returns_the_peer_address() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let listener = TcpListener::bind(&tcp, Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = TcpStream::connect(&tcp, Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let (server, peer) = listener.accept().unwrap();
+        assert_eq!(SocketAddr::V4(peer), client.local_addr().unwrap());
+        assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+
+        // 能動的に閉じる側のcloseは相手のFINを待つので, 先にクライアントから送信側を閉じておく
+        client.shutdown(Shutdown::Write).unwrap();
+        drop(server);
+    }
+
+    #[test]
+    fn nonblocking_read_reports_would_block_to_std_io() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let mut client = TcpStream::from_sock_id(&tcp, client);
+        let mut server = TcpStream::from_sock_id(&tcp, server);
+        server.set_nonblocking(true).unwrap();
+        let mut buffer = [0; 16];
+        let error = server.read(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+        client.write_all(b"hello").unwrap();
+        let nbytes = loop {
+            match server.read(&mut buffer) {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                result => break result.unwrap(),
+            }
+        };
+        assert_eq!(&buffer[..nbytes], b"hello");
+        client.shutdown(Shutdown::Write).unwrap();
+    }
+
+    #[test]
+    fn nonblocking_write_takes_what_fits_in_the_send_buffer() {
+        let config = TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        };
+        let payload: Vec<u8> = (0..4 * config.send_buffer_size)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let tcp = TCP::with_config(config).unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let mut client = TcpStream::from_sock_id(&tcp, client);
+        let server = TcpStream::from_sock_id(&tcp, server);
+        client.set_nonblocking(true).unwrap();
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            (&server).read_to_end(&mut received).unwrap();
+            received
+        });
+
+        // 送信バッファより大きくても, 入る分だけ書き込んで進む
+        let mut written = 0;
+        while written < payload.len() {
+            match client.write(&payload[written..]) {
+                Ok(nbytes) => {
+                    assert!(nbytes > 0);
+                    written += nbytes;
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(error) => panic!("{:?}", error),
+            }
+        }
+        client.shutdown(Shutdown::Write).unwrap();
+        assert!(reader.join().unwrap() == payload);
+    }
+
+    #[test]
+    fn write_timeout_reports_the_bytes_already_queued() {
+        let config = TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        };
+        let payload = vec![7; config.send_buffer_size + 2 * config.recv_buffer_size];
+        let tcp = TCP::with_config(config).unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let mut client = TcpStream::from_sock_id(&tcp, client);
+        let server = TcpStream::from_sock_id(&tcp, server);
+        client
+            .set_write_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        // 相手が読まないので, 送信バッファと相手の受信ウィンドウが埋まったところで時間切れになる
+        let written = client.write(&payload).unwrap();
+        assert!(written > 0 && written < payload.len());
+        let error = client.write(&payload[written..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        // 書き込んだと報告された分だけが, 重複も欠落もなく届く
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            (&server).read_to_end(&mut received).unwrap();
+            received
+        });
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(reader.join().unwrap(), &payload[..written]);
+    }
+
+    #[test]
+    fn std_io_code_runs_on_top_of_the_stream() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let cloned_tcp = tcp.clone();
+        let server = thread::spawn(move || {
+            // 行毎に読み, 行数を返してからdropで閉じる
+            let stream = TcpStream::accept(&cloned_tcp, listener).unwrap();
+            let lines: Vec<String> = BufReader::new(&stream)
+                .lines()
+                .collect::<io::Result<_>>()
+                .unwrap();
+            writeln!(&stream, "{} lines", lines.len()).unwrap();
+            lines
+        });
+
+        let mut client = TcpStream::connect(&tcp, Ipv4Addr::LOCALHOST, 40000).unwrap();
+        assert_eq!(client.peer_addr().unwrap().port(), 40000);
+        for i in 0..100 {
+            writeln!(client, "line {}", i).unwrap();
+        }
+        client.flush().unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "100 lines\n");
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines.len(), 100);
+        assert_eq!(lines[99], "line 99");
+        let sock_id = client.sock_id();
+        drop(client);
+        assert!(tcp.recv(sock_id, &mut [0; 1]).is_err());
+    }
+}
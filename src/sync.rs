@@ -0,0 +1,20 @@
+use std::sync::{LockResult, PoisonError};
+
+// loomのモデルテストではSocketEventsの同期プリミティブをloomのものに差し替え, スレッドの実行順を全て調べる
+#[cfg(all(test, toytcp_loom))]
+pub(crate) use loom::sync::{Condvar, Mutex};
+#[cfg(not(all(test, toytcp_loom)))]
+pub(crate) use std::sync::{Condvar, Mutex};
+
+/// ロックの取得結果から, poisonされていても中身を取り出す
+/// ロックを持ったスレッドがパニックするとpoisonされ, 以降unwrapしている全てのスレッドが連鎖してパニックしてしまう
+/// 壊れている可能性があるのはパニックした処理が触っていたソケットだけなので, それ以外はそのまま使い続ける
+pub trait LockResultExt<T> {
+    fn recover(self) -> T;
+}
+
+impl<T> LockResultExt<T> for LockResult<T> {
+    fn recover(self) -> T {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,164 @@
+use crate::socket::DefaultEndpoint;
+use rand::Rng;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime};
+
+/// クッキーの時刻カウンタが1つ進む間隔
+const COUNTER_INTERVAL: Duration = Duration::from_secs(64);
+/// 発行してからいくつ前のカウンタまでのクッキーを受け付けるか. 64秒から128秒の間有効になる
+const MAX_COUNTER_AGE: u64 = 1;
+
+// ISNの32ビットの内訳. 上から時刻カウンタ5ビット, SACK1ビット, 相手のウィンドウスケール4ビット, ハッシュ22ビット
+const COUNTER_SHIFT: u32 = 27;
+const SACK_BIT: u32 = 1 << 26;
+const WSCALE_SHIFT: u32 = 22;
+const WSCALE_MASK: u32 = 0xf;
+// ウィンドウスケールオプションがなかったことを表す値. シフト数は14までなので使われない
+const NO_WSCALE: u32 = 0xf;
+const HASH_MASK: u32 = (1 << WSCALE_SHIFT) - 1;
+
+/// SYNで交渉したもののうち, 最後のACKからは分からないのでクッキーに埋め込んでおくもの
+/// タイムスタンプは最後のACKにも付いてくるので埋め込まない
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CookieState {
+    pub sack: bool,
+    /// 相手のウィンドウスケールのシフト数. Noneならウィンドウスケールを使わない
+    pub send_wscale: Option<u8>,
+}
+
+/// SYNクッキー. リスニングソケットがSYNを受けてもソケットを作らず, 接続の情報をSYN/ACKのISNに埋め込んで返す
+/// 最後のACKのack-1がクッキーとして正しければ, その時に初めてソケットを作る
+#[derive(Debug)]
+pub struct SynCookies {
+    secret: u64,
+}
+
+impl SynCookies {
+    pub fn new(secret: u64) -> Self {
+        Self { secret }
+    }
+
+    /// localとremoteの接続で, 相手のISNがpeer_isnのSYNに返すSYN/ACKのISN
+    pub fn encode(
+        &self,
+        local: DefaultEndpoint,
+        remote: DefaultEndpoint,
+        peer_isn: u32,
+        state: CookieState,
+        now: SystemTime,
+    ) -> u32 {
+        let counter = counter_at(now);
+        let mut cookie = ((counter as u32) & 0x1f) << COUNTER_SHIFT;
+        if state.sack {
+            cookie |= SACK_BIT;
+        }
+        let wscale = state
+            .send_wscale
+            .map_or(NO_WSCALE, |shift| cmp::min(shift as u32, NO_WSCALE - 1));
+        cookie |= wscale << WSCALE_SHIFT;
+        cookie | self.hash(local, remote, peer_isn, cookie, counter)
+    }
+
+    /// 最後のACKのack-1をクッキーとして検証する. 正しく, 期限も切れていなければ埋め込んでおいた情報を返す
+    pub fn decode(
+        &self,
+        local: DefaultEndpoint,
+        remote: DefaultEndpoint,
+        peer_isn: u32,
+        cookie: u32,
+        now: SystemTime,
+    ) -> Option<CookieState> {
+        let current = counter_at(now);
+        let counter = (0..=MAX_COUNTER_AGE)
+            .filter_map(|age| current.checked_sub(age))
+            .find(|counter| (*counter as u32) & 0x1f == cookie >> COUNTER_SHIFT)?;
+        if cookie & HASH_MASK != self.hash(local, remote, peer_isn, cookie & !HASH_MASK, counter) {
+            return None;
+        }
+        let wscale = (cookie >> WSCALE_SHIFT) & WSCALE_MASK;
+        Some(CookieState {
+            sack: cookie & SACK_BIT > 0,
+            send_wscale: (wscale != NO_WSCALE).then_some(wscale as u8),
+        })
+    }
+
+    // 埋め込んだ情報(header)もハッシュに含め, 書き換えられたら検証に失敗するようにする
+    fn hash(
+        &self,
+        local: DefaultEndpoint,
+        remote: DefaultEndpoint,
+        peer_isn: u32,
+        header: u32,
+        counter: u64,
+    ) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        (local, remote, peer_isn, header, counter, self.secret).hash(&mut hasher);
+        hasher.finish() as u32 & HASH_MASK
+    }
+}
+
+impl Default for SynCookies {
+    fn default() -> Self {
+        Self::new(rand::thread_rng().gen())
+    }
+}
+
+fn counter_at(now: SystemTime) -> u64 {
+    now.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / COUNTER_INTERVAL.as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn cookie_round_trips_until_it_expires() {
+        let cookies = SynCookies::new(1);
+        let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+        let state = CookieState {
+            sack: true,
+            send_wscale: Some(7),
+        };
+        let issued = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let cookie = cookies.encode(local, remote, 1234, state, issued);
+
+        assert_eq!(
+            cookies.decode(local, remote, 1234, cookie, issued),
+            Some(state)
+        );
+        assert_eq!(
+            cookies.decode(local, remote, 1234, cookie, issued + COUNTER_INTERVAL),
+            Some(state)
+        );
+        // 期限切れ, 別の接続, 別のISN, 書き換えられたクッキーは受け付けない
+        let expired = issued + COUNTER_INTERVAL * 2;
+        assert_eq!(cookies.decode(local, remote, 1234, cookie, expired), None);
+        assert_eq!(cookies.decode(remote, local, 1234, cookie, issued), None);
+        assert_eq!(cookies.decode(local, remote, 1235, cookie, issued), None);
+        assert_eq!(
+            cookies.decode(local, remote, 1234, cookie ^ SACK_BIT, issued),
+            None
+        );
+        assert_eq!(
+            SynCookies::new(2).decode(local, remote, 1234, cookie, issued),
+            None
+        );
+
+        let no_options = CookieState {
+            sack: false,
+            send_wscale: None,
+        };
+        let cookie = cookies.encode(local, remote, 1234, no_options, issued);
+        assert_eq!(
+            cookies.decode(local, remote, 1234, cookie, issued),
+            Some(no_options)
+        );
+    }
+}
@@ -1,64 +1,197 @@
 use crate::{
-    packet::TCPPacket,
-    socket::{SockID, Socket, TcpStatus},
-    tcpflags,
+    backlog::{ReceiveBacklog, ReceivedPacket},
+    checksum::ChecksumDevice,
+    clock::Clock,
+    config::{
+        Backend, BufferWatermarks, ConntrackExport, IdleAction, IdleTimeout, KeepAlive, TcpConfig,
+    },
+    conntrack::{self, ConntrackEntry, ConntrackSnapshot},
+    device::{Device, LoopbackDevice, RawDevice},
+    eventlog::{EventLog, LogEvent, SegmentRecord},
+    fastopen::FastOpenCookies,
+    filter::SegmentInfo,
+    flowcontrol::{self, RecvAutoTune, Trimmed, MAX_WINDOW_SCALE},
+    future::{AcceptFuture, ConnectFuture, RecvFuture, SendFuture},
+    isn::IsnGenerator,
+    pacing,
+    packet::{Ecn, TCPPacket, TcpOption},
+    policy::{self, ChallengeAckLimiter, CompliancePolicy, Verdict},
+    poll::{PollEvents, PollFd},
+    portalloc::PORT_RANGE,
+    rtt::INITIAL_RTO,
+    socket::{Endpoint, RetransmissionQueueEntry, SeqNum, Socket, TcpStatus},
+    stats::{
+        ChecksumCounters, CloseReason, ClosedConnection, ConnectionInfo, ListenerStats,
+        MemoryUsage, RecentlyClosed, SocketStats, StackCounters, StackStats, Subsystem,
+        TxRingCounters, WakeupCounters, WakeupReport,
+    },
+    sync::LockResultExt,
+    syncookie::{CookieState, SynCookies},
+    tcpflags::TcpFlags,
+    timers::SocketTimers,
+    txring::TxRingDevice,
 };
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use local_ip_address;
-use pnet::{
-    packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet},
-    transport::{self, TransportChannelType},
-};
-use rand::{rngs::ThreadRng, Rng};
+use pnet::packet::Packet;
 use std::{
     cmp,
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fmt,
     net::{IpAddr, Ipv4Addr},
-    ops::Range,
-    sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard},
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc::Receiver, Arc, Mutex, RwLock, RwLockWriteGuard},
+    task::{self, Poll},
     thread,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 const MAX_TRANSMITTION: u8 = 5;
+const MSL: Duration = Duration::from_secs(30);
 const MSS: usize = 1460;
-const PORT_RANGE: Range<u16> = 40000..60000;
-const RETRANSMITTION_TIMEOUT: u64 = 3;
-const UNDETERMINED_IP_ADDR: std::net::Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
-const UNDETERMINED_PORT: u16 = 0;
-
-#[derive(Clone, Copy, PartialEq, Debug)]
-struct TCPEvent {
-    sock_id: SockID,
-    kind: TCPEventKind,
+const PER_SOCKET_PACKET_BUDGET: usize = 8;
+const RECEIVE_BATCH_SIZE: usize = 64;
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+use crate::event::{Notify, SocketEvents, Waker};
+pub use crate::event::{SocketNotification, TCPEventKind};
+// socketモジュールは非公開なので, 公開APIが受け渡す型だけをここから使えるようにする
+pub use crate::socket::{Addr, DefaultEndpoint, SockID};
+
+/// close_matchingで接続を閉じる方法
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseMode {
+    /// FINを送って通常通り閉じる
+    Graceful,
+    /// RSTを送って即座に破棄する
+    Abort,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum TCPEventKind {
-    ConnectionCompleted,
-    Acked,
-    DataArrived,
-    ConnectionClosed,
+/// shutdownで閉じる向き
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum How {
+    /// 受信側(SHUT_RD)
+    Read,
+    /// 送信側(SHUT_WR)
+    Write,
+    /// 両方(SHUT_RDWR)
+    Both,
 }
 
-pub struct TCP {
-    sockets: RwLock<HashMap<SockID, Socket>>,
-    event_condvar: (Mutex<Option<TCPEvent>>, Condvar),
+/// ノンブロッキングモードのソケットで, 操作がすぐには完了できなかった(EAGAIN/EWOULDBLOCK)
+/// send, recv, acceptが返すエラーはanyhow::Errorからdowncastして取り出せる
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WouldBlock;
+
+impl fmt::Display for WouldBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("operation would block")
+    }
 }
 
-impl TCPEvent {
-    fn new(sock_id: SockID, kind: TCPEventKind) -> Self {
-        Self { sock_id, kind }
+impl std::error::Error for WouldBlock {}
+
+/// set_read_timeout, set_write_timeoutで設定した時間内にrecv, sendが完了しなかった(SO_RCVTIMEO/SO_SNDTIMEO)
+/// WouldBlockと同じくanyhow::Errorからdowncastして取り出せる
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("operation timed out")
     }
 }
 
+impl std::error::Error for TimedOut {}
+
+/// TCPスタック. Arc<TCP>を複数のスレッドで共有して使う
+///
+/// 並行性について
+/// - TCPはSend + Syncで, 全ての公開APIは&selfで呼べる. 別々のソケットへの操作は並行に進む
+/// - 同じソケットでも, 送信するスレッドと受信するスレッドを分けてよい
+/// - 同じソケットへ複数のスレッドからsendすると, 送信バッファの空きを待っている間に他のsendの書き込みが間に入ることがある
+/// - 同じソケットへのrecvはソケット毎のreaderロックで1つずつ処理する. 各recvはストリームの連続した一部を返し,
+///   先にロックを取ったrecvほど前のデータを受け取る. データを待っている間も後続のrecvはロックを待つ
+/// - ソケットの状態は全てsocketsのロックの中で変更する. ブロックするAPIはロックを外してからSocketEventsで待つ
+/// - SocketEventsのイベントは消費されるまで保持されるので, ロックを外してからwaitするまでの間にpublishされても取りこぼさない
+///   起きた後は必ずロックを取り直して状態を確認し直すので, 古いイベントで起こされても問題ない
+pub struct TCP {
+    sockets: RwLock<HashMap<SockID, Socket>>,
+    handshake_timers: SocketTimers,
+    send_timers: SocketTimers,
+    config: TcpConfig,
+    counters: StackCounters,
+    recently_closed: Mutex<RecentlyClosed>,
+    policy: CompliancePolicy,
+    device: Arc<dyn Device>,
+    clock: Arc<Clock>,
+    checksum_counters: Arc<ChecksumCounters>,
+    tx_ring_counters: Arc<TxRingCounters>,
+    wakeups: WakeupCounters,
+    syn_cookies: SynCookies,
+    isns: IsnGenerator,
+    fast_open_cookies: FastOpenCookies,
+    // クライアントとしてサーバーから受け取ったTCP Fast Openのクッキー
+    fast_open_cache: Mutex<HashMap<Addr, Vec<u8>>>,
+    challenge_acks: Mutex<ChallengeAckLimiter>,
+}
+
 impl TCP {
-    pub fn new() -> Arc<Self> {
+    pub fn new() -> Result<Arc<Self>> {
+        Self::with_config(TcpConfig::default())
+    }
+
+    /// rawソケットを開けなければ(root権限がないなど)エラーを返す
+    pub fn with_config(config: TcpConfig) -> Result<Arc<Self>> {
         let sockets = RwLock::new(HashMap::new());
+        let device: Arc<dyn Device> = match config.backend {
+            Backend::Raw => Arc::new(RawDevice::new().context("failed to open raw socket")?),
+            Backend::Loopback => Arc::new(LoopbackDevice::default()),
+        };
+        let checksum_counters = Arc::new(ChecksumCounters::default());
+        let device: Arc<dyn Device> = Arc::new(ChecksumDevice::new(
+            device,
+            config.checksum_offload,
+            config.verify_encoding,
+            checksum_counters.clone(),
+        ));
+        let tx_ring_counters = Arc::new(TxRingCounters::default());
+        let device: Arc<dyn Device> = match config.tx_ring {
+            Some(capacity) if !config.deterministic => Arc::new(TxRingDevice::new(
+                device,
+                capacity,
+                tx_ring_counters.clone(),
+            )),
+            _ => device,
+        };
+        let clock = Arc::new(if config.deterministic {
+            Clock::simulated()
+        } else {
+            Clock::system()
+        });
+        let counters = StackCounters::new(clock.clone());
         let tcp = Arc::new(Self {
             sockets,
-            event_condvar: (Mutex::new(None), Condvar::new()),
+            clock,
+            handshake_timers: SocketTimers::default(),
+            send_timers: SocketTimers::default(),
+            counters,
+            recently_closed: Mutex::new(RecentlyClosed::default()),
+            policy: CompliancePolicy::new(config.compliance),
+            device,
+            checksum_counters,
+            tx_ring_counters,
+            wakeups: WakeupCounters::new(config.wakeup_audit),
+            syn_cookies: SynCookies::default(),
+            isns: IsnGenerator::default(),
+            fast_open_cookies: FastOpenCookies::default(),
+            fast_open_cache: Mutex::new(HashMap::new()),
+            challenge_acks: Mutex::new(ChallengeAckLimiter::new(config.challenge_ack_limit)),
+            config,
         });
+        if tcp.config.deterministic {
+            return Ok(tcp);
+        }
 
         let cloned_tcp = tcp.clone();
         thread::spawn(move || {
@@ -70,47 +203,168 @@ impl TCP {
             cloned_tcp.timer();
         });
 
-        tcp
+        let cloned_tcp = tcp.clone();
+        thread::spawn(move || {
+            cloned_tcp.handshake_timer();
+        });
+
+        let cloned_tcp = tcp.clone();
+        thread::spawn(move || {
+            cloned_tcp.sender();
+        });
+
+        if let Some(export) = tcp.config.conntrack_export.clone() {
+            let cloned_tcp = tcp.clone();
+            thread::spawn(move || {
+                cloned_tcp.conntrack_exporter(export);
+            });
+        }
+
+        Ok(tcp)
     }
 
     /// clientのactive openの最初の挙動
     /// ターゲットに接続し, 接続済みソケットのIDを返す
-    pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<SockID> {
-        let mut rng = rand::thread_rng();
+    pub fn connect(&self, addr: Addr, port: u16) -> Result<SockID> {
+        let local_addr = self.device.source_addr(addr)?;
+        let local_port = self.select_unused_port(local_addr, DefaultEndpoint::new(addr, port))?;
+        self.connect_from(local_port, addr, port)
+    }
+
+    /// connectして, 最初にdataを送る
+    /// TcpConfig::capabilitiesのfast_openを有効にしていて相手のクッキーを持っていれば, TCP Fast OpenでSYNにdataを載せる
+    /// クッキーを持っていないか相手がSYNのデータを受け取らなかった場合は, ハンドシェイクの後に送り直す
+    /// dataは送信バッファの大きさまでしか渡せない
+    pub fn connect_with_data(&self, addr: Addr, port: u16, data: &[u8]) -> Result<SockID> {
+        let local_addr = self.device.source_addr(addr)?;
+        let local_port = self.select_unused_port(local_addr, DefaultEndpoint::new(addr, port))?;
+        self.open(local_port, addr, port, data, true)
+    }
+
+    /// ローカルポートを指定してconnectする
+    /// 互いのポートを指定して同時にconnectし合うと, 同時オープン(simultaneous open)で接続できる
+    pub fn connect_from(&self, local_port: u16, addr: Addr, port: u16) -> Result<SockID> {
+        self.open(local_port, addr, port, &[], true)
+    }
+
+    /// ノンブロッキングモードのソケットでconnectする. SYNを送ったら確立を待たずにSYN_SENTのソケットのIDを返す(EINPROGRESS)
+    /// 確立するまでsendとrecvはWouldBlockを返す. 確立したかはinfoのstatusで分かる
+    pub fn connect_nonblocking(&self, addr: Addr, port: u16) -> Result<SockID> {
+        let sock_id = self.start_connect(addr, port)?;
+        self.set_nonblocking(sock_id, true)?;
+        Ok(sock_id)
+    }
+
+    /// SYNを送り, 確立を待たずにSYN_SENTのソケットのIDを返す
+    pub(crate) fn start_connect(&self, addr: Addr, port: u16) -> Result<SockID> {
+        let local_addr = self.device.source_addr(addr)?;
+        let local_port = self.select_unused_port(local_addr, DefaultEndpoint::new(addr, port))?;
+        self.open(local_port, addr, port, &[], false)
+    }
+
+    /// SYNを送って接続を始め, 確立するまで待つ. dataは確立したら(TCP Fast Openが使えればSYNで)送る
+    /// waitがfalseなら待たずに返る
+    fn open(
+        &self,
+        local_port: u16,
+        addr: Addr,
+        port: u16,
+        data: &[u8],
+        wait: bool,
+    ) -> Result<SockID> {
+        if let Err(error) = policy::check_unicast(addr) {
+            self.counters.record_rejected_connect();
+            return Err(error.into());
+        }
+
+        if self.exceeds_memory_ceiling(&self.sockets.read().recover()) {
+            self.counters.record_memory_ceiling_rejection();
+            bail!("memory ceiling exceeded");
+        }
+
         let mut socket = Socket::new(
-            get_source_ipv4_addr()?,
-            addr,
-            self.select_unused_port(&mut rng)?,
-            port,
+            self.device.clone(),
+            self.clock.clone(),
+            SockID::new(
+                DefaultEndpoint::new(self.device.source_addr(addr)?, local_port),
+                DefaultEndpoint::new(addr, port),
+            ),
             TcpStatus::SynSent,
-        )?;
-        socket.send_param.initial_seq = rng.gen_range(1..1 << 31);
-        socket.send_tcp_packet(socket.send_param.initial_seq, 0, tcpflags::SYN, &[])?;
+        );
+        self.prepare_socket(&mut socket)?;
+        socket.idle_timeout = self.config.idle_timeout;
+        socket.keepalive = self.config.keepalive;
+        socket.user_timeout = self.config.user_timeout;
+        let sock_id = socket.get_sock_id();
+        // 同じ4タプルへの並行したconnectが両方とも通らないよう, 確認してから登録するまで書き込みロックを持ち続ける
+        let mut sockets = self.sockets.write().recover();
+        let entry = match sockets.entry(sock_id) {
+            Entry::Occupied(_) => bail!("address already in use: {:?}", sock_id),
+            Entry::Vacant(entry) => entry,
+        };
+        if data.len() > socket.send_buffer_size {
+            bail!("initial data does not fit in the send buffer");
+        }
+        socket.send_param.initial_seq = self.initial_seq(sock_id);
+
+        // TCP Fast Open. クッキーを持っていればSYNにデータを載せ, 持っていなければSYNでクッキーを要求する
+        let mut syn_data: &[u8] = &[];
+        if socket.capabilities.fast_open {
+            match self.fast_open_cache.lock().recover().get(&addr) {
+                Some(cookie) => {
+                    if !data.is_empty() {
+                        socket.fast_open_cookie = Some(cookie.clone());
+                        syn_data = &data[..cmp::min(data.len(), MSS)];
+                    }
+                }
+                None => socket.fast_open_cookie = Some(Vec::new()),
+            }
+        }
+        socket.send_tcp_packet(socket.send_param.initial_seq, 0, TcpFlags::SYN, syn_data)?;
         socket.send_param.unacked_seq = socket.send_param.initial_seq;
-        socket.send_param.next = socket.send_param.initial_seq + 1;
+        socket.send_param.next = socket
+            .send_param
+            .initial_seq
+            .wrapping_add(1 + syn_data.len() as u32);
+        // 残りはハンドシェイクが終わってから送る
+        socket.unsent.extend_from_slice(&data[syn_data.len()..]);
 
-        let mut sockets = self.sockets.write().unwrap();
-        let sock_id = socket.get_sock_id();
-        sockets.insert(sock_id, socket);
+        let events = socket.events.clone();
+        let rto = socket.rtt.rto();
+        entry.insert(socket);
+        self.handshake_timers
+            .schedule(sock_id, self.clock.now() + rto);
 
         // sockets.write()でRwLockから得たwrite lockを外している
         drop(sockets);
+        if self.config.deterministic || !wait {
+            // ハンドシェイクは受信スレッド(決定的モードではpoll_receive)で進む
+            return Ok(sock_id);
+        }
         dbg!("wait for the connection completed");
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
+        // SYNの再送がsyn_retriesに達した場合はソケットが削除され, タイムアウトのエラーになる
+        events
+            .wait(TCPEventKind::ConnectionCompleted, None)
+            .context("failed to connect")?;
         dbg!("connection completed");
         Ok(sock_id)
     }
 
     /// リスニングソケットを作成し, そのSockIDを返す
-    pub fn listen(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<SockID> {
-        let socket = Socket::new(
-            local_addr,
-            UNDETERMINED_IP_ADDR, // サーバ側がlistenを開始した時点では接続先IPアドレスは未定
-            local_port,
-            UNDETERMINED_PORT, // サーバ側がlistenを開始した時点では接続先portは未定
+    pub fn listen(&self, local_addr: Addr, local_port: u16) -> Result<SockID> {
+        // 受信バッファの設定が不正なら, SYNが届いてから失敗するのではなくここでエラーにする
+        self.recv_window_scale()?;
+        let mut socket = Socket::new(
+            self.device.clone(),
+            self.clock.clone(),
+            // サーバ側がlistenを開始した時点では接続先は未定
+            SockID::listening(DefaultEndpoint::new(local_addr, local_port)),
             TcpStatus::Listen,
-        )?;
-        let mut sockets = self.sockets.write().unwrap();
+        );
+        socket.idle_timeout = self.config.idle_timeout;
+        socket.keepalive = self.config.keepalive;
+        socket.user_timeout = self.config.user_timeout;
+        let mut sockets = self.sockets.write().recover();
         let sock_id = socket.get_sock_id();
         sockets.insert(sock_id, socket);
 
@@ -121,237 +375,1421 @@ impl TCP {
     }
 
     /// 接続済みソケットが生成されるまで待機し, 生成されたらそのIDを返す
+    /// リスニングソケットがノンブロッキングモードなら, 待たずにWouldBlockを返す. acceptしたソケットには引き継がない
     pub fn accept(&self, sock_id: SockID) -> Result<SockID> {
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
-        let mut sockets = self.sockets.write().unwrap();
+        self.accept_with(sock_id, false)
+    }
 
-        // キューに詰まったソケットをdeque
-        sockets
-            .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?
-            .connection_queue
-            .pop_front()
-            .context("no connected socket")
+    /// nonblockingならソケットのモードに関わらず待たない
+    fn accept_with(&self, sock_id: SockID, nonblocking: bool) -> Result<SockID> {
+        loop {
+            let mut sockets = self.sockets.write().recover();
+            let socket = sockets
+                .get_mut(&sock_id)
+                .context(format!("no such socket: {:?}", sock_id))?;
+
+            let events = socket.events.clone();
+            let nonblocking = nonblocking || socket.nonblocking;
+            if let Some(connected) = self.pop_accept_queue(&mut sockets, sock_id) {
+                return Ok(connected);
+            }
+            if nonblocking {
+                return Err(WouldBlock.into());
+            }
+
+            drop(sockets);
+            self.wait_event(&events, TCPEventKind::ConnectionCompleted, None)?;
+        }
     }
 
-    /// バッファのデータを送信する. 必要であれば複数のパケットに分割して送信する
-    /// 全て送信したら(まだackされてなくても)リターンする
-    pub fn send(&self, sock_id: SockID, buffer: &[u8]) -> Result<()> {
-        let mut cursor = 0;
+    /// acceptを待っている接続を全て取り出す. 待っている接続がなければ空のVecを返し, ブロックしない
+    pub fn accept_pending(&self, sock_id: SockID) -> Result<Vec<SockID>> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if socket.status != TcpStatus::Listen {
+            bail!("not a listening socket: {:?}", sock_id);
+        }
+
+        let mut accepted = Vec::new();
+        while let Some(connected) = self.pop_accept_queue(&mut sockets, sock_id) {
+            accepted.push(connected);
+        }
+        Ok(accepted)
+    }
+
+    /// acceptを待っている接続の数
+    /// TcpConfig::accept_queue_watermarkを設定すれば, 増えてきた時に購読者へ通知させることもできる
+    pub fn pending_connections(&self, sock_id: SockID) -> Result<usize> {
+        let sockets = self.sockets.read().recover();
+        let socket = sockets
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if socket.status != TcpStatus::Listen {
+            bail!("not a listening socket: {:?}", sock_id);
+        }
+        Ok(socket.connection_queue.len())
+    }
+
+    /// リスニングソケットのキューから接続を1つ取り出す
+    fn pop_accept_queue(
+        &self,
+        sockets: &mut HashMap<SockID, Socket>,
+        sock_id: SockID,
+    ) -> Option<SockID> {
+        let connected = sockets.get_mut(&sock_id)?.connection_queue.pop_front()?;
+
+        // acceptされたらリスニングソケットとは無関係になる. 以降リスニングソケットを閉じても影響を受けない
+        let latency = sockets.get_mut(&connected).and_then(|socket| {
+            socket.listening_socket = None;
+            socket.syn_received_at
+        });
+        let latency = latency.map(|at| self.clock.since(at));
+        if let (Some(latency), Some(listening_socket)) = (latency, sockets.get_mut(&sock_id)) {
+            listening_socket
+                .listener_stats
+                .accept_latency
+                .record(latency);
+        }
+        Some(connected)
+    }
+
+    /// 互いに接続済みのソケットのペアを返す. listen/connect/acceptを書かずにsend/recvを試すためのテスト用
+    /// ループバックバックエンドでのみ使える
+    pub fn connected_pair(&self) -> Result<(SockID, SockID)> {
+        if self.config.backend != Backend::Loopback {
+            bail!("connected_pair requires the loopback backend");
+        }
+
+        let addr = Ipv4Addr::LOCALHOST;
+        let port = self.select_unused_port(addr, DefaultEndpoint::new(addr, 0))?;
+        let listening_socket = self.listen(addr, port)?;
+        let client = self.connect(addr, port)?;
+        if self.config.deterministic {
+            self.poll_receive()?;
+        }
+        let server = self.accept(listening_socket)?;
+        self.close(listening_socket)?;
+        Ok((client, server))
+    }
+
+    /// 届いているセグメントを, 応答によって新しく届いたものも含めて全て処理し, 処理した数を返す
+    /// 決定的モードでのみ使える
+    pub fn poll_receive(&self) -> Result<usize> {
+        if !self.config.deterministic {
+            bail!("poll_receive is only available in deterministic mode");
+        }
+
+        let mut backlog = ReceiveBacklog::new();
+        let mut handled = 0;
+        loop {
+            self.fill_backlog(&mut backlog);
+            if backlog.is_empty() {
+                return Ok(handled);
+            }
+            for received in backlog.take_round(PER_SOCKET_PACKET_BUDGET) {
+                self.handle_packet_isolated(received);
+                handled += 1;
+            }
+            self.flush_pending();
+        }
+    }
+
+    /// シミュレーション時計をdurationだけ進め, 期限を迎えたタイマーを処理する
+    /// 再送したセグメントはpoll_receiveを呼ぶまで処理されない. 決定的モードでのみ使える
+    pub fn advance_time(&self, duration: Duration) -> Result<()> {
+        if !self.config.deterministic {
+            bail!("advance_time is only available in deterministic mode");
+        }
+
+        self.clock.advance(duration);
+        let expired = self.handshake_timers.take_expired(self.clock.now());
+        let mut sockets = self.sockets.write().recover();
+        for sock_id in expired {
+            self.retransmit_handshake(&mut sockets, sock_id);
+        }
+        drop(sockets);
+        self.run_timers();
+        // 期限を迎えた遅延ACKを返す
+        self.flush_pending();
+        Ok(())
+    }
 
-        while cursor < buffer.len() {
-            let mut sockets = self.sockets.write().unwrap();
+    /// バッファのデータを送信バッファにコピーし, 送信スレッドに送らせる
+    /// 送信バッファに全て入ったら(まだ送信していなくても)リターンする. 一杯の時は送信スレッドが送って空くまでブロックする
+    /// 決定的モードでは送信スレッドがないので, その場でウィンドウの空きの分だけ送る
+    /// 空のバッファは送るものがないのでエラーにする
+    /// ノンブロッキングモードでは送信バッファに入るだけ入れて返り, 1バイトも入らなければWouldBlockを返す
+    /// 接続が確立するまでもWouldBlockを返す
+    /// 送信バッファに入れたバイト数を返す. set_write_timeoutの時間内に全て入らなければ, それまでに入れた分だけを返す
+    /// 1バイトも入らないまま時間切れになった時だけTimedOutを返す
+    pub fn send(&self, sock_id: SockID, buffer: &[u8]) -> Result<usize> {
+        if buffer.is_empty() {
+            bail!("cannot send an empty buffer");
+        }
 
+        let mut cursor = 0;
+        let mut deadline = None;
+        let mut sockets = self.lock_sockets(Subsystem::Send);
+        loop {
             let mut socket = sockets
                 .get_mut(&sock_id)
                 .context(format!("no such socket: {:?}", sock_id))?;
+            if socket.is_write_closed() || socket.fin_queued {
+                bail!("socket is shut down for writing: {:?}", sock_id);
+            }
+            if socket.nonblocking
+                && (matches!(socket.status, TcpStatus::SynSent | TcpStatus::SynRcvd)
+                    || socket.writable_bytes() == 0)
+            {
+                return Err(WouldBlock.into());
+            }
 
-            let mut send_size = cmp::min(
-                MSS,
-                cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
-            );
-
-            // window sizeが枯渇している場合はACKが来てwindow sizeが更新されるまで待機する
-            while send_size == 0 {
-                dbg!("waiting for the window size updated by ACK");
+            let len = cmp::min(socket.writable_bytes(), buffer.len() - cursor);
+            socket
+                .unsent
+                .extend_from_slice(&buffer[cursor..cursor + len]);
+            cursor += len;
+            self.kick_sender(socket)?;
+            if cursor == buffer.len() || socket.nonblocking {
+                return Ok(cursor);
+            }
 
+            // 送信バッファが一杯なので, 送信スレッドが送って空くのを待つ
+            // 少しだけ空く度に起きて細切れにコピーしないよう, send_lowatまで空くのを待つ
+            let remaining = cmp::min(buffer.len() - cursor, socket.send_buffer_size);
+            let low_watermark = cmp::max(1, cmp::min(socket.send_lowat, remaining));
+            // タイムアウトは空きを待つ度ではなく, 最初に待ち始めてからの時間で測る
+            if deadline.is_none() {
+                deadline = socket
+                    .write_timeout
+                    .map(|timeout| self.clock.now() + timeout);
+            }
+            while socket.writable_bytes() < low_watermark {
+                dbg!("waiting for room in the send buffer");
                 // 待機している間にsocketsのロックを持っていると他スレッドがACKを受信できなくなりデッドロックになってしまう
                 // そのためここでロックを外しておく必要がある
+                let events = socket.events.clone();
                 drop(sockets);
-                self.wait_event(sock_id, TCPEventKind::Acked);
+                // 途中まで入れていれば, 待てなくなった時点でその分を返す. 呼び出し側はその続きから送り直せる
+                if let Err(error) = self.wait_event(&events, TCPEventKind::Acked, deadline) {
+                    if cursor > 0 {
+                        return Ok(cursor);
+                    }
+                    return Err(error);
+                }
+                self.wakeups.record_wakeup(Subsystem::Send);
 
-                sockets = self.sockets.write().unwrap();
+                sockets = self.lock_sockets(Subsystem::Send);
                 socket = sockets
                     .get_mut(&sock_id)
                     .context(format!("no such socket: {:?}", sock_id))?;
+            }
+        }
+    }
 
-                // 新しく更新されたwindow sizeを元にsend_sizeを再計算する
-                send_size = cmp::min(
-                    MSS,
-                    cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
-                );
+    /// 送信バッファにデータが入ったので送信スレッドを起こす. 決定的モードではその場で送る
+    fn kick_sender(&self, socket: &mut Socket) -> Result<()> {
+        if self.config.deterministic {
+            return self.push_unsent(socket, false);
+        }
+        self.send_timers
+            .schedule(socket.get_sock_id(), self.clock.now());
+        Ok(())
+    }
+
+    /// 送信スレッドの関数. sendされたソケットとペーシングの時刻を迎えたソケットの送信バッファを送る
+    fn sender(&self) {
+        dbg!("begin sender thread");
+
+        loop {
+            let expired = self.send_timers.wait_expired(&self.clock);
+            self.wakeups.record_wakeup(Subsystem::Sender);
+            let mut sockets = self.lock_sockets(Subsystem::Sender);
+            for sock_id in expired {
+                if let Some(socket) = sockets.get_mut(&sock_id) {
+                    if let Err(error) = self.push_unsent(socket, false) {
+                        dbg!(error);
+                    }
+                }
+            }
+        }
+    }
+
+    /// 送信バッファのデータを, 相手の受信ウィンドウと輻輳ウィンドウの空きの分だけセグメントに分けて送る
+    /// MSSに満たない残りは, ackされていないデータがある間はNagleのアルゴリズムで保留して次の書き込みとまとめる
+    /// ペーシングで決まった時刻までは待ち, 残りは送信スレッドに任せる. 決定的モードでは時刻が進まないのでペーシングしない
+    /// flushならペーシングもNagleのアルゴリズムも待たずに, ウィンドウの空きの分だけ送る
+    /// 送り終えてFINが待っていればFINも送る. 送った分だけ空いた送信バッファを待っているsendを起こす
+    fn push_unsent(&self, socket: &mut Socket, flush: bool) -> Result<()> {
+        let mut pushed = false;
+        while !socket.unsent.is_empty() {
+            let delay = socket.pacer.delay(self.clock.now());
+            if !flush && !delay.is_zero() && !self.config.deterministic {
+                self.send_timers
+                    .schedule(socket.get_sock_id(), self.clock.now() + delay);
+                break;
+            }
+
+            // FINを待たせているなら, 残りを保留せずに送る
+            if !flush
+                && socket.capabilities.nagle
+                && socket.unsent.len() < MSS
+                && socket.send_param.in_flight() > 0
+                && !socket.fin_queued
+            {
+                break;
+            }
+
+            // ウィンドウが枯渇している場合はACKが来てウィンドウが更新されるまで待つ(process_ackから呼ばれる)
+            // ウィンドウが開いたことを知らせるACKが失われても止まったままにならないよう, パーシストタイマーを動かす
+            let send_size = sendable_size(socket, socket.unsent.len());
+            if send_size == 0 {
+                if socket.persist_deadline.is_none() {
+                    socket.persist_deadline = Some(self.clock.now() + socket.rtt.rto());
+                }
+                break;
+            }
+            socket.persist_deadline = None;
+            socket.window_probes_sent = 0;
+
+            // 送信バッファの最後のセグメントにはPSHを立て, 相手にすぐアプリケーションへ渡してもらう
+            let mut flag = TcpFlags::ACK;
+            if send_size == socket.unsent.len() {
+                flag |= TcpFlags::PSH;
             }
+            // 送信に失敗したらnextも進まないので, 送れた時だけ送信バッファから取り除く
+            let data = socket.unsent[..send_size].to_vec();
+            self.send_data(socket, flag, &data, 0)?;
+            socket.unsent.drain(..send_size);
+            pushed = true;
+        }
 
-            dbg!("current window size", socket.send_param.window);
+        if pushed {
+            // ackが届いたわけではないが, 送信バッファの空きを待っているsendを起こす
+            socket.events.publish(TCPEventKind::Acked);
+        }
+        if socket.fin_queued && socket.unsent.is_empty() {
+            socket.fin_queued = false;
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
-                tcpflags::ACK,
-                &buffer[cursor..cursor + send_size],
+                TcpFlags::FIN | TcpFlags::ACK,
+                &[],
             )?;
+            socket.send_param.next = socket.send_param.next.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    /// FINを送る. 送信バッファにデータが残っていれば, push_unsentが全て送り終えた後で送る
+    fn send_fin(&self, socket: &mut Socket) -> Result<()> {
+        socket.fin_queued = true;
+        self.push_unsent(socket, false)
+    }
 
-            cursor += send_size;
-            socket.send_param.next += send_size as u32;
-            socket.send_param.window -= send_size as u16;
+    /// データを1セグメントで送信し, 送信ウィンドウとペーシングに反映する
+    fn send_data(
+        &self,
+        socket: &mut Socket,
+        flag: TcpFlags,
+        data: &[u8],
+        urgent_pointer: u16,
+    ) -> Result<()> {
+        socket.send_tcp_packet_with_urgent(
+            socket.send_param.next,
+            socket.recv_param.next,
+            flag,
+            data,
+            urgent_pointer,
+        )?;
 
-            // 少しの間ロックを外して待機し, 受信スレッドがACKを受信できるようにしている
-            // send_windowが0になるまで送り続け, 送信がブロックされる確率を下げるため
-            drop(sockets);
-            thread::sleep(Duration::from_millis(1));
+        #[cfg(feature = "stream-hash")]
+        socket.sent_stream.update(data);
+        socket.send_param.on_sent(data.len());
+        let rate = pacing::pacing_rate(
+            self.config.pacing,
+            socket.send_param.cwnd,
+            socket.send_param.ssthresh,
+            socket.rtt.srtt(),
+        );
+        socket.pacer.on_sent(self.clock.now(), data.len(), rate);
+        Ok(())
+    }
+
+    /// 緊急データ(帯域外データ)を送る. 相手は通常のデータとは別にrecv_urgentで読み出す
+    /// URGフラグと緊急ポインタを付けた1つのセグメントで送るので, MSS以下で送信ウィンドウに収まる大きさにする
+    /// 送信バッファのデータは先に送り, 緊急データがその後ろに並ぶようにする. 送り切れなければエラーにする
+    pub fn send_urgent(&self, sock_id: SockID, buffer: &[u8]) -> Result<()> {
+        if buffer.is_empty() || buffer.len() > MSS {
+            bail!("urgent data must be 1 to {} bytes: {}", MSS, buffer.len());
+        }
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if socket.status != TcpStatus::Established && socket.status != TcpStatus::CloseWait {
+            bail!(
+                "cannot send urgent data in {}: {:?}",
+                socket.status,
+                sock_id
+            );
+        }
+
+        self.push_unsent(socket, true)?;
+        if !socket.unsent.is_empty() || sendable_size(socket, buffer.len()) < buffer.len() {
+            bail!("send window is too small for urgent data: {:?}", sock_id);
+        }
+        self.send_data(
+            socket,
+            TcpFlags::ACK | TcpFlags::PSH | TcpFlags::URG,
+            buffer,
+            buffer.len() as u16,
+        )
+    }
+
+    /// 届いている緊急データをbufferに読み込み, 読み込んだサイズを返す. ブロックせず, 緊急データがなければ0を返す
+    /// 緊急データが届いたことはsubscribeの購読者へSocketNotification::UrgentDataで通知される
+    pub fn recv_urgent(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<usize> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        let copy_size = cmp::min(buffer.len(), socket.urgent.len());
+        for (dst, src) in buffer.iter_mut().zip(socket.urgent.drain(..copy_size)) {
+            *dst = src;
         }
+        Ok(copy_size)
+    }
 
+    /// Nagleのアルゴリズムを無効にする(TCP_NODELAY). 小さな書き込みもackを待たずにすぐ送る
+    /// 無効にした時点で保留しているデータはすぐに送る
+    pub fn set_nodelay(&self, sock_id: SockID, nodelay: bool) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.capabilities.nagle = !nodelay;
+        if nodelay {
+            self.push_unsent(socket, false)?;
+        }
         Ok(())
     }
 
     /// データをバッファに読み込んで, 読み込んだサイズを返す. FINを読み込んだ場合は0を返す
     /// パケットが届くまでブロックする
+    /// set_read_timeoutの時間内に届かなければTimedOutを返す. recv_lowatに満たなくても届いていればその分だけ返す
     pub fn recv(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<usize> {
-        let mut sockets = self.sockets.write().unwrap();
+        self.recv_with(sock_id, buffer, false)
+    }
+
+    /// nonblockingならソケットのモードに関わらず待たない
+    fn recv_with(&self, sock_id: SockID, buffer: &mut [u8], nonblocking: bool) -> Result<usize> {
+        // 複数のスレッドからのrecvが待機と読み出しの途中で入れ替わらないよう, ソケット毎に直列化する
+        let reader = self
+            .sockets
+            .read()
+            .recover()
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?
+            .reader
+            .clone();
+        let _reader = reader.lock().recover();
+
+        let mut sockets = self.sockets.write().recover();
         let mut socket = sockets
             .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))
-            .unwrap();
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if socket.closed_by_app {
+            bail!("socket has been closed: {:?}", sock_id);
+        }
+        if socket.read_shutdown {
+            return Ok(0);
+        }
 
         dbg!(socket.recv_buffer.len());
         dbg!(socket.recv_param.window);
         // 受信サイズはbufferサイズのような気もするが、この出し方はちょっとよく分からない
-        let mut received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
+        let mut received_size = socket.readable_bytes();
 
-        while received_size == 0 {
-            // ペイロードを受信 or FINを受信でスキップ
-            match socket.status {
-                TcpStatus::CloseWait | TcpStatus::LastAck | TcpStatus::TimeWait => break,
-                _ => {}
+        // 低水位(recv_lowat)まで溜まるのを待つ. ただし渡されたbufferより多くは待たない
+        let low_watermark = cmp::max(1, cmp::min(socket.recv_lowat, buffer.len()));
+        let deadline = socket
+            .read_timeout
+            .map(|timeout| self.clock.now() + timeout);
+        while received_size < low_watermark {
+            // FINを受信していればこれ以上は届かないので, 溜まっている分だけ返す
+            if socket.is_peer_closed() {
+                break;
+            }
+            // 待っている間に受信側がshutdownされた
+            if socket.read_shutdown {
+                return Ok(0);
+            }
+            if nonblocking || socket.nonblocking {
+                return Err(WouldBlock.into());
             }
 
-            // sendと同じようにwait_eventでブロッキングされるため、ここでsocketsのロックを外しておかないとデッドロックに陥る
+            // sendと同じようにイベントを待ってブロッキングされるため、ここでsocketsのロックを外しておかないとデッドロックに陥る
+            let events = socket.events.clone();
             drop(sockets);
             dbg!("waiting for incoming data...");
-            self.wait_event(sock_id, TCPEventKind::DataArrived);
+            let timed_out = match self.wait_event(&events, TCPEventKind::DataArrived, deadline) {
+                Err(error) if error.is::<TimedOut>() => true,
+                result => result.map(|_| false)?,
+            };
 
-            sockets = self.sockets.write().unwrap();
+            sockets = self.sockets.write().recover();
             socket = sockets
                 .get_mut(&sock_id)
-                .context(format!("no such socket: {:?}", sock_id))
-                .unwrap();
-            received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
+                .context(format!("no such socket: {:?}", sock_id))?;
+            received_size = socket.readable_bytes();
+            if timed_out {
+                if received_size == 0 {
+                    return Err(TimedOut.into());
+                }
+                break;
+            }
         }
         let copy_size = cmp::min(buffer.len(), received_size);
         buffer[..copy_size].copy_from_slice(&socket.recv_buffer[..copy_size]);
         socket.recv_buffer.copy_within(copy_size.., 0);
-        socket.recv_param.window += copy_size as u16;
+        socket.recv_param.on_read(copy_size);
+        self.autotune_recv_buffer(socket, copy_size);
+        self.send_window_update(socket);
+        #[cfg(feature = "stream-hash")]
+        socket.received_stream.update(&buffer[..copy_size]);
+        socket.update_recv_watermark();
 
         Ok(copy_size)
     }
 
-    pub fn close(&self, sock_id: SockID) -> Result<()> {
-        let mut sockets = self.sockets.write().unwrap();
-        let mut socket = sockets
-            .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))
-            .unwrap();
+    /// 受信バッファの自動調整. RTT毎の読み出しが増えていれば受信バッファを広げる
+    /// 広げた分は受信ウィンドウが開くので, 続くsend_window_updateで相手に知らせる
+    /// 相手がウィンドウスケールに対応していなければ, ヘッダのウィンドウフィールドで表せる大きさまでしか広げない
+    fn autotune_recv_buffer(&self, socket: &mut Socket, copied: usize) {
+        let current = socket.recv_buffer.len();
+        let now = self.clock.now();
+        let size = match socket.autotune.as_mut() {
+            Some(autotune) => autotune.on_read(copied, now, socket.rtt.srtt(), current),
+            None => return,
+        };
+        let max_window = (u16::MAX as usize) << socket.recv_wscale;
+        if let Some(size) = size.map(|size| cmp::min(size, max_window)) {
+            if size > current {
+                dbg!(size);
+                socket.grow_recv_buffer(size);
+                self.counters.record_recv_buffer_grown();
+            }
+        }
+    }
 
-        socket.send_tcp_packet(
+    /// 読み出して受信ウィンドウが最後に広告したところから開いたら, 相手の送信が止まったままにならないようすぐに知らせる
+    /// 送るデータや新しいデータへのACKのついでを待っていると, ゼロウィンドウで止まっている相手はパーシストタイマーまで待たされる
+    /// 少し開く度に知らせると相手が細切れに送ってしまうので, 右端がMSSかバッファの半分だけ進んでから知らせる. RFC 1122 4.2.3.3
+    fn send_window_update(&self, socket: &mut Socket) {
+        if !matches!(
+            socket.status,
+            TcpStatus::Established | TcpStatus::FinWait1 | TcpStatus::FinWait2
+        ) {
+            return;
+        }
+        let threshold = cmp::min(MSS, socket.recv_buffer.len() / 2) as i32;
+        let edge = socket
+            .recv_param
+            .next
+            .wrapping_add(socket.recv_param.window);
+        if (edge.wrapping_sub(socket.advertised_edge) as i32) < threshold {
+            return;
+        }
+        self.counters.record_window_update();
+        if let Err(error) = socket.send_tcp_packet(
             socket.send_param.next,
             socket.recv_param.next,
-            tcpflags::FIN | tcpflags::ACK,
+            TcpFlags::ACK,
             &[],
-        )?;
+        ) {
+            dbg!(error);
+        }
+    }
+
+    pub fn close(&self, sock_id: SockID) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+
+        if socket.status == TcpStatus::Listen {
+            // リスニングソケットはどことも繋がっていないのでFINは送らない
+            socket.close_reason = Some(CloseReason::ListenerClosed);
+            self.abort_pending_children(&mut sockets, sock_id);
+            self.remove_socket(&mut sockets, sock_id);
+            return Ok(());
+        }
+
+        if socket.is_write_closed() {
+            // shutdownで既にFINを送っている. 以降はrecvもできなくなるので, 読まれていないデータは捨てる
+            // FIN_WAIT_2で相手のFINがいつまでも届かない場合に備えて, 猶予期間を過ぎたら削除する
+            socket.closed_by_app = true;
+            socket.discard_received();
+            if socket.status == TcpStatus::LastAck && socket.control.fin_acked {
+                // 送ったFINは既にackされていて, CLOSEDになっている
+                self.remove_socket(&mut sockets, sock_id);
+                return Ok(());
+            }
+            if socket.status != TcpStatus::TimeWait && socket.closing_deadline.is_none() {
+                socket.closing_deadline = Some(self.clock.now() + forced_close_grace());
+            }
+            return Ok(());
+        }
 
-        socket.send_param.next += 1;
+        self.send_fin(socket)?;
         match socket.status {
             TcpStatus::Established | TcpStatus::CloseWait => {
                 if socket.status == TcpStatus::Established {
-                    socket.status = TcpStatus::FinWait1;
+                    socket.set_status(TcpStatus::FinWait1);
                 } else if socket.status == TcpStatus::CloseWait {
-                    socket.status = TcpStatus::LastAck;
+                    socket.set_status(TcpStatus::LastAck);
                 }
+                // FINがいつまでもackされない場合はrun_timersで削除する
+                // LAST_ACKでackされた場合は受信した時点で削除される
+                socket.closing_deadline = Some(self.clock.now() + forced_close_grace());
+                if self.config.deterministic {
+                    // 待たずに返る
+                    return Ok(());
+                }
+                let events = socket.events.clone();
                 drop(sockets);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut sockets = self.sockets.write().unwrap();
-                sockets.remove(&sock_id);
+                // 待っている間に他の理由(再送の上限など)で削除されていればそれで閉じ終わっている
+                // ただしRSTで中断された場合はエラーを返す
+                match events.wait(TCPEventKind::ConnectionClosed, None) {
+                    Ok(_) => {
+                        // 能動的に閉じた側はTIME_WAITに残り, 2MSL経ってからタイマースレッドで削除される
+                        let mut sockets = self.sockets.write().recover();
+                        if sockets
+                            .get(&sock_id)
+                            .is_some_and(|socket| socket.status != TcpStatus::TimeWait)
+                        {
+                            self.remove_socket(&mut sockets, sock_id);
+                        }
+                    }
+                    Err(error)
+                        if events.failure()
+                            == Some(TCPEventKind::Reset(CloseReason::ResetReceived)) =>
+                    {
+                        return Err(error)
+                    }
+                    Err(_) => {}
+                }
                 dbg!("closed & removed", sock_id);
             }
-            TcpStatus::Listen => {
-                sockets.remove(&sock_id);
-            }
             _ => return Ok(()),
         }
 
         Ok(())
     }
 
-    fn receive_handler(&self) -> Result<()> {
-        dbg!("begin recv thread");
-        let (_, mut receiver) = transport::transport_channel(
-            655535,
-            // IPアドレスが必要なのでLayer3(Ipパケットレベルで取得する)
-            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
-        )
-        .unwrap();
+    /// 接続の片側または両側を閉じる(shutdown(2)). どちらもすぐに返り, ソケットを削除するには後でcloseを呼ぶ
+    /// Write: FINを送る. 以降sendはエラーになるが, FIN_WAIT_2の間もrecvで相手が送ってくるデータを読み続けられ,
+    ///        相手のFINが届くと0を返す
+    /// Read: 溜まっているデータと以降に届くデータを捨て, recvはすぐに0を返す. 相手には何も知らせない
+    pub fn shutdown(&self, sock_id: SockID, how: How) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if matches!(
+            socket.status,
+            TcpStatus::Listen | TcpStatus::SynSent | TcpStatus::SynRcvd
+        ) {
+            bail!(
+                "cannot shut down a socket in {}: {:?}",
+                socket.status,
+                sock_id
+            );
+        }
 
-        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
-        loop {
-            // packetは相手視点になるため, こちら視点のlocal_addrは相手視点のremote_addrで, こちら視点のremote_addrは相手視点のlocal_addrとなる
-            let (packet, remote_addr) = match packet_iter.next() {
-                Ok((p, r)) => (p, r),
-                Err(_) => continue,
-            };
+        if how != How::Read {
+            self.shutdown_write(socket)?;
+        }
+        if how != How::Write && !socket.read_shutdown {
+            socket.read_shutdown = true;
+            socket.discard_received();
+            self.send_window_update(socket);
+            // recvで待っていれば0を返させる
+            socket.events.publish(TCPEventKind::DataArrived);
+        }
+        Ok(())
+    }
 
-            let local_addr = packet.get_destination();
+    fn shutdown_write(&self, socket: &mut Socket) -> Result<()> {
+        let next_status = match socket.status {
+            TcpStatus::Established => TcpStatus::FinWait1,
+            TcpStatus::CloseWait => TcpStatus::LastAck,
+            status => bail!(
+                "cannot shut down a socket in {}: {:?}",
+                status,
+                socket.get_sock_id()
+            ),
+        };
+        self.send_fin(socket)?;
+        socket.set_status(next_status);
+        Ok(())
+    }
 
-            // pnetのTcpPacket作成
-            let tcp_packet = match TcpPacket::new(packet.payload()) {
-                Some(p) => p,
-                None => continue,
-            };
+    /// 無通信の接続を切断するまでの時間を設定する. Noneなら切断しない
+    /// リスニングソケットに設定した場合はそれ以降にacceptされる接続に引き継がれる
+    pub fn set_idle_timeout(&self, sock_id: SockID, timeout: Option<IdleTimeout>) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.idle_timeout = timeout;
+        Ok(())
+    }
 
-            // pnetのTcpPacketから自前定義のTCPPacketを作成
-            let packet = TCPPacket::from(tcp_packet);
+    /// キープアライブを設定する(SO_KEEPALIVE). Noneならプローブを送らない
+    /// リスニングソケットに設定した場合はそれ以降にacceptされる接続に引き継がれる
+    pub fn set_keepalive(&self, sock_id: SockID, keepalive: Option<KeepAlive>) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.keepalive = keepalive;
+        socket.keepalive_probes_sent = 0;
+        Ok(())
+    }
 
-            let remote_addr = match remote_addr {
-                IpAddr::V4(addr) => addr,
-                _ => continue,
-            };
+    /// ユーザータイムアウトを設定する(TCP_USER_TIMEOUT). Noneなら中断しない
+    /// 相手からの応答を待ったまま何も受信せずにtimeoutが過ぎると接続を中断し, ブロックしているAPIには"connection timed out"が返る
+    /// リスニングソケットに設定した場合はそれ以降にacceptされる接続に引き継がれる
+    pub fn set_user_timeout(&self, sock_id: SockID, timeout: Option<Duration>) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.user_timeout = timeout;
+        Ok(())
+    }
 
-            let mut sockets = self.sockets.write().unwrap();
-            let socket = match sockets.get_mut(&SockID {
-                local_addr,
-                remote_addr,
-                local_port: packet.get_dest(),
-                remote_port: packet.get_src(),
-            }) {
-                // 指定のremote_addr, remote_portでソケットが存在しない場合は新しいコネクションが考えられるため, リスニングソケットを使う
-                Some(socket) => socket,
-                None => match sockets.get_mut(&SockID {
-                    local_addr,
-                    remote_addr: UNDETERMINED_IP_ADDR,
-                    local_port: packet.get_dest(),
-                    remote_port: UNDETERMINED_PORT,
-                }) {
-                    Some(socket) => socket, // リスニングソケット
-                    None => continue,       // どのソケットにも該当しないので無視する
-                },
+    /// ノンブロッキングモード(O_NONBLOCK)にするかどうか. デフォルトはブロッキングモード
+    /// ノンブロッキングモードのsend, recv, acceptは, すぐに完了できなければ待たずにWouldBlockを返す
+    /// アプリケーション自身のイベントループから, 準備ができるまで繰り返し呼ぶ
+    pub fn set_nonblocking(&self, sock_id: SockID, nonblocking: bool) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// recvがデータを待つ時間の上限を設定する(SO_RCVTIMEO). Noneならいつまでも待つ. デフォルトはNone
+    /// 過ぎるとrecvはTimedOutを返す. std::net::TcpStreamと同じくDuration::ZEROはエラーにする
+    /// リスニングソケットに設定した場合はそれ以降にacceptされる接続に引き継がれる
+    pub fn set_read_timeout(&self, sock_id: SockID, timeout: Option<Duration>) -> Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            bail!("cannot set a zero duration timeout");
+        }
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// sendが送信バッファの空きを待つ時間の上限を設定する(SO_SNDTIMEO). Noneならいつまでも待つ. デフォルトはNone
+    /// 過ぎるとsendはTimedOutを返す. Duration::ZEROはエラーにする
+    /// リスニングソケットに設定した場合はそれ以降にacceptされる接続に引き継がれる
+    pub fn set_write_timeout(&self, sock_id: SockID, timeout: Option<Duration>) -> Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            bail!("cannot set a zero duration timeout");
+        }
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.write_timeout = timeout;
+        Ok(())
+    }
+
+    /// recvが返るために必要な受信済みバイト数を設定する(SO_RCVLOWAT). デフォルトは1
+    /// 受信バッファのサイズを超える値は受信バッファのサイズに丸められる
+    pub fn set_recv_lowat(&self, sock_id: SockID, lowat: usize) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.recv_lowat = cmp::max(1, cmp::min(lowat, socket.recv_buffer.len()));
+        Ok(())
+    }
+
+    /// 送信バッファが一杯でsendがブロックした後, 再開するために必要な空きのバイト数を設定する(SO_SNDLOWAT). デフォルトは1
+    /// 送信バッファより大きな値は送信バッファの大きさとして扱う
+    pub fn set_send_lowat(&self, sock_id: SockID, lowat: usize) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.send_lowat = cmp::max(1, lowat);
+        Ok(())
+    }
+
+    /// RTTを測る度にsubscribeの購読者へSocketNotification::RttSampleを届けるかどうか. デフォルトは届けない
+    /// 測った値を使ってアプリケーション側で並列数などを調整したい場合に使う
+    pub fn set_rtt_notifications(&self, sock_id: SockID, enabled: bool) -> Result<()> {
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.rtt_notifications = enabled;
+        Ok(())
+    }
+
+    /// 受信バッファの使用量の閾値を設定する. Noneなら通知しない
+    pub fn set_recv_buffer_watermarks(
+        &self,
+        sock_id: SockID,
+        watermarks: Option<BufferWatermarks>,
+    ) -> Result<()> {
+        if let Some(watermarks) = watermarks {
+            if watermarks.low >= watermarks.high {
+                bail!("low watermark must be lower than high watermark");
+            }
+        }
+
+        let mut sockets = self.sockets.write().recover();
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.recv_watermarks = watermarks;
+        if watermarks.is_none() {
+            socket.recv_buffer_above_high = false;
+        }
+        socket.update_recv_watermark();
+        Ok(())
+    }
+
+    /// ソケットの通知を購読する. ソケットが削除されるとチャンネルは閉じられる
+    pub fn subscribe(&self, sock_id: SockID) -> Result<Receiver<SocketNotification>> {
+        let sockets = self.sockets.read().recover();
+        let socket = sockets
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        Ok(socket.events.subscribe())
+    }
+
+    /// 複数のソケットの状態をまとめて調べる(poll(2)). どれかのreventsが空でなくなるか, timeoutが過ぎるまでブロックする
+    /// timeoutがNoneならいつまでも待ち, Duration::ZEROなら待たずに返る. 決定的モードでは時刻が進まないので待たない
+    /// reventsが空でないPollFdの数を返す. 既に無いソケットはエラーにせず, reventsにERRORを入れる
+    /// 待っている間は対象のソケットのイベントでだけ起こされるので, 1つのスレッドで多数の接続を扱える
+    pub fn poll(&self, fds: &mut [PollFd], timeout: Option<Duration>) -> Result<usize> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        // 状態を調べてから待ち始めるまでに発行されたイベントも取りこぼさないよう, 先に登録しておく
+        let waker = Arc::new(Waker::default());
+        let watcher: Arc<dyn Notify> = waker.clone();
+        let registered: Vec<Arc<SocketEvents>> = {
+            let sockets = self.sockets.read().recover();
+            fds.iter()
+                .filter_map(|fd| sockets.get(&fd.sock_id))
+                .map(|socket| {
+                    socket.events.register(watcher.clone());
+                    socket.events.clone()
+                })
+                .collect()
+        };
+
+        let ready = loop {
+            let ready = {
+                let sockets = self.sockets.read().recover();
+                for fd in fds.iter_mut() {
+                    let revents = sockets
+                        .get(&fd.sock_id)
+                        .map_or(PollEvents::ERROR, readiness);
+                    fd.revents = revents.intersection(fd.events | PollEvents::ERROR);
+                }
+                fds.iter().filter(|fd| !fd.revents.is_empty()).count()
             };
+            if ready > 0 || self.config.deterministic || !waker.wait(deadline) {
+                break ready;
+            }
+        };
+        for events in registered {
+            events.deregister(&watcher);
+        }
+        Ok(ready)
+    }
 
-            dbg!("socket.sock_id: ", socket.sock_id);
+    /// connectのFuture版. 最初にpollされた時にSYNを送り, 確立したらソケットのIDを返す
+    /// 待っている間はスレッドをブロックせず, ソケットのイベントでタスクを起こす
+    pub fn async_connect(&self, addr: Addr, port: u16) -> ConnectFuture<'_> {
+        ConnectFuture::new(self, addr, port)
+    }
 
-            if !packet.is_correct_checksum(local_addr, remote_addr) {
-                dbg!("invalid checksome");
-                continue;
+    /// acceptのFuture版
+    pub fn async_accept(&self, sock_id: SockID) -> AcceptFuture<'_> {
+        AcceptFuture::new(self, sock_id)
+    }
+
+    /// sendのFuture版. 送信バッファが空く度に入るだけ書き込み, 全て入ったら返る
+    pub fn async_send<'a>(&'a self, sock_id: SockID, buffer: &'a [u8]) -> SendFuture<'a> {
+        SendFuture::new(self, sock_id, buffer)
+    }
+
+    /// recvのFuture版
+    pub fn async_recv<'a>(&'a self, sock_id: SockID, buffer: &'a mut [u8]) -> RecvFuture<'a> {
+        RecvFuture::new(self, sock_id, buffer)
+    }
+
+    /// 次にソケットでイベントが発行された時にタスクを起こすよう登録する
+    /// 登録してから操作を試すので, その間に発行されたイベントも取りこぼさない
+    fn register_task(&self, sock_id: SockID, waker: &task::Waker) -> Result<()> {
+        let sockets = self.sockets.read().recover();
+        let socket = sockets
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.events.register_task(waker);
+        Ok(())
+    }
+
+    /// 以降にソケットで発行されるイベントをwatcherに知らせる. unwatchするかソケットが削除されるまで続く
+    #[cfg(feature = "mio")]
+    pub(crate) fn watch(&self, sock_id: SockID, watcher: Arc<dyn Notify>) -> Result<()> {
+        let sockets = self.sockets.read().recover();
+        let socket = sockets
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.events.register(watcher);
+        Ok(())
+    }
+
+    /// 既に削除されたソケットなら何もしない
+    #[cfg(feature = "mio")]
+    pub(crate) fn unwatch(&self, sock_id: SockID, watcher: &Arc<dyn Notify>) {
+        if let Some(socket) = self.sockets.read().recover().get(&sock_id) {
+            socket.events.deregister(watcher);
+        }
+    }
+
+    /// WouldBlockならPending, それ以外は結果を返す
+    fn ready_or_pending<T>(result: Result<T>) -> Poll<Result<T>> {
+        match result {
+            Err(error) if error.is::<WouldBlock>() => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+
+    pub(crate) fn poll_connected(&self, sock_id: SockID, waker: &task::Waker) -> Poll<Result<()>> {
+        // SYNの再送が上限に達するなどして削除されていれば失敗
+        if let Err(error) = self.register_task(sock_id, waker) {
+            return Poll::Ready(Err(error.context("failed to connect")));
+        }
+        let sockets = self.sockets.read().recover();
+        match sockets.get(&sock_id).map(|socket| socket.status) {
+            Some(TcpStatus::SynSent | TcpStatus::SynRcvd) => Poll::Pending,
+            Some(_) => Poll::Ready(Ok(())),
+            None => Poll::Ready(Err(anyhow!("failed to connect: {:?}", sock_id))),
+        }
+    }
+
+    pub(crate) fn poll_accept(&self, sock_id: SockID, waker: &task::Waker) -> Poll<Result<SockID>> {
+        if let Err(error) = self.register_task(sock_id, waker) {
+            return Poll::Ready(Err(error));
+        }
+        Self::ready_or_pending(self.accept_with(sock_id, true))
+    }
+
+    pub(crate) fn poll_recv(
+        &self,
+        sock_id: SockID,
+        buffer: &mut [u8],
+        waker: &task::Waker,
+    ) -> Poll<Result<usize>> {
+        if let Err(error) = self.register_task(sock_id, waker) {
+            return Poll::Ready(Err(error));
+        }
+        Self::ready_or_pending(self.recv_with(sock_id, buffer, true))
+    }
+
+    /// 送信バッファに入るだけ書き込み, 書き込んだバイト数を返す. 1バイトも入らなければPending
+    pub(crate) fn poll_send(
+        &self,
+        sock_id: SockID,
+        buffer: &[u8],
+        waker: &task::Waker,
+    ) -> Poll<Result<usize>> {
+        if let Err(error) = self.register_task(sock_id, waker) {
+            return Poll::Ready(Err(error));
+        }
+        let mut sockets = self.lock_sockets(Subsystem::Send);
+        let socket = match sockets.get_mut(&sock_id) {
+            Some(socket) => socket,
+            None => return Poll::Ready(Err(anyhow!("no such socket: {:?}", sock_id))),
+        };
+        if socket.is_write_closed() || socket.fin_queued {
+            return Poll::Ready(Err(anyhow!(
+                "socket is shut down for writing: {:?}",
+                sock_id
+            )));
+        }
+        let len = cmp::min(socket.writable_bytes(), buffer.len());
+        if len == 0 || matches!(socket.status, TcpStatus::SynSent | TcpStatus::SynRcvd) {
+            return Poll::Pending;
+        }
+        socket.unsent.extend_from_slice(&buffer[..len]);
+        Poll::Ready(self.kick_sender(socket).map(|()| len))
+    }
+
+    /// スタック全体の統計情報を返す
+    pub fn stack_stats(&self) -> StackStats {
+        let sockets = self.sockets.read().recover();
+        StackStats {
+            checksum: self.checksum_counters.snapshot(),
+            tx_ring: self.tx_ring_counters.snapshot(),
+            ..self.counters.snapshot(total_memory_usage(&sockets))
+        }
+    }
+
+    /// ソケット単位の統計情報を返す
+    pub fn socket_stats(&self, sock_id: SockID) -> Result<SocketStats> {
+        let sockets = self.sockets.read().recover();
+        let socket = sockets
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        Ok(SocketStats {
+            sock_id,
+            status: socket.status,
+            memory: socket.memory_usage(),
+            latest_rtt: socket.rtt.latest(),
+            min_rtt: socket.min_rtt,
+            srtt: socket.rtt.srtt(),
+            rttvar: socket.rtt.rttvar(),
+            rto: socket.rtt.rto(),
+            cwnd: socket.send_param.cwnd,
+            ssthresh: socket.send_param.ssthresh,
+            recv_buffer_size: socket.recv_buffer.len(),
+            duplicates_received: socket.duplicates.received,
+            duplicate_acks_suppressed: socket.duplicates.acks_suppressed,
+            #[cfg(feature = "stream-hash")]
+            sent_stream: socket.sent_stream,
+            #[cfg(feature = "stream-hash")]
+            received_stream: socket.received_stream,
+        })
+    }
+
+    /// リスニングソケット単位の統計情報を返す
+    pub fn listener_stats(&self, sock_id: SockID) -> Result<ListenerStats> {
+        let sockets = self.sockets.read().recover();
+        let socket = sockets
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if socket.status != TcpStatus::Listen {
+            bail!("not a listening socket: {:?}", sock_id);
+        }
+        Ok(ListenerStats {
+            accept_queue_len: socket.connection_queue.len(),
+            ..socket.listener_stats
+        })
+    }
+
+    /// 全ての接続(リスニングソケットを含む)の情報を返す
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let sockets = self.sockets.read().recover();
+        sockets.values().map(connection_info).collect()
+    }
+
+    /// 全接続の4タプル, 状態, タイマーをconntrack風にまとめて返す. to_jsonやwrite_toでJSONにできる
+    pub fn conntrack(&self) -> ConntrackSnapshot {
+        let sockets = self.sockets.read().recover();
+        let now = self.clock.now();
+        ConntrackSnapshot {
+            time: now
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_micros() as u64),
+            entries: sockets
+                .values()
+                .map(|socket| conntrack_entry(socket, now))
+                .collect(),
+        }
+    }
+
+    /// 1つの接続の情報を返す
+    pub fn info(&self, sock_id: SockID) -> Result<ConnectionInfo> {
+        let sockets = self.sockets.read().recover();
+        let socket = sockets
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        Ok(connection_info(socket))
+    }
+
+    /// filterがtrueを返した接続をまとめて閉じ, 閉じた接続の数を返す
+    /// 既に閉じ始めている接続は対象にならない. Gracefulの場合は再送が尽きるまでにFINがackされなければ削除する
+    pub fn close_matching(
+        &self,
+        filter: impl Fn(&ConnectionInfo) -> bool,
+        mode: CloseMode,
+    ) -> usize {
+        let mut sockets = self.sockets.write().recover();
+        let matched: Vec<SockID> = sockets
+            .values()
+            .filter(|socket| socket.closing_deadline.is_none())
+            .map(connection_info)
+            .filter(|info| filter(info))
+            .map(|info| info.sock_id)
+            .collect();
+
+        for sock_id in &matched {
+            self.force_close(&mut sockets, *sock_id, mode, forced_close_grace());
+        }
+        matched.len()
+    }
+
+    /// TcpConfig::wakeup_auditで数えた, サブシステム毎の起床回数とロックの取得回数. 無効ならNone
+    pub fn wakeup_audit(&self) -> Option<WakeupReport> {
+        self.wakeups.report()
+    }
+
+    /// wakeup_auditのカウンタを0から数え直す
+    pub fn reset_wakeup_audit(&self) {
+        self.wakeups.reset();
+    }
+
+    /// socketsの書き込みロックを取る. TcpConfig::wakeup_auditが有効ならsubsystemの取得回数として数える
+    fn lock_sockets(&self, subsystem: Subsystem) -> RwLockWriteGuard<'_, HashMap<SockID, Socket>> {
+        self.wakeups.record_lock(subsystem);
+        self.sockets.write().recover()
+    }
+
+    /// TcpConfig::memory_ceilingを超えていればtrue
+    fn exceeds_memory_ceiling(&self, sockets: &HashMap<SockID, Socket>) -> bool {
+        match self.config.memory_ceiling {
+            Some(ceiling) => total_memory_usage(sockets).total() > ceiling,
+            None => false,
+        }
+    }
+
+    /// 直近に終了した接続を古い順に返す
+    pub fn recently_closed(&self) -> Vec<ClosedConnection> {
+        self.recently_closed.lock().recover().to_vec()
+    }
+
+    /// ソケットテーブルからソケットを削除し, 終了理由を統計情報に記録する
+    fn remove_socket(&self, sockets: &mut HashMap<SockID, Socket>, sock_id: SockID) {
+        let socket = match sockets.remove(&sock_id) {
+            Some(socket) => socket,
+            None => return,
+        };
+
+        // 理由が記録されていないのはFINの交換を経て閉じた場合
+        let reason = socket.close_reason.unwrap_or(CloseReason::Fin);
+        self.counters.record_close(reason);
+        self.recently_closed
+            .lock()
+            .recover()
+            .push(ClosedConnection {
+                sock_id,
+                reason,
+                final_status: socket.status,
+                closed_at: self.clock.now(),
+            });
+        // このソケットのイベントを待っているAPIを起こす. 以降のwaitはエラーを返す
+        // 起こされたスレッドからrecently_closedやstack_statsが見えるよう, 記録してから起こす
+        // 中断された接続なら, 単に閉じられたのではなく理由(RST, タイムアウトなど)の分かるエラーにする
+        if let Some(failure) = TCPEventKind::failure(reason) {
+            socket.events.publish(failure);
+        }
+        socket.events.mark_removed();
+    }
+
+    fn receive_handler(&self) -> Result<()> {
+        dbg!("begin recv thread");
+        let mut backlog = ReceiveBacklog::new();
+        loop {
+            self.fill_backlog(&mut backlog);
+            self.wakeups.record_wakeup(Subsystem::Receive);
+
+            // 1つの接続が受信スレッドを占有しないよう, 接続毎にPER_SOCKET_PACKET_BUDGET個ずつ処理する
+            // 残りはバックログに積まれたまま次のイテレーションに回される
+            for received in backlog.take_round(PER_SOCKET_PACKET_BUDGET) {
+                self.handle_packet_isolated(received);
             }
 
-            let sock_id = socket.get_sock_id();
-            if let Err(error) = match socket.status {
-                TcpStatus::Listen => self.listen_handler(sockets, sock_id, &packet, remote_addr),
-                TcpStatus::SynRcvd => self.synrcvd_handler(sockets, sock_id, &packet),
-                TcpStatus::SynSent => self.synsent_handler(socket, &packet),
-                TcpStatus::Established => self.established_handler(socket, &packet),
-                TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
-                TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
-                _ => {
-                    dbg!("not implemented state");
-                    dbg!(packet.get_seq());
-                    dbg!(packet.get_ack());
-                    dbg!(packet.get_flag());
-                    dbg!(socket.send_param);
-                    dbg!(socket.recv_param);
-                    Ok(())
-                }
-            } {
-                dbg!(error);
+            // このイテレーションで受信したデータに対するACKと通知をソケット毎に1つにまとめて行う
+            self.flush_pending();
+        }
+    }
+
+    fn flush_pending(&self) {
+        let mut sockets = self.lock_sockets(Subsystem::Receive);
+        let mut panicked = Vec::new();
+        let now = self.clock.now();
+        for socket in sockets.values_mut() {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let ack_delayed_enough =
+                    socket.ack_deadline.is_some_and(|deadline| now >= deadline);
+                if socket.ack_pending || ack_delayed_enough {
+                    if let Err(error) = socket.send_tcp_packet(
+                        socket.send_param.next,
+                        socket.recv_param.next,
+                        TcpFlags::ACK,
+                        &[],
+                    ) {
+                        dbg!(error);
+                    }
+                }
+                if socket.delivery_pending {
+                    socket.delivery_pending = false;
+                    socket.events.publish(TCPEventKind::DataArrived);
+                }
+            }));
+            if result.is_err() {
+                panicked.push(socket.get_sock_id());
+            }
+        }
+        for sock_id in panicked {
+            self.abort_panicked(&mut sockets, sock_id);
+        }
+    }
+
+    /// handle_packetを呼び, パニックした場合はそのセグメントが属する接続だけを中断する
+    /// 受信スレッドは止めずに, 他の接続のセグメントはそのまま処理し続ける
+    fn handle_packet_isolated(&self, received: ReceivedPacket) {
+        let sock_id = received.sock_id();
+        if panic::catch_unwind(AssertUnwindSafe(|| self.handle_packet(received))).is_err() {
+            dbg!("handler panicked", sock_id);
+            let mut sockets = self.sockets.write().recover();
+            self.abort_panicked(&mut sockets, sock_id);
+        }
+    }
+
+    /// 処理中にパニックした接続を中断する. 状態が壊れているかもしれないのでRSTなども送らない
+    /// リスニングソケット宛てのセグメントでパニックした場合は, 他の接続を受け付けられるようリスナーは残す
+    fn abort_panicked(&self, sockets: &mut HashMap<SockID, Socket>, sock_id: SockID) {
+        if let Some(socket) = sockets.get_mut(&sock_id) {
+            socket.close_reason = Some(CloseReason::InternalError);
+            self.remove_socket(sockets, sock_id);
+        }
+        // 壊れた接続は取り除いたので, 以降のロックの取得はpoisonを気にしなくてよい
+        self.sockets.clear_poison();
+    }
+
+    /// 受信したパケットをバックログに積む
+    /// バックログが空であれば最初のパケットが届くまでブロックし, その後は届いている分だけ最大RECEIVE_BATCH_SIZE個まで読み込む
+    fn fill_backlog(&self, backlog: &mut ReceiveBacklog) {
+        for _ in 0..RECEIVE_BATCH_SIZE {
+            // 決定的モードでは呼び出し元を止めないよう, 届いていなければすぐに返る
+            // 遅延ACKを使う場合は, 何も届かなくてもその間隔で起きてflush_pendingで期限を確認する
+            let timeout = if backlog.is_empty() && !self.config.deterministic {
+                self.config.delayed_ack
+            } else {
+                Some(RECEIVE_POLL_INTERVAL)
+            };
+
+            let received = match self.device.recv(timeout) {
+                Ok(Some(received)) => received,
+                Ok(None) => return, // 届いているパケットは全て読み込んだ
+                Err(_) => continue,
+            };
+
+            if !backlog.push(received) {
+                dbg!("backlog overflow");
+            }
+        }
+    }
+
+    /// バックログから取り出したパケットを該当するソケットのハンドラに渡す
+    fn handle_packet(&self, received: ReceivedPacket) {
+        let packet_sock_id = received.sock_id();
+        let ReceivedPacket {
+            packet,
+            local_addr,
+            remote_addr,
+        } = received;
+
+        // ブロードキャスト/マルチキャスト宛て, あるいはそこからのセグメントは接続にならないのでRSTも返さず破棄する
+        if policy::check_unicast(local_addr)
+            .and(policy::check_unicast(remote_addr))
+            .is_err()
+        {
+            dbg!("non-unicast segment", local_addr, remote_addr);
+            self.counters.record_rejected_segment();
+            return;
+        }
+
+        if !policy::reverse_path_allows(&self.config.reverse_path, local_addr, remote_addr) {
+            dbg!("reverse path validation failed", remote_addr);
+            self.counters.record_reverse_path_drop();
+            return;
+        }
+
+        let mut sockets = self.lock_sockets(Subsystem::Receive);
+        let socket = match sockets.get_mut(&packet_sock_id) {
+            // 指定のremote_addr, remote_portでソケットが存在しない場合は新しいコネクションが考えられるため, リスニングソケットを使う
+            Some(socket) => socket,
+            None => match sockets.get_mut(&SockID::listening(packet_sock_id.local)) {
+                Some(socket) => socket, // リスニングソケット
+                None => {
+                    // どのソケットにも該当しないのでCLOSED状態とみなしてRSTを返す
+                    if self.owns_port(packet.get_dest()) {
+                        if let Verdict::Reset { seq, ack } = policy::reset_for_closed(&packet) {
+                            if let Err(error) =
+                                self.send_reset(local_addr, remote_addr, &packet, seq, ack)
+                            {
+                                dbg!(error);
+                            }
+                        }
+                    }
+                    return;
+                }
+            },
+        };
+
+        dbg!("socket.sock_id: ", socket.sock_id);
+
+        socket.log_event(LogEvent::SegmentReceived(SegmentRecord::from(&packet)));
+        socket.last_activity = self.clock.now();
+        // 相手が応答したのでキープアライブのプローブは数え直す
+        socket.last_received = socket.last_activity;
+        socket.keepalive_probes_sent = 0;
+
+        match self.policy.check(socket, &packet) {
+            Verdict::Accept => {}
+            Verdict::Drop => {
+                dbg!("dropped by compliance policy");
+                return;
+            }
+            Verdict::Ack => {
+                dbg!("challenge ack");
+                if !socket.is_acceptable(&packet) {
+                    self.counters.record_out_of_window_ack();
+                }
+                self.send_challenge_ack(socket);
+                return;
+            }
+            Verdict::Reset { seq, ack } => {
+                dbg!("reset by compliance policy");
+                if let Err(error) = self.send_reset(local_addr, remote_addr, &packet, seq, ack) {
+                    dbg!(error);
+                }
+                return;
+            }
+        }
+
+        let sock_id = socket.get_sock_id();
+        if packet.get_flag().has_rst() && socket.status != TcpStatus::Listen {
+            self.reset_handler(&mut sockets, sock_id, &packet);
+            return;
+        }
+
+        if packet.get_flag().has_syn()
+            && !matches!(
+                socket.status,
+                TcpStatus::Listen | TcpStatus::SynSent | TcpStatus::SynRcvd
+            )
+        {
+            // 同期済みの接続に届いたSYNは, シーケンス番号に関わらず従わずにチャレンジACKを返す. RFC 5961 4.2
+            // 相手が本当に接続をやり直したのならRSTが返ってくる. ハンドシェイクの最後のACKが落ちて再送されたSYN/ACKにもこれで応える
+            dbg!("syn on a synchronized connection");
+            self.send_challenge_ack(socket);
+            return;
+        }
+
+        if !matches!(socket.status, TcpStatus::Listen | TcpStatus::SynSent)
+            && !socket.accept_timestamp(&packet)
+        {
+            // 古い重複セグメント. 相手の状態を揃えるためにACKだけ返す
+            dbg!("dropped by PAWS");
+            self.counters.record_paws_drop();
+            socket.ack_pending = true;
+            return;
+        }
+
+        if !matches!(socket.status, TcpStatus::Listen | TcpStatus::SynSent)
+            && !socket.is_acceptable(&packet)
+            && !answered_by_handler(socket, &packet)
+        {
+            // 相手のSYNを受け取った後の全ての状態で, 受信ウィンドウ外のセグメントはハンドラに渡す前に破棄する
+            // 現在のRCV.NXTとウィンドウをACKで伝えて相手と状態を揃える. RFC 793
+            // ゼロウィンドウの時に届いたウィンドウプローブにもこれで応答する
+            dbg!("out of window segment");
+            let end = packet.get_seq().wrapping_add(packet.payload().len() as u32);
+            if !packet.payload().is_empty() && SeqNum(end).leq(SeqNum(socket.recv_param.next)) {
+                // 全て受信済みのデータの再送
+                self.on_duplicate_segment(socket, &packet);
+                return;
+            }
+            self.counters.record_out_of_window_ack();
+            socket.ack_pending = true;
+            return;
+        }
+
+        if let Err(error) = match socket.status {
+            TcpStatus::Listen => {
+                self.listen_handler(sockets, sock_id, &packet, packet_sock_id.remote)
+            }
+            TcpStatus::SynRcvd => self.synrcvd_handler(sockets, sock_id, &packet),
+            TcpStatus::SynSent => self.synsent_handler(socket, &packet),
+            TcpStatus::Established => self.established_handler(socket, &packet),
+            TcpStatus::CloseWait | TcpStatus::LastAck => {
+                self.close_handler(&mut sockets, sock_id, &packet)
+            }
+            TcpStatus::FinWait1 | TcpStatus::FinWait2 | TcpStatus::Closing => {
+                self.finwait_handler(socket, &packet)
+            }
+            TcpStatus::TimeWait => self.timewait_handler(socket, &packet),
+        } {
+            dbg!(error);
+        }
+    }
+
+    /// RSTを受信した際に呼ばれるhandler(LISTEN以外の全ての状態)
+    /// 正当なRSTであれば接続を中断し, connect/send/recv/closeでブロックしているスレッドを"connection reset"エラーで起こす
+    fn reset_handler(
+        &self,
+        sockets: &mut HashMap<SockID, Socket>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) {
+        dbg!("reset handler");
+        let socket = match sockets.get_mut(&sock_id) {
+            Some(socket) => socket,
+            None => return,
+        };
+
+        let valid = match socket.status {
+            // SYN_SENTではこちらのSYNをackしているRSTだけを受け入れる
+            TcpStatus::SynSent => {
+                packet.get_flag().has_ack() && packet.get_ack() == socket.send_param.next
+            }
+            // それ以外ではシーケンス番号が受信ウィンドウ内にあるRSTだけを受け入れる. ウィンドウ外のRSTは偽装されたものかもしれない
+            _ => socket.is_acceptable(packet),
+        };
+        if !valid {
+            dbg!("invalid reset");
+            return;
+        }
+        if socket.status != TcpStatus::SynSent && packet.get_seq() != socket.recv_param.next {
+            // ウィンドウ内でもRCV.NXTちょうどでなければ, シーケンス番号を当て推量した偽装かもしれない. RFC 5961 3.2
+            // チャレンジACKを返し, 本物の相手ならそのackの位置でRSTを送り直してもらう
+            dbg!("in-window reset");
+            self.send_challenge_ack(socket);
+            return;
+        }
+
+        socket.retransmission_queue.clear();
+        socket.close_reason = Some(CloseReason::ResetReceived);
+
+        // acceptされる前に中断された接続はリスニングソケットのキューからも取り除く
+        if let Some(listening_socket_id) = socket.listening_socket {
+            if let Some(listening_socket) = sockets.get_mut(&listening_socket_id) {
+                listening_socket
+                    .connection_queue
+                    .retain(|connected| *connected != sock_id);
             }
         }
+        self.remove_socket(sockets, sock_id);
     }
 
     // listen状態のsocketに対してリクエスト(3 way handshakeのSYN要求)が来た際に呼ばれるhandler
@@ -360,51 +1798,156 @@ impl TCP {
         mut sockets: RwLockWriteGuard<HashMap<SockID, Socket>>,
         listening_socket_id: SockID,
         packet: &TCPPacket,
-        remote_addr: Ipv4Addr,
+        remote: DefaultEndpoint,
     ) -> Result<()> {
         dbg!("listen handler");
 
-        if packet.get_flag() & tcpflags::ACK > 0 {
-            // 本来ならRSTをsendする
+        if packet.get_flag().has_rst() {
+            return Ok(());
+        }
+
+        if packet.get_flag().has_ack() {
+            if self.config.syn_cookies && !packet.get_flag().has_syn() {
+                let cookie = self.syn_cookies.decode(
+                    listening_socket_id.local,
+                    remote,
+                    packet.get_seq().wrapping_sub(1),
+                    packet.get_ack().wrapping_sub(1),
+                    self.clock.now(),
+                );
+                if let Some(state) = cookie {
+                    return self.accept_syn_cookie(
+                        sockets,
+                        listening_socket_id,
+                        packet,
+                        remote,
+                        state,
+                    );
+                }
+                if let Some(listening_socket) = sockets.get_mut(&listening_socket_id) {
+                    listening_socket.listener_stats.syn_cookies_rejected += 1;
+                }
+            }
+            // LISTEN状態へのACKは以前の接続の残りなので, SEG.ACKをシーケンス番号にしたRSTを返す
+            return self.send_reset(
+                listening_socket_id.local.addr(),
+                remote.addr(),
+                packet,
+                packet.get_ack(),
+                None,
+            );
+        }
+
+        if !packet.get_flag().has_syn() {
             return Ok(());
         }
 
+        // ハンドシェイク中の接続も数える. 同時に届いたSYNが全て確立してキューが溢れないようにする
+        let pending = sockets
+            .values()
+            .filter(|socket| socket.listening_socket == Some(listening_socket_id))
+            .count();
         let listening_socket = sockets
             .get_mut(&listening_socket_id)
             .context(format!("socket_id not found: {:?}", listening_socket_id))?;
+        listening_socket.listener_stats.syns_received += 1;
+        if pending >= self.config.accept_backlog {
+            // acceptが追いついていない. SYNを無視すれば相手が再送してくる
+            dbg!("accept queue overflow");
+            listening_socket.listener_stats.queue_overflows += 1;
+            return Ok(());
+        }
+
+        if self.config.syn_cookies {
+            // ソケットを作らないのでメモリの上限も見なくてよい
+            listening_socket.listener_stats.syn_cookies_sent += 1;
+            return self.send_syn_cookie(listening_socket, packet, remote);
+        }
 
-        if packet.get_flag() & tcpflags::SYN == 0 {
+        if self.exceeds_memory_ceiling(&sockets) {
+            // SYNを無視すれば相手が再送してくるので, その間にメモリが空くのを待つ
+            dbg!("memory ceiling exceeded");
+            self.counters.record_memory_ceiling_rejection();
             return Ok(());
         }
 
+        let listening_socket = sockets
+            .get_mut(&listening_socket_id)
+            .context(format!("socket_id not found: {:?}", listening_socket_id))?;
+
         // SynRcvdのソケットを作ってSYN/ACKを返す
         let mut connection_socket = Socket::new(
-            listening_socket.sock_id.local_addr,
-            remote_addr,
-            listening_socket.sock_id.local_port,
-            packet.get_src(),
+            self.device.clone(),
+            self.clock.clone(),
+            SockID::new(listening_socket.sock_id.local, remote),
             TcpStatus::SynRcvd,
-        )?;
+        );
+        self.prepare_socket(&mut connection_socket)?;
+        connection_socket.negotiate_options(packet);
+        connection_socket.idle_timeout = listening_socket.idle_timeout;
+        connection_socket.keepalive = listening_socket.keepalive;
+        connection_socket.user_timeout = listening_socket.user_timeout;
+        connection_socket.read_timeout = listening_socket.read_timeout;
+        connection_socket.write_timeout = listening_socket.write_timeout;
+        connection_socket.syn_received_at = Some(self.clock.now());
 
-        connection_socket.recv_param.next = packet.get_seq() + 1;
+        connection_socket.recv_param.next = packet.get_seq().wrapping_add(1);
         connection_socket.recv_param.initial_seq = packet.get_seq();
 
-        connection_socket.send_param.initial_seq = rand::thread_rng().gen_range(1..1 << 31);
-        connection_socket.send_param.window = packet.get_window_size();
+        // TCP Fast Open. 正しいクッキーが付いていればSYNのデータを受け取り, ハンドシェイクの完了を待たずにacceptに返す
+        // クッキーがないか正しくなければデータは受け取らず(相手はハンドシェイクの後に送り直す), SYN/ACKでクッキーを渡す
+        let mut fast_open = false;
+        if connection_socket.capabilities.fast_open {
+            match packet.fast_open_cookie() {
+                Some(cookie) if self.fast_open_cookies.validate(remote.addr(), &cookie) => {
+                    fast_open = true;
+                    let len = cmp::min(
+                        packet.payload().len(),
+                        connection_socket.recv_param.window as usize,
+                    );
+                    connection_socket.deliver(&packet.payload()[..len]);
+                    listening_socket.listener_stats.fast_open_accepted += 1;
+                }
+                _ => {
+                    connection_socket.fast_open_cookie =
+                        Some(self.fast_open_cookies.generate(remote.addr()));
+                    listening_socket.listener_stats.fast_open_cookies_sent += 1;
+                }
+            }
+        }
+
+        connection_socket.send_param.initial_seq =
+            self.initial_seq(connection_socket.get_sock_id());
+        connection_socket.send_param.init_window(
+            packet.get_seq(),
+            connection_socket.send_param.initial_seq,
+            packet.get_window_size() as u32,
+        );
         connection_socket.send_tcp_packet(
             connection_socket.send_param.initial_seq,
             connection_socket.recv_param.next,
-            tcpflags::SYN | tcpflags::ACK,
+            TcpFlags::SYN | TcpFlags::ACK,
             &[],
         )?;
 
-        connection_socket.send_param.next = connection_socket.send_param.initial_seq + 1;
+        connection_socket.send_param.next =
+            connection_socket.send_param.initial_seq.wrapping_add(1);
         connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
 
         // このコネクション自体を生成したリスニングソケットを登録
         connection_socket.listening_socket = Some(listening_socket.get_sock_id());
         dbg!("status: listen -> ", &connection_socket.status);
-        sockets.insert(connection_socket.get_sock_id(), connection_socket);
+        let sock_id = connection_socket.get_sock_id();
+        let rto = connection_socket.rtt.rto();
+        sockets.insert(sock_id, connection_socket);
+        self.handshake_timers
+            .schedule(sock_id, self.clock.now() + rto);
+        if fast_open {
+            // SYN_RCVDのままacceptに返す. 最後のACKが届いた時にはもうキューに入れない
+            if let Some(listening_socket) = sockets.get_mut(&listening_socket_id) {
+                self.push_accept_queue(listening_socket, sock_id);
+            }
+        }
 
         Ok(())
     }
@@ -420,6 +1963,15 @@ impl TCP {
     ) -> Result<()> {
         dbg!("synrcvd handler");
         dbg!(packet);
+        let flag = packet.get_flag();
+        if flag.has_syn() && !flag.has_ack() {
+            // SYN/ACKが相手に届かず, SYNが再送されてきた. 再送タイマーを待たずにSYN/ACKを送り直す
+            // ISNが違うSYNは別の接続の古いSYNなので無視する
+            if packet.get_seq() == sockets[&sock_id].recv_param.initial_seq {
+                self.resend_synack(&mut sockets, sock_id);
+            }
+            return Ok(());
+        }
         let socket = sockets.get_mut(&sock_id).unwrap();
 
         dbg!(packet.get_flag());
@@ -427,22 +1979,48 @@ impl TCP {
         dbg!(packet.get_ack());
         dbg!(socket.send_param.next);
 
-        if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
+        if packet.get_flag().has_ack()
+            && SeqNum(socket.send_param.unacked_seq).leq(SeqNum(packet.get_ack()))
+            && SeqNum(packet.get_ack()).leq(SeqNum(socket.send_param.next))
         {
-            socket.recv_param.next = packet.get_seq();
+            // RCV.NXTは相手のSYNの次のままにする. 最後のACKより先に続きのデータが届いていれば, 穴が埋まるまで待つ
+            // 同時オープンで届いた相手のSYN/ACKのSYNも受信済みになっている
             socket.send_param.unacked_seq = packet.get_ack();
-            socket.status = TcpStatus::Established;
+            let window = socket.peer_window(packet);
+            socket
+                .send_param
+                .update_window(packet.get_seq(), packet.get_ack(), window);
+            self.update_control_segments(socket);
+            socket.set_status(TcpStatus::Established);
             dbg!("status: synrcv -> {}", &socket.status);
+            self.counters.record_handshake_completed();
+            if !packet.get_flag().has_syn() {
+                // ハンドシェイクの最後のACKがデータやFINを運んでいることもある. acceptされるまで受信バッファに溜めておく
+                if let Err(error) = self.receive_data_and_fin(socket, packet) {
+                    dbg!(error);
+                }
+            }
+            // TCP Fast Openで先にacceptされていれば, 確立を待っていたデータがある
+            if !socket.unsent.is_empty() {
+                self.push_unsent(socket, false)?;
+            }
 
-            if let Some(listening_socket_id) = socket.listening_socket {
-                let listening_socket = sockets.get_mut(&listening_socket_id).unwrap();
-                listening_socket.connection_queue.push_back(sock_id);
-                self.publish_event(
-                    listening_socket.get_sock_id(),
-                    TCPEventKind::ConnectionCompleted,
-                );
+            match socket.listening_socket {
+                Some(listening_socket_id) => match sockets.get_mut(&listening_socket_id) {
+                    // TCP Fast OpenでSYNを受け取った時にキューに入れてある
+                    Some(listening_socket)
+                        if listening_socket.connection_queue.contains(&sock_id) => {}
+                    Some(listening_socket) => self.push_accept_queue(listening_socket, sock_id),
+                    None => {
+                        // リスニングソケットが先に閉じられていて, acceptされることはない
+                        dbg!("listening socket has been closed", listening_socket_id);
+                        self.force_close(&mut sockets, sock_id, CloseMode::Abort, Duration::ZERO);
+                    }
+                },
+                None => {
+                    // 同時オープンではconnectしたスレッドが待っている
+                    socket.events.publish(TCPEventKind::ConnectionCompleted);
+                }
             }
         } else {
             dbg!("synrcv handler failed");
@@ -451,58 +2029,398 @@ impl TCP {
         Ok(())
     }
 
-    // あまり実装がよくない気がする
-    fn delete_acked_segment_from_retransmissio_queue(&self, socket: &mut Socket) {
-        dbg!(socket.send_param.unacked_seq);
-
-        while let Some(item) = socket.retransmission_queue.pop_front() {
-            dbg!(socket.send_param.unacked_seq);
-            dbg!(item.packet.get_seq());
-            if socket.send_param.unacked_seq > item.packet.get_seq() {
-                dbg!("successfully acked");
-                socket.send_param.window += item.packet.payload().len() as u16;
-                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
-            } else {
-                socket.retransmission_queue.push_front(item);
-                break;
-            }
+    /// ハンドシェイクが完了した接続をリスニングソケットのacceptのキューに入れ, acceptを待っているスレッドを起こす
+    fn push_accept_queue(&self, listening_socket: &mut Socket, sock_id: SockID) {
+        listening_socket.connection_queue.push_back(sock_id);
+        listening_socket.listener_stats.handshakes_completed += 1;
+        let len = listening_socket.connection_queue.len();
+        if self.config.accept_queue_watermark == Some(len) {
+            listening_socket
+                .events
+                .notify(SocketNotification::AcceptQueueHighWatermark { len });
         }
+        listening_socket
+            .events
+            .publish(TCPEventKind::ConnectionCompleted);
     }
 
-    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("established handler");
+    /// SYNクッキーを使う場合に, ソケットを作らずにSYN/ACKを返す
+    /// SYNで交渉したオプションはISNにしたクッキーに埋め込み, 最後のACKでaccept_syn_cookieが取り出す
+    fn send_syn_cookie(
+        &self,
+        listening_socket: &Socket,
+        packet: &TCPPacket,
+        remote: DefaultEndpoint,
+    ) -> Result<()> {
+        let local = listening_socket.sock_id.local;
+        let mut capabilities = self.config.capabilities;
+        capabilities.negotiate(packet);
+        let state = CookieState {
+            sack: capabilities.sack,
+            send_wscale: capabilities
+                .window_scale
+                .then(|| packet.window_scale().unwrap_or(0)),
+        };
+        let cookie =
+            self.syn_cookies
+                .encode(local, remote, packet.get_seq(), state, self.clock.now());
 
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
+        let mut options = Vec::new();
+        if capabilities.timestamps {
+            options.push(TcpOption::Timestamps {
+                tsval: listening_socket.timestamp_now(),
+                tsecr: packet.timestamps().map_or(0, |(tsval, _)| tsval),
+            });
+        }
+        if capabilities.window_scale {
+            options.push(TcpOption::WindowScale(self.recv_window_scale()?));
+        }
+        if capabilities.sack {
+            options.push(TcpOption::SackPermitted);
+        }
+        let mut syn_ack = TCPPacket::with_options(&options, 0);
+        syn_ack.set_src(local.port());
+        syn_ack.set_dest(remote.port());
+        syn_ack.set_seq(cookie);
+        syn_ack.set_ack(packet.get_seq().wrapping_add(1));
+        syn_ack.set_flag(TcpFlags::SYN | TcpFlags::ACK);
+        // SYNのウィンドウはスケールしない
+        syn_ack.set_window_size(cmp::min(self.config.recv_buffer_size, u16::MAX as usize) as u16);
+        if let Some(filter) = &self.config.egress_filter {
+            if !filter.allows(&SegmentInfo::outgoing(
+                local.addr(),
+                remote.addr(),
+                &syn_ack,
+            )) {
+                dbg!("blocked by egress filter");
+                return Ok(());
+            }
+        }
+
+        self.device
+            .send(&syn_ack, local.addr(), remote.addr())
+            .context("failed to send SYN/ACK")?;
+        Ok(())
+    }
+
+    /// クッキーを検証できた最後のACKから, Establishedの接続を作ってacceptのキューに入れる
+    /// ACKにデータやFINが載っていれば, そのままEstablishedの接続で処理する
+    fn accept_syn_cookie(
+        &self,
+        mut sockets: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        listening_socket_id: SockID,
+        packet: &TCPPacket,
+        remote: DefaultEndpoint,
+        state: CookieState,
+    ) -> Result<()> {
+        let exceeds_memory_ceiling = self.exceeds_memory_ceiling(&sockets);
+        let listening_socket = sockets
+            .get_mut(&listening_socket_id)
+            .context(format!("socket_id not found: {:?}", listening_socket_id))?;
+        if listening_socket.connection_queue.len() >= self.config.accept_backlog {
+            // 相手は接続できたと思っているが, 再送してくるデータのACKでもう一度クッキーを検証できる
+            dbg!("accept queue overflow");
+            listening_socket.listener_stats.queue_overflows += 1;
+            return Ok(());
+        }
+        if exceeds_memory_ceiling {
+            dbg!("memory ceiling exceeded");
+            self.counters.record_memory_ceiling_rejection();
+            return Ok(());
+        }
+
+        let mut socket = Socket::new(
+            self.device.clone(),
+            self.clock.clone(),
+            SockID::new(listening_socket_id.local, remote),
+            TcpStatus::Established,
+        );
+        self.prepare_socket(&mut socket)?;
+        socket.idle_timeout = listening_socket.idle_timeout;
+        socket.keepalive = listening_socket.keepalive;
+        socket.user_timeout = listening_socket.user_timeout;
+        socket.read_timeout = listening_socket.read_timeout;
+        socket.write_timeout = listening_socket.write_timeout;
+        socket.listening_socket = Some(listening_socket_id);
+        // SYNを受信した時刻は残していないので, ハンドシェイクが完了した時刻で代わりにする
+        socket.syn_received_at = Some(self.clock.now());
+
+        socket.capabilities.sack &= state.sack;
+        socket.capabilities.window_scale &= state.send_wscale.is_some();
+        // タイムスタンプはSYNで交渉できていれば最後のACKにも付いている
+        socket.capabilities.timestamps &= packet.timestamps().is_some();
+        socket.capabilities.ecn = false;
+        if socket.capabilities.window_scale {
+            socket.send_wscale = cmp::min(state.send_wscale.unwrap_or(0), MAX_WINDOW_SCALE);
+        } else {
+            socket.recv_wscale = 0;
+            socket.send_wscale = 0;
+        }
+        if let Some((tsval, _)) = packet.timestamps() {
+            socket.ts_recent = tsval;
+        }
+
+        socket.recv_param.initial_seq = packet.get_seq().wrapping_sub(1);
+        socket.recv_param.next = packet.get_seq();
+        socket.send_param.initial_seq = packet.get_ack().wrapping_sub(1);
+        socket.send_param.next = packet.get_ack();
+        socket.send_param.unacked_seq = packet.get_ack();
+        let window = socket.peer_window(packet);
+        socket
+            .send_param
+            .init_window(packet.get_seq(), packet.get_ack(), window);
+        dbg!("status: listen -> ", &socket.status);
+        self.counters.record_handshake_completed();
+
+        let sock_id = socket.get_sock_id();
+        self.push_accept_queue(listening_socket, sock_id);
+        listening_socket.listener_stats.syn_cookies_validated += 1;
+        sockets.insert(sock_id, socket);
+
+        if !packet.payload().is_empty() || packet.get_flag().has_fin() {
+            let socket = sockets
+                .get_mut(&sock_id)
+                .context(format!("socket_id not found: {:?}", sock_id))?;
+            self.established_handler(socket, packet)?;
+        }
+        Ok(())
+    }
+
+    /// 受信したSACKブロックに含まれるセグメントに印を付ける. 印の付いたセグメントは再送タイムアウトで再送しない
+    /// 相手が受信済みのデータを捨てる(reneging)こともあるので, 累積ackされるまでは再送キューに残しておく
+    fn apply_sack(&self, socket: &mut Socket, packet: &TCPPacket) {
+        if !socket.capabilities.sack {
+            return;
+        }
+        for block in packet.sack_blocks() {
+            for item in socket.retransmission_queue.iter_mut() {
+                let len = item.packet.payload().len() as u32;
+                if len == 0 {
+                    continue;
+                }
+                let start = SeqNum(item.packet.get_seq());
+                let end = SeqNum(item.packet.get_seq().wrapping_add(len));
+                if SeqNum(block.start).leq(start) && end.leq(SeqNum(block.end)) {
+                    item.sacked = true;
+                }
+            }
+        }
+    }
+
+    /// ackを進めたセグメントのtsecrからRTTを測る
+    /// tsecrはackされたデータを送った時のtsvalなので, 再送したセグメントに対するackでも正しく測れる
+    fn sample_rtt_from_timestamp(&self, socket: &mut Socket, packet: &TCPPacket) {
+        if !socket.capabilities.timestamps {
+            return;
+        }
+        // tsecrが0のものはエコーする値がまだなかったものと区別できないので使わない
+        let tsecr = match packet.timestamps() {
+            Some((_, tsecr)) if tsecr != 0 => tsecr,
+            _ => return,
+        };
+        let elapsed = socket.timestamp_now().wrapping_sub(tsecr);
+        if (elapsed as i32) < 0 {
+            // こちらがまだ送っていない時刻のエコーは信用しない
+            return;
+        }
+        self.record_rtt_sample(socket, Duration::from_millis(elapsed as u64));
+    }
+
+    /// 届いたデータのtsecrから受信側でRTTを測る(Linuxのtcp_rcv_rtt_measure_ts). 受信バッファの自動調整に使う
+    /// データを受け取るだけの接続はackを待つものを送らないので, sample_rtt_from_timestampでは測れない
+    fn sample_receiver_rtt(&self, socket: &mut Socket, packet: &TCPPacket) {
+        if !socket.capabilities.timestamps || socket.autotune.is_none() {
+            return;
+        }
+        let tsecr = match packet.timestamps() {
+            Some((_, tsecr)) if tsecr != 0 => tsecr,
+            _ => return,
+        };
+        let elapsed = socket.timestamp_now().wrapping_sub(tsecr);
+        if let (Some(autotune), false) = (socket.autotune.as_mut(), (elapsed as i32) < 0) {
+            autotune.on_rtt_sample(Duration::from_millis(elapsed as u64));
+        }
+    }
+
+    /// 測ったRTTでRTOを計算し直す
+    fn record_rtt_sample(&self, socket: &mut Socket, rtt: Duration) {
+        socket.min_rtt = Some(match socket.min_rtt {
+            Some(min_rtt) => cmp::min(min_rtt, rtt),
+            None => rtt,
+        });
+        socket.rtt.on_sample(rtt);
+        if socket.rtt_notifications {
+            socket.events.notify(SocketNotification::RttSample {
+                rtt,
+                srtt: socket.rtt.srtt().unwrap_or(rtt),
+                rttvar: socket.rtt.rttvar(),
+            });
+        }
+    }
+
+    // あまり実装がよくない気がする
+    fn delete_acked_segment_from_retransmissio_queue(&self, socket: &mut Socket) {
+        dbg!(socket.send_param.unacked_seq);
+
+        // 1度しか送っていないセグメントのうち最後にackされたものでRTTを測る
+        // 再送したセグメントはどの送信に対するACKか分からないため測らない(Karnのアルゴリズム)
+        let mut rtt_sample = None;
+        let mut acked_bytes = 0;
+        let mut acked_any = false;
+        while let Some(item) = socket.retransmission_queue.pop_front() {
+            dbg!(socket.send_param.unacked_seq);
+            dbg!(item.packet.get_seq());
+            if item.is_acked(socket.send_param.unacked_seq) {
+                dbg!("successfully acked");
+                acked_bytes += item.packet.payload().len();
+                acked_any = true;
+                if item.transmission_count == 1 {
+                    rtt_sample = Some(self.clock.since(item.latest_transmission_time));
+                }
+                self.on_segment_acked(socket, &item);
+                socket.events.publish(TCPEventKind::Acked);
+            } else {
+                socket.retransmission_queue.push_front(item);
+                break;
+            }
+        }
+
+        self.update_control_segments(socket);
+        if acked_any {
+            socket.rtt.reset_backoff();
+        }
+        if acked_bytes > 0 {
+            socket.send_param.grow_cwnd(acked_bytes, MSS);
+        }
+        // タイムスタンプを使っている場合はsample_rtt_from_timestampで測る
+        if let (Some(rtt), false) = (rtt_sample, socket.capabilities.timestamps) {
+            self.record_rtt_sample(socket, rtt);
+        }
+    }
+
+    /// 再送キューのセグメントがackされた時の処理
+    /// 再送したセグメントのACKがRTTよりずっと早く届いた場合は, 再送ではなく元のセグメントに対するACKと考えられる
+    /// つまり再送タイムアウトは誤検知だったので, 縮めた輻輳ウィンドウを元に戻す
+    fn on_segment_acked(&self, socket: &mut Socket, item: &RetransmissionQueueEntry) {
+        if item.transmission_count == 1 {
+            return;
+        }
+        let elapsed = self.clock.since(item.latest_transmission_time);
+
+        if let Some((cwnd_before_rto, ssthresh_before_rto)) = socket.cwnd_before_rto.take() {
+            if let Some(min_rtt) = socket.min_rtt {
+                if elapsed < min_rtt / 2 {
+                    dbg!("spurious retransmission timeout");
+                    socket.send_param.cwnd = cmp::max(socket.send_param.cwnd, cwnd_before_rto);
+                    socket.send_param.ssthresh = ssthresh_before_rto;
+                    self.counters.record_spurious_rto();
+                }
+            }
+        }
+    }
+
+    /// セグメントのACKフィールドとウィンドウフィールドをまとめて処理する. ESTABLISHED以降の状態のハンドラで共通に使う
+    /// 先にSND.UNAを進めてから, 同じセグメントが広告したウィンドウでSND.WNDを更新する. ウィンドウはSND.UNAから数えるので,
+    /// ackされたセグメントを再送キューから取り除く時(Nagleで保留したデータを送る時)には新しいackと新しいウィンドウが揃っている
+    /// SND.WL1/SND.WL2より古いセグメントではウィンドウを更新しないので, 入れ替わって届いた古いACKのウィンドウで戻されることもない
+    /// 未送信のデータに対するACKならfalseを返すので, 呼び出し側はセグメントを破棄する
+    fn process_ack(&self, socket: &mut Socket, packet: &TCPPacket) -> bool {
+        let ack = packet.get_ack();
+        if SeqNum(socket.send_param.next).lt(SeqNum(ack)) {
+            // 未送信セグメントに対するackは破棄
+            return false;
+        }
+
+        let advanced = SeqNum(socket.send_param.unacked_seq).lt(SeqNum(ack));
+        if advanced {
+            socket.send_param.unacked_seq = ack;
+        }
+        // SND.UNAより古いackと一緒に広告されたウィンドウは使わない
+        let window = socket.peer_window(packet);
+        let window_updated = packet.get_flag().has_ack()
+            && ack == socket.send_param.unacked_seq
+            && socket
+                .send_param
+                .update_window(packet.get_seq(), ack, window);
+
+        if advanced {
             dbg!("pop retransmission queue");
-            socket.send_param.unacked_seq = packet.get_ack();
             self.delete_acked_segment_from_retransmissio_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            // 未送信セグメントに対するackは破棄
+            self.sample_rtt_from_timestamp(socket, packet);
+        }
+        if window_updated {
+            // ackが進まなくてもウィンドウが開いていれば, 空きを待っているsendを起こす
+            socket.events.publish(TCPEventKind::Acked);
+        }
+        // ウィンドウが空いたので送信バッファのデータを続けて送る
+        // 送ったデータが全てackされていれば, Nagleのアルゴリズムで保留していたデータも送る
+        if advanced || window_updated {
+            if let Err(error) = self.push_unsent(socket, false) {
+                dbg!(error);
+            }
+        }
+        self.apply_sack(socket, packet);
+        true
+    }
+
+    /// 相手がECEで経路上の輻輳を伝えてきた. ロスを待たずにcwndを縮め, 次のデータにCWRを立てて縮めたことを伝える. RFC 3168
+    /// 縮めた時に送信済みだったデータが全てackされるまで(1RTTに1回)は, 続けてECEを受けても縮め直さない
+    fn process_ecn_echo(&self, socket: &mut Socket, packet: &TCPPacket) {
+        if !socket.capabilities.ecn || !packet.get_flag().has_ece() {
+            return;
+        }
+        if let Some(recover) = socket.ecn_recover {
+            if SeqNum(socket.send_param.unacked_seq).lt(SeqNum(recover)) {
+                return;
+            }
+        }
+        socket.send_param.on_congestion_signal(MSS);
+        socket.cwr_pending = true;
+        socket.ecn_recover = Some(socket.send_param.next);
+        self.counters.record_ecn_cwnd_reduction();
+    }
+
+    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        dbg!("established handler");
+
+        if !self.process_ack(socket, packet) {
             return Ok(());
         }
 
-        if packet.get_flag() & tcpflags::ACK == 0 {
+        if !packet.get_flag().has_ack() {
             // ACKが立ってないパケットは破棄
             return Ok(());
         }
+        self.process_ecn_echo(socket, packet);
+        self.receive_data_and_fin(socket, packet)
+    }
 
+    /// ESTABLISHEDで受け取ったセグメントのペイロードを受信バッファに入れてから, その後ろのFINを処理する
+    /// FINはペイロードの後ろのシーケンス番号(SEG.SEQ + ペイロード長)にあるので, データを運ぶFINでも先にデータを受け取る
+    /// 応答を書いてすぐに閉じる相手は, 最後のデータとFINを1つのセグメントで送ってくる
+    fn receive_data_and_fin(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
         if !packet.payload().is_empty() {
             self.process_payload(socket, packet)?;
+            if socket.read_shutdown {
+                socket.discard_received();
+            }
         }
 
         // クライアント側はパッシブクローズになるため、急にサーバからFINを受け取ることがある(というかいつか必ず終わりが来る)
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            socket.recv_param.next = packet.get_seq() + 1;
+        // FINのセグメントでなくても, 先に届いていたFINの前の穴を埋めたならここで閉じる
+        if receive_fin(socket, packet) {
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
-                tcpflags::ACK,
+                TcpFlags::ACK,
                 &[],
             )?;
-            socket.status = TcpStatus::CloseWait;
-            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+            socket.set_status(TcpStatus::CloseWait);
+            socket.events.publish(TCPEventKind::DataArrived);
+        } else if packet.get_flag().has_fin() {
+            // FINより前のデータがまだ届いていない. 今のRCV.NXTを返して再送を促し, 穴が埋まったら閉じる
+            dbg!("out of order FIN");
+            socket.ack_pending = true;
         }
 
         Ok(())
@@ -511,263 +2429,1306 @@ impl TCP {
     // SYNSENT状態のソケットに到着したパケットの処理
     fn synsent_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
         dbg!("synsent handler");
-        if packet.get_flag() & tcpflags::ACK > 0
-            && packet.get_flag() & tcpflags::SYN > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
+        if packet.get_flag().has_syn() && !packet.get_flag().has_ack() {
+            return self.simultaneous_open(socket, packet);
+        }
+
+        if packet.get_flag().has_ack()
+            && packet.get_flag().has_syn()
+            && SeqNum(socket.send_param.unacked_seq).leq(SeqNum(packet.get_ack()))
+            && SeqNum(packet.get_ack()).leq(SeqNum(socket.send_param.next))
         {
             // synsentの状態で受けるackなので恐らくpacket.get_sequence() + 1 == packet.get_ack()になると考えられる
             // 確認したところならなかった。なぜ？
-            socket.recv_param.next = packet.get_seq() + 1;
+            socket.recv_param.next = packet.get_seq().wrapping_add(1);
 
             // これがよく分からない、nextがわかっている以上なぜこの状態を持っていないといけないのか？
             socket.recv_param.initial_seq = packet.get_seq();
 
             // これはOK
             socket.send_param.unacked_seq = packet.get_ack();
-            socket.send_param.window = packet.get_window_size();
+            socket.send_param.init_window(
+                packet.get_seq(),
+                packet.get_ack(),
+                packet.get_window_size() as u32,
+            );
+            if socket.capabilities.fast_open {
+                // 次の接続からSYNにデータを載せられるよう, SYN/ACKで渡されたクッキーを覚えておく
+                if let Some(cookie) = packet
+                    .fast_open_cookie()
+                    .filter(|cookie| !cookie.is_empty())
+                {
+                    self.fast_open_cache
+                        .lock()
+                        .recover()
+                        .insert(socket.sock_id.remote.addr(), cookie);
+                }
+            }
+            socket.negotiate_options(packet);
+            self.update_control_segments(socket);
 
-            if socket.send_param.unacked_seq > socket.send_param.initial_seq {
-                dbg!("first half");
-                socket.status = TcpStatus::Established;
+            if socket.control.syn_acked {
+                socket.set_status(TcpStatus::Established);
+                self.settle_syn_data(socket);
 
                 // ここでactive openしたclientがSYN/ACKに対してSEQ=1, ACK=1のACKを返す
                 // ちなみにSEQは相手が欲しいペイロード、ACKはこちらが欲しいペイロードの先頭を指す
                 socket.send_tcp_packet(
                     socket.send_param.next,
                     socket.recv_param.next,
-                    tcpflags::ACK,
+                    TcpFlags::ACK,
                     &[],
                 )?;
 
                 dbg!("status: synsent ->", &socket.status);
-                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            } else {
-                dbg!("second half");
-                // どのシチュエーションの処理かちょっと分からない
-                socket.status = TcpStatus::SynRcvd;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsent ->", &socket.status);
+                self.counters.record_handshake_completed();
+                socket.events.publish(TCPEventKind::ConnectionCompleted);
+                // connect_with_dataでSYNに載せきれなかったデータを送る
+                if !socket.unsent.is_empty() {
+                    self.push_unsent(socket, false)?;
+                }
             }
         }
 
         Ok(())
     }
 
+    /// TCP Fast OpenでSYNに載せたデータのうち, SYN/ACKでackされた分を確かめる
+    /// SYNのエントリは再送キューから取り除き, ackされなかった分は送信バッファの先頭に戻して通常のデータとして送り直す
+    fn settle_syn_data(&self, socket: &mut Socket) {
+        let syn_end = socket.send_param.initial_seq.wrapping_add(1);
+        if socket.send_param.next == syn_end {
+            return;
+        }
+        let item = match socket.retransmission_queue.pop_front() {
+            Some(item) => item,
+            None => return,
+        };
+        let acked = socket.send_param.unacked_seq.wrapping_sub(syn_end) as usize;
+        let data = item.packet.payload();
+        dbg!("syn data acked", acked, data.len());
+        #[cfg(feature = "stream-hash")]
+        socket.sent_stream.update(&data[..acked]);
+        socket.unsent.splice(0..0, data[acked..].iter().copied());
+        socket.send_param.next = socket.send_param.unacked_seq;
+    }
+
+    /// SYN_SENTでACKのないSYNを受け取った. 相手も同時にこちらへconnectしている(RFC 793 Figure 8)
+    /// SYN_RCVDへ遷移してSYN/ACKを返し, 相手からのSYN/ACKが届いたらsynrcvd_handlerでESTABLISHEDになる
+    fn simultaneous_open(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        dbg!("simultaneous open");
+        socket.recv_param.next = packet.get_seq().wrapping_add(1);
+        socket.recv_param.initial_seq = packet.get_seq();
+        socket.send_param.init_window(
+            packet.get_seq(),
+            socket.send_param.initial_seq,
+            packet.get_window_size() as u32,
+        );
+        socket.negotiate_options(packet);
+        socket.set_status(TcpStatus::SynRcvd);
+
+        // SYNと同じシーケンス番号でSYN/ACKを送り直す. 以降はSYNの代わりにこれをハンドシェイクのタイマーで再送する
+        socket.retransmission_queue.clear();
+        socket.send_tcp_packet(
+            socket.send_param.initial_seq,
+            socket.recv_param.next,
+            TcpFlags::SYN | TcpFlags::ACK,
+            &[],
+        )?;
+        dbg!("status: synsent ->", &socket.status);
+        Ok(())
+    }
+
     // FINWAIT1 or FINWAIT2状態のソケットに到着したパケットの処理
     // アクティブクローズ(サーバ側)
     fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
         dbg!("finwait handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmissio_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            // 未送信セグメントに対するackは破棄
+        if !self.process_ack(socket, packet) {
             return Ok(());
         }
 
-        if packet.get_flag() & tcpflags::ACK == 0 {
+        if !packet.get_flag().has_ack() {
             // ACKが立ってないパケットは破棄
             return Ok(());
         }
+        self.process_ecn_echo(socket, packet);
+
+        let has_fin = packet.get_flag().has_fin();
+        if has_fin && fin_disposition(socket.recv_param.next, packet) == FinDisposition::Duplicate {
+            // 再送されてきたFIN. データもFINも処理済みなので, ACKが届かなかったと考えて返し直すだけにする
+            dbg!("retransmitted FIN");
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                TcpFlags::ACK,
+                &[],
+            )?;
+            return Ok(());
+        }
 
         if !packet.payload().is_empty() {
+            // 送信側をshutdownした後もrecvで読めるよう, ESTABLISHEDと同じく受信バッファに溜める
             self.process_payload(socket, packet)?;
+            if socket.discards_received() {
+                socket.discard_received();
+            }
         }
 
-        if socket.status == TcpStatus::FinWait1
-            && socket.send_param.next == socket.send_param.unacked_seq
-        {
-            // 送信したFINがackされていなければFinWait2へ遷移
-            socket.status = TcpStatus::FinWait2;
-            dbg!("status: finwait1 ->", &socket.status);
+        if socket.control.fin_acked {
+            match socket.status {
+                // 送信したFINがackされたのでFinWait2へ遷移
+                TcpStatus::FinWait1 => socket.set_status(TcpStatus::FinWait2),
+                // 同時クローズで, 相手のFINを受け取った後にこちらのFINもackされた
+                TcpStatus::Closing => self.enter_time_wait(socket),
+                _ => {}
+            }
+            dbg!("fin acked ->", &socket.status);
         }
 
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            // 本来はCLOSING stateも考慮する必要があるが複雑になるので省略する
-            socket.recv_param.next += 1;
+        // ペイロードは上で処理済みなので, FINの前のデータが揃っていればRCV.NXTはFINの位置にある
+        // FINがデータを運んでいても, RCV.NXTはデータの分とFINの1だけ進む
+        if receive_fin(socket, packet) {
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
-                tcpflags::ACK,
+                TcpFlags::ACK,
                 &[],
             )?;
-            self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+            if socket.status == TcpStatus::FinWait1 {
+                // こちらのFINがまだackされていない(送信バッファの後ろで送られるのを待っている場合も含む)
+                // 同時クローズなのでCLOSINGへ進み, FINがackされた時にTIME_WAITへ進む. RFC 793
+                socket.set_status(TcpStatus::Closing);
+                dbg!("status: finwait1 ->", &socket.status);
+            } else {
+                self.enter_time_wait(socket);
+            }
+            // 送信側をshutdownした後にrecvで待っていれば0を返させる
+            socket.events.publish(TCPEventKind::DataArrived);
+        } else if has_fin {
+            // FINより前のデータがまだ届いていない. 穴が埋まった時点で受け付ける
+            dbg!("out of order FIN");
         }
 
         Ok(())
     }
 
-    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("closewiat | lastack handler");
-        socket.send_param.unacked_seq = packet.get_ack();
+    /// 最後のACKが届かなかった時に再送されてくるFINに応えられるよう, 2MSLの間はソケットを残す
+    fn enter_time_wait(&self, socket: &mut Socket) {
+        socket.set_status(TcpStatus::TimeWait);
+        socket.closing_deadline = Some(self.clock.now() + MSL * 2);
+        socket.events.publish(TCPEventKind::ConnectionClosed);
+    }
+
+    fn timewait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        dbg!("timewait handler");
+        if !packet.get_flag().has_fin() {
+            return Ok(());
+        }
+
+        // 相手にACKが届かずFINが再送されてきた. ACKを返し直して2MSLのタイマーをやり直す
+        dbg!("retransmitted FIN in TIME_WAIT");
+        socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            TcpFlags::ACK,
+            &[],
+        )?;
+        socket.closing_deadline = Some(self.clock.now() + MSL * 2);
         Ok(())
     }
 
-    fn select_unused_port(&self, rng: &mut ThreadRng) -> Result<u16> {
-        for _ in 0..(PORT_RANGE.end - PORT_RANGE.start) {
-            let local_port = rng.gen_range(PORT_RANGE);
+    // CLOSEWAIT or LASTACK状態のソケットに到着したパケットの処理
+    // 相手は送信側を閉じているが, こちらはCLOSE_WAITの間もデータを送り続けられるので, ESTABLISHEDと同じようにackを処理する
+    fn close_handler(
+        &self,
+        sockets: &mut HashMap<SockID, Socket>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        dbg!("closewiat | lastack handler");
+        let socket = sockets
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if !packet.get_flag().has_ack() {
+            // ACKが立ってないパケットは破棄
+            return Ok(());
+        }
+
+        if !self.process_ack(socket, packet) {
+            return Ok(());
+        }
+        self.process_ecn_echo(socket, packet);
 
-            let sockets = self.sockets.read().unwrap();
-            if sockets
-                .keys()
-                .all(|sock_id| local_port != sock_id.local_port)
-            {
-                return Ok(local_port);
-            }
+        if packet.get_flag().has_fin() {
+            // 相手のFINは受信済み. 再送されてきたのはACKが届かなかったからなので返し直す
+            socket.ack_pending = true;
         }
 
-        anyhow::bail!("no available port found");
+        // LAST_ACKで送ったFINがackされればCLOSEDになる(closeで待っているスレッドはprocess_ackで起こされる)
+        // アプリケーションが既にcloseしていれば, タイマーを待たずにここで削除する
+        if socket.status == TcpStatus::LastAck
+            && socket.control.fin_acked
+            && socket.closing_deadline.is_some()
+        {
+            dbg!("status: lastack -> closed", sock_id);
+            self.remove_socket(sockets, sock_id);
+        }
+        Ok(())
     }
 
-    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) {
-        let (lock, cvar) = &self.event_condvar;
-        let mut event = lock.lock().unwrap();
+    /// チャレンジACK(現在のSND.NXTとRCV.NXTのACK)を返す. TcpConfig::challenge_ack_limitを超えていれば返さない
+    fn send_challenge_ack(&self, socket: &mut Socket) {
+        if !self.challenge_acks.lock().recover().allow(self.clock.now()) {
+            dbg!("challenge ack suppressed");
+            self.counters.record_challenge_ack_suppressed();
+            return;
+        }
+        self.counters.record_challenge_ack();
+        if let Err(error) = socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            TcpFlags::ACK,
+            &[],
+        ) {
+            dbg!(error);
+        }
+    }
 
-        // cvar.waitで次のイベントの変更通知(notify_all)を待ち、通知がきたらまた次に進む
-        // 対象となるsocketが目的の状態(TCPEventKind)になったらeventをNoneにして終了する
-        loop {
-            dbg!("wait event...");
-            if let Some(ref tcp_event) = *event {
-                dbg!("match the event sock waited for! break!");
-                if tcp_event.sock_id == sock_id && tcp_event.kind == kind {
-                    break;
-                }
+    /// 受信したパケットに対してRSTを返す. ackがSomeの場合はACKフラグも立てる
+    /// リスニングソケットや存在しない接続宛てのパケットにも返せるよう, ソケットを介さずに送信する
+    fn send_reset(
+        &self,
+        local_addr: Addr,
+        remote_addr: Addr,
+        packet: &TCPPacket,
+        seq: u32,
+        ack: Option<u32>,
+    ) -> Result<()> {
+        let mut rst_packet = TCPPacket::new(0);
+        rst_packet.set_src(packet.get_dest());
+        rst_packet.set_dest(packet.get_src());
+        rst_packet.set_seq(seq);
+        rst_packet.set_data_offset(5);
+        match ack {
+            Some(ack) => {
+                rst_packet.set_ack(ack);
+                rst_packet.set_flag(TcpFlags::RST | TcpFlags::ACK);
             }
+            None => rst_packet.set_flag(TcpFlags::RST),
+        }
+        if let Some(filter) = &self.config.egress_filter {
+            if !filter.allows(&SegmentInfo::outgoing(local_addr, remote_addr, &rst_packet)) {
+                dbg!("blocked by egress filter");
+                return Ok(());
+            }
+        }
+
+        self.device
+            .send(&rst_packet, local_addr, remote_addr)
+            .context("failed to send RST")?;
+        Ok(())
+    }
+
+    /// どのソケットにも該当しないセグメントにRSTを返してよいポートか
+    /// rawソケットではカーネルのTCPスタック宛てのセグメントも見えてしまうため, このスタックが払い出すポート宛てにだけ返す
+    fn owns_port(&self, port: u16) -> bool {
+        self.config.backend == Backend::Loopback || PORT_RANGE.contains(&port)
+    }
 
-            // cvarがnotifyされるまでeventのロックを外して待機
-            dbg!("cvar wait...");
-            event = cvar.wait(event).unwrap();
+    /// 接続用のソケットにスタックの設定を反映する
+    /// イベントログが有効であれば接続用のログファイルを開く
+    fn prepare_socket(&self, socket: &mut Socket) -> Result<()> {
+        if let Some(dir) = &self.config.event_log_dir {
+            socket.event_log = Some(EventLog::create(dir, socket.get_sock_id())?);
         }
+        socket.egress_filter = self.config.egress_filter.clone();
+        socket.recv_wscale = self.recv_window_scale()?;
+        socket.set_recv_buffer_size(self.config.recv_buffer_size);
+        socket.autotune = self.config.recv_buffer_autotune.map(RecvAutoTune::new);
+        socket.send_buffer_size = self.config.send_buffer_size;
+        socket.recv_watermarks = self.config.recv_buffer_watermarks;
+        socket.send_param.cwnd = self.initial_cwnd()?;
+        socket.capabilities = self.config.capabilities;
+        socket.rtt.set_max_rto(self.config.max_rto);
+        Ok(())
+    }
+
+    /// 接続のISN. 4つ組毎に推測できない位置から始め, 時刻と共に進める(RFC 6528)
+    fn initial_seq(&self, sock_id: SockID) -> u32 {
+        self.isns
+            .generate(sock_id.local, sock_id.remote, self.clock.now())
+    }
 
-        dbg!(&event);
-        *event = None;
+    /// TcpConfig::recv_buffer_sizeから決めた, 広告するウィンドウスケールのシフト数
+    /// 受信バッファを自動で広げる場合は, 広げられる上限の大きさから決める
+    /// 設定の組み合わせが正しくなければエラーを返す
+    fn recv_window_scale(&self) -> Result<u8> {
+        let buffer_size = cmp::max(
+            self.config.recv_buffer_size,
+            self.config.recv_buffer_autotune.unwrap_or(0),
+        );
+        flowcontrol::window_scale_for(buffer_size, self.config.capabilities.window_scale)
+            .context("invalid recv_buffer_size")
     }
 
-    /// 指定のソケットIDにイベントを発行する
-    fn publish_event(&self, sock_id: SockID, kind: TCPEventKind) {
-        let (lock, cvar) = &self.event_condvar;
-        let mut e = lock.lock().unwrap();
-        *e = Some(TCPEvent::new(sock_id, kind));
-        cvar.notify_all();
+    /// 初期輻輳ウィンドウのバイト数. cwndはu32なので収まらない設定はエラーにする
+    fn initial_cwnd(&self) -> Result<u32> {
+        self.config
+            .initial_window
+            .checked_mul(MSS)
+            .and_then(|cwnd| u32::try_from(cwnd).ok())
+            .context("invalid initial_window")
+    }
+
+    /// eventsにkindが発行されるまで待機する. deadline(スタックの時計の時刻)を過ぎたらTimedOutを返す
+    /// 決定的モードでは待っていても誰も進めてくれないので, ブロックせずにエラーを返す
+    fn wait_event(
+        &self,
+        events: &SocketEvents,
+        kind: TCPEventKind,
+        deadline: Option<SystemTime>,
+    ) -> Result<()> {
+        if deadline.is_some_and(|deadline| self.clock.now() >= deadline) {
+            return Err(TimedOut.into());
+        }
+        if self.config.deterministic {
+            bail!("operation would block: drive the stack with poll_receive/advance_time");
+        }
+        let deadline = deadline.map(|deadline| Instant::now() + self.clock.until(deadline));
+        if !events.wait(kind, deadline)? {
+            return Err(TimedOut.into());
+        }
+        Ok(())
+    }
+
+    /// TcpConfig::port_allocatorでlocal_addrからremoteへの接続に使うローカルポートを選ぶ
+    fn select_unused_port(&self, local_addr: Addr, remote: DefaultEndpoint) -> Result<u16> {
+        let sockets = self.sockets.read().recover();
+        let in_use = |port: u16| sockets.keys().any(|sock_id| port == sock_id.local.port());
+        self.config
+            .port_allocator
+            .allocate(local_addr, remote, &in_use)
+            .context("no available port found")
     }
 
     /// タイマースレッド用の関数
-    /// 全てのソケットの再送キューを見て、タイムアウトしているパケットを再送する
     fn timer(&self) {
         dbg!("begin timer thread");
 
         loop {
-            let mut sockets = self.sockets.write().unwrap();
-            for (sock_id, socket) in sockets.iter_mut() {
-                // queueからpopしながら中でpush_backもしてiterateしているためあまりいい実装ではなさそう
-                // もう少し良い実装を検討してもいいかもしれない
-                while let Some(mut item) = socket.retransmission_queue.pop_front() {
-                    // 再送キューからackされたセグメントを除去する
-                    // established state以外の時に送信されたセグメントを除去するために必要
-                    if socket.send_param.unacked_seq > item.packet.get_seq() {
-                        dbg!("successfully acked", item.packet.get_seq());
-                        socket.send_param.window += item.packet.payload().len() as u16;
-                        self.publish_event(*sock_id, TCPEventKind::Acked);
-
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && socket.status == TcpStatus::LastAck
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
-                        continue;
-                    }
+            self.run_timers();
+            thread::sleep(Duration::from_millis(100));
+            self.wakeups.record_wakeup(Subsystem::Timer);
+        }
+    }
 
-                    // タイムアウトを確認
-                    if item.latest_transmission_time.elapsed().unwrap()
-                        < Duration::from_secs(RETRANSMITTION_TIMEOUT)
-                    {
-                        // 取り出したエントリがタイムアウトしてないなら、以降のキューのエントリもタイムアウトしてない
-                        // 先頭に戻す
-                        socket.retransmission_queue.push_front(item);
-                        break;
-                    }
+    /// 全てのソケットの再送キューを見て、タイムアウトしているパケットを再送する
+    /// ハンドシェイク中のソケットはhandshake_timerが受け持つのでここでは見ない
+    /// 1つのソケットが1回に再送するのは1セグメントまでで, 全体でもretransmit_budget個までに抑える
+    /// 多数のソケットで同時にロスが起きても再送がバーストにならず, 残りは次のタイマーに回される
+    fn run_timers(&self) {
+        let mut sockets = self.lock_sockets(Subsystem::Timer);
+        // 1つのソケットの処理がパニックしてもタイマースレッドは止めず, そのソケットだけを中断する
+        let mut due = Vec::new();
+        let mut panicked = Vec::new();
+        for socket in sockets.values_mut() {
+            let sock_id = socket.get_sock_id();
+            match panic::catch_unwind(AssertUnwindSafe(|| self.retransmission_due(socket))) {
+                Ok(Some(deadline)) => due.push((deadline, sock_id)),
+                Ok(None) => {}
+                Err(_) => panicked.push(sock_id),
+            }
+        }
 
-                    // ackされてなければ再送
-                    if item.transmission_count < MAX_TRANSMITTION {
-                        // 再送
-                        dbg!("retransmit");
-
-                        socket
-                            .sender
-                            .send_to(item.packet.clone(), IpAddr::V4(socket.sock_id.remote_addr))
-                            .context("failed to retransmit")
-                            .unwrap();
-
-                        item.transmission_count += 1;
-                        item.latest_transmission_time = SystemTime::now();
-                        socket.retransmission_queue.push_back(item);
-                        break;
-                    } else {
-                        dbg!("reached MAX_TRANSMISSION");
-
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && (socket.status == TcpStatus::LastAck
-                                || socket.status == TcpStatus::FinWait1
-                                || socket.status == TcpStatus::FinWait2)
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
-                    }
-                }
+        // 長く待たされているソケットから順に再送する
+        due.sort();
+        let mut exhausted = Vec::new();
+        for (i, (_, sock_id)) in due.into_iter().enumerate() {
+            if i >= self.config.retransmit_budget {
+                self.counters.record_deferred_retransmission();
+                continue;
             }
-            // ロックを外して待機
-            drop(sockets);
-            thread::sleep(Duration::from_millis(100));
+            let socket = sockets.get_mut(&sock_id).unwrap();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let exhausted = self.retransmit_expired(socket);
+                socket.debug_check_retransmission_queue();
+                exhausted
+            }));
+            match result {
+                Ok(true) => exhausted.push(sock_id),
+                Ok(false) => {}
+                Err(_) => panicked.push(sock_id),
+            }
+        }
+        for sock_id in exhausted {
+            self.remove_socket(&mut sockets, sock_id);
         }
+        for sock_id in panicked {
+            self.abort_panicked(&mut sockets, sock_id);
+        }
+        self.evict_idle_sockets(&mut sockets);
+        self.send_keepalive_probes(&mut sockets);
+        self.send_window_probes(&mut sockets);
+        self.abort_stalled_sockets(&mut sockets);
+        self.reap_closing_sockets(&mut sockets);
     }
 
-    /// パケットのペイロードを受信バッファにコピーする
-    fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        // バッファにおける読み込みの先頭位置
-        dbg!(socket.recv_param.next);
-        dbg!(packet.get_seq());
+    /// 再送キューを掃除し, 先頭のセグメントがタイムアウトしていればその時刻を返す
+    fn retransmission_due(&self, socket: &mut Socket) -> Option<SystemTime> {
+        self.collect_stale_retransmissions(socket);
+        if let TcpStatus::SynSent | TcpStatus::SynRcvd = socket.status {
+            return None;
+        }
+        self.remove_acked_retransmissions(socket);
 
-        let offset = socket.recv_buffer.len() - socket.recv_param.window as usize
-            + (packet.get_seq() - socket.recv_param.next) as usize;
+        let rto = socket.rtt.rto();
+        let item = socket.retransmission_queue.front()?;
+        if self.clock.since(item.latest_transmission_time) < rto {
+            return None;
+        }
+        Some(item.latest_transmission_time + rto)
+    }
 
-        let copy_size = cmp::min(packet.payload().len(), socket.recv_buffer.len() - offset);
-        socket.recv_buffer[offset..offset + copy_size]
-            .copy_from_slice(&packet.payload()[..copy_size]);
+    /// タイムアウトしているセグメントを1つ再送する
+    /// 再送の上限に達して接続を中断すべき場合はtrueを返す
+    fn retransmit_expired(&self, socket: &mut Socket) -> bool {
+        // queueからpopしながら中でpush_backもしてiterateしているためあまりいい実装ではなさそう
+        // もう少し良い実装を検討してもいいかもしれない
+        while let Some(mut item) = socket.retransmission_queue.pop_front() {
+            // タイムアウトを確認
+            if self.clock.since(item.latest_transmission_time) < socket.rtt.rto() {
+                // 取り出したエントリがタイムアウトしてないなら、以降のキューのエントリもタイムアウトしてない
+                // 先頭に戻す
+                socket.retransmission_queue.push_front(item);
+                break;
+            }
 
-        // ロス再送の際に穴埋めされるためにmaxを取る
-        socket.recv_param.tail =
-            cmp::max(socket.recv_param.tail, packet.get_seq() + copy_size as u32);
+            if item.sacked {
+                // 相手は受信済みなので再送せず, 穴になっている他のセグメントを先に再送する
+                self.counters.record_sack_skipped_retransmission();
+                item.latest_transmission_time = self.clock.now();
+                socket.retransmission_queue.push_back(item);
+                continue;
+            }
 
-        dbg!(offset);
-        if packet.get_seq() == socket.recv_param.next {
-            // packetの順番が入れ替わってない場合のみrecv_param.nextを進められる
-            socket.recv_param.next = socket.recv_param.tail;
-            socket.recv_param.window -= (socket.recv_param.tail - packet.get_seq()) as u16;
-        }
+            // ackされてなければ再送
+            if item.transmission_count < MAX_TRANSMITTION {
+                // 再送
+                dbg!("retransmit");
 
-        if copy_size > 0 {
-            // 受信バッファにコピーが成功(受信バッファにまだ余裕がある場合とも言える)
-            socket.send_tcp_packet(
-                socket.send_param.next,
+                // 輻輳が起きたとみなして輻輳ウィンドウを1セグメントまで縮める
+                // 同じロスで続けてタイムアウトした場合はssthreshをさらに下げない. RFC 5681
+                if socket.cwnd_before_rto.is_none() {
+                    socket.cwnd_before_rto =
+                        Some((socket.send_param.cwnd, socket.send_param.ssthresh));
+                    socket.send_param.on_loss(MSS);
+                }
+                socket.send_param.cwnd = MSS as u32;
+                socket.log_event(LogEvent::TimerFired {
+                    timer: "retransmission".to_string(),
+                });
+                socket.log_event(LogEvent::SegmentRetransmitted(SegmentRecord::from(
+                    &item.packet,
+                )));
+
+                // 再送するセグメントにはECTを付けない. RFC 3168
+                item.packet.set_ecn(Ecn::NotEct);
+                socket
+                    .transmit(&item.packet)
+                    .context("failed to retransmit")
+                    .unwrap();
+                // 先頭の未ackのセグメントがタイムアウトする度にRTOを倍にする
+                if SeqNum(item.packet.get_seq()).leq(SeqNum(socket.send_param.unacked_seq)) {
+                    socket.rtt.on_timeout();
+                }
+
+                item.transmission_count += 1;
+                item.latest_transmission_time = self.clock.now();
+                socket.retransmission_queue.push_back(item);
+                return false;
+            } else {
+                // バックオフしながら再送しても届かないので, 経路が切れたとみなして接続を中断する
+                dbg!("reached MAX_TRANSMISSION");
+                socket.close_reason = Some(CloseReason::RetransmissionExhausted);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// 再送キューからackされたセグメントを除去する
+    /// established state以外の時に送信されたセグメントを除去するために必要
+    /// 再送したエントリはキューの末尾に回されるので, ackされたエントリが先頭に並んでいるとは限らない
+    fn remove_acked_retransmissions(&self, socket: &mut Socket) {
+        let unacked_seq = socket.send_param.unacked_seq;
+        let (acked, remaining): (VecDeque<_>, VecDeque<_>) = socket
+            .retransmission_queue
+            .drain(..)
+            .partition(|item| item.is_acked(unacked_seq));
+        socket.retransmission_queue = remaining;
+
+        for item in acked {
+            dbg!("successfully acked", item.packet.get_seq());
+            socket.events.publish(TCPEventKind::Acked);
+        }
+        self.update_control_segments(socket);
+    }
+
+    /// SND.UNAが進んだ時に, 送ったSYNとFINがackされたかを更新する
+    /// LAST_ACKでFINがackされたらcloseを起こす
+    fn update_control_segments(&self, socket: &mut Socket) {
+        let (_, fin_acked) = socket
+            .control
+            .on_ack(socket.send_param.initial_seq, socket.send_param.unacked_seq);
+        if fin_acked && socket.status == TcpStatus::LastAck {
+            socket.events.publish(TCPEventKind::ConnectionClosed);
+        }
+    }
+
+    /// 再送の上限に達するより長くキューに残っているエントリを取り除く
+    /// ackの計算がずれたり, 中断されたconnectのSYNが残ったりした場合に, キューが掃除されないままになるのを防ぐ
+    fn collect_stale_retransmissions(&self, socket: &mut Socket) {
+        let max_age = retransmission_entry_max_age();
+        let now = self.clock.now();
+        let unacked_seq = socket.send_param.unacked_seq;
+        let mut stale = Vec::new();
+        socket.retransmission_queue.retain(|item| {
+            // ackされたエントリはこの後の再送タイマーの処理で取り除かれる
+            let is_stale = !item.is_acked(unacked_seq)
+                && now
+                    .duration_since(item.queued_at)
+                    .is_ok_and(|age| age > max_age);
+            if is_stale {
+                stale.push(item.packet.clone());
+            }
+            !is_stale
+        });
+
+        for packet in stale {
+            dbg!("dropping stale retransmission entry");
+            dbg!(
+                socket.sock_id,
+                socket.status,
+                packet.get_seq(),
+                packet.get_flag()
+            );
+            self.counters.record_stale_retransmission();
+        }
+    }
+
+    /// TcpConfig::conntrack_exportが指定されている場合に, conntrackを定期的に書き出すスレッドの関数
+    fn conntrack_exporter(&self, export: ConntrackExport) {
+        loop {
+            if let Err(error) = self.conntrack().write_file(&export.path) {
+                dbg!(error);
+            }
+            thread::sleep(export.interval);
+        }
+    }
+
+    /// ハンドシェイク用のタイマースレッドの関数
+    /// 期限を迎えたソケットがある時だけsocketsのロックを取る
+    fn handshake_timer(&self) {
+        dbg!("begin handshake timer thread");
+
+        loop {
+            let expired = self.handshake_timers.wait_expired(&self.clock);
+            self.wakeups.record_wakeup(Subsystem::HandshakeTimer);
+            let mut sockets = self.lock_sockets(Subsystem::HandshakeTimer);
+            for sock_id in expired {
+                self.retransmit_handshake(&mut sockets, sock_id);
+            }
+        }
+    }
+
+    /// SYN or SYN/ACKを再送し, 次のタイマーを設定する
+    /// 再送の上限に達していればソケットを削除する. connectで待機しているスレッドにはエラーが返る
+    fn retransmit_handshake(&self, sockets: &mut HashMap<SockID, Socket>, sock_id: SockID) {
+        let socket = match sockets.get_mut(&sock_id) {
+            Some(socket) => socket,
+            None => return,
+        };
+        // 既にハンドシェイクが終わっていれば後は通常の再送タイマーに任せる
+        if !matches!(socket.status, TcpStatus::SynSent | TcpStatus::SynRcvd)
+            || socket.control.syn_acked
+        {
+            return;
+        }
+        let mut item = match socket.retransmission_queue.pop_front() {
+            Some(item) => item,
+            None => return,
+        };
+
+        // SYN/ACKはRTTの推定値を使わず, 専用の間隔と上限で再送する
+        let passive = socket.status == TcpStatus::SynRcvd;
+        let (timeout, exhausted) = if passive {
+            (
+                self.synack_timeout(item.transmission_count),
+                item.transmission_count > self.config.synack_retries,
+            )
+        } else {
+            (
+                socket.rtt.rto(),
+                item.transmission_count > self.config.syn_retries,
+            )
+        };
+
+        // 古いタイマーが残っている場合もあるので, 本当にタイムアウトしているか確認する
+        let elapsed = self.clock.since(item.latest_transmission_time);
+        if elapsed < timeout {
+            socket.retransmission_queue.push_front(item);
+            self.handshake_timers
+                .schedule(sock_id, self.clock.now() + (timeout - elapsed));
+            return;
+        }
+
+        if exhausted {
+            dbg!("handshake retries exhausted", sock_id);
+            // 待っているconnectにはremove_socketで"connection timed out"を返す
+            socket.close_reason = Some(CloseReason::RetransmissionExhausted);
+            let listener = socket.listening_socket;
+            self.counters.record_handshake_failed();
+            self.remove_socket(sockets, sock_id);
+            if let Some(listener) = listener.and_then(|listener| sockets.get_mut(&listener)) {
+                listener.listener_stats.half_open_timeouts += 1;
+            }
+            return;
+        }
+
+        dbg!("retransmit handshake", sock_id);
+        socket.log_event(LogEvent::TimerFired {
+            timer: "handshake".to_string(),
+        });
+        socket.log_event(LogEvent::SegmentRetransmitted(SegmentRecord::from(
+            &item.packet,
+        )));
+        if let Err(error) = socket.transmit(&item.packet) {
+            dbg!(error);
+        }
+
+        item.transmission_count += 1;
+        item.latest_transmission_time = self.clock.now();
+        let next_timeout = if passive {
+            self.synack_timeout(item.transmission_count)
+        } else {
+            socket.rtt.on_timeout();
+            socket.rtt.rto()
+        };
+        socket.retransmission_queue.push_front(item);
+        self.handshake_timers
+            .schedule(sock_id, self.clock.now() + next_timeout);
+
+        if let Some(listener) = socket
+            .listening_socket
+            .and_then(|listener| sockets.get_mut(&listener))
+        {
+            listener.listener_stats.synack_retransmissions += 1;
+        }
+    }
+
+    /// 再送されてきたSYNに, 再送キューにあるSYN/ACKをそのまま送り直す. ISNも最初のSYN/ACKと同じになる
+    /// SYN/ACKの再送として数え, 再送タイマーの間隔と上限にも含める
+    fn resend_synack(&self, sockets: &mut HashMap<SockID, Socket>, sock_id: SockID) {
+        let socket = match sockets.get_mut(&sock_id) {
+            Some(socket) => socket,
+            None => return,
+        };
+        let mut item = match socket.retransmission_queue.pop_front() {
+            Some(item) => item,
+            None => return,
+        };
+        // 同時オープンで送ったSYNには応えない. 上限まで再送したSYN/ACKは再送タイマーが削除する
+        if !item
+            .packet
+            .get_flag()
+            .contains(TcpFlags::SYN | TcpFlags::ACK)
+            || item.transmission_count > self.config.synack_retries
+        {
+            socket.retransmission_queue.push_front(item);
+            return;
+        }
+
+        dbg!("resend SYN/ACK for retransmitted SYN", sock_id);
+        socket.log_event(LogEvent::SegmentRetransmitted(SegmentRecord::from(
+            &item.packet,
+        )));
+        if let Err(error) = socket.transmit(&item.packet) {
+            dbg!(error);
+        }
+        // 古い再送タイマーは, 発火した時に次の間隔まで延ばされる
+        item.transmission_count += 1;
+        item.latest_transmission_time = self.clock.now();
+        socket.retransmission_queue.push_front(item);
+
+        if let Some(listener) = socket
+            .listening_socket
+            .and_then(|listener| sockets.get_mut(&listener))
+        {
+            listener.listener_stats.synack_retransmissions += 1;
+        }
+    }
+
+    /// transmission_count回目に送ったSYN/ACKの再送までの時間. INITIAL_RTOから倍にしていき, max_rtoで頭打ちにする
+    fn synack_timeout(&self, transmission_count: u8) -> Duration {
+        let backoff = 1u32 << cmp::min(transmission_count.saturating_sub(1), 16);
+        cmp::min(INITIAL_RTO * backoff, self.config.max_rto)
+    }
+
+    /// idle_timeoutを過ぎても通信のない接続を切断する
+    /// RSTの場合は即座に削除し, FINの場合はFINがackされるか猶予期間(idle_timeoutと同じ時間)が過ぎたら削除する
+    fn evict_idle_sockets(&self, sockets: &mut HashMap<SockID, Socket>) {
+        let mut idle = Vec::new();
+
+        for (sock_id, socket) in sockets.iter() {
+            let timeout = match socket.idle_timeout {
+                Some(timeout) => timeout,
+                None => continue,
+            };
+
+            match socket.status {
+                TcpStatus::Established | TcpStatus::CloseWait => {}
+                _ => continue,
+            }
+            if socket.closing_deadline.is_some()
+                || self.clock.since(socket.last_activity) < timeout.duration
+            {
+                continue;
+            }
+
+            dbg!("idle timeout", sock_id);
+            idle.push((*sock_id, timeout));
+        }
+
+        for (sock_id, timeout) in idle {
+            let mode = match timeout.action {
+                IdleAction::Fin => CloseMode::Graceful,
+                IdleAction::Reset => CloseMode::Abort,
+            };
+            self.force_close(sockets, sock_id, mode, timeout.duration);
+        }
+    }
+
+    /// 一定時間何も受信していない接続にキープアライブのプローブを送る
+    /// プローブはSND.NXT-1をシーケンス番号にした空のACKで, 相手はウィンドウ外のセグメントとしてACKを返す
+    /// probes個送っても応答がなければ相手が消えたとみなして中断する. ブロックしているAPIにはエラーが返る
+    fn send_keepalive_probes(&self, sockets: &mut HashMap<SockID, Socket>) {
+        let mut dead = Vec::new();
+
+        for socket in sockets.values_mut() {
+            let keepalive = match socket.keepalive {
+                Some(keepalive) => keepalive,
+                None => continue,
+            };
+            match socket.status {
+                TcpStatus::Established | TcpStatus::CloseWait => {}
+                _ => continue,
+            }
+            // ackを待っているセグメントがあれば, 相手の不在は再送タイマーが検出する
+            if !socket.retransmission_queue.is_empty() {
+                continue;
+            }
+            let next_probe = keepalive.idle + keepalive.interval * socket.keepalive_probes_sent;
+            if self.clock.since(socket.last_received) < next_probe {
+                continue;
+            }
+
+            if socket.keepalive_probes_sent >= keepalive.probes {
+                dbg!("keepalive timeout", socket.sock_id);
+                socket.close_reason = Some(CloseReason::KeepAliveTimeout);
+                dead.push(socket.get_sock_id());
+                continue;
+            }
+
+            socket.log_event(LogEvent::TimerFired {
+                timer: "keepalive".to_string(),
+            });
+            if let Err(error) = socket.send_tcp_packet(
+                socket.send_param.next.wrapping_sub(1),
                 socket.recv_param.next,
-                tcpflags::ACK,
+                TcpFlags::ACK,
                 &[],
-            )?;
+            ) {
+                dbg!(error);
+            }
+            socket.keepalive_probes_sent += 1;
+            self.counters.record_keepalive_probe();
+        }
+
+        for sock_id in dead {
+            self.remove_socket(sockets, sock_id);
+        }
+    }
+
+    /// パーシストタイマー(RFC 1122 4.2.2.17). 送信バッファのデータが相手のウィンドウが開くのを待っている間, RTOから倍にしていく間隔でプローブを送る
+    /// プローブはキープアライブと同じく受信済みのシーケンス番号のACKで, 相手は現在のウィンドウを載せたACKを返してくる
+    /// 送信中のデータがあれば, その再送がプローブの代わりになる
+    fn send_window_probes(&self, sockets: &mut HashMap<SockID, Socket>) {
+        for socket in sockets.values_mut() {
+            let deadline = match socket.persist_deadline {
+                Some(deadline) => deadline,
+                None => continue,
+            };
+            // FIN_WAIT_1, CLOSING, LAST_ACKでも, FINの前の送信バッファのデータを送れずにいる間はプローブを送る
+            if !matches!(
+                socket.status,
+                TcpStatus::Established
+                    | TcpStatus::CloseWait
+                    | TcpStatus::FinWait1
+                    | TcpStatus::Closing
+                    | TcpStatus::LastAck
+            ) || !socket.retransmission_queue.is_empty()
+                || self.clock.now() < deadline
+            {
+                continue;
+            }
+
+            socket.log_event(LogEvent::TimerFired {
+                timer: "persist".to_string(),
+            });
+            if let Err(error) = socket.send_tcp_packet(
+                socket.send_param.next.wrapping_sub(1),
+                socket.recv_param.next,
+                TcpFlags::ACK,
+                &[],
+            ) {
+                dbg!(error);
+            }
+            socket.window_probes_sent += 1;
+            let interval = socket
+                .rtt
+                .rto()
+                .saturating_mul(1 << cmp::min(socket.window_probes_sent, 6));
+            socket.persist_deadline =
+                Some(self.clock.now() + cmp::min(interval, self.config.max_rto));
+            self.counters.record_window_probe();
+        }
+    }
+
+    /// ユーザータイムアウト(RFC 5482). 相手からの応答を待ち始めてから何も受信しないままuser_timeoutが過ぎた接続を中断する
+    /// 再送の上限やキープアライブより先に, アプリケーションが決めた時間で相手が消えたことを検出できる
+    /// パーシストタイマーのプローブには上限がないので, ゼロウィンドウのまま相手が消えた接続もここで中断する
+    fn abort_stalled_sockets(&self, sockets: &mut HashMap<SockID, Socket>) {
+        let now = self.clock.now();
+        let mut stalled = Vec::new();
+
+        for socket in sockets.values_mut() {
+            let timeout = match socket.user_timeout {
+                Some(timeout) => timeout,
+                None => continue,
+            };
+            // ハンドシェイク中はsyn_retries, synack_retriesで, TIME_WAITは2MSLで削除される
+            if matches!(
+                socket.status,
+                TcpStatus::Listen | TcpStatus::SynSent | TcpStatus::SynRcvd | TcpStatus::TimeWait
+            ) || !socket.awaits_peer()
+            {
+                socket.stalled_since = None;
+                continue;
+            }
+
+            // ackを待っているデータがあれば, 最初に送った時刻から待ち始めている
+            let started = socket
+                .retransmission_queue
+                .front()
+                .map_or(now, |item| item.queued_at);
+            let since = cmp::max(
+                *socket.stalled_since.get_or_insert(started),
+                socket.last_received,
+            );
+            if now.duration_since(since).unwrap_or_default() >= timeout {
+                dbg!("user timeout", socket.sock_id);
+                socket.close_reason = Some(CloseReason::UserTimeout);
+                stalled.push(socket.get_sock_id());
+            }
+        }
+
+        for sock_id in stalled {
+            self.remove_socket(sockets, sock_id);
+        }
+    }
+
+    /// force_closeでFINを送った接続のうち, FINがackされたか猶予期間が過ぎたものを削除する
+    /// TIME_WAITの接続もここで2MSL経ってから削除する
+    fn reap_closing_sockets(&self, sockets: &mut HashMap<SockID, Socket>) {
+        let now = self.clock.now();
+        let closed: Vec<SockID> = sockets
+            .values()
+            .filter(|socket| match (socket.status, socket.closing_deadline) {
+                (_, None) => false,
+                // TIME_WAITは2MSL経つまで, FIN_WAIT_2は相手からFINが届くまで残す
+                (TcpStatus::TimeWait | TcpStatus::FinWait2, Some(deadline)) => now >= deadline,
+                (_, Some(deadline)) => socket.control.fin_acked || now >= deadline,
+            })
+            .map(|socket| socket.get_sock_id())
+            .collect();
+
+        // ブロックしているAPIはremove_socketで起こされ, エラーが返る
+        for sock_id in closed {
+            if let Some(socket) = sockets.get_mut(&sock_id) {
+                if socket.status == TcpStatus::TimeWait {
+                    socket.close_reason = Some(CloseReason::TimeWaitReaped);
+                }
+            }
+            self.remove_socket(sockets, sock_id);
+        }
+    }
+
+    /// アプリケーションを介さずに接続を閉じる
+    /// Gracefulの場合はFINを送り, ackされるかgraceが過ぎたらreap_closing_socketsで削除する
+    /// ハンドシェイク中の接続はGracefulでもRSTを送って即座に削除する
+    fn force_close(
+        &self,
+        sockets: &mut HashMap<SockID, Socket>,
+        sock_id: SockID,
+        mode: CloseMode,
+        grace: Duration,
+    ) {
+        let socket = match sockets.get_mut(&sock_id) {
+            Some(socket) => socket,
+            None => return,
+        };
+
+        match (mode, socket.status) {
+            (_, TcpStatus::Listen) => {
+                socket.close_reason = Some(CloseReason::ListenerClosed);
+                self.abort_pending_children(sockets, sock_id);
+            }
+            (CloseMode::Graceful, TcpStatus::Established | TcpStatus::CloseWait) => {
+                if let Err(error) = self.send_fin(socket) {
+                    dbg!(error);
+                }
+                if socket.status == TcpStatus::Established {
+                    socket.set_status(TcpStatus::FinWait1);
+                } else {
+                    socket.set_status(TcpStatus::LastAck);
+                }
+                socket.closing_deadline = Some(self.clock.now() + grace);
+                return;
+            }
+            (
+                CloseMode::Graceful,
+                TcpStatus::FinWait1 | TcpStatus::FinWait2 | TcpStatus::Closing | TcpStatus::LastAck,
+            ) => {
+                // 既にFINを送っている
+                socket.closing_deadline = Some(self.clock.now() + grace);
+                return;
+            }
+            _ => {
+                if let Err(error) = socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    TcpFlags::RST | TcpFlags::ACK,
+                    &[],
+                ) {
+                    dbg!(error);
+                }
+                socket.close_reason = Some(CloseReason::ResetSent);
+            }
+        }
+        self.remove_socket(sockets, sock_id);
+    }
+
+    /// リスニングソケットを閉じる前に, まだacceptされていない接続(ハンドシェイク中のものを含む)をRSTで破棄する
+    /// acceptされた接続はリスニングソケットとのリンクが切れているので影響を受けない
+    fn abort_pending_children(
+        &self,
+        sockets: &mut HashMap<SockID, Socket>,
+        listening_socket_id: SockID,
+    ) {
+        let pending: Vec<SockID> = sockets
+            .values()
+            .filter(|socket| socket.listening_socket == Some(listening_socket_id))
+            .map(|socket| socket.get_sock_id())
+            .collect();
+
+        for sock_id in pending {
+            dbg!("abort pending connection", sock_id);
+            self.force_close(sockets, sock_id, CloseMode::Abort, Duration::ZERO);
+        }
+    }
+
+    /// パケットのペイロードを受信バッファにコピーする
+    fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        if socket.capabilities.ecn {
+            // 相手がcwndを縮めたのでECEを止める. 同じセグメントにCEが付いていればまた立てる
+            if packet.get_flag().has_cwr() {
+                socket.ece_pending = false;
+            }
+            if packet.get_ecn() == Ecn::Ce {
+                // 経路上で輻輳が起きている. 相手が早く気付けるようにすぐACKを返す
+                socket.ece_pending = true;
+                socket.ack_pending = true;
+            }
+        }
+
+        // バッファにおける読み込みの先頭位置
+        dbg!(socket.recv_param.next);
+        dbg!(packet.get_seq());
+
+        // 抜けの無いところに順番通りに届いた
+        let in_order = packet.get_seq() == socket.recv_param.next && socket.reassembly.is_empty();
+        // コピーする前に, 受信済みの左端と受信ウィンドウからはみ出した右端を切り捨てる
+        let range = match socket
+            .recv_param
+            .trim(packet.get_seq(), packet.payload().len())
+        {
+            Trimmed::Data(range) => range,
+            Trimmed::Duplicate => {
+                // 受信済みのデータの再送. ACKが届いていないかもしれないので返し直す
+                self.on_duplicate_segment(socket, packet);
+                return Ok(());
+            }
+            Trimmed::BeyondWindow => {
+                // 受信ウィンドウの外. 受け入れテストで破棄されているはずだが, 念のためACKでウィンドウを伝え直す
+                dbg!("segment beyond receive window");
+                socket.ack_pending = true;
+                return Ok(());
+            }
+        };
+        if range.end < packet.payload().len() {
+            // 相手が広告したウィンドウを越えて送ってきた. 切り捨てた分は再送してもらう
+            dbg!(
+                "trimmed beyond receive window",
+                packet.payload().len() - range.end
+            );
+            socket.ack_pending = true;
+        }
+        self.sample_receiver_rtt(socket, packet);
+
+        dbg!(&range);
+        let mut seq = packet.get_seq().wrapping_add(range.start as u32);
+        let mut data = &packet.payload()[range.clone()];
+        let mut urgent_len = 0;
+        if packet.get_flag().has_urg() {
+            // 緊急データは順番通りに届いた時だけ取り出す. 先に届いた場合は捨てて再送を待つ
+            if seq != socket.recv_param.next {
+                socket.ack_pending = true;
+                return Ok(());
+            }
+            urgent_len = cmp::min(
+                (packet.get_urgent_pointer() as usize).saturating_sub(range.start),
+                data.len(),
+            );
+            if urgent_len > 0 {
+                socket.urgent.extend(&data[..urgent_len]);
+                socket.recv_param.on_out_of_band(urgent_len);
+                socket.ack_pending = true;
+                let pending = socket.urgent.len();
+                socket
+                    .events
+                    .notify(SocketNotification::UrgentData { pending });
+                seq = seq.wrapping_add(urgent_len as u32);
+                data = &data[urgent_len..];
+            }
+        }
+        let copy_size = data.len();
+        if copy_size > 0
+            && socket
+                .reassembly
+                .contains(socket.recv_param.next, seq, copy_size)
+        {
+            // 先に届いて保持しているデータの再送. 保持しているデータには触れずにACKだけ返し直す
+            self.on_duplicate_segment(socket, packet);
+            return Ok(());
+        }
+        if seq == socket.recv_param.next {
+            socket.deliver(data);
+            // 穴が埋まったら, 先に届いていた続きのデータも受信バッファに移す
+            while let Some(segment) = socket.reassembly.pop(socket.recv_param.next) {
+                socket.deliver(&segment);
+            }
         } else {
+            socket.reassembly.insert(socket.recv_param.next, seq, data);
+        }
+        socket
+            .out_of_order
+            .insert(seq, seq.wrapping_add(copy_size as u32));
+        socket.out_of_order.advance(socket.recv_param.next);
+
+        if copy_size > 0 {
+            // 受信バッファにコピーが成功(受信バッファにまだ余裕がある場合とも言える)
+            // ACKはすぐには返さず, 受信スレッドのイテレーションの最後にまとめて返す
+            // 順番通りに届いて抜けも無ければさらに遅延ACKの期限まで待ち, 続けて届くセグメントの分とまとめる
+            // 抜けがある時や抜けを埋めた時は, 相手が早く再送できるようにすぐ返す. RFC 5681
+            match self.config.delayed_ack {
+                Some(delay) if in_order => {
+                    if packet.payload().len() >= MSS {
+                        socket.full_segments_unacked += 1;
+                    }
+                    if socket.full_segments_unacked >= 2 {
+                        socket.ack_pending = true;
+                    } else if socket.ack_deadline.is_none() {
+                        socket.ack_deadline = Some(self.clock.now() + delay);
+                    }
+                }
+                _ => socket.ack_pending = true,
+            }
+            socket.update_recv_watermark();
+        } else if urgent_len == 0 {
             // 受信バッファが溢れた時はセグメントを破棄する
             dbg!("recv buffer overflow");
         }
-        self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+
+        if packet.get_flag().has_psh() {
+            // 送信側の1回の書き込みの終わりなので, すぐにrecvを起こす
+            socket.delivery_pending = false;
+            socket.events.publish(TCPEventKind::DataArrived);
+        } else {
+            socket.delivery_pending = true;
+        }
         Ok(())
     }
+
+    /// 受信済みのデータの再送が届いた. 同じ範囲が繰り返し届いている場合はACKを間引く
+    fn on_duplicate_segment(&self, socket: &mut Socket, packet: &TCPPacket) {
+        self.counters.record_duplicate_segment();
+        if socket
+            .duplicates
+            .record(packet.get_seq(), packet.payload().len())
+        {
+            socket.ack_pending = true;
+        } else {
+            dbg!("duplicate ack suppressed");
+            self.counters.record_duplicate_ack_suppressed();
+        }
+    }
+}
+
+/// TCPの外から閉じた接続のFINがackされるのを待つ時間. 再送が尽きるまでの時間と同じにする
+/// 再送する度にRTOが倍になるので, 初期値のRTOから始めた場合の合計になる
+fn forced_close_grace() -> Duration {
+    INITIAL_RTO * ((1 << MAX_TRANSMITTION) - 1)
+}
+
+/// 再送キューのエントリの寿命. 正常であれば再送が尽きるまでの時間を超えて残ることはない
+fn retransmission_entry_max_age() -> Duration {
+    forced_close_grace() * 2
+}
+
+/// pollで報告するソケットの状態
+fn readiness(socket: &Socket) -> PollEvents {
+    let mut revents = PollEvents::empty();
+    if socket.events.failure().is_some() {
+        revents |= PollEvents::ERROR;
+    }
+    let readable = if socket.status == TcpStatus::Listen {
+        !socket.connection_queue.is_empty()
+    } else {
+        socket.is_readable() || socket.read_shutdown
+    };
+    if readable {
+        revents |= PollEvents::READABLE;
+    }
+    if matches!(socket.status, TcpStatus::Established | TcpStatus::CloseWait)
+        && !socket.fin_queued
+        && socket.is_writable()
+    {
+        revents |= PollEvents::WRITABLE;
+    }
+    revents
+}
+
+fn connection_info(socket: &Socket) -> ConnectionInfo {
+    ConnectionInfo {
+        sock_id: socket.get_sock_id(),
+        status: socket.status,
+        idle: socket.clock.since(socket.last_activity),
+        memory: socket.memory_usage(),
+        capabilities: socket.capabilities,
+    }
+}
+
+fn conntrack_entry(socket: &Socket, now: SystemTime) -> ConntrackEntry {
+    let remaining =
+        |deadline: SystemTime| deadline.duration_since(now).unwrap_or_default().as_millis() as u64;
+    let rto = socket.rtt.rto();
+    ConntrackEntry {
+        src: socket.sock_id.local.addr(),
+        sport: socket.sock_id.local.port(),
+        dst: socket.sock_id.remote.addr(),
+        dport: socket.sock_id.remote.port(),
+        state: conntrack::state_name(socket.status).to_string(),
+        idle_ms: socket.clock.since(socket.last_activity).as_millis() as u64,
+        rto_ms: rto.as_millis() as u64,
+        retransmit_in_ms: socket
+            .retransmission_queue
+            .front()
+            .map(|item| remaining(item.latest_transmission_time + rto)),
+        delayed_ack_in_ms: socket.ack_deadline.map(remaining),
+        expires_in_ms: socket.closing_deadline.map(remaining),
+        unacked: socket.send_param.in_flight(),
+    }
+}
+
+fn total_memory_usage(sockets: &HashMap<SockID, Socket>) -> MemoryUsage {
+    let mut total = MemoryUsage::default();
+    for socket in sockets.values() {
+        total += socket.memory_usage();
+    }
+    total
+}
+
+/// 受信したFINの扱い
+#[derive(Debug, PartialEq, Eq)]
+enum FinDisposition {
+    /// FINがRCV.NXTの位置にある. FINの1つ分だけRCV.NXTを進めてnextにする
+    Accept { next: u32 },
+    /// 既に受信済みのFINの再送
+    Duplicate,
+    /// FINより前のデータが欠けている
+    OutOfOrder,
+}
+
+/// 受け入れテストには通らないが, 状態のハンドラが応える必要のあるセグメント
+/// SYN_RCVDに再送されてきた相手のSYN(同時オープンで届くSYN/ACKや, TCP Fast Openでデータを受け取ったSYNも含む)と, TIME_WAITに再送されてきたFIN
+fn answered_by_handler(socket: &Socket, packet: &TCPPacket) -> bool {
+    let flag = packet.get_flag();
+    match socket.status {
+        TcpStatus::SynRcvd => flag.has_syn() && packet.get_seq() == socket.recv_param.initial_seq,
+        TcpStatus::TimeWait => {
+            flag.has_fin()
+                && fin_disposition(socket.recv_param.next, packet) == FinDisposition::Duplicate
+        }
+        _ => false,
+    }
+}
+
+/// FINはペイロードの後ろのシーケンス番号を1つ消費する
+/// そのためFINのシーケンス番号はSEG.SEQ + ペイロード長になる
+fn fin_disposition(rcv_nxt: u32, packet: &TCPPacket) -> FinDisposition {
+    let fin_seq = packet.get_seq().wrapping_add(packet.payload().len() as u32);
+    if fin_seq == rcv_nxt {
+        FinDisposition::Accept {
+            next: fin_seq.wrapping_add(1),
+        }
+    } else if fin_seq.wrapping_add(1) == rcv_nxt {
+        FinDisposition::Duplicate
+    } else {
+        FinDisposition::OutOfOrder
+    }
+}
+
+/// 届いたFINのシーケンス番号を覚え, FINより前のデータが全て揃っていればRCV.NXTをFINの後ろに進めてtrueを返す
+/// FINのセグメントでなくても, 先に届いていたFINの前の穴を埋めたならtrueになる
+/// 受信ウィンドウに収まらないデータの後ろにあるFINは覚えず, データと一緒に再送されるのを待つ
+fn receive_fin(socket: &mut Socket, packet: &TCPPacket) -> bool {
+    if packet.get_flag().has_fin() && socket.peer_fin_seq.is_none() {
+        let fin_seq = packet.get_seq().wrapping_add(packet.payload().len() as u32);
+        if fin_seq.wrapping_sub(socket.recv_param.next) <= socket.recv_param.window {
+            socket.peer_fin_seq = Some(fin_seq);
+        }
+    }
+    if socket.peer_fin_seq != Some(socket.recv_param.next) {
+        return false;
+    }
+    socket.recv_param.next = socket.recv_param.next.wrapping_add(1);
+    true
+}
+
+/// 次に送信できるセグメントのサイズ
+/// MSS, 相手の受信ウィンドウ, 輻輳ウィンドウの空きのうち最も小さいものになる
+fn sendable_size(socket: &Socket, remaining: usize) -> usize {
+    socket.send_param.sendable(MSS, remaining)
 }
 
 /*
@@ -787,3 +3748,3805 @@ pub fn get_source_ipv4_addr() -> Result<Ipv4Addr> {
         _ => bail!("failed to get ipv4 addr"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Capabilities, ChecksumOffload};
+    use crate::socket::SOCKET_BUFFER_SIZE;
+    use std::net::SocketAddrV4;
+
+    fn loopback_tcp() -> Arc<TCP> {
+        TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap()
+    }
+
+    // poll_receiveとadvance_timeで進める, 決定的モードのループバックのスタック
+    fn deterministic_config() -> TcpConfig {
+        TcpConfig {
+            backend: Backend::Loopback,
+            deterministic: true,
+            ..TcpConfig::default()
+        }
+    }
+
+    fn deterministic_tcp() -> Arc<TCP> {
+        TCP::with_config(deterministic_config()).unwrap()
+    }
+
+    // 決定的モードで相手の代わりにACKを送り, 受信させる. fromは相手側のソケット(seqはその送信済みの位置を使う)
+    // ACKを受けてこちらが送ったセグメントも, 同じpoll_receiveで相手に届く
+    fn inject_ack(tcp: &TCP, from: SockID, ack: u32, window: u16) {
+        let seq = tcp.sockets.read().unwrap()[&from].send_param.next;
+        let mut packet = TCPPacket::new(0);
+        packet.set_src(from.local.port());
+        packet.set_dest(from.remote.port());
+        packet.set_seq(seq);
+        packet.set_ack(ack);
+        packet.set_flag(TcpFlags::ACK);
+        packet.set_window_size(window);
+        tcp.device
+            .send(&packet, from.local.addr(), from.remote.addr())
+            .unwrap();
+        tcp.poll_receive().unwrap();
+    }
+
+    // 決定的モードで送信されたまま届けられていないセグメントを全て取り出す
+    fn take_sent(tcp: &TCP) -> Vec<TCPPacket> {
+        let mut sent = Vec::new();
+        while let Some(received) = tcp.device.recv(Some(Duration::ZERO)).unwrap() {
+            sent.push(received.packet);
+        }
+        sent
+    }
+
+    // 送信が必ず失敗するデバイス. 送信エラーの後も状態が壊れないことを確かめる
+    struct FailingDevice;
+
+    impl Device for FailingDevice {
+        fn send(
+            &self,
+            _packet: &TCPPacket,
+            _local_addr: Ipv4Addr,
+            _remote_addr: Ipv4Addr,
+        ) -> Result<usize> {
+            bail!("device is down")
+        }
+
+        fn recv(&self, _timeout: Option<Duration>) -> Result<Option<ReceivedPacket>> {
+            Ok(None)
+        }
+
+        fn source_addr(&self, remote_addr: Ipv4Addr) -> Result<Ipv4Addr> {
+            Ok(remote_addr)
+        }
+    }
+
+    // 並行性の約束事をコンパイル時に確認する
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn public_handles_are_send_and_sync() {
+        assert_send_sync::<TCP>();
+        assert_send_sync::<Arc<TCP>>();
+        assert_send_sync::<SockID>();
+        assert_send_sync::<SocketEvents>();
+    }
+
+    #[test]
+    fn concurrent_sends_on_one_socket_deliver_every_byte() {
+        let tcp = loopback_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let tcp = tcp.clone();
+                thread::spawn(move || tcp.send(client, &[0; 2000]))
+            })
+            .collect();
+        let mut received = 0;
+        let mut buffer = [0; 1000];
+        while received < 8000 {
+            received += tcp.recv(server, &mut buffer).unwrap();
+        }
+        for sender in senders {
+            sender.join().unwrap().unwrap();
+        }
+        assert_eq!(received, 8000);
+    }
+
+    #[test]
+    fn nonblocking_calls_return_would_block_instead_of_waiting() {
+        let tcp = deterministic_tcp();
+        fn would_block<T: std::fmt::Debug>(result: Result<T>) -> bool {
+            result.unwrap_err().is::<WouldBlock>()
+        }
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.set_nonblocking(listener, true).unwrap();
+        assert!(would_block(tcp.accept(listener)));
+
+        // 確立するまではsendもrecvも進められない
+        let client = tcp.connect_nonblocking(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        assert_eq!(tcp.info(client).unwrap().status, TcpStatus::SynSent);
+        assert!(would_block(tcp.send(client, b"hello")));
+        assert!(would_block(tcp.recv(client, &mut [0; 16])));
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.info(client).unwrap().status, TcpStatus::Established);
+        let server = tcp.accept(listener).unwrap();
+        assert!(!tcp.sockets.read().unwrap()[&server].nonblocking);
+
+        tcp.send(client, b"hello").unwrap();
+        tcp.poll_receive().unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"hello");
+
+        // 送信バッファに入るだけ入れて返り, 一杯ならWouldBlockを返す. 送信バッファより大きなbufferも同じ
+        let send_buffer_size = tcp.config.send_buffer_size;
+        let large = vec![0; 2 * send_buffer_size];
+        let queued = tcp.send(client, &large).unwrap();
+        assert!(queued > 0 && queued < large.len());
+        // 相手が受信しないので, そのうち送信バッファが埋まる
+        while let Ok(nbytes) = tcp.send(client, &large) {
+            assert!(nbytes < large.len());
+        }
+        assert!(would_block(tcp.send(client, &large)));
+        assert_eq!(tcp.sockets.read().unwrap()[&client].writable_bytes(), 0);
+
+        // ブロッキングモードに戻せば従来通り待つ(決定的モードでは待てないのでエラー)
+        tcp.set_nonblocking(listener, false).unwrap();
+        assert!(!would_block(tcp.accept(listener)));
+    }
+
+    #[test]
+    fn timeouts_stop_recv_and_send_from_blocking_forever() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        fn timed_out<T: std::fmt::Debug>(result: Result<T>) -> bool {
+            result.unwrap_err().is::<TimedOut>()
+        }
+        let (client, server) = tcp.connected_pair().unwrap();
+        assert!(tcp.set_read_timeout(server, Some(Duration::ZERO)).is_err());
+        let timeout = Duration::from_millis(50);
+        tcp.set_read_timeout(server, Some(timeout)).unwrap();
+        let mut buffer = [0; 16];
+        let start = Instant::now();
+        assert!(timed_out(tcp.recv(server, &mut buffer)));
+        assert!(start.elapsed() >= timeout);
+
+        // 時間切れでもrecv_lowatに満たないデータが届いていれば返す
+        tcp.set_recv_lowat(server, 10).unwrap();
+        tcp.send(client, b"hello").unwrap();
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"hello");
+
+        // 相手が読まないので, 送信バッファと相手の受信ウィンドウが埋まったら空かない
+        tcp.set_write_timeout(client, Some(timeout)).unwrap();
+        let stalled = vec![0; tcp.config.send_buffer_size + 2 * tcp.config.recv_buffer_size];
+        // 途中まで入れたところで時間切れになれば, 入れた分を返す. 1バイトも入らなければTimedOut
+        let queued = tcp.send(client, &stalled).unwrap();
+        assert!(queued > 0 && queued < stalled.len());
+        assert!(timed_out(tcp.send(client, &stalled[queued..])));
+
+        // 解除すれば再び待つ. 読み出して空けばsendが返る
+        // 時間切れになるまでに送信バッファへ入れた分も送られているので, その後ろに続く
+        tcp.set_write_timeout(client, None).unwrap();
+        let sender = {
+            let tcp = tcp.clone();
+            thread::spawn(move || tcp.send(client, b"world"))
+        };
+        tcp.set_read_timeout(server, None).unwrap();
+        tcp.set_recv_lowat(server, 1).unwrap();
+        let mut received = Vec::new();
+        while !received.ends_with(b"world") {
+            let nbytes = tcp.recv(server, &mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..nbytes]);
+        }
+        assert!(received.len() > tcp.config.send_buffer_size);
+        sender.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn low_watermarks_gate_recv_and_writable_readiness() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.set_nodelay(client, true).unwrap();
+        tcp.set_nonblocking(server, true).unwrap();
+        let readable = |sock_id| {
+            let mut fds = [PollFd::new(sock_id, PollEvents::READABLE)];
+            tcp.poll(&mut fds, None).unwrap() == 1
+        };
+
+        // recv_lowatに満たない間は読めない. ただし渡されたbufferより多くは待たない
+        tcp.set_recv_lowat(server, 10).unwrap();
+        tcp.send(client, b"hello").unwrap();
+        tcp.poll_receive().unwrap();
+        assert!(!readable(server));
+        let mut buffer = [0; 16];
+        assert!(tcp
+            .recv(server, &mut buffer)
+            .unwrap_err()
+            .is::<WouldBlock>());
+        assert_eq!(tcp.recv(server, &mut buffer[..4]).unwrap(), 4);
+
+        tcp.send(client, b"world").unwrap();
+        tcp.poll_receive().unwrap();
+        assert!(!readable(server));
+        tcp.send(client, b"!!!!").unwrap();
+        tcp.poll_receive().unwrap();
+        assert!(readable(server));
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 10);
+        assert_eq!(&buffer[..10], b"oworld!!!!");
+
+        // FINを受信した後は満たなくても返す
+        tcp.send(client, b"x").unwrap();
+        tcp.shutdown(client, How::Write).unwrap();
+        tcp.poll_receive().unwrap();
+        assert!(readable(server));
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 1);
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 0);
+
+        // send_lowatが送信バッファより大きければ, 送信バッファが空いた時に書き込める
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.set_nodelay(client, true).unwrap();
+        tcp.set_send_lowat(client, usize::MAX).unwrap();
+        tcp.set_nonblocking(client, true).unwrap();
+        let writable = |sock_id| {
+            let mut fds = [PollFd::new(sock_id, PollEvents::WRITABLE)];
+            tcp.poll(&mut fds, None).unwrap() == 1
+        };
+        assert!(writable(client));
+        while tcp.send(client, &[0; MSS]).is_ok() {}
+        tcp.poll_receive().unwrap();
+        assert!(!writable(client));
+        let mut buffer = [0; MSS];
+        while !writable(client) {
+            tcp.recv(server, &mut buffer).unwrap();
+            tcp.poll_receive().unwrap();
+        }
+        assert_eq!(tcp.socket_stats(client).unwrap().memory.send_buffer, 0);
+    }
+
+    #[test]
+    fn poll_reports_readiness_of_each_socket() {
+        let tcp = deterministic_tcp();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let both = PollEvents::READABLE | PollEvents::WRITABLE;
+        let mut fds = [
+            PollFd::new(listener, PollEvents::READABLE),
+            PollFd::new(client, both),
+        ];
+        // SYN_SENTではまだ書き込めない
+        assert_eq!(tcp.poll(&mut fds, None).unwrap(), 0);
+
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.poll(&mut fds, None).unwrap(), 2);
+        assert_eq!(fds[0].revents, PollEvents::READABLE);
+        assert_eq!(fds[1].revents, PollEvents::WRITABLE);
+        let server = tcp.accept(listener).unwrap();
+
+        tcp.send(client, b"hello").unwrap();
+        tcp.poll_receive().unwrap();
+        let mut fds = [
+            PollFd::new(listener, PollEvents::READABLE),
+            PollFd::new(server, PollEvents::READABLE),
+        ];
+        assert_eq!(tcp.poll(&mut fds, None).unwrap(), 1);
+        assert_eq!(fds[1].revents, PollEvents::READABLE);
+
+        // 中断された接続と既に無いソケットは, 調べたいものに含めなくてもERROR
+        tcp.close_matching(|info| info.sock_id == server, CloseMode::Abort);
+        tcp.poll_receive().unwrap();
+        let mut fds = [
+            PollFd::new(client, PollEvents::empty()),
+            PollFd::new(server, PollEvents::empty()),
+        ];
+        assert_eq!(tcp.poll(&mut fds, None).unwrap(), 2);
+        assert!(fds.iter().all(|fd| fd.revents.is_error()));
+    }
+
+    #[test]
+    fn one_thread_serves_many_connections_with_poll() {
+        const CLIENTS: usize = 50;
+        let tcp = loopback_tcp();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.set_nonblocking(listener, true).unwrap();
+
+        let cloned_tcp = tcp.clone();
+        let clients = thread::spawn(move || {
+            let tcp = cloned_tcp;
+            let sock_ids: Vec<_> = (0..CLIENTS)
+                .map(|i| {
+                    let sock_id = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+                    tcp.send(sock_id, &(i as u32).to_be_bytes()).unwrap();
+                    tcp.shutdown(sock_id, How::Write).unwrap();
+                    sock_id
+                })
+                .collect();
+            for (i, sock_id) in sock_ids.into_iter().enumerate() {
+                let mut buffer = [0; 4];
+                tcp.set_recv_lowat(sock_id, 4).unwrap();
+                assert_eq!(tcp.recv(sock_id, &mut buffer).unwrap(), 4);
+                assert_eq!(u32::from_be_bytes(buffer), i as u32);
+                tcp.close(sock_id).unwrap();
+            }
+        });
+
+        // 1つのスレッドでacceptと全ての接続のエコーを回す
+        let mut fds = vec![PollFd::new(listener, PollEvents::READABLE)];
+        let mut served = 0;
+        while served < CLIENTS {
+            let ready = tcp.poll(&mut fds, Some(Duration::from_secs(10))).unwrap();
+            assert!(ready > 0, "poll timed out");
+            let mut accepted = Vec::new();
+            if fds[0].revents.is_readable() {
+                while let Ok(server) = tcp.accept(listener) {
+                    tcp.set_nonblocking(server, true).unwrap();
+                    accepted.push(PollFd::new(server, PollEvents::READABLE));
+                }
+            }
+            let mut closed = Vec::new();
+            for fd in &fds[1..] {
+                if !fd.revents.is_readable() {
+                    continue;
+                }
+                let mut buffer = [0; 16];
+                match tcp.recv(fd.sock_id, &mut buffer) {
+                    Ok(0) => {
+                        tcp.close(fd.sock_id).unwrap();
+                        closed.push(fd.sock_id);
+                        served += 1;
+                    }
+                    Ok(nbytes) => {
+                        assert_eq!(tcp.send(fd.sock_id, &buffer[..nbytes]).unwrap(), nbytes)
+                    }
+                    Err(error) => assert!(error.is::<WouldBlock>(), "{:?}", error),
+                }
+            }
+            fds.retain(|fd| !closed.contains(&fd.sock_id));
+            fds.extend(accepted);
+        }
+        clients.join().unwrap();
+        assert_eq!(fds.len(), 1);
+    }
+
+    #[test]
+    fn concurrent_recvs_each_get_an_ordered_part_of_the_stream() {
+        let tcp = loopback_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+        // 4バイトずつ読み出させ, 番号を書いた4バイトの単位が途中で分かれないようにする
+        tcp.set_recv_lowat(server, 4).unwrap();
+
+        const WORDS: u32 = 1000;
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let tcp = tcp.clone();
+                thread::spawn(move || {
+                    let mut words = Vec::new();
+                    let mut buffer = [0; 4];
+                    loop {
+                        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+                        if nbytes == 0 {
+                            return words;
+                        }
+                        assert_eq!(nbytes, 4);
+                        words.push(u32::from_be_bytes(buffer));
+                    }
+                })
+            })
+            .collect();
+
+        let data: Vec<u8> = (0..WORDS).flat_map(|word| word.to_be_bytes()).collect();
+        for chunk in data.chunks(1000) {
+            tcp.send(client, chunk).unwrap();
+        }
+        tcp.shutdown(client, How::Write).unwrap();
+
+        let mut all = Vec::new();
+        for reader in readers {
+            let words = reader.join().unwrap();
+            // 1つのスレッドから見ると, 受け取ったデータはストリームの順番通りに並んでいる
+            assert!(words.windows(2).all(|pair| pair[0] < pair[1]));
+            all.extend(words);
+        }
+        all.sort();
+        assert_eq!(all, (0..WORDS).collect::<Vec<_>>());
+    }
+
+    /// MSSや受信バッファの境界付近の大きさのペイロードを両方向に送り, 欠けたり重複したりせずに届くことを確かめる
+    /// process_payloadのオフセット計算の境界条件をまとめて検査するためのもの
+    fn sweep_segment_sizes(tcp: &Arc<TCP>, buffer_size: usize) {
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        let mut sizes = vec![1];
+        for segments in 1..=3 {
+            sizes.extend([segments * MSS - 1, segments * MSS, segments * MSS + 1]);
+        }
+        sizes.extend([
+            buffer_size - 1,
+            buffer_size,
+            buffer_size + 1,
+            buffer_size * 2,
+            buffer_size * 2 + 1,
+        ]);
+
+        for (from, to) in [(client, server), (server, client)] {
+            for &size in &sizes {
+                let payload: Vec<u8> = (0..size).map(|i| ((i * 7 + size) % 251) as u8).collect();
+                let sender = {
+                    let tcp = tcp.clone();
+                    let payload = payload.clone();
+                    thread::spawn(move || tcp.send(from, &payload))
+                };
+
+                let mut received = vec![0; size];
+                let mut cursor = 0;
+                while cursor < size {
+                    let nbytes = tcp.recv(to, &mut received[cursor..]).unwrap();
+                    assert!(nbytes > 0, "unexpected EOF at {}/{} bytes", cursor, size);
+                    cursor += nbytes;
+                }
+                sender.join().unwrap().unwrap();
+                assert!(received == payload, "corrupted payload of {} bytes", size);
+            }
+        }
+    }
+
+    #[test]
+    fn segment_size_sweep_delivers_every_byte() {
+        sweep_segment_sizes(&loopback_tcp(), SOCKET_BUFFER_SIZE);
+
+        // MSSの倍数でない受信バッファ
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            recv_buffer_size: 5000,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        sweep_segment_sizes(&tcp, 5000);
+    }
+
+    #[test]
+    fn send_spaces_segments_at_the_pacing_rate() {
+        use crate::config::Pacing;
+        use std::time::Instant;
+
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            pacing: Pacing::Rate(50_000),
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        // sendは送信バッファにコピーしてすぐに返り, 送信スレッドが3セグメントに分けて1460バイト毎に約29ms空けて送る
+        let started = Instant::now();
+        tcp.send(client, &[1; 4000]).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(29));
+
+        let mut received = 0;
+        let mut buffer = [0; 4000];
+        while received < 4000 {
+            received += tcp.recv(server, &mut buffer).unwrap();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn accept_queue_can_be_monitored_and_drained() {
+        let tcp = TCP::with_config(TcpConfig {
+            accept_queue_watermark: Some(2),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listening_socket = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let notifications = tcp.subscribe(listening_socket).unwrap();
+
+        let clients: Vec<SockID> = (0..3)
+            .map(|_| tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap())
+            .collect();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.pending_connections(listening_socket).unwrap(), 3);
+        assert_eq!(
+            notifications.try_iter().collect::<Vec<_>>(),
+            [SocketNotification::AcceptQueueHighWatermark { len: 2 }]
+        );
+
+        let accepted = tcp.accept_pending(listening_socket).unwrap();
+        assert_eq!(accepted.len(), 3);
+        for client in clients {
+            assert!(accepted.iter().any(|server| server.remote == client.local));
+        }
+        assert_eq!(tcp.pending_connections(listening_socket).unwrap(), 0);
+        assert!(tcp.accept_pending(listening_socket).unwrap().is_empty());
+        assert!(tcp.pending_connections(accepted[0]).is_err());
+    }
+
+    #[test]
+    fn listener_stats_count_handshakes_overflows_and_accept_latency() {
+        let tcp = TCP::with_config(TcpConfig {
+            accept_backlog: 2,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        for _ in 0..3 {
+            tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        }
+        tcp.poll_receive().unwrap();
+
+        // 3つ目のSYNはキューが一杯なので破棄される
+        let stats = tcp.listener_stats(listener).unwrap();
+        assert_eq!(stats.syns_received, 3);
+        assert_eq!(stats.handshakes_completed, 2);
+        assert_eq!(stats.queue_overflows, 1);
+        assert_eq!(stats.accept_queue_len, 2);
+        assert_eq!(stats.accept_latency.total(), 0);
+
+        // SYNを受信してからacceptされるまでの時間がヒストグラムに入る
+        tcp.advance_time(Duration::from_millis(5)).unwrap();
+        let server = tcp.accept(listener).unwrap();
+        tcp.advance_time(Duration::from_millis(50)).unwrap();
+        tcp.accept(listener).unwrap();
+        assert_eq!(
+            tcp.listener_stats(listener).unwrap().accept_latency.counts,
+            [0, 1, 1, 0, 0, 0]
+        );
+
+        // 破棄されたSYNは再送されてくる
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.accept(listener).unwrap();
+        let stats = tcp.listener_stats(listener).unwrap();
+        assert_eq!(stats.syns_received, 4);
+        assert_eq!(stats.handshakes_completed, 3);
+        assert_eq!(stats.accept_queue_len, 0);
+        assert_eq!(stats.accept_latency.counts, [1, 1, 1, 0, 0, 0]);
+        assert!(tcp.listener_stats(server).is_err());
+    }
+
+    #[test]
+    fn recv_buffer_watermarks_notify_once_per_crossing() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.set_nodelay(client, true).unwrap();
+        let notifications = tcp.subscribe(server).unwrap();
+        let watermarks = |high, low| Some(BufferWatermarks { high, low });
+        assert!(tcp
+            .set_recv_buffer_watermarks(server, watermarks(500, 500))
+            .is_err());
+        tcp.set_recv_buffer_watermarks(server, watermarks(2000, 500))
+            .unwrap();
+        let crossings = || -> Vec<SocketNotification> {
+            notifications
+                .try_iter()
+                .filter(|notification| {
+                    matches!(
+                        notification,
+                        SocketNotification::BufferHighWatermark { .. }
+                            | SocketNotification::BufferLowWatermark { .. }
+                    )
+                })
+                .collect()
+        };
+
+        // highに達した時に1度だけ通知する
+        tcp.send(client, &[0; 1000]).unwrap();
+        tcp.poll_receive().unwrap();
+        assert!(crossings().is_empty());
+        tcp.send(client, &[0; 1000]).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(
+            crossings(),
+            [SocketNotification::BufferHighWatermark { used: 2000 }]
+        );
+        tcp.send(client, &[0; 1000]).unwrap();
+        tcp.poll_receive().unwrap();
+        assert!(crossings().is_empty());
+
+        // lowまで読み出されたら1度だけ通知する
+        let mut buffer = [0; 1500];
+        tcp.recv(server, &mut buffer).unwrap();
+        assert!(crossings().is_empty());
+        tcp.recv(server, &mut buffer[..1100]).unwrap();
+        assert_eq!(
+            crossings(),
+            [SocketNotification::BufferLowWatermark { used: 400 }]
+        );
+        tcp.recv(server, &mut buffer[..400]).unwrap();
+        assert!(crossings().is_empty());
+    }
+
+    #[test]
+    fn nagle_coalesces_small_writes_until_acked() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let data_segments = Arc::new(AtomicUsize::new(0));
+        let cloned_data_segments = data_segments.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.payload_len > 0 {
+                    cloned_data_segments.fetch_add(1, Ordering::SeqCst);
+                }
+                true
+            })),
+            delayed_ack: None,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        // 最初の書き込みはすぐ送り, ackされるまでの書き込みは1つにまとめる
+        for chunk in [b"a", b"b", b"c"] {
+            tcp.send(client, chunk).unwrap();
+        }
+        assert_eq!(data_segments.load(Ordering::SeqCst), 1);
+        assert_eq!(tcp.socket_stats(client).unwrap().memory.send_buffer, 2);
+        tcp.poll_receive().unwrap();
+        assert_eq!(data_segments.load(Ordering::SeqCst), 2);
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"abc");
+
+        // nodelayなら書き込む度にすぐ送る
+        tcp.set_nodelay(client, true).unwrap();
+        assert!(!tcp.info(client).unwrap().capabilities.nagle);
+        tcp.send(client, b"d").unwrap();
+        tcp.send(client, b"e").unwrap();
+        assert_eq!(data_segments.load(Ordering::SeqCst), 4);
+
+        // 保留中のデータはnodelayにした時点で送る
+        tcp.set_nodelay(client, false).unwrap();
+        tcp.send(client, b"f").unwrap();
+        assert_eq!(data_segments.load(Ordering::SeqCst), 4);
+        tcp.set_nodelay(client, true).unwrap();
+        assert_eq!(data_segments.load(Ordering::SeqCst), 5);
+        tcp.poll_receive().unwrap();
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"def");
+    }
+
+    #[test]
+    fn data_held_by_nagle_is_sent_when_a_closed_window_opens() {
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities {
+                nagle: true,
+                ..Capabilities::none()
+            },
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let una = tcp.sockets.read().unwrap()[&client].send_param.unacked_seq;
+        tcp.send(client, &[1; 1000]).unwrap();
+        tcp.send(client, b"held").unwrap();
+        assert_eq!(take_sent(&tcp).len(), 1);
+        let progress = || {
+            let sockets = tcp.sockets.read().unwrap();
+            (
+                sockets[&client].send_param.next.wrapping_sub(una),
+                sockets[&client].unsent.len(),
+            )
+        };
+
+        // 全てackされてもゼロウィンドウなので保留したまま
+        inject_ack(&tcp, server, una.wrapping_add(1000), 0);
+        assert_eq!(progress(), (1000, 4));
+        // ackは進まなくても, ウィンドウが開いた時に送る
+        inject_ack(&tcp, server, una.wrapping_add(1000), 4380);
+        assert_eq!(progress(), (1004, 0));
+    }
+
+    #[test]
+    fn delayed_ack_coalesces_acks_for_in_order_segments() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+        // serverが返す純粋なACKを数える
+        let server_port = Arc::new(AtomicU16::new(0));
+        let pure_acks = Arc::new(AtomicUsize::new(0));
+        let (cloned_server_port, cloned_pure_acks) = (server_port.clone(), pure_acks.clone());
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: Some(Duration::from_millis(40)),
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.local_port == cloned_server_port.load(Ordering::SeqCst)
+                    && info.payload_len == 0
+                {
+                    cloned_pure_acks.fetch_add(1, Ordering::SeqCst);
+                }
+                true
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        server_port.store(server.local.port(), Ordering::SeqCst);
+
+        // 小さなセグメントへのACKは期限まで遅らせる
+        tcp.send(client, b"hello").unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(pure_acks.load(Ordering::SeqCst), 0);
+        tcp.advance_time(Duration::from_millis(39)).unwrap();
+        assert_eq!(pure_acks.load(Ordering::SeqCst), 0);
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        assert_eq!(pure_acks.load(Ordering::SeqCst), 1);
+        tcp.poll_receive().unwrap();
+        assert_eq!(
+            tcp.socket_stats(client)
+                .unwrap()
+                .memory
+                .retransmission_queue,
+            0
+        );
+
+        // フルサイズのセグメント2つには待たずに1つのACKを返す
+        tcp.send(client, &[0; MSS * 2]).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(pure_acks.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            tcp.socket_stats(client)
+                .unwrap()
+                .memory
+                .retransmission_queue,
+            0
+        );
+    }
+
+    #[test]
+    fn acks_for_one_receive_round_are_coalesced() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+        let server_port = Arc::new(AtomicU16::new(0));
+        let pure_acks = Arc::new(AtomicUsize::new(0));
+        let (cloned_server_port, cloned_pure_acks) = (server_port.clone(), pure_acks.clone());
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.local_port == cloned_server_port.load(Ordering::SeqCst)
+                    && info.payload_len == 0
+                {
+                    cloned_pure_acks.fetch_add(1, Ordering::SeqCst);
+                }
+                true
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        server_port.store(server.local.port(), Ordering::SeqCst);
+
+        // 1度の受信で処理した3つのセグメント(受信ウィンドウ一杯)には, 累積ACKを1つだけ返す
+        tcp.send(client, &[0; MSS * 3]).unwrap();
+        assert_eq!(tcp.poll_receive().unwrap(), 4);
+        assert_eq!(pure_acks.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            tcp.socket_stats(client)
+                .unwrap()
+                .memory
+                .retransmission_queue,
+            0
+        );
+
+        // 別々に届いたセグメントにはそれぞれACKを返す
+        tcp.recv(server, &mut [0; MSS * 3]).unwrap();
+        tcp.poll_receive().unwrap();
+        let acks = pure_acks.load(Ordering::SeqCst);
+        tcp.send(client, b"hello").unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(pure_acks.load(Ordering::SeqCst), acks + 1);
+    }
+
+    #[test]
+    fn conntrack_exports_tuples_states_and_timers() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.send(client, b"hello").unwrap();
+
+        let snapshot = tcp.conntrack();
+        assert_eq!(snapshot.entries.len(), 2);
+        let entry = snapshot
+            .entries
+            .iter()
+            .find(|entry| entry.sport == client.local.port())
+            .unwrap();
+        assert_eq!(entry.dport, server.local.port());
+        assert_eq!(entry.state, "ESTABLISHED");
+        assert_eq!(entry.unacked, 5);
+        assert_eq!(entry.retransmit_in_ms, Some(entry.rto_ms));
+
+        // 遅延ACKの期限と, TIME_WAITの削除までの時間も載る
+        tcp.poll_receive().unwrap();
+        let entry = tcp
+            .conntrack()
+            .entries
+            .into_iter()
+            .find(|entry| entry.sport == server.local.port())
+            .unwrap();
+        assert_eq!(entry.delayed_ack_in_ms, Some(40));
+
+        tcp.close(client).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.close(server).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        let snapshot = tcp.conntrack();
+        assert_eq!(snapshot.entries.len(), 1);
+        assert_eq!(snapshot.entries[0].state, "TIME_WAIT");
+        assert_eq!(
+            snapshot.entries[0].expires_in_ms,
+            Some((MSL * 2).as_millis() as u64 - 1)
+        );
+        assert!(snapshot
+            .to_json()
+            .unwrap()
+            .contains(r#""state":"TIME_WAIT""#));
+    }
+
+    #[test]
+    fn fin_is_tracked_separately_from_data() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let drop_data = Arc::new(AtomicBool::new(false));
+        let cloned_drop_data = drop_data.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.payload_len == 0 || !cloned_drop_data.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        {
+            let sockets = tcp.sockets.read().unwrap();
+            assert!(sockets[&client].control.syn_acked);
+            assert!(sockets[&server].control.syn_acked);
+        }
+
+        // FINより前のデータが落ちたので, FINが届いてもackされない
+        drop_data.store(true, Ordering::SeqCst);
+        tcp.send(client, b"lost").unwrap();
+        tcp.shutdown(client, How::Write).unwrap();
+        drop_data.store(false, Ordering::SeqCst);
+        tcp.poll_receive().unwrap();
+        let fin_seq = {
+            let sockets = tcp.sockets.read().unwrap();
+            let socket = &sockets[&client];
+            assert_eq!(socket.status, TcpStatus::FinWait1);
+            assert!(!socket.control.fin_acked);
+            assert_eq!(
+                socket.control.fin_seq,
+                Some(socket.send_param.next.wrapping_sub(1))
+            );
+            socket.control.fin_seq.unwrap()
+        };
+
+        // データとFINが再送されて揃えば, FINも含めてackされる
+        for _ in 0..2 {
+            tcp.advance_time(INITIAL_RTO).unwrap();
+            tcp.poll_receive().unwrap();
+        }
+        let sockets = tcp.sockets.read().unwrap();
+        let socket = &sockets[&client];
+        assert_eq!(socket.status, TcpStatus::FinWait2);
+        assert!(socket.control.fin_acked);
+        assert_eq!(socket.send_param.unacked_seq, fin_seq.wrapping_add(1));
+        assert_eq!(sockets[&server].status, TcpStatus::CloseWait);
+        assert_eq!(sockets[&server].readable_bytes(), 4);
+    }
+
+    #[test]
+    fn fin_ahead_of_a_gap_is_accepted_once_the_gap_is_filled() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        let drop_data = Arc::new(AtomicBool::new(false));
+        let fins = Arc::new(AtomicUsize::new(0));
+        let (cloned_drop_data, cloned_fins) = (drop_data.clone(), fins.clone());
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.flags.has_fin() {
+                    cloned_fins.fetch_add(1, Ordering::SeqCst);
+                }
+                info.payload_len == 0 || !cloned_drop_data.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        drop_data.store(true, Ordering::SeqCst);
+        tcp.send(client, b"first").unwrap();
+        drop_data.store(false, Ordering::SeqCst);
+        tcp.send(client, b"second").unwrap();
+        tcp.shutdown(client, How::Write).unwrap();
+        tcp.poll_receive().unwrap();
+        {
+            let sockets = tcp.sockets.read().unwrap();
+            assert_eq!(sockets[&server].status, TcpStatus::Established);
+            assert!(sockets[&server].peer_fin_seq.is_some());
+            assert_eq!(sockets[&server].readable_bytes(), 0);
+        }
+
+        // 欠けていたデータが再送されて穴が埋まれば, FINの再送を待たずに閉じる
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(fins.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            tcp.socket_stats(server).unwrap().status,
+            TcpStatus::CloseWait
+        );
+
+        // FINまでのデータを全て読み終えてから0を返す
+        let mut buffer = [0; 8];
+        let mut received = Vec::new();
+        loop {
+            let nbytes = tcp.recv(server, &mut buffer).unwrap();
+            if nbytes == 0 {
+                break;
+            }
+            received.extend_from_slice(&buffer[..nbytes]);
+        }
+        assert_eq!(received, b"firstsecond");
+    }
+
+    #[test]
+    fn rtt_samples_are_reported_in_stats_and_notifications() {
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, _server) = tcp.connected_pair().unwrap();
+        let notifications = tcp.subscribe(client).unwrap();
+        // シミュレーション時計の0msはタイムスタンプのエコーに使えないので進めておく
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+
+        // 通知は有効にするまで届かない
+        tcp.send(client, b"first").unwrap();
+        tcp.advance_time(Duration::from_millis(30)).unwrap();
+        tcp.poll_receive().unwrap();
+        let stats = tcp.socket_stats(client).unwrap();
+        assert_eq!(stats.latest_rtt, Some(Duration::from_millis(30)));
+        assert_eq!(stats.srtt, Some(Duration::from_millis(30)));
+        assert_eq!(stats.rttvar, Duration::from_millis(15));
+        assert!(notifications.try_recv().is_err());
+
+        tcp.set_rtt_notifications(client, true).unwrap();
+        tcp.send(client, b"second").unwrap();
+        tcp.advance_time(Duration::from_millis(10)).unwrap();
+        tcp.poll_receive().unwrap();
+        let stats = tcp.socket_stats(client).unwrap();
+        assert_eq!(stats.latest_rtt, Some(Duration::from_millis(10)));
+        assert_eq!(stats.min_rtt, Some(Duration::from_millis(10)));
+        assert_eq!(
+            notifications.try_iter().collect::<Vec<_>>(),
+            [SocketNotification::RttSample {
+                rtt: Duration::from_millis(10),
+                srtt: stats.srtt.unwrap(),
+                rttvar: stats.rttvar,
+            }]
+        );
+    }
+
+    #[test]
+    fn handler_panic_aborts_only_the_affected_connection() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicU16, Ordering};
+
+        // victimのポートから送るセグメントでパニックさせる
+        let victim = Arc::new(AtomicU16::new(0));
+        let cloned_victim = victim.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.local_port == cloned_victim.load(Ordering::SeqCst) {
+                    panic!("handler bug");
+                }
+                true
+            })),
+            delayed_ack: None,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (broken_client, broken_server) = tcp.connected_pair().unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        victim.store(broken_server.local.port(), Ordering::SeqCst);
+
+        // broken_serverがACKを返そうとしてパニックする
+        tcp.send(broken_client, b"boom").unwrap();
+        tcp.send(client, b"hello").unwrap();
+        tcp.poll_receive().unwrap();
+        assert!(tcp.socket_stats(broken_server).is_err());
+        assert!(tcp
+            .recently_closed()
+            .iter()
+            .any(|closed| closed.sock_id == broken_server
+                && closed.reason == CloseReason::InternalError));
+        assert_eq!(tcp.stack_stats().internal_error_closes, 1);
+
+        // 他の接続はそのまま使える
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"hello");
+        tcp.send(server, b"world").unwrap();
+        tcp.poll_receive().unwrap();
+        let nbytes = tcp.recv(client, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"world");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn timer_panic_aborts_only_the_affected_connection() {
+        let tcp = deterministic_tcp();
+        let (broken_client, _) = tcp.connected_pair().unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        // 再送キューとSND.NXTを食い違わせ, 再送した後の整合性チェック(debug_assert)でパニックさせる
+        tcp.send(broken_client, b"boom").unwrap();
+        take_sent(&tcp);
+        {
+            let mut sockets = tcp.sockets.write().unwrap();
+            let socket = sockets.get_mut(&broken_client).unwrap();
+            socket.send_param.next = socket.send_param.unacked_seq;
+        }
+        tcp.advance_time(Duration::from_secs(5)).unwrap();
+        assert!(tcp.socket_stats(broken_client).is_err());
+        assert!(tcp
+            .recently_closed()
+            .iter()
+            .any(|closed| closed.sock_id == broken_client
+                && closed.reason == CloseReason::InternalError));
+
+        // 他の接続の再送は止まらない
+        tcp.send(client, b"hello").unwrap();
+        take_sent(&tcp);
+        tcp.advance_time(Duration::from_secs(5)).unwrap();
+        tcp.poll_receive().unwrap();
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"hello");
+    }
+
+    #[test]
+    fn lost_syn_is_retransmitted_after_rto() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // 最初のSYNだけ落とす
+        let dropped = Arc::new(AtomicBool::new(false));
+        let cloned_dropped = dropped.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.flags != TcpFlags::SYN || cloned_dropped.swap(true, Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+
+        let listening_socket = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        assert_eq!(tcp.poll_receive().unwrap(), 0);
+        assert!(tcp.accept(listening_socket).is_err());
+
+        // RTOの直前ではまだ再送しない
+        tcp.advance_time(INITIAL_RTO - Duration::from_millis(1))
+            .unwrap();
+        assert_eq!(tcp.poll_receive().unwrap(), 0);
+
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        // SYN, SYN/ACK, ACKの3つ
+        assert_eq!(tcp.poll_receive().unwrap(), 3);
+        let server = tcp.accept(listening_socket).unwrap();
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::Established
+        );
+        assert_eq!(
+            tcp.socket_stats(server).unwrap().status,
+            TcpStatus::Established
+        );
+    }
+
+    #[test]
+    fn failed_transmission_keeps_the_data_in_the_send_buffer() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let device = {
+            let mut sockets = tcp.sockets.write().unwrap();
+            let socket = sockets.get_mut(&client).unwrap();
+            std::mem::replace(&mut socket.device, Arc::new(FailingDevice))
+        };
+        let next = tcp.sockets.read().unwrap()[&client].send_param.next;
+        assert!(tcp.send(client, b"hello").is_err());
+        {
+            let sockets = tcp.sockets.read().unwrap();
+            assert_eq!(sockets[&client].send_param.next, next);
+            assert_eq!(sockets[&client].unsent, b"hello");
+        }
+
+        // デバイスが直れば, 送れなかった分から続けて送る
+        tcp.sockets
+            .write()
+            .unwrap()
+            .get_mut(&client)
+            .unwrap()
+            .device = device;
+        tcp.send(client, b", world").unwrap();
+        tcp.poll_receive().unwrap();
+        let mut buffer = [0; 64];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"hello, world");
+    }
+
+    #[test]
+    fn connected_pair_exchanges_data() {
+        let tcp = loopback_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        tcp.send(client, b"hello").unwrap();
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"hello");
+
+        tcp.send(server, b"world").unwrap();
+        let nbytes = tcp.recv(client, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"world");
+    }
+
+    #[test]
+    fn checksum_offload_skips_the_work_but_still_delivers_data() {
+        for offload in [
+            ChecksumOffload::default(),
+            ChecksumOffload { rx: true, tx: true },
+        ] {
+            let tcp = TCP::with_config(TcpConfig {
+                checksum_offload: offload,
+                ..deterministic_config()
+            })
+            .unwrap();
+            let (client, server) = tcp.connected_pair().unwrap();
+            tcp.send(client, b"hello").unwrap();
+            tcp.poll_receive().unwrap();
+            let mut buffer = [0; 16];
+            let nbytes = tcp.recv(server, &mut buffer).unwrap();
+            assert_eq!(&buffer[..nbytes], b"hello");
+
+            let stats = tcp.stack_stats().checksum;
+            assert_eq!(stats.failures, 0);
+            if offload.tx {
+                assert_eq!((stats.computed, stats.verified), (0, 0));
+            } else {
+                // ハンドシェイクの分も含めて, 送った全てのセグメントを受信側で検証している
+                assert!(stats.computed > 0);
+                assert_eq!(stats.computed, stats.verified);
+            }
+        }
+    }
+
+    #[test]
+    fn every_segment_kind_is_encoded_as_pnet_parses_it() {
+        let tcp = TCP::with_config(TcpConfig {
+            verify_encoding: true,
+            capabilities: Capabilities {
+                ecn: true,
+                ..Capabilities::default()
+            },
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.send(client, &[1; MSS + 100]).unwrap();
+        tcp.send_urgent(client, b"!").unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.close(client).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.close(server).unwrap();
+        tcp.poll_receive().unwrap();
+
+        let checksum = tcp.stack_stats().checksum;
+        assert!(checksum.computed > 0);
+        assert_eq!(checksum.encoding_mismatches, 0);
+    }
+
+    #[test]
+    fn active_close_holds_time_wait_for_2msl() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        tcp.close(client).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.close(server).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        assert!(tcp.socket_stats(server).is_err());
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::TimeWait
+        );
+
+        tcp.advance_time(MSL * 2 - Duration::from_millis(2))
+            .unwrap();
+        assert!(tcp.socket_stats(client).is_ok());
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        assert!(tcp.socket_stats(client).is_err());
+        assert!(
+            tcp.recently_closed()
+                .iter()
+                .any(|closed| closed.sock_id == client
+                    && closed.reason == CloseReason::TimeWaitReaped)
+        );
+    }
+
+    #[test]
+    fn simultaneous_close_goes_through_closing() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicU16, Ordering};
+
+        // サーバーからの送信は全て落とし, サーバーの代わりにセグメントを送る
+        let server_port = Arc::new(AtomicU16::new(0));
+        let cloned_server_port = server_port.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities::none(),
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port != cloned_server_port.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        server_port.store(server.local.port(), Ordering::SeqCst);
+        tcp.shutdown(client, How::Write).unwrap();
+        let (seq, fin_seq) = {
+            let sockets = tcp.sockets.read().unwrap();
+            let socket = &sockets[&client];
+            assert_eq!(socket.status, TcpStatus::FinWait1);
+            (socket.recv_param.next, socket.control.fin_seq.unwrap())
+        };
+        let inject = |seq: u32, ack: u32, flag: TcpFlags, payload: &[u8]| {
+            let mut packet = TCPPacket::new(payload.len());
+            packet.set_src(server.local.port());
+            packet.set_dest(client.local.port());
+            packet.set_seq(seq);
+            packet.set_ack(ack);
+            packet.set_flag(flag);
+            packet.set_window_size(SOCKET_BUFFER_SIZE as u16);
+            packet.set_payload(payload);
+            tcp.device
+                .send(&packet, server.local.addr(), server.remote.addr())
+                .unwrap();
+            tcp.poll_receive().unwrap();
+        };
+
+        // 相手もこちらのFINを受け取る前にデータ付きのFINを送ってきた. FINはまだackされていないのでCLOSINGへ進む
+        inject(seq, fin_seq, TcpFlags::FIN | TcpFlags::ACK, b"bye");
+        {
+            let sockets = tcp.sockets.read().unwrap();
+            let socket = &sockets[&client];
+            assert_eq!(socket.status, TcpStatus::Closing);
+            assert!(!socket.control.fin_acked);
+            // データの3バイトとFINの1だけ進む
+            assert_eq!(socket.recv_param.next, seq.wrapping_add(4));
+        }
+        let mut buffer = [0; 16];
+        assert_eq!(tcp.recv(client, &mut buffer).unwrap(), 3);
+        assert_eq!(&buffer[..3], b"bye");
+        assert_eq!(tcp.recv(client, &mut buffer).unwrap(), 0);
+
+        // FINの再送にはACKを返し直すだけでCLOSINGに留まる
+        inject(seq, fin_seq, TcpFlags::FIN | TcpFlags::ACK, b"bye");
+        assert_eq!(tcp.socket_stats(client).unwrap().status, TcpStatus::Closing);
+
+        // こちらのFINがackされればTIME_WAITへ進む
+        inject(
+            seq.wrapping_add(4),
+            fin_seq.wrapping_add(1),
+            TcpFlags::ACK,
+            &[],
+        );
+        let sockets = tcp.sockets.read().unwrap();
+        let socket = &sockets[&client];
+        assert_eq!(socket.status, TcpStatus::TimeWait);
+        assert_eq!(socket.recv_param.next, seq.wrapping_add(4));
+    }
+
+    #[test]
+    fn out_of_window_segments_are_acked_in_syn_rcvd_and_time_wait() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        // ハンドシェイクの最後のACKを落とし, 代わりに偽装したACKを送る
+        let server_acks = Arc::new(AtomicUsize::new(0));
+        let cloned_server_acks = server_acks.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.local_port == 40000 {
+                    if info.flags.is_pure_ack() {
+                        cloned_server_acks.fetch_add(1, Ordering::SeqCst);
+                    }
+                    return true;
+                }
+                info.flags.has_syn()
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        let (seq, ack) = {
+            let sockets = tcp.sockets.read().unwrap();
+            (
+                sockets[&client].send_param.next,
+                sockets[&client].recv_param.next,
+            )
+        };
+        let inject = |tcp: &TCP, from: SockID, seq: u32, ack: u32, flag: TcpFlags| {
+            let mut packet = TCPPacket::new(0);
+            packet.set_src(from.local.port());
+            packet.set_dest(from.remote.port());
+            packet.set_seq(seq);
+            packet.set_ack(ack);
+            packet.set_flag(flag);
+            packet.set_window_size(4380);
+            tcp.device
+                .send(&packet, from.local.addr(), from.remote.addr())
+                .unwrap();
+            tcp.poll_receive().unwrap();
+        };
+
+        // ackは正しくてもシーケンス番号が受信ウィンドウの外なら, 接続を確立せずにACKだけ返す
+        inject(&tcp, client, seq.wrapping_add(1 << 30), ack, TcpFlags::ACK);
+        assert_eq!(server_acks.load(Ordering::SeqCst), 1);
+        assert_eq!(tcp.pending_connections(listener).unwrap(), 0);
+        inject(&tcp, client, seq, ack, TcpFlags::ACK);
+        assert_eq!(tcp.pending_connections(listener).unwrap(), 1);
+
+        // TIME_WAITでは再送されてきたFINにだけ応えて2MSLのタイマーをやり直す
+        // 相手はもういないので, クライアントのACKに返ってくるRSTでTIME_WAITが消されないよう落とす
+        let drop_client = Arc::new(AtomicBool::new(false));
+        let cloned_drop_client = drop_client.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port == 40000 || !cloned_drop_client.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+        tcp.close(client).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.close(server).unwrap();
+        tcp.poll_receive().unwrap();
+        drop_client.store(true, Ordering::SeqCst);
+        let (seq, ack, deadline) = {
+            let sockets = tcp.sockets.read().unwrap();
+            let socket = &sockets[&client];
+            assert_eq!(socket.status, TcpStatus::TimeWait);
+            (
+                socket.recv_param.next,
+                socket.send_param.next,
+                socket.closing_deadline.unwrap(),
+            )
+        };
+        let peer = SockID::new(client.remote, client.local);
+        let fin = TcpFlags::FIN | TcpFlags::ACK;
+        tcp.advance_time(Duration::from_secs(1)).unwrap();
+        inject(&tcp, peer, seq.wrapping_add(1 << 30), ack, fin);
+        assert_eq!(
+            tcp.sockets.read().unwrap()[&client].closing_deadline,
+            Some(deadline)
+        );
+        inject(&tcp, peer, seq.wrapping_sub(1), ack, fin);
+        assert_eq!(
+            tcp.sockets.read().unwrap()[&client].closing_deadline,
+            Some(deadline + Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn data_piggybacked_on_fin_is_read_before_eof() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicU16, Ordering};
+
+        // silencedのポートからの送信は全て落とし, 代わりにセグメントを送る
+        let silenced = Arc::new(AtomicU16::new(0));
+        let cloned_silenced = silenced.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port != cloned_silenced.load(Ordering::SeqCst) || info.flags.has_syn()
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let inject = |from: SockID, seq: u32, ack: u32, payload: &[u8]| {
+            let mut packet = TCPPacket::new(payload.len());
+            packet.set_src(from.local.port());
+            packet.set_dest(from.remote.port());
+            packet.set_seq(seq);
+            packet.set_ack(ack);
+            packet.set_flag(TcpFlags::FIN | TcpFlags::ACK | TcpFlags::PSH);
+            packet.set_window_size(4380);
+            packet.set_payload(payload);
+            tcp.device
+                .send(&packet, from.local.addr(), from.remote.addr())
+                .unwrap();
+            tcp.poll_receive().unwrap();
+        };
+        let read_to_end = |sock_id: SockID| {
+            let mut received = Vec::new();
+            let mut buffer = [0; 64];
+            loop {
+                let nbytes = tcp.recv(sock_id, &mut buffer).unwrap();
+                if nbytes == 0 {
+                    return received;
+                }
+                received.extend_from_slice(&buffer[..nbytes]);
+            }
+        };
+
+        // 応答を書いてすぐに閉じるサーバー. FINはデータの後ろのシーケンス番号にある
+        let (client, server) = tcp.connected_pair().unwrap();
+        silenced.store(server.local.port(), Ordering::SeqCst);
+        let (seq, ack) = {
+            let sockets = tcp.sockets.read().unwrap();
+            (
+                sockets[&client].recv_param.next,
+                sockets[&client].send_param.next,
+            )
+        };
+        inject(server, seq, ack, b"HTTP/1.0 200 OK");
+        {
+            let sockets = tcp.sockets.read().unwrap();
+            assert_eq!(sockets[&client].status, TcpStatus::CloseWait);
+            assert_eq!(sockets[&client].recv_param.next, seq.wrapping_add(16));
+        }
+        assert_eq!(read_to_end(client), b"HTTP/1.0 200 OK");
+
+        // ハンドシェイクの最後のACKにリクエストとFINを載せてくるクライアント
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        silenced.store(client.local.port(), Ordering::SeqCst);
+        tcp.poll_receive().unwrap();
+        let (seq, ack) = {
+            let sockets = tcp.sockets.read().unwrap();
+            (
+                sockets[&client].send_param.next,
+                sockets[&client].recv_param.next,
+            )
+        };
+        inject(client, seq, ack, b"GET /");
+        let server = tcp.accept(listener).unwrap();
+        assert_eq!(tcp.info(server).unwrap().status, TcpStatus::CloseWait);
+        assert_eq!(read_to_end(server), b"GET /");
+    }
+
+    #[test]
+    fn stale_retransmission_entries_are_collected() {
+        let tcp = deterministic_tcp();
+        let (client, _) = tcp.connected_pair().unwrap();
+        tcp.advance_time(retransmission_entry_max_age() * 2)
+            .unwrap();
+
+        // 再送タイマーは切れていないが, 寿命を過ぎたエントリを紛れ込ませる
+        {
+            let mut sockets = tcp.sockets.write().unwrap();
+            let socket = sockets.get_mut(&client).unwrap();
+            let now = tcp.clock.now();
+            // 再送キューにはシーケンス番号を消費するセグメントしか入らないので, 1バイトのデータを載せる
+            let mut packet = TCPPacket::new(1);
+            packet.set_seq(socket.send_param.next);
+            packet.set_flag(TcpFlags::PSH | TcpFlags::ACK);
+            let mut item = RetransmissionQueueEntry::new(packet, now);
+            item.queued_at = now - retransmission_entry_max_age() - Duration::from_secs(1);
+            socket.retransmission_queue.push_back(item);
+        }
+
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        assert_eq!(tcp.stack_stats().stale_retransmissions, 1);
+        assert_eq!(
+            tcp.socket_stats(client)
+                .unwrap()
+                .memory
+                .retransmission_queue,
+            0
+        );
+    }
+
+    #[test]
+    fn window_probe_recovers_a_lost_window_update() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // 数を入れた分だけサーバーからのセグメントを落とす
+        let drop_server = Arc::new(AtomicUsize::new(0));
+        let cloned_drop_server = drop_server.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port != 40000
+                    || cloned_drop_server
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_err()
+            })),
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let server = tcp.accept(listener).unwrap();
+
+        let sender = {
+            let tcp = tcp.clone();
+            thread::spawn(move || tcp.send(client, &[7; 10000]))
+        };
+        // サーバーが読まないので, 送ったデータが全てackされた後はウィンドウが開くのを待つ
+        let blocked = || {
+            let sockets = tcp.sockets.read().unwrap();
+            sockets[&client].persist_deadline.is_some()
+                && sockets[&client].retransmission_queue.is_empty()
+        };
+        while !blocked() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // 読んでウィンドウが開いたことを知らせるACKを落とす
+        drop_server.store(1, Ordering::SeqCst);
+        let mut received = 0;
+        let mut buffer = [0; 10000];
+        while received < 10000 {
+            received += tcp.recv(server, &mut buffer[received..]).unwrap();
+        }
+        sender.join().unwrap().unwrap();
+        assert_eq!(buffer, [7; 10000]);
+        assert_eq!(drop_server.load(Ordering::SeqCst), 0);
+        assert!(tcp.stack_stats().window_probes >= 1);
+    }
+
+    #[test]
+    fn persist_timer_probes_a_zero_window_with_backoff() {
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities::none(),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let una = tcp.sockets.read().unwrap()[&client].send_param.unacked_seq;
+        tcp.send(client, &[1; 1000]).unwrap();
+        take_sent(&tcp);
+        // 全てackされたがウィンドウが閉じた
+        inject_ack(&tcp, server, una.wrapping_add(1000), 0);
+        tcp.send(client, &[2; 100]).unwrap();
+        assert!(take_sent(&tcp).is_empty());
+        let persist = || {
+            let sockets = tcp.sockets.read().unwrap();
+            let socket = &sockets[&client];
+            (socket.persist_deadline.is_some(), socket.window_probes_sent)
+        };
+        assert_eq!(persist(), (true, 0));
+
+        // RTO毎ではなく, プローブを送る度に間隔を倍にする
+        let rto = tcp.sockets.read().unwrap()[&client].rtt.rto();
+        let is_probe = |packet: &TCPPacket| {
+            packet.payload().is_empty()
+                && packet.get_flag() == TcpFlags::ACK
+                && packet.get_seq() == una.wrapping_add(999)
+        };
+        tcp.advance_time(rto).unwrap();
+        let sent = take_sent(&tcp);
+        assert_eq!(sent.len(), 1);
+        assert!(is_probe(&sent[0]));
+        assert_eq!(persist(), (true, 1));
+        tcp.advance_time(rto).unwrap();
+        assert!(take_sent(&tcp).is_empty());
+        tcp.advance_time(rto).unwrap();
+        assert!(take_sent(&tcp).iter().all(is_probe));
+        assert_eq!(persist(), (true, 2));
+        assert_eq!(tcp.stack_stats().window_probes, 2);
+
+        // 開いたことを知らされたら送信バッファのデータを送り, パーシストタイマーを止める
+        inject_ack(&tcp, server, una.wrapping_add(1000), 4380);
+        let sockets = tcp.sockets.read().unwrap();
+        assert!(sockets[&client].unsent.is_empty());
+        assert_eq!(sockets[&client].send_param.next, una.wrapping_add(1100));
+        drop(sockets);
+        assert_eq!(persist(), (false, 0));
+    }
+
+    #[test]
+    fn tx_ring_batches_outgoing_segments() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            tx_ring: Some(64),
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        let sender = {
+            let tcp = tcp.clone();
+            thread::spawn(move || tcp.send(client, &[7; 10000]))
+        };
+        let mut received = 0;
+        let mut buffer = [0; 1000];
+        while received < 10000 {
+            received += tcp.recv(server, &mut buffer).unwrap();
+        }
+        sender.join().unwrap().unwrap();
+
+        let stats = tcp.stack_stats().tx_ring;
+        // ループバックはリングから取り出した分を1度に書き込む
+        assert_eq!(stats.writes, stats.batches);
+        assert!(stats.packets >= stats.writes);
+        assert!(stats.packets_per_write() >= 1.0);
+    }
+
+    #[test]
+    fn simultaneous_open_establishes_both_sides() {
+        let tcp = deterministic_tcp();
+        let a = tcp.connect_from(40001, Ipv4Addr::LOCALHOST, 40002).unwrap();
+        let b = tcp.connect_from(40002, Ipv4Addr::LOCALHOST, 40001).unwrap();
+
+        // SYN x2, SYN/ACK x2
+        assert_eq!(tcp.poll_receive().unwrap(), 4);
+        for sock_id in [a, b] {
+            assert_eq!(
+                tcp.socket_stats(sock_id).unwrap().status,
+                TcpStatus::Established
+            );
+        }
+
+        tcp.send(a, b"hello").unwrap();
+        tcp.poll_receive().unwrap();
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(b, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"hello");
+    }
+
+    #[test]
+    fn sack_retransmits_only_the_missing_segment() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        // 最初のデータセグメントだけ落とし, 送信したデータセグメントを数える
+        let dropped = Arc::new(AtomicBool::new(false));
+        let data_segments = Arc::new(AtomicUsize::new(0));
+        let (cloned_dropped, cloned_data_segments) = (dropped.clone(), data_segments.clone());
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.payload_len == 0 {
+                    return true;
+                }
+                cloned_data_segments.fetch_add(1, Ordering::SeqCst);
+                cloned_dropped.swap(true, Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        let data: Vec<u8> = (0..MSS * 3).map(|i| (i % 251) as u8).collect();
+        tcp.send(client, &data).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(data_segments.load(Ordering::SeqCst), 3);
+
+        // 2つ目と3つ目はSACKで受信済みと分かっているので, 何度タイマーが走っても1つ目しか再送しない
+        // 1つ目の再送でRTOが倍になっても, 後ろの2つは先にタイムアウトする
+        for _ in 0..25 {
+            tcp.advance_time(Duration::from_millis(100)).unwrap();
+        }
+        assert_eq!(data_segments.load(Ordering::SeqCst), 4);
+        assert!(tcp.stack_stats().sack_skipped_retransmissions >= 2);
+
+        tcp.poll_receive().unwrap();
+        let mut buffer = vec![0; data.len()];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], &data[..]);
+    }
+
+    #[test]
+    fn close_wait_side_keeps_sending_and_processes_acks() {
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        // クライアントが送信側を閉じてもサーバーは応答を返し続けられる
+        tcp.close(client).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(
+            tcp.socket_stats(server).unwrap().status,
+            TcpStatus::CloseWait
+        );
+
+        tcp.send(server, b"response").unwrap();
+        tcp.poll_receive().unwrap();
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(client, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"response");
+        // タイマーを待たずにackされたセグメントが再送キューから取り除かれる
+        assert_eq!(
+            tcp.socket_stats(server)
+                .unwrap()
+                .memory
+                .retransmission_queue,
+            0
+        );
+
+        tcp.close(server).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        assert!(tcp.socket_stats(server).is_err());
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::TimeWait
+        );
+    }
+
+    #[test]
+    fn paws_drops_segments_with_old_timestamps() {
+        use crate::packet::TcpOption;
+
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        tcp.advance_time(Duration::from_secs(1)).unwrap();
+        tcp.send(client, b"new").unwrap();
+        tcp.poll_receive().unwrap();
+        let (recv_next, ts_recent) = {
+            let sockets = tcp.sockets.read().unwrap();
+            let socket = &sockets[&server];
+            assert!(socket.capabilities.timestamps);
+            (socket.recv_param.next, socket.ts_recent)
+        };
+        assert_eq!(ts_recent, 1000);
+
+        // シーケンス番号は受信ウィンドウ内だが, tsvalが古い重複セグメント
+        let mut packet = TCPPacket::with_options(
+            &[TcpOption::Timestamps {
+                tsval: ts_recent - 500,
+                tsecr: 0,
+            }],
+            3,
+        );
+        packet.set_src(client.local.port());
+        packet.set_dest(client.remote.port());
+        packet.set_seq(recv_next);
+        packet.set_flag(TcpFlags::ACK);
+        packet.set_window_size(4380);
+        packet.set_payload(b"old");
+        tcp.device
+            .send(&packet, client.local.addr(), client.remote.addr())
+            .unwrap();
+        tcp.poll_receive().unwrap();
+
+        assert_eq!(tcp.stack_stats().paws_drops, 1);
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"new");
+    }
+
+    #[test]
+    fn info_reports_negotiated_capabilities() {
+        use crate::filter::SegmentFilter;
+
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities {
+                sack: false,
+                ..Capabilities::default()
+            },
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        for sock_id in [client, server] {
+            let capabilities = tcp.info(sock_id).unwrap().capabilities;
+            assert!(!capabilities.sack);
+            assert!(capabilities.timestamps);
+        }
+
+        // オプションを付けないSYNを送ってくる相手とはどちらも使わない
+        // 相手は実在しないので, SYN/ACKにRSTが返ってこないよう落とす
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(|info| info.remote_port != 50000)),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listening_socket = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let peer = SockID::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50000),
+            listening_socket.local,
+        );
+        let mut syn = TCPPacket::new(0);
+        syn.set_src(peer.local.port());
+        syn.set_dest(peer.remote.port());
+        syn.set_seq(1000);
+        syn.set_flag(TcpFlags::SYN);
+        syn.set_window_size(4380);
+        tcp.device
+            .send(&syn, peer.local.addr(), peer.remote.addr())
+            .unwrap();
+        tcp.poll_receive().unwrap();
+
+        let child = tcp
+            .connections()
+            .into_iter()
+            .find(|info| info.status == TcpStatus::SynRcvd)
+            .unwrap();
+        // Nagleは相手と交渉するものではないので設定のまま
+        assert_eq!(
+            child.capabilities,
+            Capabilities {
+                nagle: true,
+                ..Capabilities::none()
+            }
+        );
+    }
+
+    #[test]
+    fn window_scale_is_chosen_from_recv_buffer_size() {
+        let tcp = TCP::with_config(TcpConfig {
+            recv_buffer_size: 256 * 1024,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        {
+            let sockets = tcp.sockets.read().unwrap();
+            for sock_id in [client, server] {
+                assert_eq!(sockets[&sock_id].recv_wscale, 3);
+                assert_eq!(sockets[&sock_id].recv_param.advertised(3, false), 32768);
+            }
+        }
+        assert!(tcp.info(client).unwrap().capabilities.window_scale);
+
+        tcp.send(client, &[7; 8000]).unwrap();
+        tcp.poll_receive().unwrap();
+        let mut buffer = [0; 8000];
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 8000);
+
+        // ウィンドウスケールなしでは64KBより大きいバッファを広告できない
+        let tcp = TCP::with_config(TcpConfig {
+            recv_buffer_size: 256 * 1024,
+            capabilities: Capabilities {
+                window_scale: false,
+                ..Capabilities::default()
+            },
+            ..deterministic_config()
+        })
+        .unwrap();
+        let error = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap_err();
+        assert!(format!("{:#}", error).contains("without window scaling"));
+        assert!(tcp.connect(Ipv4Addr::LOCALHOST, 40000).is_err());
+    }
+
+    #[test]
+    fn cwnd_collapses_on_timeout_and_slow_starts_on_acks() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let drop_data = Arc::new(AtomicBool::new(true));
+        let cloned_drop_data = drop_data.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.payload_len == 0 || !cloned_drop_data.load(Ordering::SeqCst)
+            })),
+            delayed_ack: None,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, _server) = tcp.connected_pair().unwrap();
+        assert_eq!(tcp.socket_stats(client).unwrap().cwnd, 10 * MSS as u32);
+
+        tcp.send(client, b"lost").unwrap();
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        let stats = tcp.socket_stats(client).unwrap();
+        assert_eq!(stats.cwnd, MSS as u32);
+        assert_eq!(stats.ssthresh, 2 * MSS as u32);
+
+        // 再送がackされるとスロースタートで広がっていく
+        drop_data.store(false, Ordering::SeqCst);
+        tcp.advance_time(INITIAL_RTO * 2).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.socket_stats(client).unwrap().cwnd, MSS as u32 + 4);
+        tcp.send(client, &[0; MSS]).unwrap();
+        tcp.poll_receive().unwrap();
+        let stats = tcp.socket_stats(client).unwrap();
+        assert_eq!(stats.cwnd, 2 * MSS as u32 + 4);
+
+        // ssthreshに達した後は輻輳回避で少しずつしか増えない
+        tcp.send(client, &[0; MSS]).unwrap();
+        tcp.poll_receive().unwrap();
+        let cwnd = tcp.socket_stats(client).unwrap().cwnd;
+        assert!(cwnd > 2 * MSS as u32 + 4 && cwnd < 3 * MSS as u32);
+    }
+
+    #[test]
+    fn initial_window_that_overflows_cwnd_is_rejected() {
+        let tcp = TCP::with_config(TcpConfig {
+            initial_window: u32::MAX as usize / MSS + 1,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let err = tcp.connect(Ipv4Addr::LOCALHOST, 80).unwrap_err();
+        assert_eq!(err.to_string(), "invalid initial_window");
+        assert!(tcp.sockets.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn spurious_rto_is_undone_only_when_the_ack_comes_too_soon() {
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities::none(),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let una = tcp.sockets.read().unwrap()[&client].send_param.unacked_seq;
+        let cwnd_and_ssthresh = || {
+            let stats = tcp.socket_stats(client).unwrap();
+            (stats.cwnd, stats.ssthresh)
+        };
+        // 100msのRTTを測っておく
+        tcp.send(client, &[1; 1000]).unwrap();
+        tcp.advance_time(Duration::from_millis(100)).unwrap();
+        inject_ack(&tcp, server, una.wrapping_add(1000), 4380);
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().min_rtt,
+            Some(Duration::from_millis(100))
+        );
+        let before = cwnd_and_ssthresh();
+
+        // 再送した直後(RTTの半分より前)に届いたACKは元のセグメントに対するものなので, 縮めた輻輳ウィンドウを戻す
+        tcp.send(client, &[2; 1000]).unwrap();
+        take_sent(&tcp);
+        let rto = tcp.sockets.read().unwrap()[&client].rtt.rto();
+        tcp.advance_time(rto).unwrap();
+        assert_eq!(cwnd_and_ssthresh().0, MSS as u32);
+        inject_ack(&tcp, server, una.wrapping_add(2000), 4380);
+        let (cwnd, ssthresh) = cwnd_and_ssthresh();
+        assert!(cwnd >= before.0);
+        assert_eq!(ssthresh, before.1);
+        assert_eq!(tcp.stack_stats().spurious_rtos, 1);
+
+        // RTTの半分以上経ってから届いたACKは再送に対するものなので, 縮めたまま
+        tcp.send(client, &[3; 1000]).unwrap();
+        take_sent(&tcp);
+        let rto = tcp.sockets.read().unwrap()[&client].rtt.rto();
+        tcp.advance_time(rto).unwrap();
+        tcp.advance_time(Duration::from_millis(60)).unwrap();
+        take_sent(&tcp);
+        inject_ack(&tcp, server, una.wrapping_add(3000), 4380);
+        assert!(cwnd_and_ssthresh().0 < before.0);
+        assert_eq!(tcp.stack_stats().spurious_rtos, 1);
+    }
+
+    #[test]
+    fn retransmissions_are_paced_by_the_budget() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let data_segments = Arc::new(AtomicUsize::new(0));
+        let cloned_data_segments = data_segments.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            retransmit_budget: 1,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.payload_len > 0 {
+                    cloned_data_segments.fetch_add(1, Ordering::SeqCst);
+                }
+                info.payload_len == 0
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let clients: Vec<SockID> = (0..3).map(|_| tcp.connected_pair().unwrap().0).collect();
+        for client in &clients {
+            tcp.send(*client, b"lost").unwrap();
+        }
+        assert_eq!(data_segments.load(Ordering::SeqCst), 3);
+
+        // 3つの接続が同時にタイムアウトしても, 1回のタイマーでは1つしか再送しない
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        assert_eq!(data_segments.load(Ordering::SeqCst), 4);
+        assert_eq!(tcp.stack_stats().deferred_retransmissions, 2);
+
+        tcp.advance_time(Duration::from_millis(100)).unwrap();
+        tcp.advance_time(Duration::from_millis(100)).unwrap();
+        assert_eq!(data_segments.load(Ordering::SeqCst), 6);
+        assert_eq!(tcp.stack_stats().deferred_retransmissions, 3);
+    }
+
+    #[test]
+    fn out_of_window_segment_is_answered_with_current_ack() {
+        use crate::filter::{SegmentFilter, SegmentInfo};
+
+        let sent = Arc::new(Mutex::new(Vec::<SegmentInfo>::new()));
+        let cloned_sent = sent.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities {
+                timestamps: false,
+                ..Capabilities::default()
+            },
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                cloned_sent.lock().unwrap().push(*info);
+                true
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let recv_next = tcp.sockets.read().unwrap()[&server].recv_param.next;
+        sent.lock().unwrap().clear();
+
+        // 受信ウィンドウ(4380バイト)より先のデータ
+        let mut packet = TCPPacket::new(3);
+        packet.set_src(client.local.port());
+        packet.set_dest(client.remote.port());
+        packet.set_seq(recv_next.wrapping_add(10000));
+        packet.set_flag(TcpFlags::ACK);
+        packet.set_window_size(4380);
+        packet.set_payload(b"far");
+        tcp.device
+            .send(&packet, client.local.addr(), client.remote.addr())
+            .unwrap();
+        tcp.poll_receive().unwrap();
+
+        assert_eq!(tcp.stack_stats().out_of_window_acks, 1);
+        let sent = sent.lock().unwrap();
+        assert!(sent
+            .iter()
+            .any(|info| info.local_port == server.local.port()
+                && info.flags == TcpFlags::ACK
+                && info.ack == recv_next));
+        assert_eq!(
+            tcp.sockets.read().unwrap()[&server].recv_param.next,
+            recv_next
+        );
+    }
+
+    #[test]
+    fn rto_adapts_to_measured_rtt_and_backs_off() {
+        use crate::filter::SegmentFilter;
+        use crate::rtt::MIN_RTO;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        for timestamps in [true, false] {
+            let drop_data = Arc::new(AtomicBool::new(false));
+            let cloned_drop_data = drop_data.clone();
+            let tcp = TCP::with_config(TcpConfig {
+                capabilities: Capabilities {
+                    timestamps,
+                    ..Capabilities::default()
+                },
+                egress_filter: Some(SegmentFilter::new(move |info| {
+                    info.payload_len == 0 || !cloned_drop_data.load(Ordering::SeqCst)
+                })),
+                delayed_ack: None,
+                ..deterministic_config()
+            })
+            .unwrap();
+            let (client, server) = tcp.connected_pair().unwrap();
+            tcp.advance_time(Duration::from_millis(10)).unwrap();
+            assert_eq!(tcp.socket_stats(client).unwrap().rto, INITIAL_RTO);
+
+            // ループバックなのでRTTはほぼ0になり, RTOは下限まで縮む
+            tcp.send(client, b"ping").unwrap();
+            tcp.poll_receive().unwrap();
+            let stats = tcp.socket_stats(client).unwrap();
+            assert_eq!(stats.srtt, Some(Duration::ZERO));
+            assert_eq!(stats.rto, MIN_RTO);
+
+            // ロスしたセグメントはINITIAL_RTOを待たずに再送され, 再送の度にRTOが倍になる
+            drop_data.store(true, Ordering::SeqCst);
+            tcp.send(client, b"lost").unwrap();
+            tcp.advance_time(MIN_RTO).unwrap();
+            assert_eq!(tcp.socket_stats(client).unwrap().rto, MIN_RTO * 2);
+
+            drop_data.store(false, Ordering::SeqCst);
+            tcp.advance_time(MIN_RTO * 2).unwrap();
+            tcp.poll_receive().unwrap();
+            let mut buffer = [0; 16];
+            let nbytes = tcp.recv(server, &mut buffer).unwrap();
+            assert_eq!(&buffer[..nbytes], b"pinglost");
+        }
+    }
+
+    #[test]
+    fn retransmission_backs_off_up_to_max_rto_and_aborts() {
+        use crate::filter::SegmentFilter;
+
+        let tcp = TCP::with_config(TcpConfig {
+            max_rto: Duration::from_secs(2),
+            egress_filter: Some(SegmentFilter::new(|info| info.payload_len == 0)),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, _server) = tcp.connected_pair().unwrap();
+        tcp.send(client, b"lost").unwrap();
+
+        // 1秒, 2秒と倍になった後は上限の2秒のまま再送する
+        let mut rtos = Vec::new();
+        for _ in 0..8 {
+            tcp.advance_time(Duration::from_secs(1)).unwrap();
+            rtos.push(tcp.socket_stats(client).unwrap().rto.as_secs());
+        }
+        assert_eq!(rtos, [2; 8]);
+
+        // MAX_TRANSMITTION回送っても届かなければ接続を中断する
+        tcp.advance_time(Duration::from_secs(1)).unwrap();
+        assert!(tcp.socket_stats(client).is_err());
+        assert!(tcp.send(client, b"more").is_err());
+        let closed = tcp.recently_closed();
+        assert!(closed.iter().any(|closed| closed.sock_id == client
+            && closed.reason == CloseReason::RetransmissionExhausted));
+        assert_eq!(tcp.stack_stats().retransmission_aborts, 1);
+    }
+
+    #[test]
+    fn wakeup_audit_reports_activity_by_subsystem() {
+        use crate::stats::Subsystem;
+
+        assert!(deterministic_tcp().wakeup_audit().is_none());
+
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            wakeup_audit: true,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.send(client, &[1; 3000]).unwrap();
+        let mut buffer = [0; 3000];
+        let mut received = 0;
+        while received < buffer.len() {
+            received += tcp.recv(server, &mut buffer[received..]).unwrap();
+        }
+        let report = tcp.wakeup_audit().unwrap();
+        // sendは送信バッファにコピーするだけで, セグメントは送信スレッドが送る
+        assert!(report.get(Subsystem::Send).unwrap().lock_acquisitions >= 1);
+        assert!(report.get(Subsystem::Sender).unwrap().lock_acquisitions >= 1);
+        assert!(report.get(Subsystem::Receive).unwrap().lock_acquisitions > 0);
+
+        // 何もしていない間も, 受信スレッドと再送タイマーは一定の間隔で起きてロックを取っている
+        tcp.reset_wakeup_audit();
+        thread::sleep(Duration::from_millis(350));
+        let report = tcp.wakeup_audit().unwrap();
+        assert!(report.elapsed >= Duration::from_millis(350));
+        let timer = report.get(Subsystem::Timer).unwrap();
+        assert!(timer.wakeups >= 2);
+        assert!(timer.lock_acquisitions >= 2);
+        assert!(report.get(Subsystem::Receive).unwrap().wakeups >= 2);
+        assert_eq!(report.get(Subsystem::Send).unwrap().wakeups, 0);
+        assert_eq!(report.get(Subsystem::Sender).unwrap().wakeups, 0);
+        assert!(report.wakeups_per_sec() >= timer.wakeups_per_sec);
+    }
+
+    #[test]
+    fn sequential_port_allocator_makes_ports_reproducible() {
+        use crate::portalloc::SequentialPorts;
+
+        let tcp = TCP::with_config(TcpConfig {
+            port_allocator: Arc::new(SequentialPorts::starting_at(50000)),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        assert_eq!(server.local.port(), 50000);
+        assert_eq!(client.local.port(), 50001);
+
+        // 閉じたリスニングソケットのポートも使い回さずに次へ進む
+        let (client, _) = tcp.connected_pair().unwrap();
+        assert_eq!(client.local.port(), 50003);
+    }
+
+    #[test]
+    fn connect_times_out_after_syn_retries() {
+        use crate::filter::SegmentFilter;
+
+        // 相手から何も返ってこないよう, SYNを全て落とす
+        let syns = Arc::new(Mutex::new(Vec::new()));
+        let cloned_syns = syns.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            syn_retries: 2,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.flags.has_syn() {
+                    cloned_syns.lock().unwrap().push(info.seq);
+                    return false;
+                }
+                true
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let started = tcp.clock.now();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let events = tcp.sockets.read().unwrap()[&client].events.clone();
+
+        // 1秒, 2秒, 4秒と間隔を倍にしながら再送し, syn_retries回再送しても応答がなければ諦める
+        while tcp.socket_stats(client).is_ok() {
+            tcp.advance_time(Duration::from_millis(500)).unwrap();
+        }
+        assert_eq!(syns.lock().unwrap().len(), 3);
+        assert_eq!(tcp.clock.since(started), Duration::from_secs(7));
+        let error = events
+            .wait(TCPEventKind::ConnectionCompleted, None)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "connection timed out");
+        assert!(tcp
+            .recently_closed()
+            .iter()
+            .any(|closed| closed.sock_id == client
+                && closed.reason == CloseReason::RetransmissionExhausted));
+    }
+
+    #[test]
+    fn syn_cookies_allocate_sockets_only_for_validated_acks() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // 最初はクライアントの最後のACKを落とし, SYNだけが届く状態にする
+        let drop_acks = Arc::new(AtomicBool::new(true));
+        let cloned_drop_acks = drop_acks.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            syn_cookies: true,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port == 40000
+                    || info.flags.has_syn()
+                    || !cloned_drop_acks.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let clients: Vec<_> = (0..16)
+            .map(|_| tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap())
+            .collect();
+        tcp.poll_receive().unwrap();
+
+        // SYN/ACKは返すが, サーバー側にはソケットを作らない
+        let stats = tcp.listener_stats(listener).unwrap();
+        assert_eq!(stats.syns_received, 16);
+        assert_eq!(stats.syn_cookies_sent, 16);
+        assert_eq!(tcp.connections().len(), 1 + clients.len());
+        assert!(tcp
+            .connections()
+            .iter()
+            .all(|info| info.status != TcpStatus::SynRcvd));
+        tcp.advance_time(INITIAL_RTO * 4).unwrap();
+        assert_eq!(tcp.poll_receive().unwrap(), 0);
+        assert_eq!(tcp.listener_stats(listener).unwrap().syn_cookies_sent, 16);
+
+        // 最後のACKが落ちても, 次のデータのACKでクッキーを検証して接続を作る
+        drop_acks.store(false, Ordering::SeqCst);
+        tcp.send(clients[0], b"hello").unwrap();
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+        let info = tcp.info(server).unwrap();
+        assert_eq!(info.status, TcpStatus::Established);
+        assert_eq!(
+            info.capabilities,
+            tcp.info(clients[0]).unwrap().capabilities
+        );
+        assert!(info.capabilities.sack && info.capabilities.window_scale);
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"hello");
+        tcp.send(server, b"world").unwrap();
+        tcp.poll_receive().unwrap();
+        let nbytes = tcp.recv(clients[0], &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"world");
+
+        // 期限の切れたクッキーのACKにはRSTを返す
+        tcp.advance_time(Duration::from_secs(128)).unwrap();
+        tcp.send(clients[1], b"late").unwrap();
+        tcp.poll_receive().unwrap();
+        let stats = tcp.listener_stats(listener).unwrap();
+        assert_eq!(stats.syn_cookies_validated, 1);
+        assert_eq!(stats.syn_cookies_rejected, 1);
+        assert!(tcp.info(clients[1]).is_err());
+        assert_eq!(tcp.pending_connections(listener).unwrap(), 0);
+    }
+
+    #[test]
+    fn fast_open_carries_data_in_the_syn_once_the_client_has_a_cookie() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // 立てている間はクライアントからのSYN以外を落とす
+        let drop_client = Arc::new(AtomicBool::new(false));
+        let cloned_drop_client = drop_client.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities {
+                fast_open: true,
+                ..Capabilities::default()
+            },
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.remote_port != 40000
+                    || info.flags.has_syn()
+                    || !cloned_drop_client.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let read = |sock_id: SockID| {
+            let mut buffer = [0; 64];
+            let nbytes = tcp.recv(sock_id, &mut buffer).unwrap();
+            buffer[..nbytes].to_vec()
+        };
+
+        // 最初の接続はSYNでクッキーを要求し, データはハンドシェイクの後に送る
+        tcp.connect_with_data(Ipv4Addr::LOCALHOST, 40000, b"first")
+            .unwrap();
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+        assert_eq!(read(server), b"first");
+        let stats = tcp.listener_stats(listener).unwrap();
+        assert_eq!(
+            (stats.fast_open_cookies_sent, stats.fast_open_accepted),
+            (1, 0)
+        );
+
+        // クッキーを持っていればSYNにデータを載せ, サーバーは最後のACKを待たずにacceptに返す
+        drop_client.store(true, Ordering::SeqCst);
+        let client = tcp
+            .connect_with_data(Ipv4Addr::LOCALHOST, 40000, b"second")
+            .unwrap();
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+        assert_eq!(tcp.info(server).unwrap().status, TcpStatus::SynRcvd);
+        assert_eq!(read(server), b"second");
+        assert_eq!(tcp.listener_stats(listener).unwrap().fast_open_accepted, 1);
+        {
+            let sockets = tcp.sockets.read().unwrap();
+            let socket = &sockets[&client];
+            assert_eq!(socket.status, TcpStatus::Established);
+            assert_eq!(socket.send_param.unacked_seq, socket.send_param.next);
+            assert!(socket.retransmission_queue.is_empty() && socket.unsent.is_empty());
+        }
+        // 最後のACKが届く前から応答を書き込める. SYN/ACKの再送にACKが返れば確立して送られる
+        tcp.send(server, b"reply").unwrap();
+        drop_client.store(false, Ordering::SeqCst);
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(read(client), b"reply");
+        assert_eq!(tcp.info(server).unwrap().status, TcpStatus::Established);
+        assert_eq!(tcp.pending_connections(listener).unwrap(), 0);
+
+        // クッキーが正しくなければSYNのデータは受け取らず, クライアントがハンドシェイクの後に送り直す
+        tcp.fast_open_cache
+            .lock()
+            .unwrap()
+            .insert(Ipv4Addr::LOCALHOST, vec![0; 8]);
+        tcp.connect_with_data(Ipv4Addr::LOCALHOST, 40000, b"third")
+            .unwrap();
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+        assert_eq!(tcp.info(server).unwrap().status, TcpStatus::Established);
+        assert_eq!(read(server), b"third");
+        let stats = tcp.listener_stats(listener).unwrap();
+        assert_eq!(
+            (stats.fast_open_cookies_sent, stats.fast_open_accepted),
+            (2, 1)
+        );
+        assert_ne!(
+            tcp.fast_open_cache.lock().unwrap()[&Ipv4Addr::LOCALHOST],
+            vec![0; 8]
+        );
+    }
+
+    #[test]
+    fn retransmitted_syn_is_answered_with_the_same_synack() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // 最初のSYN/ACKは落とす
+        let drop_server = Arc::new(AtomicBool::new(true));
+        let cloned_drop_server = drop_server.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port != 40000 || !cloned_drop_server.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        let (client_isn, server_isn) = {
+            let sockets = tcp.sockets.read().unwrap();
+            let server = sockets
+                .values()
+                .find(|socket| socket.status == TcpStatus::SynRcvd)
+                .unwrap();
+            (
+                sockets[&client].send_param.initial_seq,
+                server.send_param.initial_seq,
+            )
+        };
+
+        // 再送タイマーより先にクライアントのSYNが再送されてくれば, その場でSYN/ACKを送り直す
+        drop_server.store(false, Ordering::SeqCst);
+        let inject = |seq: u32| {
+            let mut syn = TCPPacket::new(0);
+            syn.set_src(client.local.port());
+            syn.set_dest(client.remote.port());
+            syn.set_seq(seq);
+            syn.set_flag(TcpFlags::SYN);
+            syn.set_window_size(4380);
+            tcp.device
+                .send(&syn, client.local.addr(), client.remote.addr())
+                .unwrap();
+            tcp.poll_receive().unwrap();
+        };
+        // ISNの違うSYNは無視する
+        inject(client_isn.wrapping_add(1000));
+        assert_eq!(tcp.info(client).unwrap().status, TcpStatus::SynSent);
+        inject(client_isn);
+        assert_eq!(tcp.info(client).unwrap().status, TcpStatus::Established);
+        assert_eq!(
+            tcp.sockets.read().unwrap()[&client].recv_param.initial_seq,
+            server_isn
+        );
+        assert_eq!(
+            tcp.listener_stats(listener).unwrap().synack_retransmissions,
+            1
+        );
+        let server = tcp.accept(listener).unwrap();
+        assert_eq!(tcp.info(server).unwrap().status, TcpStatus::Established);
+    }
+
+    #[test]
+    fn lost_handshake_ack_is_recovered_by_synack_retransmission() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // クライアントからはSYNしか届かない
+        let drop_client = Arc::new(AtomicBool::new(true));
+        let cloned_drop_client = drop_client.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            synack_retries: 2,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port == 40000
+                    || info.flags.has_syn()
+                    || !cloned_drop_client.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.info(client).unwrap().status, TcpStatus::Established);
+        assert_eq!(tcp.pending_connections(listener).unwrap(), 0);
+
+        // SYN/ACKはINITIAL_RTOから倍の間隔で再送される
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(
+            tcp.listener_stats(listener).unwrap().synack_retransmissions,
+            1
+        );
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        assert_eq!(
+            tcp.listener_stats(listener).unwrap().synack_retransmissions,
+            1
+        );
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        assert_eq!(
+            tcp.listener_stats(listener).unwrap().synack_retransmissions,
+            2
+        );
+
+        // 再送したSYN/ACKへのACKが届けば接続が確立する
+        drop_client.store(false, Ordering::SeqCst);
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+        assert_eq!(tcp.info(server).unwrap().status, TcpStatus::Established);
+
+        // 最後のACKがいつまでも届かなければ, 上限まで再送した後に半開きの接続を削除する
+        drop_client.store(true, Ordering::SeqCst);
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.connections().len(), 5);
+        for _ in 0..7 {
+            tcp.advance_time(INITIAL_RTO).unwrap();
+            tcp.poll_receive().unwrap();
+        }
+        let stats = tcp.listener_stats(listener).unwrap();
+        assert_eq!(stats.synack_retransmissions, 4);
+        assert_eq!(stats.half_open_timeouts, 1);
+        assert_eq!(tcp.connections().len(), 4);
+        assert_eq!(tcp.info(client).unwrap().status, TcpStatus::Established);
+        assert_eq!(tcp.pending_connections(listener).unwrap(), 0);
+    }
+
+    #[test]
+    fn idle_timeout_closes_connections_without_traffic() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let timeout = |action| {
+            Some(IdleTimeout {
+                duration: Duration::from_secs(10),
+                action,
+            })
+        };
+        tcp.set_idle_timeout(client, timeout(IdleAction::Reset))
+            .unwrap();
+
+        // 送受信がある度に数え直す
+        tcp.advance_time(Duration::from_secs(6)).unwrap();
+        tcp.send(client, b"ping").unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.advance_time(Duration::from_secs(6)).unwrap();
+        assert!(tcp.socket_stats(client).is_ok());
+
+        // Resetなら10秒経った時点でRSTを送って削除する
+        tcp.advance_time(Duration::from_secs(4)).unwrap();
+        assert!(tcp.socket_stats(client).is_err());
+        tcp.poll_receive().unwrap();
+        assert!(tcp.socket_stats(server).is_err());
+        let reasons: Vec<_> = tcp
+            .recently_closed()
+            .iter()
+            .map(|closed| (closed.sock_id, closed.reason))
+            .collect();
+        assert!(reasons.contains(&(client, CloseReason::ResetSent)));
+        assert!(reasons.contains(&(server, CloseReason::ResetReceived)));
+
+        // リスニングソケットに設定するとacceptした接続に引き継がれる. FinならFINを送って閉じ始める
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.set_idle_timeout(listener, timeout(IdleAction::Fin))
+            .unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+        tcp.advance_time(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            tcp.socket_stats(server).unwrap().status,
+            TcpStatus::FinWait1
+        );
+        tcp.poll_receive().unwrap();
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::CloseWait
+        );
+        assert_eq!(tcp.recv(client, &mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn keepalive_probes_detect_a_vanished_peer() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let vanished = Arc::new(AtomicBool::new(false));
+        let cloned_vanished = vanished.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port != 40000 || !cloned_vanished.load(Ordering::SeqCst)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let server_addr = Ipv4Addr::LOCALHOST;
+        let listener = tcp.listen(server_addr, 40000).unwrap();
+        let client = tcp.connect(server_addr, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+        tcp.set_keepalive(
+            client,
+            Some(KeepAlive {
+                idle: Duration::from_secs(10),
+                interval: Duration::from_secs(1),
+                probes: 3,
+            }),
+        )
+        .unwrap();
+
+        // 相手が応答している間はプローブを送っても接続は続く
+        tcp.advance_time(Duration::from_secs(10)).unwrap();
+        assert_eq!(tcp.stack_stats().keepalive_probes, 1);
+        tcp.poll_receive().unwrap();
+        tcp.advance_time(Duration::from_secs(5)).unwrap();
+        assert_eq!(tcp.stack_stats().keepalive_probes, 1);
+        assert!(tcp.socket_stats(client).is_ok());
+
+        // 相手が何も送らずに消えると, probes個のプローブの後に中断する
+        vanished.store(true, Ordering::SeqCst);
+        tcp.advance_time(Duration::from_secs(5)).unwrap();
+        for expected in 2..=4 {
+            tcp.poll_receive().unwrap();
+            assert_eq!(tcp.stack_stats().keepalive_probes, expected);
+            assert!(tcp.socket_stats(client).is_ok());
+            tcp.advance_time(Duration::from_secs(1)).unwrap();
+        }
+        assert!(tcp.socket_stats(client).is_err());
+        let error = tcp.send(client, b"hello").unwrap_err();
+        assert!(format!("{:#}", error).contains("no such socket"));
+        assert!(tcp.recently_closed().iter().any(
+            |closed| closed.sock_id == client && closed.reason == CloseReason::KeepAliveTimeout
+        ));
+        assert_eq!(tcp.stack_stats().keepalive_timeouts, 1);
+        assert!(tcp.socket_stats(server).is_ok());
+    }
+
+    #[test]
+    fn user_timeout_aborts_a_connection_waiting_on_a_vanished_peer() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let vanished = Arc::new(AtomicBool::new(false));
+        let cloned_vanished = vanished.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port != 40000 || !cloned_vanished.load(Ordering::SeqCst)
+            })),
+            user_timeout: Some(Duration::from_secs(3)),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        let server = tcp.accept(listener).unwrap();
+
+        // 待っているものがなければ, 何も受信しなくても中断しない
+        tcp.advance_time(Duration::from_secs(10)).unwrap();
+        assert!(tcp.socket_stats(client).is_ok());
+
+        // 送ったデータへのackが届かないまま, 送った時から3秒経つと中断する
+        vanished.store(true, Ordering::SeqCst);
+        tcp.send(client, b"hello").unwrap();
+        for _ in 0..2 {
+            tcp.advance_time(Duration::from_secs(1)).unwrap();
+            tcp.poll_receive().unwrap();
+            assert!(tcp.socket_stats(client).is_ok());
+        }
+        tcp.advance_time(Duration::from_secs(1)).unwrap();
+        assert!(tcp.socket_stats(client).is_err());
+        assert!(tcp
+            .recently_closed()
+            .iter()
+            .any(|closed| closed.sock_id == client && closed.reason == CloseReason::UserTimeout));
+        assert_eq!(tcp.stack_stats().user_timeouts, 1);
+        assert_eq!(tcp.stack_stats().retransmission_aborts, 0);
+        assert!(tcp.socket_stats(server).is_ok());
+
+        // 送信バッファが一杯でブロックしているsendは入れた分だけを返す. 接続は中断されて続きは送れない
+        let vanished = Arc::new(AtomicBool::new(false));
+        let cloned_vanished = vanished.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                info.local_port != 40000 || !cloned_vanished.load(Ordering::SeqCst)
+            })),
+            send_buffer_size: MSS,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.accept(listener).unwrap();
+        tcp.set_user_timeout(client, Some(Duration::from_millis(300)))
+            .unwrap();
+        vanished.store(true, Ordering::SeqCst);
+        let queued = tcp.send(client, &[1; 8 * MSS]).unwrap();
+        assert!(queued < 8 * MSS);
+        assert!(tcp.send(client, &[1; 8 * MSS]).is_err());
+        assert!(tcp
+            .recently_closed()
+            .iter()
+            .any(|closed| closed.sock_id == client && closed.reason == CloseReason::UserTimeout));
+    }
+
+    #[test]
+    fn shutdown_write_keeps_receiving_until_peer_fin() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        tcp.send(client, b"request").unwrap();
+        tcp.shutdown(client, How::Write).unwrap();
+        tcp.poll_receive().unwrap();
+        assert!(tcp.send(client, b"more").is_err());
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::FinWait2
+        );
+
+        // サーバーはリクエストの終わりを読んでから応答を返す
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"request");
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 0);
+        tcp.send(server, b"response").unwrap();
+        tcp.close(server).unwrap();
+        tcp.poll_receive().unwrap();
+
+        // FINを送った後に届いたデータも読め, 相手のFINの後は0が返る
+        let nbytes = tcp.recv(client, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"response");
+        assert_eq!(tcp.recv(client, &mut buffer).unwrap(), 0);
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::TimeWait
+        );
+
+        tcp.close(client).unwrap();
+        assert!(tcp.recv(client, &mut buffer).is_err());
+    }
+
+    #[test]
+    fn reading_sends_a_window_update_once_an_mss_opens() {
+        let tcp = TCP::with_config(TcpConfig {
+            recv_buffer_size: 3 * MSS,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.send(client, &[1; 3 * MSS]).unwrap();
+        tcp.poll_receive().unwrap();
+        let peer_window = || tcp.sockets.read().unwrap()[&client].send_param.window;
+        assert_eq!(peer_window(), 0);
+
+        // MSS分開くまでは知らせない
+        let mut buffer = [0; 1000];
+        tcp.recv(server, &mut buffer).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.stack_stats().window_updates, 0);
+        assert_eq!(peer_window(), 0);
+
+        // 送るデータがなくても, 開いたことをACKで知らせて止まっている相手に送らせる
+        tcp.recv(server, &mut buffer).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.stack_stats().window_updates, 1);
+        assert_eq!(peer_window(), 2000);
+        // 残りの540バイトはNagleでACKを待つ
+        tcp.send(client, &[2; 2000]).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(
+            tcp.sockets.read().unwrap()[&server].readable_bytes(),
+            3 * MSS - 2000 + MSS
+        );
+    }
+
+    #[test]
+    fn send_buffers_data_until_the_window_opens() {
+        let tcp = TCP::with_config(TcpConfig {
+            recv_buffer_size: 3 * MSS,
+            send_buffer_size: 4 * MSS,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        // ウィンドウを超える分は送信バッファに残してすぐに返る
+        tcp.send(client, &[1; 5 * MSS]).unwrap();
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().memory.send_buffer,
+            2 * MSS
+        );
+        tcp.send(client, &[2; 2 * MSS]).unwrap();
+        // 送信バッファが一杯なら空くまでブロックする(決定的モードではエラー)
+        assert!(tcp.send(client, &[3; 1]).is_err());
+
+        // FINは送信バッファのデータを全て送った後に送る
+        tcp.close(client).unwrap();
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::FinWait1
+        );
+        let mut received = Vec::new();
+        let mut buffer = [0; 4 * MSS];
+        loop {
+            tcp.poll_receive().unwrap();
+            let nbytes = tcp.recv(server, &mut buffer).unwrap();
+            if nbytes == 0 {
+                break;
+            }
+            received.extend_from_slice(&buffer[..nbytes]);
+        }
+        assert_eq!(received, [[1; 5 * MSS].as_slice(), &[2; 2 * MSS]].concat());
+        tcp.poll_receive().unwrap();
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::FinWait2
+        );
+    }
+
+    #[test]
+    fn recv_buffer_grows_when_the_reader_keeps_up() {
+        let tcp = TCP::with_config(TcpConfig {
+            recv_buffer_size: 3 * MSS,
+            recv_buffer_autotune: Some(10 * MSS),
+            ..deterministic_config()
+        })
+        .unwrap();
+        // 時刻0のタイムスタンプはエコーされてもRTTを測れないので, 時計を進めておく
+        tcp.advance_time(Duration::from_secs(1)).unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let peer_window = || tcp.sockets.read().unwrap()[&client].send_param.window;
+        let mut buffer = vec![0; 10 * MSS];
+
+        // 1RTTでバッファ全体を読み切れたので, 読んだ分の2倍まで広げて相手に知らせる
+        tcp.send(client, &[1; 3 * MSS]).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 3 * MSS);
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.socket_stats(server).unwrap().recv_buffer_size, 6 * MSS);
+        assert_eq!(peer_window(), 6 * MSS as u32);
+
+        // 上限より大きくはしない
+        tcp.send(client, &[2; 6 * MSS]).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 6 * MSS);
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.socket_stats(server).unwrap().recv_buffer_size, 10 * MSS);
+        assert_eq!(tcp.stack_stats().recv_buffers_grown, 2);
+
+        // 読み出しが増えなければそれ以上は広げない
+        tcp.send(client, &[3; MSS]).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), MSS);
+        assert_eq!(tcp.stack_stats().recv_buffers_grown, 2);
+    }
+
+    #[test]
+    fn last_ack_is_removed_once_its_fin_is_acked() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.close(client).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.close(server).unwrap();
+        assert_eq!(tcp.socket_stats(server).unwrap().status, TcpStatus::LastAck);
+
+        // 時間を進めなくても, FINへのACKを受け取った時点で削除される
+        tcp.poll_receive().unwrap();
+        assert!(tcp.socket_stats(server).is_err());
+        let closed = tcp.recently_closed();
+        let closed = closed
+            .iter()
+            .find(|closed| closed.sock_id == server)
+            .unwrap();
+        assert_eq!(closed.reason, CloseReason::Fin);
+        assert_eq!(closed.final_status, TcpStatus::LastAck);
+
+        // shutdownの後でcloseした場合, 既にackされていればその場で削除する
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.close(client).unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.shutdown(server, How::Write).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.socket_stats(server).unwrap().status, TcpStatus::LastAck);
+        tcp.close(server).unwrap();
+        assert!(tcp.socket_stats(server).is_err());
+    }
+
+    #[test]
+    fn shutdown_read_discards_incoming_data() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        tcp.send(server, b"unread").unwrap();
+        tcp.poll_receive().unwrap();
+        tcp.shutdown(client, How::Read).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(tcp.recv(client, &mut buffer).unwrap(), 0);
+
+        // 後から届いたデータも読めず, 受信バッファにも残らない. 送信側はまだ使える
+        tcp.send(server, b"late").unwrap();
+        tcp.send(client, b"request").unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.recv(client, &mut buffer).unwrap(), 0);
+        assert_eq!(tcp.sockets.read().unwrap()[&client].readable_bytes(), 0);
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"request");
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::Established
+        );
+
+        // 両方閉じればFINも送る
+        tcp.shutdown(client, How::Both).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 0);
+        assert_eq!(
+            tcp.socket_stats(client).unwrap().status,
+            TcpStatus::FinWait2
+        );
+        assert!(tcp.shutdown(client, How::Write).is_err());
+    }
+
+    #[test]
+    fn reverse_path_drops_unexpected_sources() {
+        use crate::config::{Prefix, ReversePath};
+
+        let tcp = TCP::with_config(TcpConfig {
+            reverse_path: ReversePath::Strict(vec![Prefix::new(Ipv4Addr::new(10, 0, 0, 0), 8)]),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let listening_socket = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        assert_eq!(tcp.poll_receive().unwrap(), 1);
+        assert!(tcp.accept(listening_socket).is_err());
+        assert_eq!(tcp.stack_stats().reverse_path_drops, 1);
+
+        let loose = ReversePath::Loose;
+        let local = Ipv4Addr::new(192, 168, 0, 1);
+        assert!(policy::reverse_path_allows(
+            &loose,
+            local,
+            Ipv4Addr::new(192, 168, 0, 2)
+        ));
+        assert!(!policy::reverse_path_allows(
+            &loose,
+            local,
+            Ipv4Addr::new(0, 1, 2, 3)
+        ));
+        assert!(!policy::reverse_path_allows(
+            &loose,
+            local,
+            Ipv4Addr::new(250, 0, 0, 1)
+        ));
+        assert!(!policy::reverse_path_allows(
+            &loose,
+            local,
+            Ipv4Addr::LOCALHOST
+        ));
+        assert!(!policy::reverse_path_allows(&loose, local, local));
+    }
+
+    #[test]
+    fn data_transfer_survives_sequence_wraparound() {
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        // 送信するデータの途中で2^32を跨ぐようにシーケンス番号をずらす
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        {
+            let mut sockets = tcp.sockets.write().unwrap();
+            let seq = u32::MAX - 100;
+            let socket = sockets.get_mut(&client).unwrap();
+            socket.send_param.unacked_seq = seq;
+            socket.send_param.next = seq;
+            let socket = sockets.get_mut(&server).unwrap();
+            socket.recv_param.next = seq;
+        }
+
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        tcp.send(client, &data).unwrap();
+        tcp.poll_receive().unwrap();
+        let mut buffer = [0; 2000];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], &data[..]);
+
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        let sockets = tcp.sockets.read().unwrap();
+        let socket = sockets.get(&client).unwrap();
+        assert_eq!(socket.send_param.unacked_seq, 899);
+        assert_eq!(socket.send_param.next, 899);
+        assert!(socket.retransmission_queue.is_empty());
+    }
+
+    #[test]
+    fn overlapping_and_duplicate_segments_are_trimmed() {
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (_client, server) = tcp.connected_pair().unwrap();
+        let segment = |seq: u32, payload: &[u8]| {
+            let mut packet = TCPPacket::new(payload.len());
+            packet.set_seq(seq);
+            packet.set_flag(TcpFlags::ACK);
+            packet.set_payload(payload);
+            packet
+        };
+
+        let mut sockets = tcp.sockets.write().unwrap();
+        let socket = sockets.get_mut(&server).unwrap();
+        let next = socket.recv_param.next;
+        tcp.process_payload(socket, &segment(next + 100, &[2; 100]))
+            .unwrap();
+        assert_eq!(socket.reassembly.len(), 100);
+
+        // 保持しているデータと全く同じセグメントはACKを返すだけ
+        socket.ack_pending = false;
+        tcp.process_payload(socket, &segment(next + 100, &[7; 100]))
+            .unwrap();
+        assert!(socket.ack_pending);
+        assert_eq!(socket.reassembly.len(), 100);
+
+        // 一部が重なるセグメントは新しい部分だけを使う
+        tcp.process_payload(socket, &segment(next + 50, &[9; 100]))
+            .unwrap();
+        assert_eq!(socket.reassembly.len(), 150);
+        tcp.process_payload(socket, &segment(next, &[1; 60]))
+            .unwrap();
+        assert_eq!(socket.recv_param.next, next + 200);
+        assert!(socket.reassembly.is_empty());
+        let mut expected = vec![1; 60];
+        expected.extend_from_slice(&[9; 40]);
+        expected.extend_from_slice(&[2; 100]);
+        assert_eq!(&socket.recv_buffer[..200], &expected[..]);
+
+        // 受信バッファに移した後の再送もバッファには触れない
+        socket.ack_pending = false;
+        tcp.process_payload(socket, &segment(next, &[8; 60]))
+            .unwrap();
+        assert!(socket.ack_pending);
+        assert_eq!(socket.recv_param.next, next + 200);
+        assert_eq!(&socket.recv_buffer[..200], &expected[..]);
+
+        // 受信ウィンドウの右端からはみ出した部分は切り捨て, ウィンドウの外から始まるセグメントは捨てる
+        let right_edge = socket.recv_param.next + socket.recv_param.window;
+        socket.ack_pending = false;
+        tcp.process_payload(socket, &segment(right_edge - 50, &[3; 100]))
+            .unwrap();
+        assert!(socket.ack_pending);
+        assert_eq!(socket.reassembly.len(), 50);
+        tcp.process_payload(socket, &segment(right_edge, &[4; 100]))
+            .unwrap();
+        assert_eq!(socket.reassembly.len(), 50);
+        drop(sockets);
+        assert_eq!(tcp.stack_stats().duplicate_segments, 2);
+    }
+
+    #[test]
+    fn payload_is_trimmed_to_both_edges_of_the_receive_window() {
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (_client, server) = tcp.connected_pair().unwrap();
+        let segment = |seq: u32, payload: &[u8]| {
+            let mut packet = TCPPacket::new(payload.len());
+            packet.set_seq(seq);
+            packet.set_flag(TcpFlags::ACK);
+            packet.set_payload(payload);
+            packet
+        };
+        let numbered = |len: usize| -> Vec<u8> { (0..len).map(|i| i as u8).collect() };
+
+        let mut sockets = tcp.sockets.write().unwrap();
+        let socket = sockets.get_mut(&server).unwrap();
+        let next = socket.recv_param.next;
+        let window = socket.recv_param.window;
+
+        // 左端: 受信済みの部分を切り捨て, 続きだけを受信バッファに入れる
+        tcp.process_payload(socket, &segment(next.wrapping_sub(50), &numbered(100)))
+            .unwrap();
+        assert_eq!(socket.recv_param.next, next.wrapping_add(50));
+        assert_eq!(&socket.recv_buffer[..50], &numbered(100)[50..]);
+
+        // 右端: ウィンドウからはみ出した部分を切り捨て, ACKでウィンドウを伝え直す
+        socket.ack_pending = false;
+        let payload = numbered(window as usize);
+        tcp.process_payload(socket, &segment(next.wrapping_add(50), &payload))
+            .unwrap();
+        assert!(socket.ack_pending);
+        assert_eq!(socket.recv_param.next, next.wrapping_add(window));
+        assert_eq!(socket.recv_param.window, 0);
+        assert_eq!(
+            &socket.recv_buffer[50..window as usize],
+            &payload[..window as usize - 50]
+        );
+
+        // ゼロウィンドウ: 次に受け取るはずのセグメントも入れずにACKだけ返し, その先のセグメントは捨てる
+        socket.ack_pending = false;
+        tcp.process_payload(socket, &segment(next.wrapping_add(window), &[9; 10]))
+            .unwrap();
+        assert!(socket.ack_pending);
+        assert_eq!(socket.recv_param.next, next.wrapping_add(window));
+        tcp.process_payload(socket, &segment(next.wrapping_add(window + 10), &[9; 10]))
+            .unwrap();
+        assert!(socket.reassembly.is_empty());
+        drop(sockets);
+
+        // 両端: 100バイト読んで開いたウィンドウに, 前後20バイトずつはみ出したセグメントが届く
+        let mut buffer = [0; 100];
+        assert_eq!(tcp.recv(server, &mut buffer).unwrap(), 100);
+        let mut sockets = tcp.sockets.write().unwrap();
+        let socket = sockets.get_mut(&server).unwrap();
+        tcp.process_payload(
+            socket,
+            &segment(next.wrapping_add(window - 20), &numbered(140)),
+        )
+        .unwrap();
+        assert_eq!(socket.recv_param.next, next.wrapping_add(window + 100));
+        assert_eq!(socket.recv_param.window, 0);
+        // 読んだ分は前に詰められているので, 新しいデータは受信バッファの末尾に入る
+        let end = window as usize;
+        assert_eq!(&socket.recv_buffer[end - 100..end], &numbered(140)[20..120]);
+    }
+
+    #[test]
+    fn two_holes_are_reassembled_in_order() {
+        use crate::filter::SegmentFilter;
+        use std::collections::HashSet;
+
+        // 2番目と4番目のセグメントを最初の1回だけ落とし, 受信側に穴を2つ作る
+        let dropped = Arc::new(Mutex::new(HashSet::new()));
+        let cloned_dropped = dropped.clone();
+        let first_seq = Arc::new(Mutex::new(None));
+        let cloned_first_seq = first_seq.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            recv_buffer_size: MSS * 8,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.payload_len == 0 {
+                    return true;
+                }
+                let first = *cloned_first_seq.lock().unwrap().get_or_insert(info.seq);
+                let index = info.seq.wrapping_sub(first) as usize / MSS;
+                !(index == 1 || index == 3) || !cloned_dropped.lock().unwrap().insert(index)
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        let data: Vec<u8> = (0..MSS * 5).map(|i| (i % 251) as u8).collect();
+        tcp.send(client, &data).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(dropped.lock().unwrap().len(), 2);
+        assert_eq!(tcp.socket_stats(server).unwrap().memory.reassembly, MSS * 2);
+
+        let mut received = Vec::new();
+        let mut buffer = [0; 2000];
+        for _ in 0..10 {
+            tcp.advance_time(INITIAL_RTO).unwrap();
+            tcp.poll_receive().unwrap();
+            while let Ok(nbytes) = tcp.recv(server, &mut buffer) {
+                if nbytes == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..nbytes]);
+            }
+            if received.len() == data.len() {
+                break;
+            }
+        }
+        assert!(received == data, "reassembled data was corrupted");
+        assert_eq!(tcp.socket_stats(server).unwrap().memory.reassembly, 0);
+    }
+
+    #[test]
+    fn urgent_data_is_delivered_out_of_band() {
+        let tcp = deterministic_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let events = tcp.subscribe(server).unwrap();
+
+        tcp.send(client, b"hello ").unwrap();
+        tcp.send_urgent(client, b"!").unwrap();
+        tcp.send(client, b"world").unwrap();
+        assert!(tcp.send_urgent(client, &[]).is_err());
+        assert!(tcp.send_urgent(client, &[0; MSS + 1]).is_err());
+        tcp.poll_receive().unwrap();
+
+        assert!(events
+            .try_iter()
+            .any(|event| event == SocketNotification::UrgentData { pending: 1 }));
+        let mut buffer = [0; 16];
+        assert_eq!(tcp.recv_urgent(server, &mut buffer).unwrap(), 1);
+        assert_eq!(&buffer[..1], b"!");
+        assert_eq!(tcp.recv_urgent(server, &mut buffer).unwrap(), 0);
+
+        // 緊急データは通常のデータの流れには含まれない
+        let mut received = Vec::new();
+        while received.len() < 11 {
+            let nbytes = tcp.recv(server, &mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..nbytes]);
+        }
+        assert_eq!(received, b"hello world");
+    }
+
+    #[test]
+    fn ecn_echo_reduces_cwnd_without_loss() {
+        use crate::filter::SegmentFilter;
+
+        // どちらかがECNを使わなければ交渉しない
+        let tcp = deterministic_tcp();
+        let (client, _) = tcp.connected_pair().unwrap();
+        assert!(!tcp.info(client).unwrap().capabilities.ecn);
+
+        let flags = Arc::new(Mutex::new(Vec::new()));
+        let cloned_flags = flags.clone();
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            capabilities: Capabilities {
+                ecn: true,
+                ..Capabilities::default()
+            },
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                cloned_flags
+                    .lock()
+                    .unwrap()
+                    .push((info.local_port, info.flags));
+                true
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        assert!(tcp.info(client).unwrap().capabilities.ecn);
+        assert!(tcp.info(server).unwrap().capabilities.ecn);
+
+        // 経路上のルーターの代わりにCEの印を付けてから届ける
+        tcp.send(client, &[1; 100]).unwrap();
+        let mut segment = tcp.device.recv(Some(Duration::ZERO)).unwrap().unwrap();
+        assert_eq!(segment.packet.get_ecn(), Ecn::Ect0);
+        segment.packet.set_ecn(Ecn::Ce);
+        tcp.device
+            .send(&segment.packet, segment.remote_addr, segment.local_addr)
+            .unwrap();
+        let cwnd = tcp.socket_stats(client).unwrap().cwnd;
+        tcp.poll_receive().unwrap();
+
+        // ECEの立ったACKを受けてロスなしでcwndを縮める
+        assert!(tcp.socket_stats(client).unwrap().cwnd < cwnd);
+        assert_eq!(tcp.stack_stats().ecn_cwnd_reductions, 1);
+        assert!(flags
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|&(port, flag)| { port == server.local.port() && flag.has_ece() }));
+
+        // 次のデータにCWRを立て, それを受けた相手はECEを止める
+        flags.lock().unwrap().clear();
+        tcp.send(client, &[2; 100]).unwrap();
+        tcp.poll_receive().unwrap();
+        let flags = flags.lock().unwrap();
+        assert!(flags
+            .iter()
+            .any(|&(port, flag)| port == client.local.port() && flag.has_cwr()));
+        assert!(flags
+            .iter()
+            .all(|&(port, flag)| port != server.local.port() || !flag.has_ece()));
+        assert_eq!(tcp.stack_stats().ecn_cwnd_reductions, 1);
+    }
+
+    #[test]
+    fn repeated_duplicates_are_acked_with_backoff() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+        let server_port = Arc::new(AtomicU16::new(0));
+        let acks = Arc::new(AtomicUsize::new(0));
+        let (cloned_server_port, cloned_acks) = (server_port.clone(), acks.clone());
+        let tcp = TCP::with_config(TcpConfig {
+            delayed_ack: None,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.local_port == cloned_server_port.load(Ordering::SeqCst) {
+                    cloned_acks.fetch_add(1, Ordering::SeqCst);
+                }
+                true
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        server_port.store(server.local.port(), Ordering::SeqCst);
+
+        tcp.send(client, b"hello").unwrap();
+        let segment = tcp.device.recv(Some(Duration::ZERO)).unwrap().unwrap();
+        // 1回目は普通に受信し, 続く8回の再送には1, 2, 4, 8回目にだけACKを返す
+        for _ in 0..9 {
+            tcp.device
+                .send(&segment.packet, segment.remote_addr, segment.local_addr)
+                .unwrap();
+            tcp.poll_receive().unwrap();
+        }
+        assert_eq!(acks.load(Ordering::SeqCst), 5);
+        let stats = tcp.socket_stats(server).unwrap();
+        assert_eq!(stats.duplicates_received, 8);
+        assert_eq!(stats.duplicate_acks_suppressed, 4);
+        assert_eq!(tcp.stack_stats().duplicate_segments, 8);
+        assert_eq!(tcp.stack_stats().duplicate_acks_suppressed, 4);
+        let mut buffer = [0; 16];
+        let nbytes = tcp.recv(server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..nbytes], b"hello");
+    }
+
+    #[test]
+    fn blind_rst_and_syn_get_rate_limited_challenge_acks() {
+        use crate::filter::SegmentFilter;
+        use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+        let server_port = Arc::new(AtomicU16::new(0));
+        let acks = Arc::new(AtomicUsize::new(0));
+        let (cloned_server_port, cloned_acks) = (server_port.clone(), acks.clone());
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities::none(),
+            challenge_ack_limit: 2,
+            egress_filter: Some(SegmentFilter::new(move |info| {
+                if info.local_port == cloned_server_port.load(Ordering::SeqCst) {
+                    cloned_acks.fetch_add(1, Ordering::SeqCst);
+                }
+                true
+            })),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        server_port.store(server.local.port(), Ordering::SeqCst);
+        let rcv_nxt = tcp.sockets.read().unwrap()[&server].recv_param.next;
+        // 接続を知らない攻撃者の代わりにクライアントを騙って送る
+        let inject = |seq: u32, flag: TcpFlags| {
+            let mut packet = TCPPacket::new(0);
+            packet.set_src(client.local.port());
+            packet.set_dest(server.local.port());
+            packet.set_seq(seq);
+            packet.set_flag(flag);
+            tcp.device
+                .send(&packet, client.local.addr(), client.remote.addr())
+                .unwrap();
+            tcp.poll_receive().unwrap();
+        };
+
+        // ウィンドウ内だがRCV.NXTちょうどではないRSTと, 同期済みの接続へのSYNには従わずにチャレンジACKを返す
+        inject(rcv_nxt.wrapping_add(100), TcpFlags::RST);
+        inject(rcv_nxt.wrapping_add(12345), TcpFlags::SYN);
+        assert_eq!(acks.load(Ordering::SeqCst), 2);
+        // 1秒に2つまでしか返さない
+        inject(rcv_nxt.wrapping_add(100), TcpFlags::RST);
+        assert_eq!(acks.load(Ordering::SeqCst), 2);
+        let stats = tcp.stack_stats();
+        assert_eq!(
+            (stats.challenge_acks, stats.challenge_acks_suppressed),
+            (2, 1)
+        );
+        assert_eq!(
+            tcp.socket_stats(server).unwrap().status,
+            TcpStatus::Established
+        );
+        tcp.advance_time(Duration::from_secs(1)).unwrap();
+        inject(rcv_nxt.wrapping_add(1), TcpFlags::RST);
+        assert_eq!(acks.load(Ordering::SeqCst), 3);
+
+        // RCV.NXTちょうどのRSTだけが接続を中断させる
+        inject(rcv_nxt, TcpFlags::RST);
+        assert!(tcp.socket_stats(server).is_err());
+    }
+
+    #[test]
+    fn reordered_acks_do_not_restore_a_stale_window() {
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities::none(),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.send(client, &[1; 2000]).unwrap();
+        // 送ったデータは捨て, 相手の代わりにACKを作って届ける
+        while tcp.device.recv(Some(Duration::ZERO)).unwrap().is_some() {}
+        let (una, seq) = {
+            let sockets = tcp.sockets.read().unwrap();
+            (
+                sockets[&client].send_param.unacked_seq,
+                sockets[&server].send_param.next,
+            )
+        };
+        let send_ack = |ack: u32, window: u16| {
+            let mut packet = TCPPacket::new(0);
+            packet.set_src(server.local.port());
+            packet.set_dest(client.local.port());
+            packet.set_seq(seq);
+            packet.set_ack(ack);
+            packet.set_flag(TcpFlags::ACK);
+            packet.set_window_size(window);
+            tcp.device
+                .send(&packet, server.local.addr(), server.remote.addr())
+                .unwrap();
+        };
+        let send_param = || tcp.sockets.read().unwrap()[&client].send_param;
+
+        // 新しいACKの後に, 大きなウィンドウを広告していた古いACKが届く
+        send_ack(una.wrapping_add(MSS as u32), 1000);
+        send_ack(una, 4380);
+        tcp.poll_receive().unwrap();
+        assert_eq!(send_param().unacked_seq, una.wrapping_add(MSS as u32));
+        assert_eq!(send_param().window, 1000);
+
+        // ackが進まないセグメントでもウィンドウだけは更新する
+        send_ack(una.wrapping_add(2000), 0);
+        tcp.poll_receive().unwrap();
+        assert_eq!(send_param().writable(), 0);
+        // ウィンドウが開くまでは送信バッファに溜めておき, 開いたACKを受け取った時に送る
+        tcp.send(client, &[2; 10]).unwrap();
+        assert_eq!(tcp.sockets.read().unwrap()[&client].unsent.len(), 10);
+        send_ack(una.wrapping_add(2000), 3000);
+        tcp.poll_receive().unwrap();
+        assert!(tcp.sockets.read().unwrap()[&client].unsent.is_empty());
+        assert_eq!(send_param().writable(), 2990);
+    }
+
+    #[test]
+    fn partially_acked_segment_is_retransmitted() {
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities::none(),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        tcp.send(client, &[1; 1000]).unwrap();
+        while tcp.device.recv(Some(Duration::ZERO)).unwrap().is_some() {}
+        let (una, seq) = {
+            let sockets = tcp.sockets.read().unwrap();
+            (
+                sockets[&client].send_param.unacked_seq,
+                sockets[&server].send_param.next,
+            )
+        };
+        let send_ack = |ack: u32| {
+            let mut packet = TCPPacket::new(0);
+            packet.set_src(server.local.port());
+            packet.set_dest(client.local.port());
+            packet.set_seq(seq);
+            packet.set_ack(ack);
+            packet.set_flag(TcpFlags::ACK);
+            packet.set_window_size(4380);
+            tcp.device
+                .send(&packet, server.local.addr(), server.remote.addr())
+                .unwrap();
+        };
+        let queued = || {
+            tcp.sockets.read().unwrap()[&client]
+                .retransmission_queue
+                .len()
+        };
+
+        // 相手のウィンドウに収まった先頭の300バイトだけがackされても, 残りのためにセグメントを再送する
+        send_ack(una.wrapping_add(300));
+        tcp.poll_receive().unwrap();
+        assert_eq!(queued(), 1);
+        tcp.advance_time(INITIAL_RTO).unwrap();
+        let retransmitted = tcp
+            .device
+            .recv(Some(Duration::ZERO))
+            .unwrap()
+            .unwrap()
+            .packet;
+        assert_eq!(retransmitted.get_seq(), una);
+        assert_eq!(retransmitted.payload().len(), 1000);
+
+        send_ack(una.wrapping_add(1000));
+        tcp.poll_receive().unwrap();
+        assert_eq!(queued(), 0);
+    }
+
+    #[test]
+    fn rto_backs_off_while_the_head_segment_is_only_partially_acked() {
+        let tcp = TCP::with_config(TcpConfig {
+            capabilities: Capabilities::none(),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let una = tcp.sockets.read().unwrap()[&client].send_param.unacked_seq;
+        tcp.send(client, &[1; 1000]).unwrap();
+        take_sent(&tcp);
+        // 先頭のセグメントの途中までackされると, そのseqはSND.UNAより前になる
+        inject_ack(&tcp, server, una.wrapping_add(300), 4380);
+        let rto = || tcp.sockets.read().unwrap()[&client].rtt.rto();
+        let before = rto();
+
+        // それでも先頭のセグメントなので, 再送する度にRTOを倍にする
+        tcp.advance_time(before).unwrap();
+        assert_eq!(take_sent(&tcp)[0].get_seq(), una);
+        assert_eq!(rto(), before * 2);
+        tcp.advance_time(before * 2).unwrap();
+        assert_eq!(take_sent(&tcp)[0].get_seq(), una);
+        assert_eq!(rto(), before * 4);
+    }
+
+    // 決定的モードでこのスタックに宛ててsrc_portからdest_portへのセグメントを届け, 返されたセグメントを取り出す
+    // poll_receiveは返したRSTまで処理してしまうので, 1つだけhandle_packetに渡す
+    fn reply_to(
+        tcp: &TCP,
+        src_port: u16,
+        dest_port: u16,
+        flag: TcpFlags,
+        seq: u32,
+        ack: u32,
+        payload: &[u8],
+    ) -> Vec<TCPPacket> {
+        let mut packet = TCPPacket::new(payload.len());
+        packet.set_src(src_port);
+        packet.set_dest(dest_port);
+        packet.set_seq(seq);
+        packet.set_ack(ack);
+        packet.set_flag(flag);
+        packet.set_window_size(1000);
+        packet.set_payload(payload);
+        tcp.handle_packet(ReceivedPacket {
+            packet,
+            local_addr: Ipv4Addr::LOCALHOST,
+            remote_addr: Ipv4Addr::LOCALHOST,
+        });
+        take_sent(tcp)
+    }
+
+    #[test]
+    fn segments_for_closed_ports_are_answered_with_rfc793_resets() {
+        let tcp = deterministic_tcp();
+
+        // ACKのないセグメントにはSEQ 0でSEG.SEQ + SEG.LENをackするRST/ACKを返す. SYNとFINも1つと数える
+        let sent = reply_to(&tcp, 50000, 40000, TcpFlags::SYN, 1000, 0, &[]);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_flag(), TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!((sent[0].get_src(), sent[0].get_dest()), (40000, 50000));
+        assert_eq!((sent[0].get_seq(), sent[0].get_ack()), (0, 1001));
+        let sent = reply_to(&tcp, 50000, 40000, TcpFlags::SYN, 1000, 0, &[1; 10]);
+        assert_eq!(sent[0].get_ack(), 1011);
+
+        // ACKが立っていればSEG.ACKをSEQにしたRSTを返す
+        let sent = reply_to(&tcp, 50000, 40000, TcpFlags::ACK, 1000, 5555, &[1; 10]);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_flag(), TcpFlags::RST);
+        assert_eq!(sent[0].get_seq(), 5555);
+
+        // RSTにはRSTを返さない
+        assert!(reply_to(&tcp, 50000, 40000, TcpFlags::RST, 1000, 0, &[]).is_empty());
+    }
+
+    #[test]
+    fn ack_to_a_listening_socket_is_answered_with_a_reset() {
+        let tcp = deterministic_tcp();
+        tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+
+        let sent = reply_to(&tcp, 50000, 40000, TcpFlags::ACK, 1000, 7777, &[]);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_flag(), TcpFlags::RST);
+        assert_eq!(sent[0].get_seq(), 7777);
+        assert_eq!(sent[0].get_dest(), 50000);
+        // リスニングソケットは残り, SYNは受け付ける
+        let sent = reply_to(&tcp, 50000, 40000, TcpFlags::SYN, 1000, 0, &[]);
+        assert_eq!(sent[0].get_flag(), TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(sent[0].get_ack(), 1001);
+    }
+
+    #[test]
+    fn concurrent_connects_on_one_tuple_register_only_one_socket() {
+        use std::sync::Barrier;
+
+        let tcp = loopback_tcp();
+        tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let barrier = Arc::new(Barrier::new(8));
+        let connects: Vec<_> = (0..8)
+            .map(|_| {
+                let tcp = tcp.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    tcp.connect_from(50000, Ipv4Addr::LOCALHOST, 40000)
+                })
+            })
+            .collect();
+        let results: Vec<_> = connects
+            .into_iter()
+            .map(|connect| connect.join().unwrap())
+            .collect();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        for result in results.iter().filter(|result| result.is_err()) {
+            let error = result.as_ref().unwrap_err();
+            assert!(format!("{:#}", error).contains("address already in use"));
+        }
+    }
+
+    #[test]
+    fn memory_ceiling_rejects_new_connections_until_buffers_drain() {
+        let tcp = TCP::with_config(TcpConfig {
+            memory_ceiling: Some(1000),
+            ..deterministic_config()
+        })
+        .unwrap();
+        let (client, server) = tcp.connected_pair().unwrap();
+        let listener = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+
+        // 読まれていないデータはソケット毎にもスタック全体でも数えられる
+        tcp.send(client, &[0; 2 * MSS]).unwrap();
+        tcp.poll_receive().unwrap();
+        // ackされたSYN/ACKは再送タイマーが動いた時に再送キューから取り除かれる
+        tcp.advance_time(Duration::from_millis(1)).unwrap();
+        assert_eq!(
+            tcp.socket_stats(server).unwrap().memory.recv_buffer,
+            2 * MSS
+        );
+        assert_eq!(tcp.socket_stats(client).unwrap().memory.total(), 0);
+        assert_eq!(tcp.stack_stats().memory.total(), 2 * MSS);
+
+        // 上限を超えている間はconnectもSYNも受け付けない
+        assert!(tcp.connect(Ipv4Addr::LOCALHOST, 40000).is_err());
+        assert!(reply_to(&tcp, 50000, 40000, TcpFlags::SYN, 100, 0, &[]).is_empty());
+        assert_eq!(tcp.stack_stats().memory_ceiling_rejections, 2);
+        assert!(tcp.accept_pending(listener).unwrap().is_empty());
+
+        // 読み出して下回れば, また受け付ける
+        let mut buffer = [0; 2 * MSS];
+        let mut received = 0;
+        while received < buffer.len() {
+            received += tcp.recv(server, &mut buffer[received..]).unwrap();
+        }
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.stack_stats().memory.total(), 0);
+        tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        assert_eq!(tcp.accept_pending(listener).unwrap().len(), 1);
+        assert_eq!(tcp.stack_stats().memory_ceiling_rejections, 2);
+    }
+
+    #[test]
+    fn connect_rejects_broadcast_and_multicast() {
+        use crate::policy::AddressError;
+
+        let tcp = loopback_tcp();
+        let error = tcp.connect(Ipv4Addr::BROADCAST, 80).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AddressError>(),
+            Some(&AddressError::Broadcast(Ipv4Addr::BROADCAST))
+        );
+        let multicast = Ipv4Addr::new(224, 0, 0, 1);
+        let error = tcp.connect(multicast, 80).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AddressError>(),
+            Some(&AddressError::Multicast(multicast))
+        );
+        assert_eq!(tcp.stack_stats().rejected_connects, 2);
+        assert!(tcp.connections().is_empty());
+    }
+
+    #[test]
+    fn reset_wakes_blocked_recv() {
+        let tcp = loopback_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        let [receiver, aborted] = [client, server].map(|sock_id| {
+            let tcp = tcp.clone();
+            thread::spawn(move || tcp.recv(sock_id, &mut [0; 16]))
+        });
+        // recvがブロックするまで待ってからサーバー側をRSTで閉じる
+        thread::sleep(Duration::from_millis(100));
+        tcp.close_matching(|info| info.sock_id == server, CloseMode::Abort);
+
+        let error = receiver.join().unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "connection reset");
+        // RSTを送った側で待っていたrecvにも中断されたことが分かるエラーを返す
+        let error = aborted.join().unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "connection aborted");
+        assert!(tcp
+            .recently_closed()
+            .iter()
+            .any(|closed| closed.sock_id == client && closed.reason == CloseReason::ResetReceived));
+    }
+
+    #[test]
+    fn closing_listener_aborts_only_pending_connections() {
+        let tcp = deterministic_tcp();
+        let listening_socket = tcp.listen(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let accepted_client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+        let accepted_server = tcp.accept(listening_socket).unwrap();
+        let pending_client = tcp.connect(Ipv4Addr::LOCALHOST, 40000).unwrap();
+        tcp.poll_receive().unwrap();
+
+        tcp.close(listening_socket).unwrap();
+        // acceptされていない接続はRSTで破棄される
+        tcp.poll_receive().unwrap();
+        assert!(tcp.socket_stats(pending_client).is_err());
+        assert!(tcp.recently_closed().iter().any(|closed| {
+            closed.sock_id == pending_client && closed.reason == CloseReason::ResetReceived
+        }));
+
+        // acceptされた接続はそのまま使える
+        assert_eq!(
+            tcp.socket_stats(accepted_server).unwrap().status,
+            TcpStatus::Established
+        );
+        assert_eq!(
+            tcp.socket_stats(accepted_client).unwrap().status,
+            TcpStatus::Established
+        );
+    }
+
+    #[cfg(feature = "stream-hash")]
+    #[test]
+    fn stream_hashes_match_across_connection() {
+        let tcp = loopback_tcp();
+        let (client, server) = tcp.connected_pair().unwrap();
+
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        let sender = {
+            let tcp = tcp.clone();
+            let data = data.clone();
+            thread::spawn(move || tcp.send(client, &data))
+        };
+        let mut received = 0;
+        let mut buffer = [0; 1000];
+        while received < data.len() {
+            received += tcp.recv(server, &mut buffer).unwrap();
+        }
+        sender.join().unwrap().unwrap();
+
+        let sent = tcp.socket_stats(client).unwrap().sent_stream;
+        assert_eq!(sent.bytes, data.len() as u64);
+        assert_eq!(sent, tcp.socket_stats(server).unwrap().received_stream);
+    }
+
+    fn fin_packet(seq: u32, payload: &[u8]) -> TCPPacket {
+        let mut packet = TCPPacket::new(payload.len());
+        packet.set_seq(seq);
+        packet.set_flag(TcpFlags::FIN | TcpFlags::ACK);
+        packet.set_payload(payload);
+        packet
+    }
+
+    #[test]
+    fn fin_without_data_consumes_one_sequence_number() {
+        let packet = fin_packet(1000, &[]);
+        assert_eq!(
+            fin_disposition(1000, &packet),
+            FinDisposition::Accept { next: 1001 }
+        );
+    }
+
+    #[test]
+    fn fin_with_data_is_placed_after_payload() {
+        let packet = fin_packet(1000, b"hello");
+        // ペイロードを処理した後のRCV.NXTはSEG.SEQ + 5になっている
+        assert_eq!(
+            fin_disposition(1005, &packet),
+            FinDisposition::Accept { next: 1006 }
+        );
+        // ペイロードを処理する前だとFINの位置にはまだ届いていない
+        assert_eq!(fin_disposition(1000, &packet), FinDisposition::OutOfOrder);
+    }
+
+    #[test]
+    fn retransmitted_fin_is_duplicate() {
+        assert_eq!(
+            fin_disposition(1001, &fin_packet(1000, &[])),
+            FinDisposition::Duplicate
+        );
+        assert_eq!(
+            fin_disposition(1006, &fin_packet(1000, b"hello")),
+            FinDisposition::Duplicate
+        );
+    }
+
+    #[test]
+    fn fin_after_missing_data_is_out_of_order() {
+        assert_eq!(
+            fin_disposition(1000, &fin_packet(1500, b"data")),
+            FinDisposition::OutOfOrder
+        );
+    }
+
+    #[test]
+    fn fin_sequence_wraps_around() {
+        let packet = fin_packet(u32::MAX - 1, b"ab");
+        assert_eq!(
+            fin_disposition(0, &packet),
+            FinDisposition::Accept { next: 1 }
+        );
+    }
+}
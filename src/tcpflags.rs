@@ -1,41 +1,190 @@
-pub const CWR: u8 = 1 << 7;
-pub const ECE: u8 = 1 << 6;
-pub const URG: u8 = 1 << 5;
-pub const ACK: u8 = 1 << 4;
-pub const PSH: u8 = 1 << 3;
-pub const RST: u8 = 1 << 2;
-pub const SYN: u8 = 1 << 1;
-pub const FIN: u8 = 1;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug, Display};
+use std::ops::{BitAnd, BitOr, BitOrAssign, Not};
 
-pub fn get_bit_mask(flag: u8) -> u8 {
-    let mask = CWR | ECE | URG | ACK | PSH | RST | SYN | FIN;
-    mask ^ flag
+/// TCPヘッダのコントロールフラグ(13バイト目)の集合
+/// 生のu8をビット演算で調べると比較の向きや優先順位を間違えやすいので, 名前の付いた問い合わせを使う
+/// イベントログなどにはu8のまま書き出す
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TcpFlags(u8);
+
+impl TcpFlags {
+    pub const CWR: Self = Self(1 << 7);
+    pub const ECE: Self = Self(1 << 6);
+    pub const URG: Self = Self(1 << 5);
+    pub const ACK: Self = Self(1 << 4);
+    pub const PSH: Self = Self(1 << 3);
+    pub const RST: Self = Self(1 << 2);
+    pub const SYN: Self = Self(1 << 1);
+    pub const FIN: Self = Self(1);
+
+    // Displayで並べる順番
+    const NAMES: [(Self, &'static str); 8] = [
+        (Self::SYN, "SYN"),
+        (Self::FIN, "FIN"),
+        (Self::ACK, "ACK"),
+        (Self::RST, "RST"),
+        (Self::CWR, "CWR"),
+        (Self::ECE, "ECE"),
+        (Self::PSH, "PSH"),
+        (Self::URG, "URG"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// otherのフラグが全て立っているか
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// otherのフラグのどれかが立っているか
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub const fn has_syn(self) -> bool {
+        self.contains(Self::SYN)
+    }
+
+    pub const fn has_ack(self) -> bool {
+        self.contains(Self::ACK)
+    }
+
+    pub const fn has_fin(self) -> bool {
+        self.contains(Self::FIN)
+    }
+
+    pub const fn has_rst(self) -> bool {
+        self.contains(Self::RST)
+    }
+
+    pub const fn has_psh(self) -> bool {
+        self.contains(Self::PSH)
+    }
+
+    pub const fn has_urg(self) -> bool {
+        self.contains(Self::URG)
+    }
+
+    pub const fn has_ece(self) -> bool {
+        self.contains(Self::ECE)
+    }
+
+    pub const fn has_cwr(self) -> bool {
+        self.contains(Self::CWR)
+    }
+
+    /// ACKが立っていて, シーケンス番号を消費するSYN, FINも接続を壊すRSTも立っていない
+    /// PSH, URGやECNのフラグは見ない
+    pub const fn is_pure_ack(self) -> bool {
+        self.has_ack() && !self.intersects(Self(Self::SYN.0 | Self::FIN.0 | Self::RST.0))
+    }
 }
 
-pub fn flag_to_string(flag: u8) -> String {
-    let mut flag_str = String::new();
-    flag_str.reserve(50);
+impl From<u8> for TcpFlags {
+    fn from(bits: u8) -> Self {
+        Self(bits)
+    }
+}
 
-    if flag & SYN > 0 {
-        flag_str.push_str("SYN ");
+impl From<TcpFlags> for u8 {
+    fn from(flags: TcpFlags) -> Self {
+        flags.0
     }
-    if flag & FIN > 0 {
-        flag_str.push_str("FIN ");
+}
+
+impl BitOr for TcpFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
     }
-    if flag & RST > 0 {
-        flag_str.push_str("RST ");
+}
+
+impl BitOrAssign for TcpFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
     }
-    if flag & CWR > 0 {
-        flag_str.push_str("CWR ");
+}
+
+impl BitAnd for TcpFlags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
     }
-    if flag & ECE > 0 {
-        flag_str.push_str("ECE ");
+}
+
+impl Not for TcpFlags {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0)
     }
-    if flag & PSH > 0 {
-        flag_str.push_str("PSH ");
+}
+
+/// 立っているフラグを空白区切りで並べる. 例: "SYN ACK"
+impl Display for TcpFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (flag, name) in Self::NAMES {
+            if self.contains(flag) {
+                if !first {
+                    f.write_str(" ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        Ok(())
     }
-    if flag & URG > 0 {
-        flag_str.push_str("URG");
+}
+
+impl Debug for TcpFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TcpFlags({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_queries_and_display() {
+        let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+        assert!(syn_ack.has_syn() && syn_ack.has_ack() && !syn_ack.has_fin());
+        assert!(!syn_ack.is_pure_ack());
+        assert!((TcpFlags::ACK | TcpFlags::PSH | TcpFlags::ECE).is_pure_ack());
+        assert!(!(TcpFlags::ACK | TcpFlags::RST).is_pure_ack());
+        assert!(!TcpFlags::PSH.is_pure_ack());
+        assert!(
+            syn_ack.contains(TcpFlags::SYN) && !syn_ack.contains(TcpFlags::SYN | TcpFlags::FIN)
+        );
+        assert!(syn_ack.intersects(TcpFlags::SYN | TcpFlags::FIN));
+        assert_eq!(syn_ack & !TcpFlags::SYN, TcpFlags::ACK);
+        assert_eq!(TcpFlags::from_bits(0x12), syn_ack);
+
+        assert_eq!(syn_ack.to_string(), "SYN ACK");
+        assert_eq!(
+            (TcpFlags::URG | TcpFlags::ACK | TcpFlags::ECE).to_string(),
+            "ACK ECE URG"
+        );
+        assert_eq!(TcpFlags::empty().to_string(), "");
+        assert_eq!(format!("{:?}", TcpFlags::RST), "TcpFlags(RST)");
     }
-    flag_str
 }
@@ -0,0 +1,71 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex};
+use std::time::SystemTime;
+
+use crate::clock::Clock;
+use crate::socket::SockID;
+use crate::sync::LockResultExt;
+
+/// ソケット毎の期限を近い順に並べたヒープ. 期限を迎えたソケットだけを見ればよいタイマーに使う
+/// 全ソケットを100ms毎に舐めるタイマースレッドとは別に, ハンドシェイク中(SynSent, SynRcvd)の再送と,
+/// 送信スレッドが送信バッファのデータを送る時刻を管理する. 大量のソケットがあっても期限を迎えたものだけを処理できる
+#[derive(Default)]
+pub struct SocketTimers {
+    deadlines: Mutex<BinaryHeap<Reverse<(SystemTime, SockID)>>>,
+    condvar: Condvar,
+}
+
+impl SocketTimers {
+    /// sock_idのタイマーをdeadlineに設定する
+    /// 古いタイマーは取り消さないので, 期限を迎えた側で本当に再送が必要かどうか確認すること
+    pub fn schedule(&self, sock_id: SockID, deadline: SystemTime) {
+        let mut deadlines = self.deadlines.lock().recover();
+        let earliest = deadlines.peek().map(|Reverse((at, _))| *at);
+        deadlines.push(Reverse((deadline, sock_id)));
+        // 今待っている期限より早ければ待ち直してもらう
+        if earliest.is_none_or(|at| deadline < at) {
+            self.condvar.notify_one();
+        }
+    }
+
+    /// now以前に期限を迎えたタイマーを取り出して返す
+    pub fn take_expired(&self, now: SystemTime) -> Vec<SockID> {
+        take_expired(&mut self.deadlines.lock().recover(), now)
+    }
+
+    /// 期限を迎えたタイマーがあればそれらを取り出して返す. なければ次の期限まで待機する
+    pub fn wait_expired(&self, clock: &Clock) -> Vec<SockID> {
+        let mut deadlines = self.deadlines.lock().recover();
+        loop {
+            let now = clock.now();
+            let expired = take_expired(&mut deadlines, now);
+            if !expired.is_empty() {
+                return expired;
+            }
+
+            deadlines = match deadlines.peek() {
+                Some(Reverse((at, _))) => {
+                    let timeout = at.duration_since(now).unwrap_or_default();
+                    self.condvar.wait_timeout(deadlines, timeout).recover().0
+                }
+                None => self.condvar.wait(deadlines).recover(),
+            };
+        }
+    }
+}
+
+fn take_expired(
+    deadlines: &mut BinaryHeap<Reverse<(SystemTime, SockID)>>,
+    now: SystemTime,
+) -> Vec<SockID> {
+    let mut expired = Vec::new();
+    while let Some(Reverse((at, sock_id))) = deadlines.peek() {
+        if *at > now {
+            break;
+        }
+        expired.push(*sock_id);
+        deadlines.pop();
+    }
+    expired
+}
@@ -0,0 +1,234 @@
+// tokioのAsyncRead/AsyncWriteとStreamを実装したTcpStream, TcpListener
+// 読み書きはTCP::async_recvなどと同じくソケットのイベントでタスクのWakerを起こすので, tokioのランタイムのスレッドをブロックしない
+// hyperなどtokioの上に書かれたアプリケーションをこのスタックに載せて相互接続を試す用
+
+use anyhow::Result;
+use futures_core::Stream;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::socket::{Addr, DefaultEndpoint, SockID};
+use crate::stream::io_error;
+use crate::tcp::{How, TCP};
+
+/// tokio::net::TcpStreamの代わりに使える接続
+/// dropするとcloseする. 能動的に閉じるcloseは相手のFINを待つので, ランタイムのスレッドを塞がないよう別スレッドで閉じる
+pub struct TcpStream {
+    tcp: Arc<TCP>,
+    sock_id: SockID,
+}
+
+impl TcpStream {
+    /// addr:portに接続する
+    pub async fn connect(tcp: &Arc<TCP>, addr: Addr, port: u16) -> Result<Self> {
+        let sock_id = tcp.async_connect(addr, port).await?;
+        Ok(Self::from_sock_id(tcp, sock_id))
+    }
+
+    /// 確立済みの接続を包む. 以降closeはこのTcpStreamに任せる
+    pub fn from_sock_id(tcp: &Arc<TCP>, sock_id: SockID) -> Self {
+        Self {
+            tcp: tcp.clone(),
+            sock_id,
+        }
+    }
+
+    pub fn sock_id(&self) -> SockID {
+        self.sock_id
+    }
+
+    pub fn peer_addr(&self) -> DefaultEndpoint {
+        self.sock_id.remote
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // 空のバッファで0を返すとEOFと区別できないので, 読まずに返る
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let unfilled = buf.initialize_unfilled();
+        match self.tcp.poll_recv(self.sock_id, unfilled, cx.waker()) {
+            Poll::Ready(Ok(nbytes)) => {
+                buf.advance(nbytes);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(error)) => Poll::Ready(Err(io_error(error))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    /// 送信バッファに入るだけ書き込む
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.tcp
+            .poll_send(self.sock_id, buf, cx.waker())
+            .map_err(io_error)
+    }
+
+    /// 送信バッファのデータは送信スレッドが送るので, ここで待つものはない
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// FINを送る. shutdownはブロックしない
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(
+            self.tcp
+                .shutdown(self.sock_id, How::Write)
+                .map_err(io_error),
+        )
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let tcp = self.tcp.clone();
+        let sock_id = self.sock_id;
+        thread::spawn(move || {
+            if let Err(error) = tcp.close(sock_id) {
+                dbg!(error);
+            }
+        });
+    }
+}
+
+/// tokio::net::TcpListenerの代わりに使えるリスニングソケット. Streamとしてacceptした接続を順に返す
+/// dropするとリスニングソケットをcloseする
+pub struct TcpListener {
+    tcp: Arc<TCP>,
+    sock_id: SockID,
+}
+
+impl TcpListener {
+    /// addr:portで待ち受ける
+    pub fn bind(tcp: &Arc<TCP>, addr: Addr, port: u16) -> Result<Self> {
+        let sock_id = tcp.listen(addr, port)?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            sock_id,
+        })
+    }
+
+    pub fn sock_id(&self) -> SockID {
+        self.sock_id
+    }
+
+    /// 接続を1つ受け付け, 相手のアドレスと一緒に返す
+    pub async fn accept(&self) -> Result<(TcpStream, DefaultEndpoint)> {
+        let sock_id = self.tcp.async_accept(self.sock_id).await?;
+        Ok((TcpStream::from_sock_id(&self.tcp, sock_id), sock_id.remote))
+    }
+}
+
+impl Stream for TcpListener {
+    type Item = Result<TcpStream>;
+
+    /// Noneを返すことはない
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.tcp
+            .poll_accept(self.sock_id, cx.waker())
+            .map_ok(|sock_id| TcpStream::from_sock_id(&self.tcp, sock_id))
+            .map(Some)
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        // リスニングソケットのcloseはブロックしない
+        if let Err(error) = self.tcp.close(self.sock_id) {
+            dbg!(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, TcpConfig};
+    use std::future::poll_fn;
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tokio_io_runs_on_top_of_the_stack() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let mut listener = TcpListener::bind(&tcp, Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let server = tokio::spawn(async move {
+            // Streamとして受け付け, 受け取ったものを全てエコーする
+            let next = poll_fn(|cx| Pin::new(&mut listener).poll_next(cx));
+            let mut stream = next.await.unwrap().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let payload: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let mut client = TcpStream::connect(&tcp, Ipv4Addr::LOCALHOST, 40000)
+            .await
+            .unwrap();
+        assert_eq!(client.peer_addr().port(), 40000);
+        client.write_all(&payload).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).await.unwrap();
+        server.await.unwrap();
+        assert!(echoed == payload);
+    }
+
+    // ランタイムのスレッドが1つでも, acceptとconnectを同じスレッドで待ち合わせられる
+    #[tokio::test]
+    async fn accepted_stream_reads_writes_and_shuts_down() {
+        let tcp = TCP::with_config(TcpConfig {
+            backend: Backend::Loopback,
+            ..TcpConfig::default()
+        })
+        .unwrap();
+        let listener = TcpListener::bind(&tcp, Ipv4Addr::LOCALHOST, 40000).unwrap();
+        let (accepted, client) = tokio::join!(
+            listener.accept(),
+            TcpStream::connect(&tcp, Ipv4Addr::LOCALHOST, 40000)
+        );
+        let (mut server, peer_addr) = accepted.unwrap();
+        let mut client = client.unwrap();
+        assert_eq!(peer_addr, client.sock_id().local);
+        assert_eq!(server.peer_addr(), peer_addr);
+
+        server.write_all(b"hello").await.unwrap();
+        let mut greeting = [0; 5];
+        client.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        client.write_all(b"bye").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+
+        server.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::Result;
+use pnet::packet::Packet;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::backlog::ReceivedPacket;
+use crate::device::{Device, OutgoingSegment};
+use crate::packet::TCPPacket;
+use crate::socket::Addr;
+use crate::stats::TxRingCounters;
+use crate::sync::LockResultExt;
+
+/// 送信するセグメントを溜めておく有界キュー(TXリング)
+struct TxRing {
+    queue: Mutex<VecDeque<OutgoingSegment>>,
+    // 送信スレッドを起こす
+    not_empty: Condvar,
+    // リングが一杯で待っているsendを起こす
+    not_full: Condvar,
+    capacity: usize,
+    // TxRingDeviceが捨てられたら, 残りを書き出して送信スレッドを終わらせる
+    shutdown: AtomicBool,
+}
+
+/// sendしたセグメントをTXリングに積み, 専用の送信スレッドがまとめてinnerに書き出すデバイスのラッパー
+/// 各ソケットは送信の完了を待たずに返れるので, 送信スレッドに溜まった分を連続して書き込める
+/// ペーシングなどの送信のスケジューリングも送信スレッドに集約できる
+/// innerがsend_batchで1度に書き出せない場合(RawDeviceなど)は書き込みの回数自体は減らない
+pub struct TxRingDevice {
+    inner: Arc<dyn Device>,
+    ring: Arc<TxRing>,
+    counters: Arc<TxRingCounters>,
+    sender: Option<JoinHandle<()>>,
+}
+
+impl TxRingDevice {
+    /// 送信スレッドを起動する. リングにはcapacity個までセグメントを積める
+    pub fn new(inner: Arc<dyn Device>, capacity: usize, counters: Arc<TxRingCounters>) -> Self {
+        let ring = Arc::new(TxRing {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: capacity.max(1),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let cloned_inner = inner.clone();
+        let cloned_ring = ring.clone();
+        let cloned_counters = counters.clone();
+        let sender = thread::spawn(move || drain(cloned_inner, cloned_ring, cloned_counters));
+
+        Self {
+            inner,
+            ring,
+            counters,
+            sender: Some(sender),
+        }
+    }
+}
+
+impl Drop for TxRingDevice {
+    fn drop(&mut self) {
+        // 待機中の送信スレッドがshutdownを見落とさないよう, queueのロックを取ってから起こす
+        let queue = self.ring.queue.lock().recover();
+        self.ring.shutdown.store(true, Ordering::SeqCst);
+        self.ring.not_empty.notify_one();
+        drop(queue);
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.join();
+        }
+    }
+}
+
+/// 送信スレッドの関数. リングに溜まっているセグメントを全て取り出して1度に書き出す
+/// shutdownされたらリングに残っている分を書き出してから終わる
+fn drain(inner: Arc<dyn Device>, ring: Arc<TxRing>, counters: Arc<TxRingCounters>) {
+    loop {
+        let mut queue = ring.queue.lock().recover();
+        while queue.is_empty() {
+            if ring.shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            queue = ring.not_empty.wait(queue).recover();
+        }
+        let batch: Vec<OutgoingSegment> = queue.drain(..).collect();
+        drop(queue);
+        ring.not_full.notify_all();
+
+        match inner.send_batch(&batch) {
+            Ok(writes) => counters.record_batch(batch.len(), writes),
+            // 送れなかったセグメントは再送に任せる
+            Err(error) => {
+                dbg!(error);
+            }
+        }
+    }
+}
+
+impl Device for TxRingDevice {
+    fn send(&self, packet: &TCPPacket, local_addr: Addr, remote_addr: Addr) -> Result<usize> {
+        let mut queue = self.ring.queue.lock().recover();
+        if queue.len() >= self.ring.capacity {
+            // 送信スレッドが追いつくまで待つ
+            self.counters.record_full_wait();
+            while queue.len() >= self.ring.capacity {
+                queue = self.ring.not_full.wait(queue).recover();
+            }
+        }
+        queue.push_back(OutgoingSegment {
+            packet: packet.clone(),
+            local_addr,
+            remote_addr,
+        });
+        self.ring.not_empty.notify_one();
+        Ok(packet.packet().len())
+    }
+
+    fn recv(&self, timeout: Option<Duration>) -> Result<Option<ReceivedPacket>> {
+        self.inner.recv(timeout)
+    }
+
+    fn source_addr(&self, remote_addr: Addr) -> Result<Addr> {
+        self.inner.source_addr(remote_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::LoopbackDevice;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn dropping_the_ring_flushes_queued_segments_and_stops_the_sender() {
+        let inner = Arc::new(LoopbackDevice::default());
+        let counters = Arc::new(TxRingCounters::default());
+        let ring = TxRingDevice::new(inner.clone(), 16, counters.clone());
+        for _ in 0..10 {
+            ring.send(&TCPPacket::new(0), Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST)
+                .unwrap();
+        }
+
+        // 送信スレッドが終わるまで戻らないので, この時点で全て書き出されている
+        drop(ring);
+        assert_eq!(counters.snapshot().packets, 10);
+        for _ in 0..10 {
+            assert!(inner.recv(Some(Duration::ZERO)).unwrap().is_some());
+        }
+        assert_eq!(Arc::strong_count(&inner), 1);
+    }
+}